@@ -0,0 +1,382 @@
+use super::Point;
+use anyhow::Result;
+use std::f64::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+
+/*
+ * @file crs.rs
+ * 坐标参考系以及坐标转换
+ * 所有的转换都以WGS84经纬度为中转:  源坐标 -> WGS84 -> 目标坐标
+ */
+
+/// 支持的坐标参考系
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Crs {
+    /// WGS84经纬度 EPSG:4326
+    #[default]
+    Wgs84,
+    /// 国测局坐标(火星坐标) 高德、腾讯等国内图源使用
+    Gcj02,
+    /// 百度坐标 在GCJ-02基础上再次加偏
+    Bd09,
+    /// Web墨卡托 EPSG:3857 单位米 但只有赤道上长度是真实的
+    WebMercator,
+    /// UTM投影 WGS84椭球 单位米
+    Utm { zone: u8, north: bool },
+}
+
+// WGS84椭球
+const WGS84_A: f64 = 6378137.0;
+const WGS84_F: f64 = 1.0 / 298.257223563;
+const UTM_K0: f64 = 0.9996;
+
+// GCJ-02使用的克拉索夫斯基椭球
+const KRASOVSKY_A: f64 = 6378245.0;
+const KRASOVSKY_EE: f64 = 0.006_693_421_622_965_943;
+
+const BD_X_PI: f64 = PI * 3000.0 / 180.0;
+
+impl Crs {
+    /// 是否为经纬度坐标
+    pub fn is_geographic(&self) -> bool {
+        matches!(self, Crs::Wgs84 | Crs::Gcj02 | Crs::Bd09)
+    }
+
+    /// 坐标单位是否为真实的米 可以直接计算欧式距离
+    pub fn is_metric(&self) -> bool {
+        matches!(self, Crs::Utm { .. })
+    }
+
+    /// 经纬度所在的UTM分带
+    pub fn utm_for(point: &Point) -> Crs {
+        let zone = (((point.0 + 180.0) / 6.0).floor() as i64).clamp(0, 59) + 1;
+        Crs::Utm {
+            zone: zone as u8,
+            north: point.1 >= 0.0,
+        }
+    }
+
+    /// 转换为WGS84经纬度
+    pub fn to_wgs84(&self, p: &Point) -> Point {
+        match self {
+            Crs::Wgs84 => *p,
+            Crs::Gcj02 => gcj02_to_wgs84(p),
+            Crs::Bd09 => gcj02_to_wgs84(&bd09_to_gcj02(p)),
+            Crs::WebMercator => Point(
+                (p.0 / WGS84_A).to_degrees(),
+                (2.0 * (p.1 / WGS84_A).exp().atan() - PI / 2.0).to_degrees(),
+            ),
+            Crs::Utm { zone, north } => utm_inverse(p, *zone, *north),
+        }
+    }
+
+    /// 从WGS84经纬度转换到当前坐标系
+    pub fn from_wgs84(&self, p: &Point) -> Point {
+        match self {
+            Crs::Wgs84 => *p,
+            Crs::Gcj02 => wgs84_to_gcj02(p),
+            Crs::Bd09 => gcj02_to_bd09(&wgs84_to_gcj02(p)),
+            Crs::WebMercator => {
+                // 超出web墨卡托的纬度范围时截断
+                let lat = p.1.clamp(-85.051_128_78, 85.051_128_78).to_radians();
+                Point(
+                    WGS84_A * p.0.to_radians(),
+                    WGS84_A * (PI / 4.0 + lat / 2.0).tan().ln(),
+                )
+            }
+            Crs::Utm { zone, north } => utm_forward(p, *zone, *north),
+        }
+    }
+
+    /// 坐标转换
+    pub fn transform(&self, p: &Point, to: &Crs) -> Point {
+        if self == to {
+            return *p;
+        }
+        to.from_wgs84(&self.to_wgs84(p))
+    }
+}
+
+impl FromStr for Crs {
+    type Err = anyhow::Error;
+    /// 支持 wgs84 gcj02 bd09 webmercator utm:51n utm:50s 以及对应的EPSG编码
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim().to_ascii_lowercase();
+        match s.as_str() {
+            "wgs84" | "epsg:4326" => return Ok(Crs::Wgs84),
+            "gcj02" | "gcj-02" => return Ok(Crs::Gcj02),
+            "bd09" | "bd-09" => return Ok(Crs::Bd09),
+            "webmercator" | "epsg:3857" | "epsg:900913" => return Ok(Crs::WebMercator),
+            _ => {}
+        }
+        let utm = |zone: &str, north: bool| -> Result<Crs> {
+            let zone: u8 = zone.parse()?;
+            if !(1..=60).contains(&zone) {
+                return Err(anyhow::anyhow!("utm zone {} out of range 1-60", zone));
+            }
+            Ok(Crs::Utm { zone, north })
+        };
+        if let Some(code) = s.strip_prefix("epsg:") {
+            if let Some(zone) = code.strip_prefix("326") {
+                return utm(zone, true);
+            }
+            if let Some(zone) = code.strip_prefix("327") {
+                return utm(zone, false);
+            }
+        }
+        if let Some(zone) = s.strip_prefix("utm:") {
+            if let Some(zone) = zone.strip_suffix('n') {
+                return utm(zone, true);
+            }
+            if let Some(zone) = zone.strip_suffix('s') {
+                return utm(zone, false);
+            }
+            return utm(zone, true);
+        }
+        Err(anyhow::anyhow!("unsupported crs {}", s))
+    }
+}
+
+impl fmt::Display for Crs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Crs::Wgs84 => write!(f, "wgs84"),
+            Crs::Gcj02 => write!(f, "gcj02"),
+            Crs::Bd09 => write!(f, "bd09"),
+            Crs::WebMercator => write!(f, "webmercator"),
+            Crs::Utm { zone, north } => write!(f, "utm:{}{}", zone, if *north { "n" } else { "s" }),
+        }
+    }
+}
+
+// GCJ-02只在中国境内加偏
+fn out_of_china(p: &Point) -> bool {
+    p.0 < 72.004 || p.0 > 137.8347 || p.1 < 0.8293 || p.1 > 55.8271
+}
+
+fn transform_lat(x: f64, y: f64) -> f64 {
+    let mut ret = -100.0 + 2.0 * x + 3.0 * y + 0.2 * y * y + 0.1 * x * y + 0.2 * x.abs().sqrt();
+    ret += (20.0 * (6.0 * x * PI).sin() + 20.0 * (2.0 * x * PI).sin()) * 2.0 / 3.0;
+    ret += (20.0 * (y * PI).sin() + 40.0 * (y / 3.0 * PI).sin()) * 2.0 / 3.0;
+    ret += (160.0 * (y / 12.0 * PI).sin() + 320.0 * (y * PI / 30.0).sin()) * 2.0 / 3.0;
+    ret
+}
+
+fn transform_lng(x: f64, y: f64) -> f64 {
+    let mut ret = 300.0 + x + 2.0 * y + 0.1 * x * x + 0.1 * x * y + 0.1 * x.abs().sqrt();
+    ret += (20.0 * (6.0 * x * PI).sin() + 20.0 * (2.0 * x * PI).sin()) * 2.0 / 3.0;
+    ret += (20.0 * (x * PI).sin() + 40.0 * (x / 3.0 * PI).sin()) * 2.0 / 3.0;
+    ret += (150.0 * (x / 12.0 * PI).sin() + 300.0 * (x / 30.0 * PI).sin()) * 2.0 / 3.0;
+    ret
+}
+
+pub fn wgs84_to_gcj02(p: &Point) -> Point {
+    if out_of_china(p) {
+        return *p;
+    }
+    let mut dlat = transform_lat(p.0 - 105.0, p.1 - 35.0);
+    let mut dlng = transform_lng(p.0 - 105.0, p.1 - 35.0);
+    let radlat = p.1.to_radians();
+    let magic = 1.0 - KRASOVSKY_EE * radlat.sin() * radlat.sin();
+    let sqrt_magic = magic.sqrt();
+    dlat = (dlat * 180.0) / ((KRASOVSKY_A * (1.0 - KRASOVSKY_EE)) / (magic * sqrt_magic) * PI);
+    dlng = (dlng * 180.0) / (KRASOVSKY_A / sqrt_magic * radlat.cos() * PI);
+    Point(p.0 + dlng, p.1 + dlat)
+}
+
+/// GCJ-02没有解析的逆变换 通过迭代逼近 误差在毫米级
+pub fn gcj02_to_wgs84(p: &Point) -> Point {
+    if out_of_china(p) {
+        return *p;
+    }
+    let mut wgs = *p;
+    for _ in 0..10 {
+        let gcj = wgs84_to_gcj02(&wgs);
+        let (dx, dy) = (gcj.0 - p.0, gcj.1 - p.1);
+        wgs = Point(wgs.0 - dx, wgs.1 - dy);
+        if dx.abs() < 1e-10 && dy.abs() < 1e-10 {
+            break;
+        }
+    }
+    wgs
+}
+
+pub fn gcj02_to_bd09(p: &Point) -> Point {
+    let z = (p.0 * p.0 + p.1 * p.1).sqrt() + 0.00002 * (p.1 * BD_X_PI).sin();
+    let theta = p.1.atan2(p.0) + 0.000003 * (p.0 * BD_X_PI).cos();
+    Point(z * theta.cos() + 0.0065, z * theta.sin() + 0.006)
+}
+
+pub fn bd09_to_gcj02(p: &Point) -> Point {
+    let x = p.0 - 0.0065;
+    let y = p.1 - 0.006;
+    let z = (x * x + y * y).sqrt() - 0.00002 * (y * BD_X_PI).sin();
+    let theta = y.atan2(x) - 0.000003 * (x * BD_X_PI).cos();
+    Point(z * theta.cos(), z * theta.sin())
+}
+
+// 横轴墨卡托投影 Snyder, Map Projections: A Working Manual
+fn utm_forward(p: &Point, zone: u8, north: bool) -> Point {
+    let e2 = WGS84_F * (2.0 - WGS84_F);
+    let ep2 = e2 / (1.0 - e2);
+    let lon0 = (zone as f64 - 1.0) * 6.0 - 180.0 + 3.0;
+    let phi = p.1.to_radians();
+    let (sin_phi, cos_phi, tan_phi) = (phi.sin(), phi.cos(), phi.tan());
+    let n = WGS84_A / (1.0 - e2 * sin_phi * sin_phi).sqrt();
+    let t = tan_phi * tan_phi;
+    let c = ep2 * cos_phi * cos_phi;
+    let a = cos_phi * (p.0 - lon0).to_radians();
+    let m = meridian_arc(phi, e2);
+    let x = UTM_K0
+        * n
+        * (a + (1.0 - t + c) * a.powi(3) / 6.0
+            + (5.0 - 18.0 * t + t * t + 72.0 * c - 58.0 * ep2) * a.powi(5) / 120.0)
+        + 500000.0;
+    let mut y = UTM_K0
+        * (m + n
+            * tan_phi
+            * (a * a / 2.0
+                + (5.0 - t + 9.0 * c + 4.0 * c * c) * a.powi(4) / 24.0
+                + (61.0 - 58.0 * t + t * t + 600.0 * c - 330.0 * ep2) * a.powi(6) / 720.0));
+    if !north {
+        y += 10000000.0;
+    }
+    Point(x, y)
+}
+
+fn utm_inverse(p: &Point, zone: u8, north: bool) -> Point {
+    let e2 = WGS84_F * (2.0 - WGS84_F);
+    let ep2 = e2 / (1.0 - e2);
+    let lon0 = (zone as f64 - 1.0) * 6.0 - 180.0 + 3.0;
+    let x = p.0 - 500000.0;
+    let y = if north { p.1 } else { p.1 - 10000000.0 };
+    let m = y / UTM_K0;
+    let mu = m / (WGS84_A * (1.0 - e2 / 4.0 - 3.0 * e2 * e2 / 64.0 - 5.0 * e2.powi(3) / 256.0));
+    let e1 = (1.0 - (1.0 - e2).sqrt()) / (1.0 + (1.0 - e2).sqrt());
+    let phi1 = mu
+        + (3.0 * e1 / 2.0 - 27.0 * e1.powi(3) / 32.0) * (2.0 * mu).sin()
+        + (21.0 * e1 * e1 / 16.0 - 55.0 * e1.powi(4) / 32.0) * (4.0 * mu).sin()
+        + (151.0 * e1.powi(3) / 96.0) * (6.0 * mu).sin()
+        + (1097.0 * e1.powi(4) / 512.0) * (8.0 * mu).sin();
+    let (sin_phi1, cos_phi1, tan_phi1) = (phi1.sin(), phi1.cos(), phi1.tan());
+    let n1 = WGS84_A / (1.0 - e2 * sin_phi1 * sin_phi1).sqrt();
+    let t1 = tan_phi1 * tan_phi1;
+    let c1 = ep2 * cos_phi1 * cos_phi1;
+    let r1 = WGS84_A * (1.0 - e2) / (1.0 - e2 * sin_phi1 * sin_phi1).powf(1.5);
+    let d = x / (n1 * UTM_K0);
+    let phi = phi1
+        - (n1 * tan_phi1 / r1)
+            * (d * d / 2.0
+                - (5.0 + 3.0 * t1 + 10.0 * c1 - 4.0 * c1 * c1 - 9.0 * ep2) * d.powi(4) / 24.0
+                + (61.0 + 90.0 * t1 + 298.0 * c1 + 45.0 * t1 * t1 - 252.0 * ep2 - 3.0 * c1 * c1)
+                    * d.powi(6)
+                    / 720.0);
+    let lon = (d - (1.0 + 2.0 * t1 + c1) * d.powi(3) / 6.0
+        + (5.0 - 2.0 * c1 + 28.0 * t1 - 3.0 * c1 * c1 + 8.0 * ep2 + 24.0 * t1 * t1) * d.powi(5)
+            / 120.0)
+        / cos_phi1;
+    Point(lon0 + lon.to_degrees(), phi.to_degrees())
+}
+
+// 赤道到纬度phi的子午线弧长
+fn meridian_arc(phi: f64, e2: f64) -> f64 {
+    let e4 = e2 * e2;
+    let e6 = e4 * e2;
+    WGS84_A
+        * ((1.0 - e2 / 4.0 - 3.0 * e4 / 64.0 - 5.0 * e6 / 256.0) * phi
+            - (3.0 * e2 / 8.0 + 3.0 * e4 / 32.0 + 45.0 * e6 / 1024.0) * (2.0 * phi).sin()
+            + (15.0 * e4 / 256.0 + 45.0 * e6 / 1024.0) * (4.0 * phi).sin()
+            - (35.0 * e6 / 3072.0) * (6.0 * phi).sin())
+}
+
+// test
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::algorithm::Metric;
+
+    fn assert_close(a: &Point, b: &Point, eps: f64) {
+        assert!(
+            (a.0 - b.0).abs() < eps && (a.1 - b.1).abs() < eps,
+            "{:?} != {:?}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn test_parse_crs() {
+        assert_eq!("EPSG:4326".parse::<Crs>().unwrap(), Crs::Wgs84);
+        assert_eq!(
+            "epsg:32651".parse::<Crs>().unwrap(),
+            Crs::Utm {
+                zone: 51,
+                north: true
+            }
+        );
+        assert_eq!(
+            "utm:50s".parse::<Crs>().unwrap(),
+            Crs::Utm {
+                zone: 50,
+                north: false
+            }
+        );
+        assert_eq!("gcj02".parse::<Crs>().unwrap().to_string(), "gcj02");
+        assert!("utm:61n".parse::<Crs>().is_err());
+        assert!("epsg:2000".parse::<Crs>().is_err());
+    }
+
+    #[test]
+    fn test_utm() {
+        // 中央经线和赤道的交点
+        let utm = Crs::Utm {
+            zone: 31,
+            north: true,
+        };
+        assert_close(
+            &utm.from_wgs84(&Point(3.0, 0.0)),
+            &Point(500000.0, 0.0),
+            1e-6,
+        );
+        let shanghai = Point(121.4737, 31.2304);
+        let utm = Crs::utm_for(&shanghai);
+        assert_eq!(
+            utm,
+            Crs::Utm {
+                zone: 51,
+                north: true
+            }
+        );
+        let projected = utm.from_wgs84(&shanghai);
+        assert_close(&utm.to_wgs84(&projected), &shanghai, 1e-8);
+        // 投影后的距离和haversine距离一致
+        let other = Point(121.4837, 31.2354);
+        let planar = Metric::Euclidean.distance(&projected, &utm.from_wgs84(&other));
+        let haversine = Metric::Haversine.distance(&shanghai, &other);
+        assert!((planar - haversine).abs() / haversine < 0.005);
+    }
+
+    #[test]
+    fn test_web_mercator() {
+        let p = Point(121.4737, 31.2304);
+        let projected = Crs::WebMercator.from_wgs84(&p);
+        assert_close(&Crs::WebMercator.to_wgs84(&projected), &p, 1e-9);
+    }
+
+    #[test]
+    fn test_gcj02_bd09() {
+        let p = Point(121.4737, 31.2304);
+        let gcj = Crs::Wgs84.transform(&p, &Crs::Gcj02);
+        // 上海地区火星坐标的偏移大约是500米
+        let offset = Metric::Haversine.distance(&p, &gcj);
+        assert!(offset > 300.0 && offset < 700.0);
+        assert_close(&Crs::Gcj02.transform(&gcj, &Crs::Wgs84), &p, 1e-7);
+        let bd = Crs::Wgs84.transform(&p, &Crs::Bd09);
+        // 百度坐标的逆变换是近似的 误差在米级以下
+        assert_close(&Crs::Bd09.transform(&bd, &Crs::Wgs84), &p, 1e-5);
+        // 境外不加偏
+        let london = Point(-0.1276, 51.5072);
+        assert_close(&Crs::Wgs84.transform(&london, &Crs::Gcj02), &london, 1e-12);
+    }
+}
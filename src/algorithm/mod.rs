@@ -1,3 +1,6 @@
+pub mod crs;
+pub use crs::Crs;
+
 use anyhow::Result;
 use geo::algorithm::haversine_distance::HaversineDistance;
 use geojson::{Geometry, Value};
@@ -6,6 +9,144 @@ use geojson::{Geometry, Value};
 pub struct Point(pub f64, pub f64);
 pub struct Line(pub Vec<[f64; 2]>);
 
+/// 地球平均半径 与geo的haversine保持一致 单位米
+pub const EARTH_RADIUS: f64 = 6371008.8;
+
+/// 距离的度量方式
+/// Haversine: 坐标为经纬度, 距离单位为米, 投影时在查询点附近做局部等距投影
+/// Euclidean: 直接在坐标上计算欧式距离, 经纬度坐标下单位为度(兼容旧版本)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Metric {
+    #[default]
+    Haversine,
+    Euclidean,
+}
+
+impl Metric {
+    /// 两点之间的距离
+    pub fn distance(&self, pa: &Point, pb: &Point) -> f64 {
+        match self {
+            Metric::Haversine => {
+                use geo::Point;
+                Point::new(pa.0, pa.1).haversine_distance(&Point::new(pb.0, pb.1))
+            }
+            Metric::Euclidean => eu_distance(pa, pb),
+        }
+    }
+
+    /// linestring的长度
+    pub fn linestring_length(&self, geometry: &Geometry) -> Result<f64> {
+        match self {
+            Metric::Haversine => linestring_distance(geometry),
+            Metric::Euclidean => linestring_eu_distance(geometry),
+        }
+    }
+
+    /// 点到linestring的最短距离
+    /// 返回结果 (min_distance, offset, offset_point), 距离和偏移量的单位由度量方式决定
+    pub fn linear_reference(&self, point: Point, line: &[[f64; 2]]) -> (f64, f64, Point) {
+        match self {
+            Metric::Haversine => {
+                let projection = LocalProjection::new(point);
+                let local: Vec<[f64; 2]> = line.iter().map(|p| projection.forward(p)).collect();
+                let (distance, offset, close_point) =
+                    linear_reference_distance(Point(0.0, 0.0), &local);
+                (distance, offset, projection.inverse(&close_point))
+            }
+            Metric::Euclidean => linear_reference_distance(point, line),
+        }
+    }
+
+    /// linestring上偏移量从start到end的部分 start大于end时返回空
+    pub fn substring(&self, line: &[[f64; 2]], start: f64, end: f64) -> Vec<[f64; 2]> {
+        let mut result: Vec<[f64; 2]> = vec![];
+        if line.is_empty() || start > end {
+            return result;
+        }
+        let mut walked = 0.0;
+        let count = line.len().saturating_sub(1);
+        for (index, segment) in line.windows(2).enumerate() {
+            let (a, b) = (segment[0], segment[1]);
+            let length = self.distance(&Point(a[0], a[1]), &Point(b[0], b[1]));
+            let interpolate = |offset: f64| {
+                let ratio = if length > 0.0 {
+                    ((offset - walked) / length).clamp(0.0, 1.0)
+                } else {
+                    0.0
+                };
+                [a[0] + (b[0] - a[0]) * ratio, a[1] + (b[1] - a[1]) * ratio]
+            };
+            if result.is_empty() && (start < walked + length || index + 1 == count) {
+                result.push(interpolate(start));
+            }
+            if !result.is_empty() {
+                if end <= walked + length {
+                    result.push(interpolate(end));
+                    return result;
+                }
+                result.push(b);
+            }
+            walked += length;
+        }
+        // 偏移量超出长度时截止到终点
+        if result.is_empty() {
+            result.push(*line.last().unwrap());
+        }
+        result
+    }
+
+    /// from指向to的方向角 单位度 以x轴正方向(东)为0 逆时针为正
+    pub fn heading(&self, from: &Point, to: &Point) -> f64 {
+        let (dx, dy) = match self {
+            Metric::Haversine => {
+                let local = LocalProjection::new(*from).forward(&[to.0, to.1]);
+                (local[0], local[1])
+            }
+            Metric::Euclidean => (to.0 - from.0, to.1 - from.1),
+        };
+        dy.atan2(dx).to_degrees()
+    }
+
+    /// 距离换算成point处经度和纬度方向的坐标跨度 用于构建查询的bbox
+    pub fn coord_span(&self, distance: f64, at: &Point) -> (f64, f64) {
+        match self {
+            Metric::Haversine => {
+                let projection = LocalProjection::new(*at);
+                (distance / projection.kx, distance / projection.ky)
+            }
+            Metric::Euclidean => (distance, distance),
+        }
+    }
+}
+
+// 以origin为中心的局部等距圆柱投影 投影后的单位为米
+// 城市范围内和haversine距离的误差很小
+struct LocalProjection {
+    origin: Point,
+    kx: f64, // 每度经度对应的米数
+    ky: f64, // 每度纬度对应的米数
+}
+
+impl LocalProjection {
+    fn new(origin: Point) -> Self {
+        let ky = EARTH_RADIUS.to_radians();
+        // 避免在极点附近除0
+        let kx = ky * origin.1.to_radians().cos().max(1e-6);
+        LocalProjection { origin, kx, ky }
+    }
+
+    fn forward(&self, p: &[f64; 2]) -> [f64; 2] {
+        [
+            (p[0] - self.origin.0) * self.kx,
+            (p[1] - self.origin.1) * self.ky,
+        ]
+    }
+
+    fn inverse(&self, p: &Point) -> Point {
+        Point(p.0 / self.kx + self.origin.0, p.1 / self.ky + self.origin.1)
+    }
+}
+
 impl TryInto<Line> for geojson::Geometry {
     type Error = anyhow::Error;
     fn try_into(self) -> Result<Line> {
@@ -25,6 +166,7 @@ impl TryInto<Line> for geojson::Geometry {
 /// 计算linestring的半正弦距离
 pub fn linestring_distance(geometry: &Geometry) -> Result<f64> {
     match &geometry.value {
+        Value::LineString(line_string) if line_string.len() < 2 => Ok(0.0),
         Value::LineString(line_string) => {
             let mut distance = 0.0;
             let line1 = &line_string[0..line_string.len() - 1];
@@ -42,9 +184,21 @@ pub fn linestring_distance(geometry: &Geometry) -> Result<f64> {
 // 点到直线的最短距离
 // 返回结果 最短距离, 偏移距离, 偏移点
 // (min_distance, offset, offset_point)
+// 只有一个点的线返回到该点的距离 空的线距离为f64::MAX
 pub fn linear_reference_distance(point: Point, line: &[[f64; 2]]) -> (f64, f64, Point) {
+    match line {
+        [] => return (f64::MAX, 0.0, point),
+        [p] => {
+            return (
+                eu_distance(&point, &Point(p[0], p[1])),
+                0.0,
+                Point(p[0], p[1]),
+            )
+        }
+        _ => {}
+    }
     let mut min_distance = f64::MAX;
-    let mut length_parsed = 0 as f64;
+    let mut length_parsed = 0.0;
     let mut final_offset = f64::MAX;
     let mut final_offset_point = Point(0.0, 0.0);
     let line1 = &line[0..line.len() - 1];
@@ -76,14 +230,9 @@ fn point_to_line_distance(point: Point, start: Point, end: Point) -> (f64, f64,
     let vec_start_point = (point.0 - start.0, point.1 - start.1);
     let vec_start_end = (end.0 - start.0, end.1 - start.1);
     let dot = vec_start_point.0 * vec_start_end.0 + vec_start_point.1 * vec_start_end.1;
-    let radio = dot / length * length;
-    let radio = if radio > 1 as f64 {
-        1.0
-    } else if radio < 0 as f64 {
-        0.0
-    } else {
-        radio
-    };
+    // 投影比例为 dot / length^2, 旧版本写成了 dot / length * length, 长度不为1的线段上投影点错误
+    let radio = dot / (length * length);
+    let radio = radio.clamp(0.0, 1.0);
     // 投影点坐标
     let offset_point = Point(
         start.0 + radio * vec_start_end.0,
@@ -104,6 +253,7 @@ pub fn eu_distance(pa: &Point, pb: &Point) -> f64 {
 // linestring的欧式距离
 pub fn linestring_eu_distance(geometry: &Geometry) -> Result<f64> {
     match &geometry.value {
+        Value::LineString(line_string) if line_string.len() < 2 => Ok(0.0),
         Value::LineString(line_string) => {
             let mut distance = 0.0;
             let line1 = &line_string[0..line_string.len() - 1];
@@ -116,3 +266,90 @@ pub fn linestring_eu_distance(geometry: &Geometry) -> Result<f64> {
         _ => Err(anyhow::anyhow!("geometry is not linestring")),
     }
 }
+
+// test
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_point_to_line_distance() {
+        // 经纬度下约100米的线段 投影点在线段中间 而不是被截断到起点
+        let (distance, offset, p) = point_to_line_distance(
+            Point(121.0004, 31.0002),
+            Point(121.0, 31.0),
+            Point(121.001, 31.0),
+        );
+        assert!((distance - 0.0002).abs() < 1e-12, "{}", distance);
+        assert!((offset - 0.0004).abs() < 1e-12, "{}", offset);
+        assert!((p.0 - 121.0004).abs() < 1e-12 && (p.1 - 31.0).abs() < 1e-12);
+
+        let line = [[121.0, 31.0], [121.001, 31.0], [121.001, 31.001]];
+        let (distance, offset, p) = linear_reference_distance(Point(121.0012, 31.0005), &line);
+        assert!((distance - 0.0002).abs() < 1e-12, "{}", distance);
+        assert!((offset - 0.0015).abs() < 1e-12, "{}", offset);
+        assert!((p.0 - 121.001).abs() < 1e-12 && (p.1 - 31.0005).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_metric_linear_reference() {
+        let line = [[121.0, 31.0], [121.01, 31.0]];
+        let point = Point(121.005, 31.001);
+        let (distance, offset, close_point) = Metric::Euclidean.linear_reference(point, &line);
+        assert!((distance - 0.001).abs() < 1e-9);
+        assert!((offset - 0.005).abs() < 1e-9);
+        assert!((close_point.0 - 121.005).abs() < 1e-9);
+
+        let (distance, offset, close_point) = Metric::Haversine.linear_reference(point, &line);
+        let expected = Metric::Haversine.distance(&point, &Point(121.005, 31.0));
+        assert!((distance - expected).abs() < 0.01);
+        let expected = Metric::Haversine.distance(&Point(121.0, 31.0), &Point(121.005, 31.0));
+        assert!((offset - expected).abs() < 0.01);
+        assert!((close_point.0 - 121.005).abs() < 1e-9);
+        assert!((close_point.1 - 31.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_linear_reference_distance() {
+        let line = [[0.0, 0.0], [2.0, 0.0], [2.0, 2.0]];
+        // 投影在线段内部 以及第二段上 偏移量累加前面线段的长度
+        assert_eq!(linear_reference_distance(Point(1.0, 1.0), &line).0, 1.0);
+        let (distance, offset, p) = linear_reference_distance(Point(3.0, 1.5), &line);
+        assert_eq!((distance, offset, p.0, p.1), (1.0, 3.5, 2.0, 1.5));
+        // 起点之前和终点之后取端点
+        let (distance, offset, p) = linear_reference_distance(Point(-3.0, -4.0), &line);
+        assert_eq!((distance, offset, p.0, p.1), (5.0, 0.0, 0.0, 0.0));
+        let (distance, offset, p) = linear_reference_distance(Point(2.0, 5.0), &line);
+        assert_eq!((distance, offset, p.0, p.1), (3.0, 4.0, 2.0, 2.0));
+        // 在拐点上 以及到两段距离相同时取前一段
+        let (distance, offset, _) = linear_reference_distance(Point(2.0, 0.0), &line);
+        assert_eq!((distance, offset), (0.0, 2.0));
+        let (distance, offset, _) = linear_reference_distance(Point(3.0, -1.0), &line);
+        assert!((distance - 2.0_f64.sqrt()).abs() < 1e-12);
+        assert_eq!(offset, 2.0);
+        // 重复的顶点 长度为0的线段不影响偏移量
+        let repeated = [[0.0, 0.0], [1.0, 0.0], [1.0, 0.0], [1.0, 1.0]];
+        let (distance, offset, _) = linear_reference_distance(Point(1.5, 0.5), &repeated);
+        assert_eq!((distance, offset), (0.5, 1.5));
+        // 只有一个点 以及空的线
+        let (distance, offset, p) = linear_reference_distance(Point(3.0, 4.0), &[[0.0, 0.0]]);
+        assert_eq!((distance, offset, p.0, p.1), (5.0, 0.0, 0.0, 0.0));
+        assert_eq!(linear_reference_distance(Point(3.0, 4.0), &[]).0, f64::MAX);
+    }
+
+    #[test]
+    fn test_metric_substring() {
+        let line = [[0.0, 0.0], [1.0, 0.0], [1.0, 2.0]];
+        let metric = Metric::Euclidean;
+        assert_eq!(
+            metric.substring(&line, 0.5, 2.0),
+            vec![[0.5, 0.0], [1.0, 0.0], [1.0, 1.0]]
+        );
+        assert_eq!(
+            metric.substring(&line, 1.0, 3.0),
+            vec![[1.0, 0.0], [1.0, 2.0]]
+        );
+        assert_eq!(metric.substring(&line, 0.0, 10.0), line.to_vec());
+        assert!(metric.substring(&line, 2.0, 1.0).is_empty());
+    }
+}
@@ -0,0 +1,636 @@
+use super::graph::RoadGraph;
+use super::path::{MinState, Path};
+use super::snapshot::{section_end, Cursor};
+use anyhow::Result;
+use log::{debug, info};
+use memmap2::Mmap;
+use petgraph::graph::EdgeIndex;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path as FsPath, PathBuf};
+
+/*
+ * @file ch.rs
+ * Contraction Hierarchies
+ * 按照重要性依次收缩节点, 收缩时添加保持最短路径不变的shortcut,
+ * 查询时起点和终点都只沿着rank升高的方向搜索, 搜索空间远小于Dijkstra。
+ * 只包含原始路网 不包含匹配过程中加入的虚拟节点 不支持转向限制。
+ *
+ * 文件布局(小端序):
+ *   header | magic[8] version:u32 pad:u32 node_count:u64 arc_count:u64 fingerprint:u64
+ *   ranks  | node_count * u64
+ *   arcs   | arc_count * (from:u32 to:u32 kind:u32 pad:u32 a:u64 b:u64 weight:f64)
+ */
+
+pub const CH_MAGIC: &[u8; 8] = b"RMMCH\0\0\0";
+pub const CH_VERSION: u32 = 1;
+const CH_HEADER_SIZE: usize = 8 + 4 + 4 + 8 * 3;
+const CH_ARC_SIZE: usize = 4 * 4 + 8 * 3;
+/// 默认的扩展名 `edges.json` 对应 `edges.json.ch`
+pub const CH_EXTENSION: &str = "ch";
+
+// 见证搜索最多settle的节点数 超过后认为没有见证路径 只会多添加shortcut 不影响正确性
+const WITNESS_SETTLED_LIMIT: usize = 500;
+
+#[derive(Clone, Copy, Debug)]
+enum ArcKind {
+    Edge(usize),            // 路网中的edge索引
+    Shortcut(usize, usize), // 由两条arc组成
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Arc {
+    from: usize,
+    to: usize,
+    weight: f64,
+    kind: ArcKind,
+}
+
+pub struct ContractionHierarchy {
+    rank: Vec<usize>,
+    arcs: Vec<Arc>,
+    up: Vec<Vec<usize>>,   // 从节点出发 指向rank更高节点的arc
+    down: Vec<Vec<usize>>, // 到达节点 来自rank更高节点的arc
+    fingerprint: u64,
+}
+
+// 上行搜索的结果 节点 -> (距离, 到达该节点的arc)
+type SearchSpace = HashMap<usize, (f64, Option<usize>)>;
+
+impl ContractionHierarchy {
+    /// 对路网进行收缩
+    pub fn build(road_graph: &RoadGraph) -> Self {
+        let node_size = road_graph.graph.node_count();
+        let mut arcs: Vec<Arc> = vec![];
+        // 尚未收缩的图 邻接节点 -> (权重, arc)
+        let mut out: Vec<HashMap<usize, (f64, usize)>> = vec![HashMap::new(); node_size];
+        let mut inc: Vec<HashMap<usize, (f64, usize)>> = vec![HashMap::new(); node_size];
+        for index in 0..road_graph.network.edges.len() {
+            let (from, to) = road_graph
+                .graph
+                .edge_endpoints(EdgeIndex::new(index))
+                .unwrap();
+            let (from, to) = (from.index(), to.index());
+            let weight = road_graph.network.edges[index].get_length();
+            if from == to {
+                continue;
+            }
+            arcs.push(Arc {
+                from,
+                to,
+                weight,
+                kind: ArcKind::Edge(index),
+            });
+            let arc = arcs.len() - 1;
+            // 平行边只保留最短的
+            if out[from].get(&to).is_none_or(|(w, _)| weight < *w) {
+                out[from].insert(to, (weight, arc));
+                inc[to].insert(from, (weight, arc));
+            }
+        }
+
+        let mut contracted = vec![false; node_size];
+        let mut deleted_neighbors = vec![0i64; node_size];
+        let mut rank = vec![0; node_size];
+        let mut heap = BinaryHeap::new();
+        for node in 0..node_size {
+            let shortcuts = Self::shortcuts(&out, &inc, &contracted, node);
+            let priority = Self::priority(&out, &inc, node, shortcuts.len(), 0);
+            heap.push(Reverse((priority, node)));
+        }
+        let mut order = 0;
+        while let Some(Reverse((_, node))) = heap.pop() {
+            if contracted[node] {
+                continue;
+            }
+            // 延迟更新 优先级变差时重新放回堆中
+            let shortcuts = Self::shortcuts(&out, &inc, &contracted, node);
+            let priority =
+                Self::priority(&out, &inc, node, shortcuts.len(), deleted_neighbors[node]);
+            if let Some(Reverse((next, _))) = heap.peek() {
+                if priority > *next {
+                    heap.push(Reverse((priority, node)));
+                    continue;
+                }
+            }
+            for (from, to, weight, first, second) in shortcuts {
+                arcs.push(Arc {
+                    from,
+                    to,
+                    weight,
+                    kind: ArcKind::Shortcut(first, second),
+                });
+                let arc = arcs.len() - 1;
+                if out[from].get(&to).is_none_or(|(w, _)| weight < *w) {
+                    out[from].insert(to, (weight, arc));
+                    inc[to].insert(from, (weight, arc));
+                }
+            }
+            for neighbor in out[node].keys().chain(inc[node].keys()) {
+                deleted_neighbors[*neighbor] += 1;
+            }
+            for neighbor in out[node].keys().copied().collect::<Vec<_>>() {
+                inc[neighbor].remove(&node);
+            }
+            for neighbor in inc[node].keys().copied().collect::<Vec<_>>() {
+                out[neighbor].remove(&node);
+            }
+            out[node].clear();
+            inc[node].clear();
+            contracted[node] = true;
+            rank[node] = order;
+            order += 1;
+        }
+        let edge_count = road_graph.network.edges.len();
+        info!(
+            "build contraction hierarchy: {} nodes, {} edges, {} shortcuts",
+            node_size,
+            edge_count,
+            arcs.len()
+                - arcs
+                    .iter()
+                    .filter(|a| matches!(a.kind, ArcKind::Edge(_)))
+                    .count()
+        );
+        Self::from_parts(rank, arcs, fingerprint(road_graph))
+    }
+
+    // 收缩node需要添加的shortcut (from, to, weight, 第一段arc, 第二段arc)
+    fn shortcuts(
+        out: &[HashMap<usize, (f64, usize)>],
+        inc: &[HashMap<usize, (f64, usize)>],
+        contracted: &[bool],
+        node: usize,
+    ) -> Vec<(usize, usize, f64, usize, usize)> {
+        let mut shortcuts = vec![];
+        let max_out = out[node].values().map(|(w, _)| *w).fold(0.0, f64::max);
+        for (&from, &(in_weight, in_arc)) in inc[node].iter() {
+            let witness = Self::witness_search(out, contracted, from, node, in_weight + max_out);
+            for (&to, &(out_weight, out_arc)) in out[node].iter() {
+                if to == from {
+                    continue;
+                }
+                let weight = in_weight + out_weight;
+                if witness.get(&to).is_none_or(|d| *d > weight) {
+                    shortcuts.push((from, to, weight, in_arc, out_arc));
+                }
+            }
+        }
+        shortcuts
+    }
+
+    // 不经过skip节点 从source出发的受限Dijkstra
+    fn witness_search(
+        out: &[HashMap<usize, (f64, usize)>],
+        contracted: &[bool],
+        source: usize,
+        skip: usize,
+        max_cost: f64,
+    ) -> HashMap<usize, f64> {
+        let mut dist: HashMap<usize, f64> = HashMap::new();
+        let mut heap = BinaryHeap::new();
+        let mut settled = 0;
+        dist.insert(source, 0.0);
+        heap.push(MinState {
+            cost: 0.0,
+            item: source,
+        });
+        while let Some(MinState { cost, item }) = heap.pop() {
+            if cost > dist[&item] {
+                continue;
+            }
+            settled += 1;
+            if cost > max_cost || settled > WITNESS_SETTLED_LIMIT {
+                break;
+            }
+            for (&next, &(weight, _)) in out[item].iter() {
+                if next == skip || contracted[next] {
+                    continue;
+                }
+                let next_cost = cost + weight;
+                if next_cost < *dist.get(&next).unwrap_or(&f64::MAX) {
+                    dist.insert(next, next_cost);
+                    heap.push(MinState {
+                        cost: next_cost,
+                        item: next,
+                    });
+                }
+            }
+        }
+        dist
+    }
+
+    // 边差 + 已收缩的邻居数 越小越先收缩
+    fn priority(
+        out: &[HashMap<usize, (f64, usize)>],
+        inc: &[HashMap<usize, (f64, usize)>],
+        node: usize,
+        shortcuts: usize,
+        deleted_neighbors: i64,
+    ) -> i64 {
+        shortcuts as i64 - (out[node].len() + inc[node].len()) as i64 + deleted_neighbors
+    }
+
+    fn from_parts(rank: Vec<usize>, arcs: Vec<Arc>, fingerprint: u64) -> Self {
+        let mut up = vec![vec![]; rank.len()];
+        let mut down = vec![vec![]; rank.len()];
+        for (index, arc) in arcs.iter().enumerate() {
+            if rank[arc.from] < rank[arc.to] {
+                up[arc.from].push(index);
+            } else {
+                down[arc.to].push(index);
+            }
+        }
+        ContractionHierarchy {
+            rank,
+            arcs,
+            up,
+            down,
+            fingerprint,
+        }
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.rank.len()
+    }
+
+    /// 是否由当前的路网构建
+    pub fn is_built_from(&self, road_graph: &RoadGraph) -> bool {
+        self.fingerprint == fingerprint(road_graph)
+    }
+
+    // 沿着rank升高的方向搜索 backward为true时在反向图上搜索
+    fn upward(&self, start: usize, backward: bool) -> SearchSpace {
+        let mut space: SearchSpace = HashMap::new();
+        if start >= self.rank.len() {
+            return space;
+        }
+        let mut heap = BinaryHeap::new();
+        space.insert(start, (0.0, None));
+        heap.push(MinState {
+            cost: 0.0,
+            item: start,
+        });
+        while let Some(MinState { cost, item }) = heap.pop() {
+            if cost > space[&item].0 {
+                continue;
+            }
+            let arcs = if backward {
+                &self.down[item]
+            } else {
+                &self.up[item]
+            };
+            for &index in arcs {
+                let arc = &self.arcs[index];
+                let next = if backward { arc.from } else { arc.to };
+                let next_cost = cost + arc.weight;
+                if next_cost < space.get(&next).map_or(f64::MAX, |s| s.0) {
+                    space.insert(next, (next_cost, Some(index)));
+                    heap.push(MinState {
+                        cost: next_cost,
+                        item: next,
+                    });
+                }
+            }
+        }
+        space
+    }
+
+    /// 两个节点之间的最短路径 路径中的edge为路网edge索引
+    pub fn query(&self, from: usize, to: usize) -> Option<Path> {
+        let forward = self.upward(from, false);
+        let backward = self.upward(to, true);
+        let mut best: Option<(f64, usize)> = None;
+        for (node, (cost, _)) in forward.iter() {
+            if let Some((other, _)) = backward.get(node) {
+                if best.is_none_or(|(b, _)| cost + other < b) {
+                    best = Some((cost + other, *node));
+                }
+            }
+        }
+        let (cost, meet) = best?;
+        let mut arcs = vec![];
+        let mut node = meet;
+        while let Some(arc) = forward[&node].1 {
+            arcs.push(arc);
+            node = self.arcs[arc].from;
+        }
+        arcs.reverse();
+        let mut node = meet;
+        while let Some(arc) = backward[&node].1 {
+            arcs.push(arc);
+            node = self.arcs[arc].to;
+        }
+        let mut path = Path {
+            cost,
+            nodes: vec![from],
+            edges: vec![],
+        };
+        for arc in arcs {
+            self.unpack(arc, &mut path);
+        }
+        Some(path)
+    }
+
+    // 展开shortcut 得到原始的edge
+    fn unpack(&self, arc: usize, path: &mut Path) {
+        let mut stack = vec![arc];
+        while let Some(arc) = stack.pop() {
+            let arc = &self.arcs[arc];
+            match arc.kind {
+                ArcKind::Edge(edge) => {
+                    path.edges.push(edge);
+                    path.nodes.push(arc.to);
+                }
+                ArcKind::Shortcut(first, second) => {
+                    stack.push(second);
+                    stack.push(first);
+                }
+            }
+        }
+    }
+
+    /// 多对多的最短距离 不可达为f64::MAX
+    /// 结果的第i行第j列为sources[i]到targets[j]的距离
+    pub fn many_to_many(&self, sources: &[usize], targets: &[usize]) -> Vec<Vec<f64>> {
+        // 反向搜索空间中的节点 -> (target序号, 距离)
+        let mut buckets: HashMap<usize, Vec<(usize, f64)>> = HashMap::new();
+        for (j, target) in targets.iter().enumerate() {
+            for (node, (cost, _)) in self.upward(*target, true) {
+                buckets.entry(node).or_default().push((j, cost));
+            }
+        }
+        let mut table = vec![vec![f64::MAX; targets.len()]; sources.len()];
+        for (i, source) in sources.iter().enumerate() {
+            for (node, (cost, _)) in self.upward(*source, false) {
+                if let Some(bucket) = buckets.get(&node) {
+                    for (j, other) in bucket {
+                        if cost + other < table[i][*j] {
+                            table[i][*j] = cost + other;
+                        }
+                    }
+                }
+            }
+        }
+        debug!(
+            "many to many {}x{} with {} buckets",
+            sources.len(),
+            targets.len(),
+            buckets.len()
+        );
+        table
+    }
+}
+
+// 路网的指纹 用于检查持久化的结果是否和当前路网一致
+fn fingerprint(road_graph: &RoadGraph) -> u64 {
+    // FNV-1a
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut feed = |v: u64| {
+        for byte in v.to_le_bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    };
+    feed(road_graph.graph.node_count() as u64);
+    feed(road_graph.network.edges.len() as u64);
+    for index in 0..road_graph.network.edges.len() {
+        let (from, to) = road_graph
+            .graph
+            .edge_endpoints(EdgeIndex::new(index))
+            .unwrap();
+        feed(from.index() as u64);
+        feed(to.index() as u64);
+        feed(road_graph.network.edges[index].get_length().to_bits());
+    }
+    hash
+}
+
+/// `edges.json` -> `edges.json.ch`
+pub fn default_ch_path(path: &FsPath) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".");
+    name.push(CH_EXTENSION);
+    PathBuf::from(name)
+}
+
+/// 写入文件
+pub fn write(path: &FsPath, ch: &ContractionHierarchy) -> Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    w.write_all(CH_MAGIC)?;
+    w.write_all(&CH_VERSION.to_le_bytes())?;
+    w.write_all(&0u32.to_le_bytes())?;
+    for v in [ch.rank.len() as u64, ch.arcs.len() as u64, ch.fingerprint] {
+        w.write_all(&v.to_le_bytes())?;
+    }
+    for rank in ch.rank.iter() {
+        w.write_all(&(*rank as u64).to_le_bytes())?;
+    }
+    for arc in ch.arcs.iter() {
+        let (kind, a, b) = match arc.kind {
+            ArcKind::Edge(edge) => (0u32, edge, 0),
+            ArcKind::Shortcut(first, second) => (1u32, first, second),
+        };
+        w.write_all(&(arc.from as u32).to_le_bytes())?;
+        w.write_all(&(arc.to as u32).to_le_bytes())?;
+        w.write_all(&kind.to_le_bytes())?;
+        w.write_all(&0u32.to_le_bytes())?;
+        w.write_all(&(a as u64).to_le_bytes())?;
+        w.write_all(&(b as u64).to_le_bytes())?;
+        w.write_all(&arc.weight.to_le_bytes())?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// 读取文件 并检查是否和当前路网一致
+pub fn read(path: &FsPath, road_graph: &RoadGraph) -> Result<ContractionHierarchy> {
+    let file = File::open(path)?;
+    // 文件只读, 构建完成后不会被修改
+    let mmap = unsafe { Mmap::map(&file)? };
+    let ch = decode(
+        &mmap,
+        road_graph.graph.node_count(),
+        road_graph.network.edges.len(),
+    )?;
+    if !ch.is_built_from(road_graph) {
+        return Err(anyhow::anyhow!(
+            "contraction hierarchy {} not match road network, rebuild it",
+            path.display()
+        ));
+    }
+    Ok(ch)
+}
+
+// 节点数量和edge数量来自当前路网 用于检查文件中的下标
+fn decode(buf: &[u8], expected_nodes: usize, edge_count: usize) -> Result<ContractionHierarchy> {
+    let mut cursor = Cursor::new(buf, 0);
+    if cursor.bytes(8)? != CH_MAGIC {
+        return Err(anyhow::anyhow!("not a contraction hierarchy file"));
+    }
+    let version = cursor.u32()?;
+    if version != CH_VERSION {
+        return Err(anyhow::anyhow!(
+            "contraction hierarchy version {} not supported (expect {})",
+            version,
+            CH_VERSION
+        ));
+    }
+    let _pad = cursor.u32()?;
+    let node_count = cursor.u64()? as usize;
+    let arc_count = cursor.u64()? as usize;
+    let fingerprint = cursor.u64()?;
+    if node_count != expected_nodes {
+        return Err(anyhow::anyhow!(
+            "contraction hierarchy has {} nodes, road network has {}",
+            node_count,
+            expected_nodes
+        ));
+    }
+    // 数量来自文件 分配内存之前先检查和文件大小一致
+    let ranks_end = section_end(CH_HEADER_SIZE, node_count, 8)?;
+    if buf.len() != section_end(ranks_end, arc_count, CH_ARC_SIZE)? {
+        return Err(anyhow::anyhow!("contraction hierarchy file is corrupted"));
+    }
+    let mut rank = Vec::with_capacity(node_count);
+    for _ in 0..node_count {
+        rank.push(cursor.u64()? as usize);
+    }
+    let mut arcs = Vec::with_capacity(arc_count);
+    for index in 0..arc_count {
+        let from = cursor.u32()? as usize;
+        let to = cursor.u32()? as usize;
+        let kind = cursor.u32()?;
+        let _pad = cursor.u32()?;
+        let a = cursor.u64()? as usize;
+        let b = cursor.u64()? as usize;
+        let weight = cursor.f64()?;
+        let kind = match kind {
+            0 if a < edge_count => ArcKind::Edge(a),
+            _ if a < index && b < index => ArcKind::Shortcut(a, b),
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "contraction hierarchy arc {} is corrupted",
+                    index
+                ))
+            }
+        };
+        if from >= node_count || to >= node_count {
+            return Err(anyhow::anyhow!(
+                "contraction hierarchy arc {} is corrupted",
+                index
+            ));
+        }
+        arcs.push(Arc {
+            from,
+            to,
+            weight,
+            kind,
+        });
+    }
+    if cursor.bytes(1).is_ok() {
+        return Err(anyhow::anyhow!("contraction hierarchy file is corrupted"));
+    }
+    Ok(ContractionHierarchy::from_parts(rank, arcs, fingerprint))
+}
+
+// test
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graph::fixture;
+
+    #[test]
+    fn test_ch_query_matches_dijkstra() {
+        // 网格之外的路网包含单向道路 部分节点之间不可达
+        for network in [
+            fixture::grid(5, 100.0),
+            fixture::parallel_roads(),
+            fixture::roundabout(),
+            fixture::one_way_loop(),
+        ] {
+            check_ch_query(&RoadGraph::new(network.unwrap()));
+        }
+    }
+
+    fn check_ch_query(graph: &RoadGraph) {
+        let ch = ContractionHierarchy::build(graph);
+        let nodes: Vec<usize> = (0..graph.network.nodes.len()).collect();
+        let table = ch.many_to_many(&nodes, &nodes);
+        for &from in nodes.iter() {
+            for &to in nodes.iter() {
+                let expected = graph.short_path(from, to).map(|p| p.cost).ok();
+                let path = ch.query(from, to);
+                assert_eq!(expected.is_some(), path.is_some());
+                if let (Some(expected), Some(path)) = (expected, path) {
+                    assert!((path.cost - expected).abs() < 1e-6);
+                    assert!((table[from][to] - expected).abs() < 1e-6);
+                    assert_eq!(path.nodes.first(), Some(&from));
+                    assert_eq!(path.nodes.last(), Some(&to));
+                    let length: f64 = path
+                        .edges
+                        .iter()
+                        .map(|e| graph.network.edges[*e].get_length())
+                        .sum();
+                    assert!((length - expected).abs() < 1e-6);
+                } else {
+                    assert_eq!(table[from][to], f64::MAX);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_ch_round_trip() {
+        let graph = RoadGraph::new(fixture::grid(5, 100.0).unwrap());
+        let ch = ContractionHierarchy::build(&graph);
+        let path = std::env::temp_dir().join(format!("rmm-ch-{}.ch", std::process::id()));
+        write(&path, &ch).unwrap();
+        let loaded = read(&path, &graph).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.rank, ch.rank);
+        assert_eq!(loaded.arcs.len(), ch.arcs.len());
+        let (from, to) = (0, graph.network.nodes.len() - 1);
+        assert_eq!(
+            loaded.query(from, to).unwrap().edges,
+            ch.query(from, to).unwrap().edges
+        );
+
+        // 文件头中的数量溢出时返回错误而不是panic
+        let mut buf = CH_MAGIC.to_vec();
+        buf.extend_from_slice(&CH_VERSION.to_le_bytes());
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        for count in [u64::MAX, 1, 0] {
+            buf.extend_from_slice(&count.to_le_bytes());
+        }
+        assert!(decode(&buf, usize::MAX, 0).is_err());
+    }
+
+    #[test]
+    fn test_ch_rejects_corrupted_file() {
+        let graph = RoadGraph::new(fixture::grid(3, 100.0).unwrap());
+        let ch = ContractionHierarchy::build(&graph);
+        let path = std::env::temp_dir().join(format!("rmm-ch-bad-{}.ch", std::process::id()));
+        write(&path, &ch).unwrap();
+        let buf = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let (nodes, edges) = (graph.graph.node_count(), graph.network.edges.len());
+        assert!(decode(&buf, nodes, edges).is_ok());
+
+        // 节点数量和路网不一致
+        assert!(decode(&buf, nodes + 1, edges).is_err());
+
+        // arc引用了不存在的edge
+        let index = ch
+            .arcs
+            .iter()
+            .position(|a| matches!(a.kind, ArcKind::Edge(_)))
+            .unwrap();
+        let offset = CH_HEADER_SIZE + nodes * 8 + index * CH_ARC_SIZE + 16;
+        let mut corrupted = buf.clone();
+        corrupted[offset..offset + 8].copy_from_slice(&(edges as u64).to_le_bytes());
+        assert!(decode(&corrupted, nodes, edges).is_err());
+    }
+}
@@ -0,0 +1,302 @@
+use super::network::{Edge, EdgeType, Network};
+use super::{snapshot::Snapshot, PathAlgorithm, RoutingMode};
+use crate::algorithm;
+use crate::mm::{Config, MMatch};
+use anyhow::Result;
+use std::collections::HashMap;
+
+/*
+ * @file fixture.rs
+ * 内置的小型路网
+ * 在代码中构建网格、平行道路、环岛、单向环路和双向分离的道路, 不依赖外部文件,
+ * 用于匹配的端到端测试和基准。坐标为wgs84, 构建时使用相对ORIGIN的米,
+ * edge id为"起点>终点", 匹配结果可以直接阅读。
+ * 另外提供测试共用的匹配参数、匹配器, 以及手写geojson路网的辅助函数。
+ */
+
+/// 所有路网的原点 (经度, 纬度)
+pub const ORIGIN: algorithm::Point = algorithm::Point(121.0, 31.0);
+
+/// 相对ORIGIN的米换算成wgs84坐标
+pub fn coord(x: f64, y: f64) -> [f64; 2] {
+    let (kx, ky) = algorithm::Metric::Haversine.coord_span(1.0, &ORIGIN);
+    [ORIGIN.0 + x * kx, ORIGIN.1 + y * ky]
+}
+
+/// 依次添加节点和edge构建路网
+#[derive(Default)]
+pub struct NetworkBuilder {
+    nodes: Vec<(String, [f64; 2])>,
+    edges: Vec<(String, String, Vec<[f64; 2]>)>, // (起点, 终点, 中间点)
+}
+
+impl NetworkBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 添加节点 坐标为相对ORIGIN的米
+    pub fn node(&mut self, id: &str, x: f64, y: f64) -> &mut Self {
+        self.nodes.push((id.to_string(), [x, y]));
+        self
+    }
+
+    /// 两个节点之间的单向直线edge
+    pub fn one_way(&mut self, from: &str, to: &str) -> &mut Self {
+        self.one_way_via(from, to, &[])
+    }
+
+    /// 经过中间点的单向edge
+    pub fn one_way_via(&mut self, from: &str, to: &str, via: &[[f64; 2]]) -> &mut Self {
+        self.edges
+            .push((from.to_string(), to.to_string(), via.to_vec()));
+        self
+    }
+
+    /// 两个节点之间的双向edge 两条edge的几何相同 方向相反
+    pub fn two_way(&mut self, from: &str, to: &str) -> &mut Self {
+        self.one_way(from, to).one_way(to, from)
+    }
+
+    /// 构建路网 edge引用了不存在的节点或者id重复时返回错误
+    pub fn build(&self) -> Result<Network> {
+        let points: HashMap<&str, [f64; 2]> = self
+            .nodes
+            .iter()
+            .map(|(id, point)| (id.as_str(), *point))
+            .collect();
+        let point = |id: &str| {
+            points
+                .get(id)
+                .copied()
+                .ok_or_else(|| anyhow::anyhow!("node {} not found", id))
+        };
+        let mut edges = vec![];
+        let mut ids = HashMap::new();
+        for (from, to, via) in self.edges.iter() {
+            let id = format!("{}>{}", from, to);
+            if ids.insert(id.clone(), edges.len()).is_some() {
+                return Err(anyhow::anyhow!("duplicate edge {}", id));
+            }
+            let mut line = vec![point(from)?];
+            line.extend(via.iter().copied());
+            line.push(point(to)?);
+            let geometry = geojson::Geometry::new(geojson::Value::LineString(
+                line.iter().map(|p| coord(p[0], p[1]).to_vec()).collect(),
+            ));
+            let length = algorithm::linestring_distance(&geometry)?;
+            edges.push(Edge::new(
+                id,
+                from.clone(),
+                to.clone(),
+                length,
+                length,
+                EdgeType::Real,
+                "".to_string(),
+                geometry,
+            ));
+        }
+        let nodes = self.nodes.iter().map(|(id, _)| id.clone()).collect();
+        Ok(Network::from_parts(
+            nodes,
+            edges,
+            vec![],
+            algorithm::Metric::Haversine,
+        ))
+    }
+}
+
+/// n x n的双向网格 节点间隔spacing米 节点id为"x_y" 节点0_0在ORIGIN
+pub fn grid(n: usize, spacing: f64) -> Result<Network> {
+    let mut builder = NetworkBuilder::new();
+    let id = |x: usize, y: usize| format!("{}_{}", x, y);
+    for y in 0..n {
+        for x in 0..n {
+            builder.node(&id(x, y), x as f64 * spacing, y as f64 * spacing);
+        }
+    }
+    for y in 0..n {
+        for x in 0..n {
+            if x + 1 < n {
+                builder.two_way(&id(x, y), &id(x + 1, y));
+            }
+            if y + 1 < n {
+                builder.two_way(&id(x, y), &id(x, y + 1));
+            }
+        }
+    }
+    builder.build()
+}
+
+/// 向东的主路a>b和北侧30米的辅路c>d 两端通过a>c和d>b连接
+/// w>a在主路之前 b>e在主路之后 全部为单向
+pub fn parallel_roads() -> Result<Network> {
+    NetworkBuilder::new()
+        .node("w", -200.0, 0.0)
+        .node("a", 0.0, 0.0)
+        .node("b", 600.0, 0.0)
+        .node("e", 800.0, 0.0)
+        .node("c", 50.0, 30.0)
+        .node("d", 550.0, 30.0)
+        .one_way("w", "a")
+        .one_way("a", "b")
+        .one_way("b", "e")
+        .one_way("a", "c")
+        .one_way("c", "d")
+        .one_way("d", "b")
+        .build()
+}
+
+/// 半径40米的逆时针单向环岛 环上的节点为n/w/s/e
+/// 四个方向的双向进出道路连接N/W/S/E 长200米
+pub fn roundabout() -> Result<Network> {
+    let radius = 40.0;
+    let arc = |from: f64, to: f64| -> Vec<[f64; 2]> {
+        // 每15度一个中间点
+        (1..6)
+            .map(|i| {
+                let angle = (from + (to - from) * i as f64 / 6.0).to_radians();
+                [radius * angle.cos(), radius * angle.sin()]
+            })
+            .collect()
+    };
+    NetworkBuilder::new()
+        .node("e", radius, 0.0)
+        .node("n", 0.0, radius)
+        .node("w", -radius, 0.0)
+        .node("s", 0.0, -radius)
+        .node("E", radius + 200.0, 0.0)
+        .node("N", 0.0, radius + 200.0)
+        .node("W", -radius - 200.0, 0.0)
+        .node("S", 0.0, -radius - 200.0)
+        .one_way_via("e", "n", &arc(0.0, 90.0))
+        .one_way_via("n", "w", &arc(90.0, 180.0))
+        .one_way_via("w", "s", &arc(180.0, 270.0))
+        .one_way_via("s", "e", &arc(270.0, 360.0))
+        .two_way("E", "e")
+        .two_way("N", "n")
+        .two_way("W", "w")
+        .two_way("S", "s")
+        .build()
+}
+
+/// 顺时针的单向矩形环路 a(0,0) -> b(0,300) -> c(400,300) -> d(400,0) -> a
+/// o在a的西边200米 o和a之间双向
+pub fn one_way_loop() -> Result<Network> {
+    NetworkBuilder::new()
+        .node("o", -200.0, 0.0)
+        .node("a", 0.0, 0.0)
+        .node("b", 0.0, 300.0)
+        .node("c", 400.0, 300.0)
+        .node("d", 400.0, 0.0)
+        .two_way("o", "a")
+        .one_way("a", "b")
+        .one_way("b", "c")
+        .one_way("c", "d")
+        .one_way("d", "a")
+        .build()
+}
+
+/// 上下行分离的道路 南侧向东w1 -> m1 -> e1 北侧20米向西e2 -> m2 -> w2
+/// 两端通过e1>e2和w2>w1掉头
+pub fn dual_carriageway() -> Result<Network> {
+    NetworkBuilder::new()
+        .node("w1", 0.0, 0.0)
+        .node("m1", 300.0, 0.0)
+        .node("e1", 600.0, 0.0)
+        .node("e2", 600.0, 20.0)
+        .node("m2", 300.0, 20.0)
+        .node("w2", 0.0, 20.0)
+        .one_way("w1", "m1")
+        .one_way("m1", "e1")
+        .one_way("e1", "e2")
+        .one_way("e2", "m2")
+        .one_way("m2", "w2")
+        .one_way("w2", "w1")
+        .build()
+}
+
+/// geojson路网中的一条edge coords为坐标数组的JSON
+pub fn feature(edge_id: i64, from: i64, to: i64, coords: &str) -> String {
+    format!(
+        r#"{{"type":"Feature","properties":{{"edge_id":{},"from_node_id":{},"to_node_id":{}}},"geometry":{{"type":"LineString","coordinates":{}}}}}"#,
+        edge_id, from, to, coords
+    )
+}
+
+/// 由geojson的feature构建路网
+pub fn geojson_network<S: AsRef<str>>(features: &[S]) -> Result<Network> {
+    let features: Vec<&str> = features.iter().map(|f| f.as_ref()).collect();
+    let geojson = format!(
+        r#"{{"type":"FeatureCollection","features":[{}]}}"#,
+        features.join(",")
+    );
+    Network::try_from(geojson.parse::<geojson::GeoJson>()?)
+}
+
+/// 路网构建的匹配器 距离为Haversine
+pub fn matcher(network: Network) -> Result<MMatch> {
+    MMatch::from_snapshot(Snapshot::new(network)?, algorithm::Metric::Haversine)
+}
+
+/// 测试用的匹配参数 单位为米
+pub fn config() -> Config {
+    Config {
+        gps_err: 10.0,
+        radius: 50.0,
+        max_radius: 50.0,
+        knn: 4,
+        v_max: 30.0,
+        factor: 4.0,
+        reverse_tolerance: 50.0,
+        road_netwok_path: "".to_string(),
+        metric: algorithm::Metric::Haversine,
+        routing: RoutingMode::NodeBased,
+        path_algorithm: PathAlgorithm::Dijkstra,
+    }
+}
+
+// test
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graph::{validate_network, RoadGraph};
+
+    #[test]
+    fn test_fixtures() {
+        let networks = [
+            grid(4, 100.0).unwrap(),
+            parallel_roads().unwrap(),
+            roundabout().unwrap(),
+            one_way_loop().unwrap(),
+            dual_carriageway().unwrap(),
+        ];
+        for network in networks {
+            let graph = RoadGraph::new(network);
+            let report = validate_network(&graph, 1.0);
+            assert!(report.is_valid(), "{}", report);
+        }
+
+        let network = grid(4, 100.0).unwrap();
+        assert_eq!((network.nodes.len(), network.edges.len()), (16, 48));
+        let edge = network.find_edge_by_id("0_0>1_0").unwrap();
+        assert_eq!(edge.get_to_node(), "1_0");
+        assert!((edge.get_length() - 100.0).abs() < 0.1);
+        let ring = roundabout().unwrap();
+        let quarter = ring.find_edge_by_id("e>n").unwrap().get_length();
+        assert!((quarter - 40.0 * std::f64::consts::FRAC_PI_2).abs() < 1.0);
+
+        assert!(NetworkBuilder::new()
+            .node("a", 0.0, 0.0)
+            .one_way("a", "b")
+            .build()
+            .is_err());
+        assert!(NetworkBuilder::new()
+            .node("a", 0.0, 0.0)
+            .node("b", 1.0, 0.0)
+            .one_way("a", "b")
+            .one_way("a", "b")
+            .build()
+            .is_err());
+    }
+}
@@ -1,12 +1,25 @@
+use super::ch::ContractionHierarchy;
 use super::network::Network;
+use super::path::{MinState, Path};
+use super::turn::TurnModel;
+use crate::algorithm;
 use crate::graph::Edge;
 use anyhow::Result;
+use geojson::Value;
 use log::{debug, error, info};
-use petgraph::graph::{Graph, NodeIndex};
+use petgraph::graph::{EdgeIndex, Graph, NodeIndex};
+use petgraph::visit::EdgeRef;
 use petgraph::Directed;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::Arc;
+#[derive(Clone)]
 pub struct RoadGraph {
     pub network: Network,
-    graph: Graph<f64, f64, Directed, usize>,
+    pub(crate) graph: Graph<f64, f64, Directed, usize>,
+    pub(crate) node_points: Vec<Option<algorithm::Point>>, // 节点坐标 取自edge几何的端点 虚拟节点可能没有
+    turns: Arc<TurnModel>,                                 // 转向模型和CH只读 clone出的图共享
+    ch: Option<Arc<ContractionHierarchy>>,
+    real_nodes: usize, // 路网中的节点数 之后的都是匹配时加入的虚拟节点
 }
 
 impl RoadGraph {
@@ -18,9 +31,13 @@ impl RoadGraph {
         let mut gh = RoadGraph {
             network,
             graph: Graph::with_capacity(node_size, edge_size),
+            node_points: vec![None; node_size],
+            turns: Arc::default(),
+            ch: None,
+            real_nodes: node_size,
         };
         // 添加node
-        for (_, _) in gh.network.nodes.iter().enumerate() {
+        for _ in gh.network.nodes.iter() {
             // let Some(node) = gh.network.find_node_by_index(index);
             gh.graph.add_node(1.0);
         }
@@ -32,11 +49,25 @@ impl RoadGraph {
             let to = gh.network.find_node_by_id(&edge.get_to_node()).unwrap();
             gh.graph
                 .add_edge(NodeIndex::new(from), NodeIndex::new(to), edge.get_length());
+            if let Value::LineString(line) = &edge.get_geometry().value {
+                let point = |p: &Vec<f64>| Some(algorithm::Point(p[0], p[1]));
+                gh.node_points[from] =
+                    gh.node_points[from].or_else(|| line.first().and_then(point));
+                gh.node_points[to] = gh.node_points[to].or_else(|| line.last().and_then(point));
+            }
         }
+        gh.set_turn_model(TurnModel::default());
         info!("finish construct origin road network graph...");
         gh
     }
 
+    /// 设置节点坐标 用于A*的启发函数
+    pub fn set_node_point(&mut self, node: usize, point: algorithm::Point) {
+        if node < self.node_points.len() {
+            self.node_points[node] = Some(point);
+        }
+    }
+
     pub fn update_graph(&mut self, edge: Edge) -> Result<()> {
         // 检查from_node 是否存在
         let from_node_index: NodeIndex<usize>;
@@ -45,6 +76,7 @@ impl RoadGraph {
         } else {
             from_node_index = self.graph.add_node(1.0);
             self.network.add_node(edge.get_from_node().clone())?;
+            self.node_points.push(None);
         }
         // 进行一次校验
         if self.network.nodes.len() != self.graph.node_count() {
@@ -58,44 +90,150 @@ impl RoadGraph {
         } else {
             to_node_index = self.graph.add_node(1.0);
             self.network.add_node(edge.get_to_node().clone())?;
+            self.node_points.push(None);
         }
         self.graph
             .add_edge(from_node_index, to_node_index, edge.get_length());
         Ok(())
     }
 
-    pub fn short_path(&self, from: usize, to: usize) -> Result<f64> {
-        use petgraph::algo::dijkstra;
-        let result = dijkstra(
-            &self.graph,
-            NodeIndex::new(from),
-            Some(NodeIndex::new(to)),
-            |e| *e.weight(),
-        );
-        for (node, weight) in &result {
-            debug!("node: {:?}, weight: {:?}", node, weight);
+    /// 删除匹配时加入的虚拟节点和虚拟边 路网中的节点和edge索引不变
+    pub fn clear_dummy(&mut self) {
+        // 虚拟边都在路网edge之后 删除时交换到空位的也只会是虚拟边
+        while self.graph.node_count() > self.real_nodes {
+            let last = NodeIndex::new(self.graph.node_count() - 1);
+            self.graph.remove_node(last);
         }
-        if !result.contains_key(&NodeIndex::new(to)) {
-            return Err(anyhow::anyhow!("no path"));
+        self.network.truncate_nodes(self.real_nodes);
+        self.node_points.truncate(self.real_nodes);
+    }
+
+    /// 设置转向限制和转向代价 只在基于edge的路径查询中生效
+    pub fn set_turn_model(&mut self, mut turns: TurnModel) {
+        turns.prepare(&self.network);
+        self.turns = Arc::new(turns);
+    }
+
+    pub fn turn_model(&self) -> &TurnModel {
+        &self.turns
+    }
+
+    /// 设置Contraction Hierarchies 需要由当前路网构建
+    pub fn set_contraction_hierarchy(&mut self, ch: ContractionHierarchy) -> Result<()> {
+        if !ch.is_built_from(self) {
+            return Err(anyhow::anyhow!(
+                "contraction hierarchy not match road network"
+            ));
         }
-        Ok(result[&NodeIndex::new(to)])
+        self.ch = Some(Arc::new(ch));
+        Ok(())
     }
 
-    pub fn short_k_path(
+    pub fn contraction_hierarchy(&self) -> Option<&ContractionHierarchy> {
+        self.ch.as_deref()
+    }
+
+    /// 基于edge(线图)的最短路径
+    /// 返回从from_edge终点到to_edge起点的代价 包含经过的edge长度以及转向代价
+    /// from_edge和to_edge为路网中edge的索引
+    pub fn short_path_edge_based(&self, from_edge: usize, to_edge: usize) -> Result<f64> {
+        self.edge_based(from_edge, to_edge, f64::MAX)
+            .map(|(cost, _)| cost)
+    }
+
+    /// 基于edge的最短路径 代价不超过max_cost
+    /// 返回路径的edges为from_edge和to_edge之间经过的edge, nodes从from_edge的终点到to_edge的起点
+    pub fn short_path_edge_based_within(
+        &self,
+        from_edge: usize,
+        to_edge: usize,
+        max_cost: f64,
+    ) -> Result<Path> {
+        let (cost, edges) = self.edge_based(from_edge, to_edge, max_cost)?;
+        let node = |edge: usize, to: bool| {
+            let (from, end) = self.graph.edge_endpoints(EdgeIndex::new(edge)).unwrap();
+            if to {
+                end.index()
+            } else {
+                from.index()
+            }
+        };
+        let mut nodes = vec![node(from_edge, true)];
+        nodes.extend(edges.iter().map(|e| node(*e, true)));
+        Ok(Path { cost, nodes, edges })
+    }
+
+    // 线图上的Dijkstra 返回代价以及中间经过的edge
+    fn edge_based(
         &self,
-        from: NodeIndex<usize>,
-        to: NodeIndex<usize>,
-        k: usize,
-    ) -> Result<f64> {
-        use petgraph::algo::k_shortest_path;
-        let result = k_shortest_path(&self.graph, from, Some(to), k, |e| *e.weight());
-        for (node, weight) in &result {
-            debug!("node: {:?}, weight: {:?}", node, weight);
+        from_edge: usize,
+        to_edge: usize,
+        max_cost: f64,
+    ) -> Result<(f64, Vec<usize>)> {
+        let edge_size = self.network.edges.len();
+        if from_edge >= edge_size || to_edge >= edge_size {
+            return Err(anyhow::anyhow!("edge index out of range"));
         }
-        if !result.contains_key(&to) {
+        let mut dist: HashMap<usize, f64> = HashMap::new();
+        let mut prev: HashMap<usize, usize> = HashMap::new();
+        let mut heap = BinaryHeap::new();
+        let mut best = f64::MAX;
+        let mut best_prev = from_edge;
+        dist.insert(from_edge, 0.0);
+        heap.push(MinState {
+            cost: 0.0,
+            item: from_edge,
+        });
+        while let Some(MinState { cost, item }) = heap.pop() {
+            if cost >= best {
+                break;
+            }
+            if cost > *dist.get(&item).unwrap_or(&f64::MAX) {
+                continue;
+            }
+            let (_, node) = self.graph.edge_endpoints(EdgeIndex::new(item)).unwrap();
+            for next in self.graph.edges(node) {
+                let next_edge = next.id().index();
+                // 匹配过程中加入的虚拟边不参与
+                if next_edge >= edge_size {
+                    continue;
+                }
+                let turn_cost = match self.turns.transition_cost(item, next_edge) {
+                    Some(turn_cost) => turn_cost,
+                    None => continue,
+                };
+                if next_edge == to_edge {
+                    if cost + turn_cost < best && cost + turn_cost <= max_cost {
+                        best = cost + turn_cost;
+                        best_prev = item;
+                    }
+                    continue;
+                }
+                let next_cost = cost + turn_cost + *next.weight();
+                if next_cost > max_cost {
+                    continue;
+                }
+                if next_cost < *dist.get(&next_edge).unwrap_or(&f64::MAX) {
+                    dist.insert(next_edge, next_cost);
+                    prev.insert(next_edge, item);
+                    heap.push(MinState {
+                        cost: next_cost,
+                        item: next_edge,
+                    });
+                }
+            }
+        }
+        if best == f64::MAX {
             return Err(anyhow::anyhow!("no path"));
         }
-        Ok(result[&to])
+        let mut edges = vec![];
+        let mut edge = best_prev;
+        while edge != from_edge {
+            edges.push(edge);
+            edge = prev[&edge];
+        }
+        edges.reverse();
+        Ok((best, edges))
     }
 }
 
@@ -1,7 +1,18 @@
+pub mod ch;
+pub use ch::ContractionHierarchy;
+pub mod fixture;
 pub mod network;
 pub use network::*;
+#[allow(clippy::module_inception)]
 pub mod graph;
 pub use graph::*;
+pub mod path;
+pub use path::*;
+pub mod snapshot;
+pub mod turn;
+pub use turn::*;
+pub mod validate;
+pub use validate::*;
 
 // test
 #[cfg(test)]
@@ -15,6 +26,7 @@ mod test {
 
     // 读取geojson文件
     #[test]
+    #[ignore = "needs edges_shanghai.json which is not in the repo"]
     fn test_road_graph_path() {
         log::log_init();
         let file = File::open("edges_shanghai.json").unwrap();
@@ -25,6 +37,19 @@ mod test {
         let from = graph.network.find_node_by_id("31000028835").unwrap();
         let to = graph.network.find_node_by_id("31000028718").unwrap();
         let result = graph.short_path(from, to).unwrap();
-        assert_eq!(result, 2630.016424987122);
+        assert_eq!(result.cost, 2630.016424987122);
+    }
+
+    // 不依赖外部文件的网格路网 对角之间的最短路径长度固定
+    #[test]
+    fn test_fixture_road_graph_path() {
+        let network = super::fixture::grid(5, 100.0).unwrap();
+        let graph = graph::RoadGraph::new(network);
+        let from = graph.network.find_node_by_id("0_0").unwrap();
+        let to = graph.network.find_node_by_id("4_4").unwrap();
+        let result = graph.short_path(from, to).unwrap();
+        assert!((result.cost - 800.0).abs() < 1.0, "{}", result.cost);
+        assert_eq!(result.edges.len(), 8);
+        assert!(graph.short_path(to, from).is_ok());
     }
 }
@@ -1,17 +1,24 @@
 use crate::algorithm;
 use anyhow::Result;
 use geojson::{Geometry, Value};
+use log::warn;
 use std::collections::HashMap;
+use std::sync::Arc;
 /**
  * @file network.rs
  * 定义路网信息结构体
  * 从文件中读取路网信息, 并存储在内存中。
+ * edge加载后不再变化, 放在Arc中, clone出的路网共享edge, 只有node各自一份(匹配时会加入虚拟节点)。
  */
+#[derive(Clone)]
 pub struct Network {
-    edges_index: HashMap<String, usize>, // edge的id对应的索引
-    nodes_index: HashMap<String, usize>, // node的id对应的索引
-    pub edges: Vec<Edge>,                // 所有的edge
-    pub nodes: Vec<String>,              // 所有的node
+    edges_index: Arc<HashMap<String, usize>>, // edge的id对应的索引
+    nodes_index: HashMap<String, usize>,      // node的id对应的索引
+    pub edges: Arc<Vec<Edge>>,                // 所有的edge
+    pub nodes: Vec<String>,                   // 所有的node
+    pub duplicate_edges: Vec<String>,         // 加载时因id重复被跳过的edge
+    pub invalid_features: Vec<String>,        // 加载时因缺少几何、属性或者id被跳过的feature及原因
+    metric: algorithm::Metric,                // edge长度的度量方式
 }
 
 #[derive(Clone)]
@@ -22,19 +29,19 @@ pub enum EdgeType {
 
 #[derive(Clone)]
 pub struct Edge {
-    id: String,       // edge的id
-    from: String,     // edge的起点
-    to: String,       // edge的终点
-    length: f64,      // edge的欧式长度 为了适配不同的坐标体系
-    real_length: f64, // edge的真实长度 此长度的单位是米
-    #[allow(dead_code)]
-    edge_type: EdgeType, // edge的类型
-    #[allow(dead_code)]
-    name: String, // edge的名字
-    geometry: Geometry, // edge的几何信息
+    id: String,               // edge的id
+    from: String,             // edge的起点
+    to: String,               // edge的终点
+    length: f64,              // edge的欧式长度 为了适配不同的坐标体系
+    real_length: f64,         // edge的真实长度 此长度的单位是米
+    edge_type: EdgeType,      // edge的类型
+    name: String,             // edge的名字
+    geometry: Geometry,       // edge的几何信息
+    speed_limit: Option<f64>, // 限速 单位km/h
 }
 
 impl Edge {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: String,
         from: String,
@@ -54,6 +61,7 @@ impl Edge {
             edge_type,
             name,
             geometry,
+            speed_limit: None,
         }
     }
 }
@@ -71,10 +79,22 @@ impl Edge {
         self.to.clone()
     }
 
+    pub fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    pub fn get_edge_type(&self) -> EdgeType {
+        self.edge_type.clone()
+    }
+
     pub fn get_length(&self) -> f64 {
         self.length
     }
 
+    pub fn get_real_length(&self) -> f64 {
+        self.real_length
+    }
+
     pub fn set_length(&mut self, length: f64) {
         self.length = length;
     }
@@ -83,16 +103,24 @@ impl Edge {
         self.real_length = real_length;
     }
 
+    pub fn get_speed_limit(&self) -> Option<f64> {
+        self.speed_limit
+    }
+
+    pub fn set_speed_limit(&mut self, speed_limit: Option<f64>) {
+        self.speed_limit = speed_limit;
+    }
+
     pub fn get_geometry(&self) -> &geojson::Geometry {
         &self.geometry
     }
 
     // reurn minx, miny, maxx, maxy
     pub fn get_geom_rect(&self) -> Result<(algorithm::Point, algorithm::Point)> {
-        let mut min_lng = 180 as f64;
-        let mut min_lat = 90 as f64;
-        let mut max_lng = -180 as f64;
-        let mut max_lat = -90 as f64;
+        let mut min_lng = 180.0;
+        let mut min_lat = 90.0;
+        let mut max_lng = -180.0;
+        let mut max_lat = -90.0;
         match &self.geometry.value {
             Value::LineString(line_string) => {
                 for point in line_string {
@@ -119,6 +147,72 @@ impl Edge {
     }
 }
 
+// 限速属性 speed_limit或者maxspeed(OSM) 数字或者"50"/"30 mph"这样的字符串 统一为km/h
+fn speed_limit(properties: &geojson::JsonObject) -> Option<f64> {
+    let value = properties
+        .get("speed_limit")
+        .or_else(|| properties.get("maxspeed"))?;
+    let speed = match value {
+        serde_json::Value::Number(n) => n.as_f64()?,
+        serde_json::Value::String(s) => {
+            let s = s.trim();
+            let (number, factor) = match s.strip_suffix("mph") {
+                Some(number) => (number.trim(), 1.609344),
+                None => (s.trim_end_matches("km/h").trim(), 1.0),
+            };
+            number.parse::<f64>().ok()? * factor
+        }
+        _ => return None,
+    };
+    (speed > 0.0).then_some(speed)
+}
+
+// 整数形式的id 转换成字符串保存
+fn integer_id(properties: &geojson::JsonObject, key: &str) -> std::result::Result<String, String> {
+    match properties.get(key) {
+        Some(value) => value
+            .as_i64()
+            .map(|id| id.to_string())
+            .ok_or_else(|| format!("{} {} is not an integer", key, value)),
+        None => Err(format!("missing {}", key)),
+    }
+}
+
+// 解析一个feature 出错时返回原因
+fn parse_feature(feature: geojson::Feature) -> std::result::Result<Edge, String> {
+    let geometry = feature.geometry.ok_or("missing geometry")?;
+    if !matches!(geometry.value, Value::LineString(_)) {
+        return Err("geometry is not linestring".to_string());
+    }
+    let properties = feature.properties.ok_or("missing properties")?;
+    let id = integer_id(&properties, "edge_id")?;
+    let from = integer_id(&properties, "from_node_id")?;
+    let to = integer_id(&properties, "to_node_id")?;
+    let real_length = algorithm::linestring_distance(&geometry).map_err(|e| e.to_string())?;
+    let name = match properties.get("name") {
+        Some(v) => {
+            if v.is_string() {
+                v.as_str().unwrap().to_string()
+            } else {
+                "".to_string()
+            }
+        }
+        None => "".to_string(),
+    };
+    Ok(Edge {
+        id,
+        from,
+        to,
+        // 默认使用米作为长度单位
+        length: real_length,
+        real_length,
+        edge_type: EdgeType::Real,
+        name,
+        geometry,
+        speed_limit: speed_limit(&properties),
+    })
+}
+
 impl TryFrom<geojson::GeoJson> for Network {
     // use anyhow::Error;
     type Error = anyhow::Error;
@@ -127,36 +221,18 @@ impl TryFrom<geojson::GeoJson> for Network {
         let mut nodes_index = HashMap::new();
         let mut edges = Vec::new();
         let mut nodes = Vec::new();
+        let mut duplicate_edges = Vec::new();
+        let mut invalid_features = Vec::new();
         match value {
             geojson::GeoJson::FeatureCollection(feature_collection) => {
-                for feature in feature_collection.features {
-                    let geometry = feature.geometry.unwrap();
-                    let properties = feature.properties.unwrap();
-                    let from = properties["from_node_id"].as_i64().unwrap().to_string();
-                    let to = properties["to_node_id"].as_i64().unwrap().to_string();
-                    let real_length = algorithm::linestring_distance(&geometry)?;
-                    let name = match properties.get("name") {
-                        Some(v) => {
-                            if v.is_string() {
-                                v.as_str().unwrap().to_string()
-                            } else {
-                                "".to_string()
-                            }
+                for (index, feature) in feature_collection.features.into_iter().enumerate() {
+                    let edge = match parse_feature(feature) {
+                        Ok(edge) => edge,
+                        Err(reason) => {
+                            warn!("feature {} {}, skipped", index, reason);
+                            invalid_features.push(format!("feature {}: {}", index, reason));
+                            continue;
                         }
-                        None => "".to_string(),
-                    };
-                    let edge_type = EdgeType::Real;
-                    let length = algorithm::linestring_eu_distance(&geometry)?;
-                    let id = properties["edge_id"].as_i64().unwrap().to_string();
-                    let edge = Edge {
-                        id,
-                        from,
-                        to,
-                        length,
-                        real_length,
-                        edge_type,
-                        name,
-                        geometry,
                     };
                     if !nodes_index.contains_key(&edge.from) {
                         nodes.push(edge.from.clone());
@@ -170,6 +246,9 @@ impl TryFrom<geojson::GeoJson> for Network {
                         let key = edge.id.clone();
                         edges.push(edge);
                         edges_index.insert(key, edges.len() - 1);
+                    } else {
+                        warn!("duplicate edge id {}, skipped", edge.id);
+                        duplicate_edges.push(edge.id);
                     }
                 }
             }
@@ -178,15 +257,99 @@ impl TryFrom<geojson::GeoJson> for Network {
             }
         }
         Ok(Network {
-            edges_index,
+            edges_index: Arc::new(edges_index),
             nodes_index,
-            edges,
+            edges: Arc::new(edges),
             nodes,
+            duplicate_edges,
+            invalid_features,
+            metric: algorithm::Metric::Haversine,
         })
     }
 }
 
 impl Network {
+    /// 由已经解析好的node和edge构建路网 例如从快照中加载
+    /// metric为edges中length使用的度量方式
+    pub fn from_parts(
+        nodes: Vec<String>,
+        edges: Vec<Edge>,
+        duplicate_edges: Vec<String>,
+        metric: algorithm::Metric,
+    ) -> Self {
+        let nodes_index = nodes
+            .iter()
+            .enumerate()
+            .map(|(index, node)| (node.clone(), index))
+            .collect();
+        let edges_index = edges
+            .iter()
+            .enumerate()
+            .map(|(index, edge)| (edge.id.clone(), index))
+            .collect();
+        Network {
+            edges_index: Arc::new(edges_index),
+            nodes_index,
+            edges: Arc::new(edges),
+            nodes,
+            duplicate_edges,
+            invalid_features: vec![],
+            metric,
+        }
+    }
+
+    pub fn get_metric(&self) -> algorithm::Metric {
+        self.metric
+    }
+
+    /// 把所有edge的几何从from坐标系转换到to坐标系 并重新计算长度
+    /// 解析时的real_length按照经纬度计算, from不是WGS84时即使不转换也要重新计算
+    pub fn reproject(&mut self, from: &algorithm::Crs, to: &algorithm::Crs) -> Result<()> {
+        if *from == algorithm::Crs::Wgs84 && *to == algorithm::Crs::Wgs84 {
+            return Ok(());
+        }
+        for edge in Arc::make_mut(&mut self.edges).iter_mut() {
+            let line = match &mut edge.geometry.value {
+                Value::LineString(line) => line,
+                _ => return Err(anyhow::anyhow!("geometry is not linestring")),
+            };
+            let mut wgs84 = Vec::with_capacity(line.len());
+            for p in line.iter_mut() {
+                let origin = from.to_wgs84(&algorithm::Point(p[0], p[1]));
+                wgs84.push(vec![origin.0, origin.1]);
+                if from != to {
+                    let target = to.from_wgs84(&origin);
+                    p[0] = target.0;
+                    p[1] = target.1;
+                }
+            }
+            // 真实长度始终在WGS84下用haversine计算
+            edge.real_length =
+                algorithm::linestring_distance(&Geometry::new(Value::LineString(wgs84)))?;
+            edge.length = match self.metric {
+                algorithm::Metric::Haversine => edge.real_length,
+                algorithm::Metric::Euclidean => self.metric.linestring_length(&edge.geometry)?,
+            };
+        }
+        Ok(())
+    }
+
+    /// 切换edge长度的度量方式 重新计算所有edge的length
+    /// 需要在构建RoadGraph之前调用
+    pub fn set_metric(&mut self, metric: algorithm::Metric) -> Result<()> {
+        if metric == self.metric {
+            return Ok(());
+        }
+        for edge in Arc::make_mut(&mut self.edges).iter_mut() {
+            edge.length = match metric {
+                algorithm::Metric::Haversine => edge.real_length,
+                algorithm::Metric::Euclidean => metric.linestring_length(&edge.geometry)?,
+            };
+        }
+        self.metric = metric;
+        Ok(())
+    }
+
     pub fn find_edge_by_index(&self, index: usize) -> Option<Edge> {
         if index < self.edges.len() {
             Some(self.edges[index].clone())
@@ -196,11 +359,13 @@ impl Network {
     }
 
     pub fn find_edge_by_id(&self, id: &str) -> Option<Edge> {
-        if let Some(index) = self.edges_index.get(id) {
-            Some(self.edges[*index].clone())
-        } else {
-            None
-        }
+        self.edges_index
+            .get(id)
+            .map(|index| self.edges[*index].clone())
+    }
+
+    pub fn find_edge_index(&self, id: &str) -> Option<usize> {
+        self.edges_index.get(id).copied()
     }
 
     pub fn find_node_by_index(&self, index: usize) -> Option<String> {
@@ -212,11 +377,7 @@ impl Network {
     }
 
     pub fn find_node_by_id(&self, id: &str) -> Option<usize> {
-        if let Some(index) = self.nodes_index.get(id) {
-            Some(*index)
-        } else {
-            None
-        }
+        self.nodes_index.get(id).copied()
     }
 
     /// 添加node
@@ -230,12 +391,18 @@ impl Network {
         }
     }
 
+    /// 只保留前len个节点 用于删除匹配时加入的虚拟节点
+    pub(crate) fn truncate_nodes(&mut self, len: usize) {
+        for node in self.nodes.drain(len.min(self.nodes.len())..) {
+            self.nodes_index.remove(&node);
+        }
+    }
+
     /// 添加edge
     pub fn add_edge(&mut self, edge: Edge) -> Result<usize> {
         if !self.edges_index.contains_key(&edge.id) {
-            self.edges.push(edge.clone());
-            self.edges_index
-                .insert(edge.id.clone(), self.edges.len() - 1);
+            Arc::make_mut(&mut self.edges).push(edge.clone());
+            Arc::make_mut(&mut self.edges_index).insert(edge.id.clone(), self.edges.len() - 1);
             Ok(self.edges.len() - 1)
         } else {
             Err(anyhow::anyhow!("edge already exists"))
@@ -0,0 +1,493 @@
+use super::graph::RoadGraph;
+use anyhow::Result;
+use petgraph::graph::{EdgeIndex, NodeIndex};
+use petgraph::visit::EdgeRef;
+use petgraph::Direction;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+/*
+ * @file path.rs
+ * 节点图上的最短路径查询
+ */
+
+// 最小堆中的元素
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) struct MinState {
+    pub cost: f64,
+    pub item: usize,
+}
+
+impl Eq for MinState {}
+
+impl Ord for MinState {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .cost
+            .partial_cmp(&self.cost)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| self.item.cmp(&other.item))
+    }
+}
+
+impl PartialOrd for MinState {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// 最短路径算法
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum PathAlgorithm {
+    #[default]
+    Dijkstra,
+    /// 以节点之间的直线距离作为启发函数
+    AStar,
+    /// 从起点和终点同时搜索
+    Bidirectional,
+}
+
+/// 最短路径
+#[derive(Clone, Debug)]
+pub struct Path {
+    pub cost: f64,
+    pub nodes: Vec<usize>, // 经过的节点索引 包含起点和终点
+    pub edges: Vec<usize>, // 经过的edge在图中的索引 小于路网edge数量的为路网中的edge 其余为虚拟边
+}
+
+// 前驱 (上一个节点, 经过的edge)
+type Predecessors = HashMap<usize, (usize, usize)>;
+
+impl RoadGraph {
+    /// 使用Dijkstra查询两个节点之间的最短路径
+    pub fn short_path(&self, from: usize, to: usize) -> Result<Path> {
+        self.short_path_by(from, to, PathAlgorithm::Dijkstra)
+    }
+
+    /// 只经过路网中的edge 代价不超过max_cost的最短路径
+    pub fn short_path_within(&self, from: usize, to: usize, max_cost: f64) -> Result<Path> {
+        let node_size = self.graph.node_count();
+        if from >= node_size || to >= node_size {
+            return Err(anyhow::anyhow!("node index out of range"));
+        }
+        let real_edges = self.network.edges.len();
+        self.astar(from, to, max_cost, |_| 0.0, |edge, _| edge >= real_edges)
+            .ok_or_else(|| anyhow::anyhow!("no path"))
+    }
+
+    /// 使用指定的算法查询两个节点之间的最短路径
+    pub fn short_path_by(&self, from: usize, to: usize, algorithm: PathAlgorithm) -> Result<Path> {
+        self.short_path_by_within(from, to, algorithm, f64::MAX)
+    }
+
+    /// 使用指定的算法查询代价不超过max_cost的最短路径
+    pub fn short_path_by_within(
+        &self,
+        from: usize,
+        to: usize,
+        algorithm: PathAlgorithm,
+        max_cost: f64,
+    ) -> Result<Path> {
+        let node_size = self.graph.node_count();
+        if from >= node_size || to >= node_size {
+            return Err(anyhow::anyhow!("node index out of range"));
+        }
+        if from == to {
+            return Ok(Path {
+                cost: 0.0,
+                nodes: vec![from],
+                edges: vec![],
+            });
+        }
+        let path = match algorithm {
+            PathAlgorithm::Dijkstra => self.astar(from, to, max_cost, |_| 0.0, |_, _| false),
+            PathAlgorithm::AStar => {
+                let metric = self.network.get_metric();
+                let target = self.node_points[to];
+                let heuristic = |node| match (self.node_points[node], target) {
+                    (Some(a), Some(b)) => metric.distance(&a, &b),
+                    _ => 0.0,
+                };
+                self.astar(from, to, max_cost, heuristic, |_, _| false)
+            }
+            PathAlgorithm::Bidirectional => self.bidirectional(from, to, max_cost),
+        };
+        path.ok_or_else(|| anyhow::anyhow!("no path"))
+    }
+
+    // 启发函数为0时即Dijkstra
+    // 虚拟节点没有坐标时启发函数为0 启发函数不一定一致 所以允许节点被重复展开
+    // skip(edge, next_node) 为true的edge不参与搜索 代价超过max_cost的节点不再展开
+    fn astar<H, S>(
+        &self,
+        from: usize,
+        to: usize,
+        max_cost: f64,
+        heuristic: H,
+        skip: S,
+    ) -> Option<Path>
+    where
+        H: Fn(usize) -> f64,
+        S: Fn(usize, usize) -> bool,
+    {
+        let mut dist: HashMap<usize, f64> = HashMap::new();
+        let mut prev: Predecessors = HashMap::new();
+        let mut heap = BinaryHeap::new();
+        dist.insert(from, 0.0);
+        heap.push(MinState {
+            cost: heuristic(from),
+            item: from,
+        });
+        while let Some(MinState { cost, item }) = heap.pop() {
+            if item == to {
+                let (mut nodes, mut edges) = Self::follow(to, from, &prev);
+                nodes.reverse();
+                edges.reverse();
+                return Some(Path {
+                    cost: dist[&to],
+                    nodes,
+                    edges,
+                });
+            }
+            let g = dist[&item];
+            if cost > g + heuristic(item) {
+                continue;
+            }
+            for edge in self.graph.edges(NodeIndex::new(item)) {
+                let next = edge.target().index();
+                if skip(edge.id().index(), next) {
+                    continue;
+                }
+                let next_g = g + *edge.weight();
+                if next_g > max_cost {
+                    continue;
+                }
+                if next_g < *dist.get(&next).unwrap_or(&f64::MAX) {
+                    dist.insert(next, next_g);
+                    prev.insert(next, (item, edge.id().index()));
+                    heap.push(MinState {
+                        cost: next_g + heuristic(next),
+                        item: next,
+                    });
+                }
+            }
+        }
+        None
+    }
+
+    // 两侧代价超过max_cost的节点都不再展开
+    fn bidirectional(&self, from: usize, to: usize, max_cost: f64) -> Option<Path> {
+        // 0为正向 1为反向
+        let mut dist: [HashMap<usize, f64>; 2] = [HashMap::new(), HashMap::new()];
+        let mut link: [Predecessors; 2] = [HashMap::new(), HashMap::new()];
+        let mut heaps = [BinaryHeap::new(), BinaryHeap::new()];
+        for (side, node) in [(0, from), (1, to)] {
+            dist[side].insert(node, 0.0);
+            heaps[side].push(MinState {
+                cost: 0.0,
+                item: node,
+            });
+        }
+        let mut best = f64::MAX;
+        let mut meet: Option<(usize, usize, usize)> = None; // (正向节点, edge, 反向节点)
+        loop {
+            let top = |side: usize| heaps[side].peek().map(|s: &MinState| s.cost);
+            let side = match (top(0), top(1)) {
+                (Some(f), Some(b)) => {
+                    if f + b >= best {
+                        break;
+                    }
+                    if f <= b {
+                        0
+                    } else {
+                        1
+                    }
+                }
+                _ => break,
+            };
+            let MinState { cost, item } = heaps[side].pop().unwrap();
+            if cost > dist[side][&item] {
+                continue;
+            }
+            let direction = if side == 0 {
+                Direction::Outgoing
+            } else {
+                Direction::Incoming
+            };
+            for edge in self.graph.edges_directed(NodeIndex::new(item), direction) {
+                let next = if side == 0 {
+                    edge.target().index()
+                } else {
+                    edge.source().index()
+                };
+                let next_cost = cost + *edge.weight();
+                if next_cost > max_cost {
+                    continue;
+                }
+                if next_cost < *dist[side].get(&next).unwrap_or(&f64::MAX) {
+                    dist[side].insert(next, next_cost);
+                    link[side].insert(next, (item, edge.id().index()));
+                    heaps[side].push(MinState {
+                        cost: next_cost,
+                        item: next,
+                    });
+                }
+                if let Some(other) = dist[1 - side].get(&next) {
+                    if next_cost + other < best && next_cost + other <= max_cost {
+                        best = next_cost + other;
+                        meet = Some(if side == 0 {
+                            (item, edge.id().index(), next)
+                        } else {
+                            (next, edge.id().index(), item)
+                        });
+                    }
+                }
+            }
+        }
+        let (forward_node, edge, backward_node) = meet?;
+        let (mut nodes, mut edges) = Self::follow(forward_node, from, &link[0]);
+        nodes.reverse();
+        edges.reverse();
+        edges.push(edge);
+        let (backward_nodes, backward_edges) = Self::follow(backward_node, to, &link[1]);
+        nodes.extend(backward_nodes);
+        edges.extend(backward_edges);
+        Some(Path {
+            cost: best,
+            nodes,
+            edges,
+        })
+    }
+
+    /// 多源的有界Dijkstra 返回代价不超过max_cost的节点及其代价
+    /// sources: (节点, 初始代价)
+    pub fn reachable_nodes(&self, sources: &[(usize, f64)], max_cost: f64) -> HashMap<usize, f64> {
+        let mut dist: HashMap<usize, f64> = HashMap::new();
+        let mut heap = BinaryHeap::new();
+        for &(node, cost) in sources {
+            if cost <= max_cost && cost < *dist.get(&node).unwrap_or(&f64::MAX) {
+                dist.insert(node, cost);
+                heap.push(MinState { cost, item: node });
+            }
+        }
+        while let Some(MinState { cost, item }) = heap.pop() {
+            if cost > dist[&item] {
+                continue;
+            }
+            for edge in self.graph.edges(NodeIndex::new(item)) {
+                let next = edge.target().index();
+                let next_cost = cost + *edge.weight();
+                if next_cost <= max_cost && next_cost < *dist.get(&next).unwrap_or(&f64::MAX) {
+                    dist.insert(next, next_cost);
+                    heap.push(MinState {
+                        cost: next_cost,
+                        item: next,
+                    });
+                }
+            }
+        }
+        dist
+    }
+
+    /// Yen算法 两个节点之间k条无环的最短路径 按代价从小到大排列
+    /// 只经过路网中的edge, 匹配时添加的虚拟边不参与
+    pub fn short_k_path(&self, from: usize, to: usize, k: usize) -> Result<Vec<Path>> {
+        let node_size = self.graph.node_count();
+        if from >= node_size || to >= node_size {
+            return Err(anyhow::anyhow!("node index out of range"));
+        }
+        let real_edges = self.network.edges.len();
+        let first = self
+            .astar(from, to, f64::MAX, |_| 0.0, |edge, _| edge >= real_edges)
+            .ok_or_else(|| anyhow::anyhow!("no path"))?;
+        let mut paths = vec![first];
+        let mut candidates: Vec<Path> = vec![];
+        while paths.len() < k {
+            let last = paths.last().unwrap().clone();
+            for spur in 0..last.edges.len() {
+                let spur_node = last.nodes[spur];
+                let root_edges = &last.edges[..spur];
+                // 与当前路径有相同前缀的路径 下一条edge不能再使用
+                let banned_edges: HashSet<usize> = paths
+                    .iter()
+                    .filter(|p| p.edges.len() > spur && p.edges[..spur] == *root_edges)
+                    .map(|p| p.edges[spur])
+                    .collect();
+                // 前缀上的节点不能再经过 保证路径无环
+                let banned_nodes: HashSet<usize> = last.nodes[..spur].iter().copied().collect();
+                let skip = |edge: usize, next: usize| {
+                    edge >= real_edges
+                        || banned_edges.contains(&edge)
+                        || banned_nodes.contains(&next)
+                };
+                let spur_path = match self.astar(spur_node, to, f64::MAX, |_| 0.0, skip) {
+                    Some(path) => path,
+                    None => continue,
+                };
+                let root_cost: f64 = root_edges
+                    .iter()
+                    .map(|e| *self.graph.edge_weight(EdgeIndex::new(*e)).unwrap())
+                    .sum();
+                let mut nodes = last.nodes[..spur].to_vec();
+                nodes.extend(spur_path.nodes);
+                let mut edges = root_edges.to_vec();
+                edges.extend(spur_path.edges);
+                let path = Path {
+                    cost: root_cost + spur_path.cost,
+                    nodes,
+                    edges,
+                };
+                if !paths
+                    .iter()
+                    .chain(candidates.iter())
+                    .any(|p| p.edges == path.edges)
+                {
+                    candidates.push(path);
+                }
+            }
+            if candidates.is_empty() {
+                break;
+            }
+            let best = candidates
+                .iter()
+                .enumerate()
+                .min_by(|a, b| a.1.cost.partial_cmp(&b.1.cost).unwrap())
+                .map(|(index, _)| index)
+                .unwrap();
+            paths.push(candidates.swap_remove(best));
+        }
+        Ok(paths)
+    }
+
+    // 从start沿着links走到end 返回经过的节点和edge
+    fn follow(start: usize, end: usize, links: &Predecessors) -> (Vec<usize>, Vec<usize>) {
+        let mut nodes = vec![start];
+        let mut edges = vec![];
+        let mut node = start;
+        while node != end {
+            let (n, e) = links[&node];
+            edges.push(e);
+            nodes.push(n);
+            node = n;
+        }
+        (nodes, edges)
+    }
+
+    /// 路径经过的路网edge id 忽略虚拟边
+    pub fn path_edge_ids(&self, path: &Path) -> Vec<String> {
+        path.edges
+            .iter()
+            .filter_map(|e| self.network.find_edge_by_index(*e))
+            .map(|e| e.get_edge_id())
+            .collect()
+    }
+}
+
+// test
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graph::fixture;
+
+    #[test]
+    fn test_short_path_algorithms() {
+        let graph = RoadGraph::new(fixture::grid(3, 100.0).unwrap());
+        let from = graph.network.find_node_by_id("0_0").unwrap();
+        let to = graph.network.find_node_by_id("2_2").unwrap();
+        let dijkstra = graph.short_path(from, to).unwrap();
+        assert_eq!(dijkstra.nodes.len(), 5);
+        assert_eq!(dijkstra.edges.len(), 4);
+        assert_eq!(dijkstra.nodes[0], from);
+        assert_eq!(*dijkstra.nodes.last().unwrap(), to);
+        for algorithm in [PathAlgorithm::AStar, PathAlgorithm::Bidirectional] {
+            let path = graph.short_path_by(from, to, algorithm).unwrap();
+            assert!((path.cost - dijkstra.cost).abs() < 1e-6);
+            assert_eq!(path.nodes.first(), Some(&from));
+            assert_eq!(path.nodes.last(), Some(&to));
+            // 路径连续 且长度和代价一致
+            let mut length = 0.0;
+            for (i, edge) in path.edges.iter().enumerate() {
+                let (a, b) = graph.graph.edge_endpoints(EdgeIndex::new(*edge)).unwrap();
+                assert_eq!((a.index(), b.index()), (path.nodes[i], path.nodes[i + 1]));
+                length += graph.network.edges[*edge].get_length();
+            }
+            assert!((length - path.cost).abs() < 1e-6);
+            assert_eq!(graph.path_edge_ids(&path).len(), 4);
+        }
+    }
+
+    #[test]
+    fn test_short_path_no_path() {
+        let graph = RoadGraph::new(fixture::grid(3, 100.0).unwrap());
+        let from = graph.network.find_node_by_id("0_0").unwrap();
+        let path = graph
+            .short_path_by(from, from, PathAlgorithm::AStar)
+            .unwrap();
+        assert_eq!(path.cost, 0.0);
+        assert_eq!(path.nodes, vec![from]);
+        assert!(graph
+            .short_path_by(from, 100, PathAlgorithm::Bidirectional)
+            .is_err());
+
+        // 最短路径为400 限制代价之后没有路径
+        let to = graph.network.find_node_by_id("2_2").unwrap();
+        for algorithm in [
+            PathAlgorithm::Dijkstra,
+            PathAlgorithm::AStar,
+            PathAlgorithm::Bidirectional,
+        ] {
+            let cost = graph.short_path_by(from, to, algorithm).unwrap().cost;
+            let path = graph
+                .short_path_by_within(from, to, algorithm, cost + 1e-6)
+                .unwrap();
+            assert!((path.cost - cost).abs() < 1e-6);
+            assert!(graph
+                .short_path_by_within(from, to, algorithm, cost - 1.0)
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_short_k_path() {
+        let graph = RoadGraph::new(fixture::grid(3, 100.0).unwrap());
+        let from = graph.network.find_node_by_id("0_0").unwrap();
+        let to = graph.network.find_node_by_id("2_2").unwrap();
+        // 3x3网格对角之间有6条最短路径 不同纬度上经度方向的长度略有差异
+        let paths = graph.short_k_path(from, to, 8).unwrap();
+        assert_eq!(paths.len(), 8);
+        let shortest = paths[0].cost;
+        for (i, path) in paths.iter().enumerate() {
+            if i > 0 {
+                assert!(path.cost >= paths[i - 1].cost);
+            }
+            let nodes: HashSet<&usize> = path.nodes.iter().collect();
+            assert_eq!(nodes.len(), path.nodes.len());
+            assert_eq!(path.nodes.first(), Some(&from));
+            assert_eq!(path.nodes.last(), Some(&to));
+            assert!(paths[..i].iter().all(|p| p.edges != path.edges));
+        }
+        assert!(paths[..6].iter().all(|p| (p.cost - shortest).abs() < 0.1));
+        assert!(paths[6].cost > shortest + 1.0);
+        assert_eq!(graph.short_k_path(from, from, 3).unwrap().len(), 1);
+
+        // 匹配时加入的虚拟边不参与 路径中的edge都可以用路网的edge索引访问
+        let mut graph = graph;
+        let real_edges = graph.network.edges.len();
+        let dummy = crate::graph::Edge::new(
+            "dummy".to_string(),
+            "1".to_string(),
+            "9".to_string(),
+            0.1,
+            0.1,
+            crate::graph::EdgeType::Dummy,
+            "".to_string(),
+            geojson::Geometry::new(geojson::Value::LineString(vec![])),
+        );
+        graph.update_graph(dummy).unwrap();
+        let paths = graph.short_k_path(from, to, 3).unwrap();
+        assert!((paths[0].cost - shortest).abs() < 1e-9);
+        assert!(paths
+            .iter()
+            .all(|p| p.edges.iter().all(|e| *e < real_edges)));
+    }
+}
@@ -0,0 +1,808 @@
+use super::network::{Edge, EdgeType, Network};
+use crate::algorithm;
+use anyhow::Result;
+use geojson::{Geometry, Value};
+use log::{debug, info, warn};
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/*
+ * @file snapshot.rs
+ * 路网二进制快照
+ * 解析大的geojson路网 计算haversine长度非常耗时,
+ * 通过 `rmm build` 预先生成快照, 之后直接加载快照即可。
+ *
+ * 快照中还可以保存空间索引的片段, 匹配时的坐标转换和距离与构建时一致就直接使用, 否则重新构建。
+ *
+ * 文件布局(小端序, 所有定长区块按8字节对齐, 可直接mmap):
+ *   header   | magic[8] version:u32 flags:u32 node_count:u64 edge_count:u64
+ *            | coord_count:u64 dup_count:u64 string_bytes:u64 segment_count:u64
+ *            | index_source:u32 index_crs:u32 index_metric:u32 reserved:u32
+ *   nodes    | node_count * StrRef
+ *   edges    | edge_count * EdgeRecord
+ *   coords   | coord_count * [f64; 2]
+ *   dups     | dup_count * StrRef
+ *   segments | segment_count * SegmentRecord
+ *   strings  | string_bytes 所有字符串的utf8拼接
+ */
+
+pub const SNAPSHOT_MAGIC: &[u8; 8] = b"RMMNET\0\0";
+pub const SNAPSHOT_VERSION: u32 = 4;
+/// 快照默认的扩展名 `edges.json` 对应 `edges.json.rmm`
+pub const SNAPSHOT_EXTENSION: &str = "rmm";
+
+// flags 第0位: edge长度为欧式距离(度) 否则为米
+const FLAG_EUCLIDEAN: u32 = 1;
+// flags 第1位: 包含空间索引
+const FLAG_INDEX: u32 = 2;
+
+const HEADER_SIZE: usize = 8 + 4 + 4 + 8 * 6 + 4 * 4;
+const STR_REF_SIZE: usize = 8;
+const EDGE_RECORD_SIZE: usize = 8 + 4 + 4 + 8 + 4 + 4 + 8 + 8 + 8 + 8;
+const COORD_SIZE: usize = 16;
+const SEGMENT_RECORD_SIZE: usize = 16 + 16 + 8 + 4 + 4 + 8;
+
+/// 空间索引中每个片段包含的最大线段数
+const SEGMENT_CHUNK: usize = 4;
+
+/// 空间索引中的折线片段 指向所属的edge
+/// 长的弯曲道路按照片段建立索引, 外包矩形更贴合, 查询时也只需要计算片段内的线段
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SegmentRef {
+    pub edge: usize,  // 路网edge索引
+    pub start: usize, // 片段起始顶点在edge折线中的序号
+    pub end: usize,   // 片段结束顶点的序号(包含)
+    pub offset: f64,  // 片段起点距edge起点的长度 单位和路网长度一致
+}
+
+/// 空间索引的片段 (外包矩形的最小点, 最大点, 片段)
+pub type IndexedSegment = ([f64; 2], [f64; 2], SegmentRef);
+
+/// 空间索引 依赖匹配时的坐标系和距离
+/// 构建时路网从source转换到crs, 片段的偏移量按照metric计算
+#[derive(Clone)]
+pub struct SegmentIndex {
+    pub source: algorithm::Crs,
+    pub crs: algorithm::Crs,
+    pub metric: algorithm::Metric,
+    pub segments: Vec<IndexedSegment>,
+}
+
+impl SegmentIndex {
+    /// 由已经转换到crs的路网构建
+    pub fn build(
+        network: &Network,
+        source: algorithm::Crs,
+        crs: algorithm::Crs,
+        metric: algorithm::Metric,
+    ) -> Result<Self> {
+        let mut segments = vec![];
+        for (index, edge) in network.edges.iter().enumerate() {
+            match &edge.get_geometry().value {
+                Value::LineString(line) => {
+                    let line: Vec<[f64; 2]> = line.iter().map(|p| [p[0], p[1]]).collect();
+                    segments.extend(segment_chunks(index, &line, metric));
+                }
+                _ => return Err(anyhow::anyhow!("geometry is not linestring")),
+            }
+        }
+        Ok(SegmentIndex {
+            source,
+            crs,
+            metric,
+            segments,
+        })
+    }
+
+    /// 片段坐标和路网当前的坐标一致 并且偏移量使用相同的距离
+    pub fn matches(&self, metric: algorithm::Metric) -> bool {
+        self.source == self.crs && self.metric == metric
+    }
+}
+
+/// 将折线按照SEGMENT_CHUNK切分成片段 少于两个顶点的折线不参与索引
+pub fn segment_chunks(
+    edge: usize,
+    line: &[[f64; 2]],
+    metric: algorithm::Metric,
+) -> Vec<IndexedSegment> {
+    let mut chunks = vec![];
+    let mut offset = 0.0;
+    let mut start = 0;
+    while start + 1 < line.len() {
+        let end = (start + SEGMENT_CHUNK).min(line.len() - 1);
+        let (mut min, mut max) = (line[start], line[start]);
+        for p in line[start..=end].iter() {
+            min = [min[0].min(p[0]), min[1].min(p[1])];
+            max = [max[0].max(p[0]), max[1].max(p[1])];
+        }
+        chunks.push((
+            min,
+            max,
+            SegmentRef {
+                edge,
+                start,
+                end,
+                offset,
+            },
+        ));
+        for segment in line[start..=end].windows(2) {
+            offset += metric.distance(
+                &algorithm::Point(segment[0][0], segment[0][1]),
+                &algorithm::Point(segment[1][0], segment[1][1]),
+            );
+        }
+        start = end;
+    }
+    chunks
+}
+
+/// 加载后的路网以及保存的空间索引
+#[derive(Clone)]
+pub struct Snapshot {
+    pub network: Network,
+    pub index: Option<SegmentIndex>, // 坐标转换或者距离不一致时 匹配时重新构建
+}
+
+impl Snapshot {
+    /// 构建快照 edge的几何必须是LineString
+    pub fn new(network: Network) -> Result<Self> {
+        for edge in network.edges.iter() {
+            if !matches!(edge.get_geometry().value, Value::LineString(_)) {
+                return Err(anyhow::anyhow!(
+                    "edge {} geometry is not linestring",
+                    edge.get_edge_id()
+                ));
+            }
+        }
+        Ok(Snapshot {
+            network,
+            index: None,
+        })
+    }
+
+    /// 构建匹配时使用的空间索引 路网从source转换到crs, 偏移量按照metric计算
+    /// 路网本身保持原来的坐标
+    pub fn with_index(
+        mut self,
+        source: &algorithm::Crs,
+        crs: &algorithm::Crs,
+        metric: algorithm::Metric,
+    ) -> Result<Self> {
+        let mut network = self.network.clone();
+        network.reproject(source, crs)?;
+        self.index = Some(SegmentIndex::build(&network, *source, *crs, metric)?);
+        Ok(self)
+    }
+
+    /// 坐标转换 保存的空间索引只有在相同的坐标转换下才保留
+    pub fn reproject(self, from: &algorithm::Crs, to: &algorithm::Crs) -> Result<Self> {
+        let index = self
+            .index
+            .filter(|index| index.source == *from && index.crs == *to)
+            // 转换之后路网的坐标和索引一致
+            .map(|index| SegmentIndex {
+                source: *to,
+                ..index
+            });
+        let mut network = self.network;
+        network.reproject(from, to)?;
+        let mut snapshot = Snapshot::new(network)?;
+        snapshot.index = index;
+        Ok(snapshot)
+    }
+
+    /// 路网外包矩形的中心
+    pub fn center(&self) -> Option<algorithm::Point> {
+        let mut rects = self
+            .network
+            .edges
+            .iter()
+            .filter_map(|e| e.get_geom_rect().ok());
+        let (mut min, mut max) = rects.next()?;
+        for (rmin, rmax) in rects {
+            min = algorithm::Point(min.0.min(rmin.0), min.1.min(rmin.1));
+            max = algorithm::Point(max.0.max(rmax.0), max.1.max(rmax.1));
+        }
+        Some(algorithm::Point(
+            (min.0 + max.0) / 2.0,
+            (min.1 + max.1) / 2.0,
+        ))
+    }
+}
+
+/// 加载路网
+/// 如果path本身是快照文件直接加载, 如果存在比path更新的同名快照 `path.rmm` 则加载快照,
+/// 否则按照geojson解析。
+pub fn load(path: &Path) -> Result<Snapshot> {
+    if is_snapshot(path)? {
+        info!("load road network snapshot {}", path.display());
+        return read(path);
+    }
+    let cache = default_snapshot_path(path);
+    if is_fresh(&cache, path) {
+        info!("found road network snapshot {}", cache.display());
+        match read(&cache) {
+            Ok(snapshot) => return Ok(snapshot),
+            Err(e) => warn!(
+                "load snapshot {} failed: {}, fallback to geojson",
+                cache.display(),
+                e
+            ),
+        }
+    }
+    load_geojson(path)
+}
+
+/// 直接解析geojson路网 不使用快照缓存
+pub fn load_geojson(path: &Path) -> Result<Snapshot> {
+    debug!("load geojson file {} ", path.display());
+    let reader = BufReader::new(File::open(path)?);
+    let geojson = geojson::GeoJson::from_reader(reader)?;
+    Snapshot::new(Network::try_from(geojson)?)
+}
+
+/// `edges.json` -> `edges.json.rmm`
+pub fn default_snapshot_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".");
+    name.push(SNAPSHOT_EXTENSION);
+    PathBuf::from(name)
+}
+
+/// 检查文件头是否为快照
+pub fn is_snapshot(path: &Path) -> Result<bool> {
+    use std::io::Read;
+    let mut magic = [0u8; 8];
+    let mut file = File::open(path)?;
+    match file.read_exact(&mut magic) {
+        Ok(_) => Ok(&magic == SNAPSHOT_MAGIC),
+        Err(_) => Ok(false),
+    }
+}
+
+// 快照存在并且不比源文件旧
+pub(crate) fn is_fresh(cache: &Path, source: &Path) -> bool {
+    let modified = |p: &Path| p.metadata().and_then(|m| m.modified()).ok();
+    match (modified(cache), modified(source)) {
+        (Some(cache), Some(source)) => cache >= source,
+        _ => false,
+    }
+}
+
+/// 写快照
+pub fn write(path: &Path, snapshot: &Snapshot) -> Result<()> {
+    let network = &snapshot.network;
+    let mut strings = StringTable::default();
+    let nodes = network
+        .nodes
+        .iter()
+        .map(|n| strings.push(n))
+        .collect::<Result<Vec<_>>>()?;
+    let dups = network
+        .duplicate_edges
+        .iter()
+        .map(|id| strings.push(id))
+        .collect::<Result<Vec<_>>>()?;
+    let mut coord_count = 0u64;
+    let mut edges = Vec::with_capacity(network.edges.len());
+    for edge in network.edges.iter() {
+        let coords = match &edge.get_geometry().value {
+            Value::LineString(line) => line.len() as u64,
+            _ => return Err(anyhow::anyhow!("geometry is not linestring")),
+        };
+        let from = network
+            .find_node_by_id(&edge.get_from_node())
+            .ok_or_else(|| anyhow::anyhow!("node {} not found", edge.get_from_node()))?;
+        let to = network
+            .find_node_by_id(&edge.get_to_node())
+            .ok_or_else(|| anyhow::anyhow!("node {} not found", edge.get_to_node()))?;
+        edges.push((
+            strings.push(&edge.get_edge_id())?,
+            u32::try_from(from)?,
+            u32::try_from(to)?,
+            strings.push(&edge.get_name())?,
+            coord_count,
+            coords,
+        ));
+        coord_count += coords;
+    }
+
+    let mut w = BufWriter::new(File::create(path)?);
+    w.write_all(SNAPSHOT_MAGIC)?;
+    w.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+    let mut flags = metric_code(&network.get_metric());
+    if snapshot.index.is_some() {
+        flags |= FLAG_INDEX;
+    }
+    w.write_all(&flags.to_le_bytes())?;
+    let segments = snapshot
+        .index
+        .as_ref()
+        .map_or(&[][..], |index| index.segments.as_slice());
+    for v in [
+        network.nodes.len() as u64,
+        network.edges.len() as u64,
+        coord_count,
+        dups.len() as u64,
+        strings.bytes.len() as u64,
+        segments.len() as u64,
+    ] {
+        w.write_all(&v.to_le_bytes())?;
+    }
+    let index_key = match &snapshot.index {
+        Some(index) => [
+            crs_code(&index.source),
+            crs_code(&index.crs),
+            metric_code(&index.metric),
+        ],
+        None => [0; 3],
+    };
+    for v in index_key.iter().chain(&[0]) {
+        w.write_all(&v.to_le_bytes())?;
+    }
+    for r in nodes.iter() {
+        write_str_ref(&mut w, r)?;
+    }
+    for (edge, (id, from, to, name, coord_start, coords)) in network.edges.iter().zip(edges.iter())
+    {
+        let edge_type: u32 = match edge.get_edge_type() {
+            EdgeType::Real => 0,
+            EdgeType::Dummy => 1,
+        };
+        write_str_ref(&mut w, id)?;
+        w.write_all(&from.to_le_bytes())?;
+        w.write_all(&to.to_le_bytes())?;
+        write_str_ref(&mut w, name)?;
+        w.write_all(&edge_type.to_le_bytes())?;
+        // 限速 0表示未知
+        let speed_limit = edge.get_speed_limit().unwrap_or(0.0) as f32;
+        w.write_all(&speed_limit.to_le_bytes())?;
+        w.write_all(&coord_start.to_le_bytes())?;
+        w.write_all(&coords.to_le_bytes())?;
+        w.write_all(&edge.get_length().to_le_bytes())?;
+        w.write_all(&edge.get_real_length().to_le_bytes())?;
+    }
+    for edge in network.edges.iter() {
+        if let Value::LineString(line) = &edge.get_geometry().value {
+            for p in line {
+                w.write_all(&p[0].to_le_bytes())?;
+                w.write_all(&p[1].to_le_bytes())?;
+            }
+        }
+    }
+    for r in dups.iter() {
+        write_str_ref(&mut w, r)?;
+    }
+    for (min, max, segment) in segments.iter() {
+        for v in min.iter().chain(max.iter()) {
+            w.write_all(&v.to_le_bytes())?;
+        }
+        w.write_all(&(segment.edge as u64).to_le_bytes())?;
+        w.write_all(&u32::try_from(segment.start)?.to_le_bytes())?;
+        w.write_all(&u32::try_from(segment.end)?.to_le_bytes())?;
+        w.write_all(&segment.offset.to_le_bytes())?;
+    }
+    w.write_all(&strings.bytes)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// 读快照
+pub fn read(path: &Path) -> Result<Snapshot> {
+    let file = File::open(path)?;
+    // 快照只读, 构建完成后不会被修改
+    let mmap = unsafe { Mmap::map(&file)? };
+    decode(&mmap)
+}
+
+fn decode(buf: &[u8]) -> Result<Snapshot> {
+    let mut header = Cursor::new(buf, 0);
+    if header.bytes(8)? != SNAPSHOT_MAGIC {
+        return Err(anyhow::anyhow!("not a road network snapshot"));
+    }
+    let version = header.u32()?;
+    if version != SNAPSHOT_VERSION {
+        return Err(anyhow::anyhow!(
+            "snapshot version {} not supported (expect {}), rebuild it with `rmm build`",
+            version,
+            SNAPSHOT_VERSION
+        ));
+    }
+    let flags = header.u32()?;
+    let metric = metric_from_code(flags & FLAG_EUCLIDEAN);
+    let node_count = header.u64()? as usize;
+    let edge_count = header.u64()? as usize;
+    let coord_count = header.u64()? as usize;
+    let dup_count = header.u64()? as usize;
+    let string_bytes = header.u64()? as usize;
+    let segment_count = header.u64()? as usize;
+    let index_source = header.u32()?;
+    let index_crs = header.u32()?;
+    let index_metric = header.u32()?;
+
+    // 区块的位置由文件头计算 文件损坏时不能溢出
+    let nodes_at = HEADER_SIZE;
+    let edges_at = section_end(nodes_at, node_count, STR_REF_SIZE)?;
+    let coords_at = section_end(edges_at, edge_count, EDGE_RECORD_SIZE)?;
+    let dups_at = section_end(coords_at, coord_count, COORD_SIZE)?;
+    let segments_at = section_end(dups_at, dup_count, STR_REF_SIZE)?;
+    let strings_at = section_end(segments_at, segment_count, SEGMENT_RECORD_SIZE)?;
+    if buf.len() != section_end(strings_at, string_bytes, 1)? {
+        return Err(anyhow::anyhow!("snapshot is truncated or corrupted"));
+    }
+    let strings = &buf[strings_at..];
+
+    let mut cursor = Cursor::new(buf, nodes_at);
+    let mut nodes = Vec::with_capacity(node_count);
+    for _ in 0..node_count {
+        nodes.push(cursor.str_ref(strings)?);
+    }
+
+    let mut cursor = Cursor::new(buf, edges_at);
+    let mut edges = Vec::with_capacity(edge_count);
+    let mut line_sizes = Vec::with_capacity(edge_count);
+    for _ in 0..edge_count {
+        let id = cursor.str_ref(strings)?;
+        let from = cursor.u32()? as usize;
+        let to = cursor.u32()? as usize;
+        let name = cursor.str_ref(strings)?;
+        let edge_type = match cursor.u32()? {
+            0 => EdgeType::Real,
+            _ => EdgeType::Dummy,
+        };
+        let speed_limit = f32::from_bits(cursor.u32()?) as f64;
+        let coord_start = cursor.u64()? as usize;
+        let coords = cursor.u64()? as usize;
+        let length = cursor.f64()?;
+        let real_length = cursor.f64()?;
+        let coord_end = coord_start.checked_add(coords);
+        if coord_end.is_none_or(|end| end > coord_count) || from >= node_count || to >= node_count {
+            return Err(anyhow::anyhow!("snapshot edge {} is corrupted", id));
+        }
+        let mut coord_cursor = Cursor::new(buf, coords_at + coord_start * COORD_SIZE);
+        let mut line = Vec::with_capacity(coords);
+        for _ in 0..coords {
+            line.push(vec![coord_cursor.f64()?, coord_cursor.f64()?]);
+        }
+        line_sizes.push(coords);
+        let mut edge = Edge::new(
+            id,
+            nodes[from].clone(),
+            nodes[to].clone(),
+            length,
+            real_length,
+            edge_type,
+            name,
+            Geometry::new(Value::LineString(line)),
+        );
+        edge.set_speed_limit((speed_limit > 0.0).then_some(speed_limit));
+        edges.push(edge);
+    }
+
+    let mut cursor = Cursor::new(buf, dups_at);
+    let mut duplicate_edges = Vec::with_capacity(dup_count);
+    for _ in 0..dup_count {
+        duplicate_edges.push(cursor.str_ref(strings)?);
+    }
+
+    let index = if flags & FLAG_INDEX != 0 {
+        let mut cursor = Cursor::new(buf, segments_at);
+        let mut segments = Vec::with_capacity(segment_count);
+        for _ in 0..segment_count {
+            let min = [cursor.f64()?, cursor.f64()?];
+            let max = [cursor.f64()?, cursor.f64()?];
+            let edge = cursor.u64()? as usize;
+            let start = cursor.u32()? as usize;
+            let end = cursor.u32()? as usize;
+            let offset = cursor.f64()?;
+            // 片段引用的顶点必须在edge的折线内
+            if line_sizes
+                .get(edge)
+                .is_none_or(|&size| start >= end || end >= size)
+            {
+                return Err(anyhow::anyhow!("snapshot spatial index is corrupted"));
+            }
+            let segment = SegmentRef {
+                edge,
+                start,
+                end,
+                offset,
+            };
+            segments.push((min, max, segment));
+        }
+        Some(SegmentIndex {
+            source: crs_from_code(index_source)?,
+            crs: crs_from_code(index_crs)?,
+            metric: metric_from_code(index_metric),
+            segments,
+        })
+    } else {
+        None
+    };
+    Ok(Snapshot {
+        network: Network::from_parts(nodes, edges, duplicate_edges, metric),
+        index,
+    })
+}
+
+// 距离在文件中的编码 和flags的第0位一致
+fn metric_code(metric: &algorithm::Metric) -> u32 {
+    match metric {
+        algorithm::Metric::Haversine => 0,
+        algorithm::Metric::Euclidean => FLAG_EUCLIDEAN,
+    }
+}
+
+fn metric_from_code(code: u32) -> algorithm::Metric {
+    match code {
+        0 => algorithm::Metric::Haversine,
+        _ => algorithm::Metric::Euclidean,
+    }
+}
+
+// 坐标系在文件中的编码 UTM的分带和南北半球放在高位
+fn crs_code(crs: &algorithm::Crs) -> u32 {
+    match crs {
+        algorithm::Crs::Wgs84 => 0,
+        algorithm::Crs::Gcj02 => 1,
+        algorithm::Crs::Bd09 => 2,
+        algorithm::Crs::WebMercator => 3,
+        algorithm::Crs::Utm { zone, north } => 4 | (*zone as u32) << 8 | (*north as u32) << 16,
+    }
+}
+
+fn crs_from_code(code: u32) -> Result<algorithm::Crs> {
+    Ok(match code & 0xff {
+        0 => algorithm::Crs::Wgs84,
+        1 => algorithm::Crs::Gcj02,
+        2 => algorithm::Crs::Bd09,
+        3 => algorithm::Crs::WebMercator,
+        4 => algorithm::Crs::Utm {
+            zone: (code >> 8) as u8,
+            north: (code >> 16) & 1 != 0,
+        },
+        _ => return Err(anyhow::anyhow!("unknown crs {} in snapshot", code)),
+    })
+}
+
+// 从at开始count个size字节的区块之后的位置
+pub(crate) fn section_end(at: usize, count: usize, size: usize) -> Result<usize> {
+    count
+        .checked_mul(size)
+        .and_then(|len| at.checked_add(len))
+        .ok_or_else(|| anyhow::anyhow!("file is truncated or corrupted"))
+}
+
+#[derive(Default)]
+struct StringTable {
+    bytes: Vec<u8>,
+}
+
+impl StringTable {
+    // 偏移量和长度超过u32时返回错误 不能写出损坏的文件
+    fn push(&mut self, s: &str) -> Result<(u32, u32)> {
+        let offset = u32::try_from(self.bytes.len())?;
+        let len = u32::try_from(s.len())?;
+        self.bytes.extend_from_slice(s.as_bytes());
+        Ok((offset, len))
+    }
+}
+
+fn write_str_ref(w: &mut impl Write, r: &(u32, u32)) -> Result<()> {
+    w.write_all(&r.0.to_le_bytes())?;
+    w.write_all(&r.1.to_le_bytes())?;
+    Ok(())
+}
+
+// 带边界检查的小端序读取
+pub(crate) struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    pub(crate) fn new(buf: &'a [u8], pos: usize) -> Self {
+        Cursor { buf, pos }
+    }
+
+    pub(crate) fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .pos
+            .checked_add(n)
+            .and_then(|end| self.buf.get(self.pos..end))
+            .ok_or_else(|| anyhow::anyhow!("file is truncated or corrupted"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into()?))
+    }
+
+    pub(crate) fn f64(&mut self) -> Result<f64> {
+        Ok(f64::from_le_bytes(self.bytes(8)?.try_into()?))
+    }
+
+    fn str_ref(&mut self, strings: &[u8]) -> Result<String> {
+        let offset = self.u32()? as usize;
+        let len = self.u32()? as usize;
+        let bytes = offset
+            .checked_add(len)
+            .and_then(|end| strings.get(offset..end))
+            .ok_or_else(|| anyhow::anyhow!("snapshot string table is corrupted"))?;
+        Ok(std::str::from_utf8(bytes)?.to_string())
+    }
+}
+
+// test
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graph::fixture::{self, feature};
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let network = fixture::geojson_network(&[
+            r#"{"type":"Feature","properties":{"edge_id":1,"from_node_id":1,"to_node_id":2,"name":"a","maxspeed":"30 mph"},"geometry":{"type":"LineString","coordinates":[[121.0,31.0],[121.001,31.0005],[121.002,31.0]]}}"#.to_string(),
+            feature(2, 2, 1, "[[121.002,31.0],[121.0,31.0]]"),
+            feature(2, 2, 3, "[[121.002,31.0],[121.003,31.0]]"),
+        ])
+        .unwrap();
+        let snapshot = Snapshot::new(network).unwrap();
+        let path = std::env::temp_dir().join(format!("rmm-snapshot-{}.rmm", std::process::id()));
+        write(&path, &snapshot).unwrap();
+        assert!(is_snapshot(&path).unwrap());
+        let loaded = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let network = &loaded.network;
+        assert_eq!(network.nodes, snapshot.network.nodes);
+        assert_eq!(network.duplicate_edges, vec!["2".to_string()]);
+        assert_eq!(network.edges.len(), 2);
+        let edge = network.find_edge_by_id("1").unwrap();
+        let origin = snapshot.network.find_edge_by_id("1").unwrap();
+        assert_eq!(edge.get_name(), "a");
+        assert_eq!(edge.get_to_node(), "2");
+        assert_eq!(edge.get_length(), origin.get_length());
+        assert_eq!(edge.get_real_length(), origin.get_real_length());
+        assert_eq!(edge.get_geometry(), origin.get_geometry());
+        let center = loaded.center().unwrap();
+        assert!((center.0 - 121.001).abs() < 1e-9 && (center.1 - 31.00025).abs() < 1e-9);
+        let speed = origin.get_speed_limit().unwrap();
+        assert!((speed - 48.28).abs() < 0.01);
+        assert!((edge.get_speed_limit().unwrap() - speed).abs() < 1e-4);
+        assert!(network
+            .find_edge_by_id("2")
+            .unwrap()
+            .get_speed_limit()
+            .is_none());
+    }
+
+    #[test]
+    fn test_snapshot_index() {
+        use algorithm::{Crs, Metric};
+        let network = fixture::grid(3, 100.0).unwrap();
+        let snapshot = Snapshot::new(network)
+            .unwrap()
+            .with_index(&Crs::Wgs84, &Crs::Wgs84, Metric::Haversine)
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("rmm-index-{}.rmm", std::process::id()));
+        write(&path, &snapshot).unwrap();
+        let loaded = read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let (index, saved) = (loaded.index.as_ref().unwrap(), snapshot.index.unwrap());
+        assert_eq!(index.segments, saved.segments);
+        assert!(index.matches(Metric::Haversine) && !index.matches(Metric::Euclidean));
+
+        // 坐标转换和构建时一致才保留索引
+        let kept = loaded.clone().reproject(&Crs::Wgs84, &Crs::Wgs84).unwrap();
+        assert!(kept.index.is_some());
+        // 使用保存的索引和重新构建的索引匹配结果一致
+        let [x, y] = fixture::coord(130.0, 20.0);
+        let point = algorithm::Point(x, y);
+        let snap = |snapshot: Snapshot| {
+            let mm = crate::mm::MMatch::from_snapshot(snapshot, Metric::Haversine).unwrap();
+            let snap = mm.snap(&point, 50.0, 1).remove(0);
+            (snap.edge_id, snap.offset)
+        };
+        let rebuilt = Snapshot::new(loaded.network.clone()).unwrap();
+        assert_eq!(snap(kept), snap(rebuilt));
+        let dropped = loaded.clone().reproject(&Crs::Gcj02, &Crs::Wgs84).unwrap();
+        assert!(dropped.index.is_none());
+
+        // 构建时从gcj02转换到wgs84 加载后做相同的转换即可使用
+        let network = fixture::grid(3, 100.0).unwrap();
+        let snapshot = Snapshot::new(network)
+            .unwrap()
+            .with_index(&Crs::Gcj02, &Crs::Wgs84, Metric::Haversine)
+            .unwrap();
+        assert!(!snapshot.index.as_ref().unwrap().matches(Metric::Haversine));
+        let snapshot = snapshot.reproject(&Crs::Gcj02, &Crs::Wgs84).unwrap();
+        let index = snapshot.index.as_ref().unwrap();
+        assert!(index.matches(Metric::Haversine));
+        let rebuilt =
+            SegmentIndex::build(&snapshot.network, Crs::Wgs84, Crs::Wgs84, Metric::Haversine)
+                .unwrap();
+        assert_eq!(index.segments, rebuilt.segments);
+    }
+
+    #[test]
+    fn test_snapshot_rejects_corrupted_header() {
+        // 文件头中的数量溢出时返回错误而不是panic
+        for counts in [
+            [u64::MAX, 0, 0, 0, 0, 0],
+            [0, u64::MAX / 2, 0, 0, 0, 0],
+            [0, 0, 0, 1, u64::MAX, 0],
+            [0, 0, 0, 0, 0, u64::MAX / 2],
+        ] {
+            let mut buf = SNAPSHOT_MAGIC.to_vec();
+            buf.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+            buf.extend_from_slice(&FLAG_INDEX.to_le_bytes());
+            for count in counts {
+                buf.extend_from_slice(&count.to_le_bytes());
+            }
+            buf.extend_from_slice(&[0; 16]);
+            assert_eq!(buf.len(), HEADER_SIZE);
+            assert!(decode(&buf).is_err());
+        }
+        // edge中的坐标区间溢出
+        let mut buf = SNAPSHOT_MAGIC.to_vec();
+        buf.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        for count in [1u64, 1, 0, 0, 0, 0] {
+            buf.extend_from_slice(&count.to_le_bytes());
+        }
+        buf.extend_from_slice(&[0; 16]);
+        buf.extend_from_slice(&[0; STR_REF_SIZE]);
+        let mut edge = vec![0u8; EDGE_RECORD_SIZE];
+        edge[32..40].copy_from_slice(&u64::MAX.to_le_bytes());
+        edge[40..48].copy_from_slice(&1u64.to_le_bytes());
+        buf.extend_from_slice(&edge);
+        assert!(decode(&buf).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_rejects_corrupted_index() {
+        // 片段引用了不存在的edge
+        let network = fixture::grid(2, 100.0).unwrap();
+        let snapshot = Snapshot::new(network)
+            .unwrap()
+            .with_index(
+                &algorithm::Crs::Wgs84,
+                &algorithm::Crs::Wgs84,
+                algorithm::Metric::Haversine,
+            )
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("rmm-bad-index-{}.rmm", std::process::id()));
+        write(&path, &snapshot).unwrap();
+        let mut buf = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(decode(&buf).is_ok());
+        let network = &snapshot.network;
+        let coords = network.edges.len() * 2;
+        let segments_at = HEADER_SIZE
+            + network.nodes.len() * STR_REF_SIZE
+            + network.edges.len() * EDGE_RECORD_SIZE
+            + coords * COORD_SIZE;
+        // 第一个片段的edge索引
+        let edge_at = segments_at + 32;
+        assert_eq!(buf[edge_at..edge_at + 8], 0u64.to_le_bytes());
+        buf[edge_at..edge_at + 8].copy_from_slice(&100u64.to_le_bytes());
+        assert!(decode(&buf).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_rejects_other_version() {
+        let mut buf = SNAPSHOT_MAGIC.to_vec();
+        buf.extend_from_slice(&(SNAPSHOT_VERSION + 1).to_le_bytes());
+        buf.resize(HEADER_SIZE, 0);
+        assert!(decode(&buf).is_err());
+    }
+}
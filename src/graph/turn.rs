@@ -0,0 +1,348 @@
+use super::network::Network;
+use crate::algorithm;
+use anyhow::Result;
+use geojson::Value;
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+/*
+ * @file turn.rs
+ * 转向限制和转向代价
+ * 只在基于edge的路径查询(RoutingMode::EdgeBased)中生效, 节点模式无法区分从哪条edge进入路口。
+ */
+
+/// 路径查询的模式
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum RoutingMode {
+    /// 在节点图上查询 不考虑转向
+    #[default]
+    NodeBased,
+    /// 在edge的线图上查询 支持转向限制和转向代价
+    EdgeBased,
+    /// 使用Contraction Hierarchies批量查询两层候选点之间的距离 不考虑转向
+    Contracted,
+}
+
+/// 转向限制 key为路网中edge的索引
+#[derive(Default)]
+pub struct TurnRestrictions {
+    banned: HashSet<(usize, usize)>,      // 禁止 from -> to
+    only: HashMap<usize, HashSet<usize>>, // from 只能驶入的edge
+}
+
+impl TurnRestrictions {
+    pub fn len(&self) -> usize {
+        self.banned.len() + self.only.values().map(|v| v.len()).sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 从from驶入to是否允许
+    pub fn is_allowed(&self, from: usize, to: usize) -> bool {
+        if self.banned.contains(&(from, to)) {
+            return false;
+        }
+        match self.only.get(&from) {
+            Some(only) => only.contains(&to),
+            None => true,
+        }
+    }
+
+    /// 添加一条限制 kind采用OSM的restriction取值 no_* 为禁止 only_* 为只允许
+    pub fn add(&mut self, from: usize, to: usize, kind: &str) -> Result<()> {
+        if kind.starts_with("no_") {
+            self.banned.insert((from, to));
+        } else if kind.starts_with("only_") {
+            self.only.entry(from).or_default().insert(to);
+        } else {
+            return Err(anyhow::anyhow!("unknown restriction {}", kind));
+        }
+        Ok(())
+    }
+
+    /// 按照扩展名加载 json为Overpass导出的OSM relation 其他按照csv处理
+    pub fn from_file(path: &Path, network: &Network) -> Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let restrictions = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => Self::from_osm_json(&content, network)?,
+            _ => Self::from_csv(&content, network)?,
+        };
+        info!(
+            "load {} turn restrictions from {}",
+            restrictions.len(),
+            path.display()
+        );
+        Ok(restrictions)
+    }
+
+    /// csv格式: from_edge_id,to_edge_id[,restriction]
+    /// restriction缺省为no_entry, 支持#开头的注释, 第一条记录的第一列以from开头时作为表头
+    pub fn from_csv(content: &str, network: &Network) -> Result<Self> {
+        let mut restrictions = TurnRestrictions::default();
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .comment(Some(b'#'))
+            .trim(csv::Trim::All)
+            .from_reader(content.as_bytes());
+        for (index, record) in reader.records().enumerate() {
+            let record = record?;
+            let line = record.position().map_or(0, |p| p.line());
+            if record.len() < 2 {
+                return Err(anyhow::anyhow!("line {}: expect at least 2 fields", line));
+            }
+            if index == 0 && record[0].starts_with("from") {
+                continue;
+            }
+            let kind = record.get(2).unwrap_or("no_entry");
+            let (from, to) = match (
+                network.find_edge_index(&record[0]),
+                network.find_edge_index(&record[1]),
+            ) {
+                (Some(from), Some(to)) => (from, to),
+                _ => {
+                    warn!("line {}: edge not found, skipped", line);
+                    continue;
+                }
+            };
+            restrictions.add(from, to, kind)?;
+        }
+        Ok(restrictions)
+    }
+
+    /// Overpass json中 type=restriction 的relation
+    /// way的ref和路网edge id对应, via节点的ref和路网node id对应
+    pub fn from_osm_json(content: &str, network: &Network) -> Result<Self> {
+        let mut restrictions = TurnRestrictions::default();
+        let value: serde_json::Value = serde_json::from_str(content)?;
+        let elements = value["elements"]
+            .as_array()
+            .ok_or_else(|| anyhow::anyhow!("elements not found in osm json"))?;
+        for element in elements {
+            if element["type"] != "relation" || element["tags"]["type"] != "restriction" {
+                continue;
+            }
+            let kind = match element["tags"]["restriction"].as_str() {
+                Some(kind) => kind,
+                None => continue,
+            };
+            let members = match element["members"].as_array() {
+                Some(members) => members,
+                None => continue,
+            };
+            let member = |role: &str| -> Option<String> {
+                members
+                    .iter()
+                    .find(|m| m["role"] == role)
+                    .and_then(|m| m["ref"].as_i64())
+                    .map(|r| r.to_string())
+            };
+            let (from, via, to) = match (member("from"), member("via"), member("to")) {
+                (Some(from), Some(via), Some(to)) => (from, via, to),
+                _ => continue,
+            };
+            let (from, to) = match (network.find_edge_index(&from), network.find_edge_index(&to)) {
+                (Some(from), Some(to)) => (from, to),
+                _ => continue,
+            };
+            // via节点必须是两条edge的连接点
+            if network.edges[from].get_to_node() != via || network.edges[to].get_from_node() != via
+            {
+                warn!("restriction {} via node not match, skipped", element["id"]);
+                continue;
+            }
+            restrictions.add(from, to, kind)?;
+        }
+        Ok(restrictions)
+    }
+}
+
+/// 根据转向角计算的代价 单位和路网长度一致
+#[derive(Clone, Debug)]
+pub struct TurnCost {
+    pub straight_angle: f64, // 转向角小于该值视为直行 单位度
+    pub u_turn_angle: f64,   // 转向角大于该值视为掉头 单位度
+    pub left: f64,
+    pub right: f64,
+    pub u_turn: f64,
+}
+
+impl Default for TurnCost {
+    fn default() -> Self {
+        TurnCost {
+            straight_angle: 30.0,
+            u_turn_angle: 150.0,
+            left: 0.0,
+            right: 0.0,
+            u_turn: 0.0,
+        }
+    }
+}
+
+impl TurnCost {
+    /// angle: 转向角 (-180, 180] 左转为正
+    pub fn cost(&self, angle: f64) -> f64 {
+        let abs = angle.abs();
+        if abs < self.straight_angle {
+            0.0
+        } else if abs > self.u_turn_angle {
+            self.u_turn
+        } else if angle > 0.0 {
+            self.left
+        } else {
+            self.right
+        }
+    }
+}
+
+/// 转向模型
+#[derive(Default)]
+pub struct TurnModel {
+    pub restrictions: TurnRestrictions,
+    pub cost: TurnCost,
+    headings: Vec<(f64, f64)>, // 每个edge起点和终点处的方向角
+}
+
+impl TurnModel {
+    pub fn new(restrictions: TurnRestrictions, cost: TurnCost) -> Self {
+        TurnModel {
+            restrictions,
+            cost,
+            headings: vec![],
+        }
+    }
+
+    /// 预先计算每个edge首尾两段的方向角
+    pub(crate) fn prepare(&mut self, network: &Network) {
+        let metric = network.get_metric();
+        self.headings = network
+            .edges
+            .iter()
+            .map(|edge| match &edge.get_geometry().value {
+                Value::LineString(line) if line.len() >= 2 => {
+                    let point = |p: &Vec<f64>| algorithm::Point(p[0], p[1]);
+                    let n = line.len();
+                    (
+                        metric.heading(&point(&line[0]), &point(&line[1])),
+                        metric.heading(&point(&line[n - 2]), &point(&line[n - 1])),
+                    )
+                }
+                _ => (0.0, 0.0),
+            })
+            .collect();
+    }
+
+    /// 从from驶入to的转向角 左转为正
+    pub fn turn_angle(&self, from: usize, to: usize) -> f64 {
+        let mut angle = self.headings[to].0 - self.headings[from].1;
+        while angle > 180.0 {
+            angle -= 360.0;
+        }
+        while angle <= -180.0 {
+            angle += 360.0;
+        }
+        angle
+    }
+
+    /// 从from驶入to的代价 不允许时返回None
+    pub fn transition_cost(&self, from: usize, to: usize) -> Option<f64> {
+        if !self.restrictions.is_allowed(from, to) {
+            return None;
+        }
+        Some(self.cost.cost(self.turn_angle(from, to)))
+    }
+}
+
+// test
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graph::{
+        fixture::{self, feature},
+        RoadGraph,
+    };
+
+    // 1 --10--> 2 --20--> 3
+    //           |         |
+    //           30        40
+    //           v         v
+    //           4 <--60-- 5
+    //           4 --50--> 5
+    fn build() -> RoadGraph {
+        let features = [
+            feature(10, 1, 2, "[[121.0,31.0],[121.001,31.0]]"),
+            feature(20, 2, 3, "[[121.001,31.0],[121.002,31.0]]"),
+            feature(30, 2, 4, "[[121.001,31.0],[121.001,30.999]]"),
+            feature(40, 3, 5, "[[121.002,31.0],[121.002,30.999]]"),
+            feature(50, 4, 5, "[[121.001,30.999],[121.002,30.999]]"),
+            feature(60, 5, 4, "[[121.002,30.999],[121.001,30.999]]"),
+        ];
+        let network = fixture::geojson_network(&features).unwrap();
+        RoadGraph::new(network)
+    }
+
+    #[test]
+    fn test_turn_restriction_csv() {
+        let graph = build();
+        let content = "from_edge_id,to_edge_id,restriction\n# comment\n10,30\n20,40,only_straight_on\n99,10\n";
+        let restrictions = TurnRestrictions::from_csv(content, &graph.network).unwrap();
+        assert_eq!(restrictions.len(), 2);
+        let index = |id: &str| graph.network.find_edge_index(id).unwrap();
+        assert!(!restrictions.is_allowed(index("10"), index("30")));
+        assert!(restrictions.is_allowed(index("10"), index("20")));
+        assert!(restrictions.is_allowed(index("20"), index("40")));
+        assert!(!restrictions.is_allowed(index("20"), index("50")));
+
+        // 注释之后的表头 带引号的字段
+        let content = "# restrictions\n\nfrom,to,kind\n\"10\", 30 ,no_left_turn\n";
+        let restrictions = TurnRestrictions::from_csv(content, &graph.network).unwrap();
+        assert_eq!(restrictions.len(), 1);
+        assert!(!restrictions.is_allowed(index("10"), index("30")));
+
+        // 只接受no_和only_开头的取值
+        for kind in ["none", "nonsense", "only", "onlyway"] {
+            let content = format!("10,30,{}\n", kind);
+            assert!(TurnRestrictions::from_csv(&content, &graph.network).is_err());
+        }
+        assert!(TurnRestrictions::from_csv("10\n", &graph.network).is_err());
+    }
+
+    #[test]
+    fn test_turn_angle_and_cost() {
+        let mut graph = build();
+        let cost = TurnCost {
+            right: 5.0,
+            ..Default::default()
+        };
+        graph.set_turn_model(TurnModel::new(TurnRestrictions::default(), cost));
+        let index = |id: &str| graph.network.find_edge_index(id).unwrap();
+        let turns = graph.turn_model();
+        assert!(turns.turn_angle(index("10"), index("20")).abs() < 1.0);
+        assert!((turns.turn_angle(index("10"), index("30")) + 90.0).abs() < 1.0);
+        assert!((turns.turn_angle(index("50"), index("60")).abs() - 180.0).abs() < 1.0);
+        assert_eq!(turns.transition_cost(index("10"), index("30")), Some(5.0));
+        assert_eq!(turns.transition_cost(index("10"), index("20")), Some(0.0));
+    }
+
+    #[test]
+    fn test_edge_based_detour() {
+        let mut graph = build();
+        let index = |graph: &RoadGraph, id: &str| graph.network.find_edge_index(id).unwrap();
+        let (from, to) = (index(&graph, "10"), index(&graph, "30"));
+        let direct = graph.short_path_edge_based(from, to).unwrap();
+        assert!(direct.abs() < 1e-9);
+
+        let mut restrictions = TurnRestrictions::default();
+        restrictions.add(from, to, "no_right_turn").unwrap();
+        graph.set_turn_model(TurnModel::new(restrictions, TurnCost::default()));
+        // 2 -> 3 -> 5 -> 4 之后无法再进入30
+        assert!(graph.short_path_edge_based(from, to).is_err());
+        let to = index(&graph, "60");
+        let detour = graph.short_path_edge_based(from, to).unwrap();
+        assert!(detour > 200.0);
+    }
+}
@@ -0,0 +1,300 @@
+use super::graph::RoadGraph;
+use crate::algorithm;
+use geo::algorithm::haversine_distance::HaversineDistance;
+use geojson::Value;
+use petgraph::algo::kosaraju_scc;
+use petgraph::unionfind::UnionFind;
+use petgraph::visit::EdgeRef;
+use petgraph::Direction;
+use std::collections::HashMap;
+use std::fmt;
+
+/*
+ * @file validate.rs
+ * 路网校验
+ * 检查路网中会导致路径查询失败(no path)的常见问题。
+ */
+
+// edge几何端点和节点位置不一致
+pub struct EndpointMismatch {
+    pub edge_id: String,
+    pub node_id: String,
+    pub expected: algorithm::Point, // 节点的参考位置(第一次出现该节点的edge端点)
+    pub actual: algorithm::Point,   // 当前edge的端点
+    pub distance: f64,              // 两者的距离 单位米
+}
+
+// 悬挂节点: 只能进入或者只能离开的节点
+pub enum DanglingKind {
+    Source, // 没有入边
+    Sink,   // 没有出边
+}
+
+pub struct DanglingNode {
+    pub node_id: String,
+    pub kind: DanglingKind,
+}
+
+pub struct NetworkReport {
+    pub node_count: usize,
+    pub edge_count: usize,
+    pub invalid_features: Vec<String>, // 缺少几何、属性或者id的feature
+    pub duplicate_edges: Vec<String>,  // 重复的edge id
+    pub zero_length_edges: Vec<String>, // 长度为0的edge
+    pub degenerate_edges: Vec<String>, // 少于两个顶点或者存在重复顶点的edge
+    pub endpoint_mismatches: Vec<EndpointMismatch>,
+    pub self_loops: Vec<String>,
+    pub dangling_nodes: Vec<DanglingNode>,
+    pub weak_components: Vec<usize>,   // 弱连通分量的节点数 从大到小
+    pub strong_components: Vec<usize>, // 强连通分量的节点数 从大到小
+    pub limit: usize,                  // 打印时每一类最多输出的条数
+}
+
+impl NetworkReport {
+    /// 没有会破坏路网结构的错误
+    /// 连通性问题只作为提示 不影响结果
+    pub fn is_valid(&self) -> bool {
+        self.invalid_features.is_empty()
+            && self.duplicate_edges.is_empty()
+            && self.zero_length_edges.is_empty()
+            && self.degenerate_edges.is_empty()
+            && self.endpoint_mismatches.is_empty()
+    }
+}
+
+/// 校验路网
+/// tolerance: edge端点与节点位置允许的最大偏差 单位米
+pub fn validate_network(road_graph: &RoadGraph, tolerance: f64) -> NetworkReport {
+    let network = &road_graph.network;
+    let mut report = NetworkReport {
+        node_count: network.nodes.len(),
+        edge_count: network.edges.len(),
+        invalid_features: network.invalid_features.clone(),
+        duplicate_edges: network.duplicate_edges.clone(),
+        zero_length_edges: vec![],
+        degenerate_edges: vec![],
+        endpoint_mismatches: vec![],
+        self_loops: vec![],
+        dangling_nodes: vec![],
+        weak_components: vec![],
+        strong_components: vec![],
+        limit: 20,
+    };
+
+    // 节点的参考位置
+    let mut node_points: HashMap<String, algorithm::Point> = HashMap::new();
+    for edge in network.edges.iter() {
+        let coords = match &edge.get_geometry().value {
+            Value::LineString(line_string) => line_string,
+            _ => {
+                report.degenerate_edges.push(edge.get_edge_id());
+                continue;
+            }
+        };
+        if coords.len() < 2 || coords.windows(2).any(|w| w[0] == w[1]) {
+            report.degenerate_edges.push(edge.get_edge_id());
+        }
+        if edge.get_length() == 0.0 || edge.get_real_length() == 0.0 {
+            report.zero_length_edges.push(edge.get_edge_id());
+        }
+        if edge.get_from_node() == edge.get_to_node() {
+            report.self_loops.push(edge.get_edge_id());
+        }
+        if coords.is_empty() {
+            continue;
+        }
+        let first = coords.first().unwrap();
+        let last = coords.last().unwrap();
+        for (node_id, point) in [
+            (edge.get_from_node(), algorithm::Point(first[0], first[1])),
+            (edge.get_to_node(), algorithm::Point(last[0], last[1])),
+        ] {
+            match node_points.get(&node_id) {
+                Some(expected) => {
+                    let distance = geo::Point::new(expected.0, expected.1)
+                        .haversine_distance(&geo::Point::new(point.0, point.1));
+                    if distance > tolerance {
+                        report.endpoint_mismatches.push(EndpointMismatch {
+                            edge_id: edge.get_edge_id(),
+                            node_id,
+                            expected: *expected,
+                            actual: point,
+                            distance,
+                        });
+                    }
+                }
+                None => {
+                    node_points.insert(node_id, point);
+                }
+            }
+        }
+    }
+
+    // 连通性
+    let graph = &road_graph.graph;
+    let mut union_find = UnionFind::new(graph.node_count());
+    for edge in graph.edge_references() {
+        union_find.union(edge.source().index(), edge.target().index());
+    }
+    let mut weak: HashMap<usize, usize> = HashMap::new();
+    for label in union_find.into_labeling() {
+        *weak.entry(label).or_insert(0) += 1;
+    }
+    report.weak_components = weak.into_values().collect();
+    report.weak_components.sort_by(|a, b| b.cmp(a));
+    report.strong_components = kosaraju_scc(graph).iter().map(|c| c.len()).collect();
+    report.strong_components.sort_by(|a, b| b.cmp(a));
+
+    for node in graph.node_indices() {
+        let kind = if graph
+            .neighbors_directed(node, Direction::Incoming)
+            .next()
+            .is_none()
+        {
+            DanglingKind::Source
+        } else if graph
+            .neighbors_directed(node, Direction::Outgoing)
+            .next()
+            .is_none()
+        {
+            DanglingKind::Sink
+        } else {
+            continue;
+        };
+        report.dangling_nodes.push(DanglingNode {
+            node_id: network.nodes[node.index()].clone(),
+            kind,
+        });
+    }
+    report
+}
+
+impl fmt::Display for NetworkReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "nodes: {}, edges: {}", self.node_count, self.edge_count)?;
+        write_ids(f, "invalid features", &self.invalid_features, self.limit)?;
+        write_ids(f, "duplicate edge ids", &self.duplicate_edges, self.limit)?;
+        write_ids(f, "zero length edges", &self.zero_length_edges, self.limit)?;
+        write_ids(f, "degenerate edges", &self.degenerate_edges, self.limit)?;
+        writeln!(f, "endpoint mismatches: {}", self.endpoint_mismatches.len())?;
+        for m in self.endpoint_mismatches.iter().take(self.limit) {
+            writeln!(
+                f,
+                "  edge {} node {}: ({}, {}) vs ({}, {}), {:.3}m",
+                m.edge_id,
+                m.node_id,
+                m.actual.0,
+                m.actual.1,
+                m.expected.0,
+                m.expected.1,
+                m.distance
+            )?;
+        }
+        write_ids(f, "self loops", &self.self_loops, self.limit)?;
+        writeln!(f, "dangling nodes: {}", self.dangling_nodes.len())?;
+        for node in self.dangling_nodes.iter().take(self.limit) {
+            let kind = match node.kind {
+                DanglingKind::Source => "no incoming edge",
+                DanglingKind::Sink => "no outgoing edge",
+            };
+            writeln!(f, "  {} ({})", node.node_id, kind)?;
+        }
+        write_components(f, "weakly connected components", &self.weak_components)?;
+        write_components(f, "strongly connected components", &self.strong_components)
+    }
+}
+
+fn write_ids(f: &mut fmt::Formatter<'_>, title: &str, ids: &[String], limit: usize) -> fmt::Result {
+    writeln!(f, "{}: {}", title, ids.len())?;
+    for id in ids.iter().take(limit) {
+        writeln!(f, "  {}", id)?;
+    }
+    if ids.len() > limit {
+        writeln!(f, "  ... {} more", ids.len() - limit)?;
+    }
+    Ok(())
+}
+
+fn write_components(f: &mut fmt::Formatter<'_>, title: &str, sizes: &[usize]) -> fmt::Result {
+    let largest = sizes.first().copied().unwrap_or(0);
+    let total: usize = sizes.iter().sum();
+    writeln!(
+        f,
+        "{}: {} (largest {} nodes, {} nodes outside)",
+        title,
+        sizes.len(),
+        largest,
+        total - largest
+    )
+}
+
+// test
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graph::fixture::{self, feature};
+
+    fn build(features: Vec<String>) -> RoadGraph {
+        let network = fixture::geojson_network(&features).unwrap();
+        RoadGraph::new(network)
+    }
+
+    #[test]
+    fn test_validate_clean_network() {
+        let graph = build(vec![
+            feature(1, 1, 2, "[[121.0,31.0],[121.001,31.0]]"),
+            feature(2, 2, 1, "[[121.001,31.0],[121.0,31.0]]"),
+        ]);
+        let report = validate_network(&graph, 1.0);
+        assert!(report.is_valid());
+        assert_eq!(report.strong_components, vec![2]);
+        assert!(report.dangling_nodes.is_empty());
+    }
+
+    #[test]
+    fn test_validate_broken_network() {
+        let graph = build(vec![
+            feature(1, 1, 2, "[[121.0,31.0],[121.001,31.0]]"),
+            feature(1, 2, 3, "[[121.001,31.0],[121.002,31.0]]"),
+            feature(2, 2, 3, "[[121.0011,31.0],[121.002,31.0]]"),
+            feature(3, 3, 3, "[[121.002,31.0],[121.002,31.0]]"),
+            feature(4, 4, 5, "[[122.0,31.0],[122.001,31.0]]"),
+        ]);
+        let report = validate_network(&graph, 1.0);
+        assert!(!report.is_valid());
+        assert_eq!(report.duplicate_edges, vec!["1".to_string()]);
+        assert_eq!(report.zero_length_edges, vec!["3".to_string()]);
+        assert_eq!(report.degenerate_edges, vec!["3".to_string()]);
+        assert_eq!(report.self_loops, vec!["3".to_string()]);
+        assert_eq!(report.endpoint_mismatches.len(), 1);
+        assert_eq!(report.endpoint_mismatches[0].edge_id, "2");
+        assert_eq!(report.weak_components, vec![3, 2]);
+        assert_eq!(report.strong_components.len(), 5);
+        assert_eq!(report.dangling_nodes.len(), 3);
+    }
+
+    #[test]
+    fn test_validate_malformed_features() {
+        let graph = build(vec![
+            feature(1, 1, 2, "[[121.0,31.0],[121.001,31.0]]"),
+            r#"{"type":"Feature","properties":{"edge_id":2,"from_node_id":2,"to_node_id":1},"geometry":null}"#.to_string(),
+            r#"{"type":"Feature","properties":null,"geometry":{"type":"LineString","coordinates":[[121.0,31.0],[121.001,31.0]]}}"#.to_string(),
+            r#"{"type":"Feature","properties":{"edge_id":"x","from_node_id":2,"to_node_id":1},"geometry":{"type":"LineString","coordinates":[[121.0,31.0],[121.001,31.0]]}}"#.to_string(),
+            r#"{"type":"Feature","properties":{"edge_id":5,"to_node_id":1},"geometry":{"type":"LineString","coordinates":[[121.0,31.0],[121.001,31.0]]}}"#.to_string(),
+            r#"{"type":"Feature","properties":{"edge_id":6,"from_node_id":2,"to_node_id":1},"geometry":{"type":"Point","coordinates":[121.0,31.0]}}"#.to_string(),
+        ]);
+        let report = validate_network(&graph, 1.0);
+        assert!(!report.is_valid());
+        assert_eq!(report.edge_count, 1);
+        assert_eq!(
+            report.invalid_features,
+            vec![
+                "feature 1: missing geometry",
+                "feature 2: missing properties",
+                "feature 3: edge_id \"x\" is not an integer",
+                "feature 4: missing from_node_id",
+                "feature 5: geometry is not linestring",
+            ]
+        );
+    }
+}
@@ -1,4 +1,7 @@
 pub mod algorithm;
 pub mod graph;
 pub mod mm;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod server;
 pub mod utils;
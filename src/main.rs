@@ -1,71 +1,1395 @@
-use clap::Parser;
-use log::{debug, info};
+use clap::{Args as ClapArgs, Parser, Subcommand, ValueEnum};
+use geo::MapCoords;
+use log::{debug, info, warn};
+use rmm::algorithm;
+use rmm::algorithm::Crs;
+use rmm::graph;
+use rmm::mm;
 use rmm::mm::model;
 use rmm::mm::stmatch;
 use rmm::mm::traj;
 use rmm::utils;
+use std::collections::HashMap;
 use std::fs;
 use std::fs::File;
-use std::io::BufReader;
-use std::path::PathBuf;
-use wkt;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
 #[derive(Parser, Debug)]
 #[command(name = "RMM")]
 #[command(author = "pengxin.wu <wupeaking@gmail.com>")]
 #[command(version = "0.1")]
 #[command(about = "fast map matching using rust", long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
-    /// gps tolerance scope
-    #[arg(long, default_value_t = 0.0001)]
-    gps_err: f64,
-    /// find candidate points in radius
-    #[arg(long, default_value_t = 0.01)]
-    radius: f64,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    match_args: MatchArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// check road network for problems that break routing
+    ValidateNetwork(ValidateNetworkArgs),
+    /// build a binary snapshot of road network for fast loading
+    Build(BuildArgs),
+    /// shortest route between coordinates on the road network
+    Route(RouteArgs),
+    /// road segments reachable from a coordinate within a distance or time budget
+    Isochrone(IsochroneArgs),
+    /// snap points in a csv file to their nearest edges
+    Nearest(NearestArgs),
+    /// match trajectories and accumulate per-edge counts and speeds
+    Aggregate(Box<AggregateArgs>),
+    /// match trajectories and count origin-destination pairs per time window
+    Od(Box<OdArgs>),
+    /// match trajectories and compare with ground truth routes
+    Evaluate(Box<EvaluateArgs>),
+    /// generate noisy trajectories with ground truth along random routes
+    Synth(SynthArgs),
+    /// serve matching, snapping and routing as a local http json service
+    Serve(Box<ServeArgs>),
+}
+
+#[derive(ClapArgs, Debug)]
+// 服务不读取轨迹文件 替换掉MatchArgs中必需的GPS_TRAJ_FILE
+#[command(mut_arg("input_file", |arg| {
+    clap::Arg::new(arg.get_id().clone())
+        .value_parser(clap::value_parser!(PathBuf))
+        .hide(true)
+}))]
+struct ServeArgs {
+    /// address to listen on
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    bind: String,
+    /// worker threads, they share the road network
+    #[arg(long, default_value_t = 4)]
+    workers: usize,
+    /// default radius of /snap and /route [default: 50 meter, 0.0005 degree]
+    #[arg(long)]
+    snap_radius: Option<f64>,
+    #[command(flatten)]
+    match_args: MatchArgs,
+}
+
+#[derive(ClapArgs, Debug)]
+struct SynthArgs {
+    /// number of trajectories
+    #[arg(long, default_value_t = 10)]
+    count: usize,
+    /// random seed, same seed gives same trajectories
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    /// sampling interval, in seconds
+    #[arg(long, default_value_t = 10)]
+    interval: u64,
+    /// driving speed, in m/s
+    #[arg(long, default_value_t = 10.0)]
+    speed: f64,
+    /// standard deviation of gaussian noise, in meters
+    #[arg(long, default_value_t = 10.0)]
+    noise: f64,
+    /// probability of a point being an outlier
+    #[arg(long, default_value_t = 0.0)]
+    outlier_rate: f64,
+    /// distance of outliers from their true positions, in meters
+    #[arg(long, default_value_t = 200.0)]
+    outlier_distance: f64,
+    /// min length of routes, in meters
+    #[arg(long, default_value_t = 1000.0)]
+    min_length: f64,
+    /// max length of routes, in meters
+    #[arg(long, default_value_t = 5000.0)]
+    max_length: f64,
+    /// unix seconds of the first point of each trajectory
+    #[arg(long, default_value_t = 1672531200)]
+    start_time: u64,
+    /// ground truth json for evaluate --truth
+    #[arg(long, value_name = "TRUTH_FILE")]
+    truth_output: Option<PathBuf>,
+    /// output trajectories geojson path [default: stdout]
+    #[arg(short, long, value_name = "OUTPUT_FILE")]
+    output: Option<PathBuf>,
+    #[command(flatten)]
+    network: NetworkArgs,
+}
+
+#[derive(ClapArgs, Debug)]
+struct EvaluateArgs {
+    /// ground truth json in trajectory order: [{"path": [edge ids], "points": [edge id or null]}]
+    #[arg(long, value_name = "TRUTH_FILE", required = true)]
+    truth: PathBuf,
+    /// per trajectory metrics csv
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// INPUT_FILE is ndjson output of `rmm --stream`, evaluate it without matching
+    #[arg(long)]
+    from_matched: bool,
+    #[command(flatten)]
+    match_args: MatchArgs,
+}
+
+#[derive(ClapArgs, Debug)]
+struct OdArgs {
+    /// origins and destinations are matched edges, their nearer nodes or zones
+    #[arg(long, value_enum, default_value_t = OdLevelArg::Edge)]
+    level: OdLevelArg,
+    /// zone polygons geojson, required by --level zone
+    #[arg(long, value_name = "ZONES_FILE")]
+    zones: Option<PathBuf>,
+    /// property of zone id, falls back to feature id and feature index
+    #[arg(long, default_value = "zone_id")]
+    zone_id_property: String,
+    /// crs of zone polygons
+    #[arg(long, default_value_t = Crs::Wgs84)]
+    zone_crs: Crs,
+    /// length of time windows by departure time, in minutes, 0 for a single window
+    #[arg(long, default_value_t = 60)]
+    window_minutes: u64,
+    /// output csv path [default: stdout]
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    #[command(flatten)]
+    match_args: MatchArgs,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum OdLevelArg {
+    Edge,
+    Node,
+    Zone,
+}
+
+#[derive(ClapArgs, Debug)]
+struct AggregateArgs {
+    /// length of time-of-day buckets, in minutes
+    #[arg(long, default_value_t = 60)]
+    bucket_minutes: u64,
+    /// timezone of time-of-day buckets, in hours east of utc
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    utc_offset: f64,
+    /// speed percentiles, comma separated
+    #[arg(long, value_delimiter = ',', default_values_t = vec![50.0, 85.0])]
+    percentiles: Vec<f64>,
+    /// only use traversals covering the whole edge for speed statistics
+    #[arg(long)]
+    complete_only: bool,
+    /// output format
+    #[arg(long, value_enum, default_value_t = AggregateFormat::Geojson)]
+    format: AggregateFormat,
+    /// output path [default: stdout]
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    #[command(flatten)]
+    match_args: MatchArgs,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum AggregateFormat {
+    /// edges with statistics as properties
+    Geojson,
+    /// one row per edge, with a count and speed column for every time-of-day bucket
+    Csv,
+}
+
+#[derive(ClapArgs, Debug)]
+struct NearestArgs {
+    /// number of nearest edges for each point
+    #[arg(short, long, default_value_t = 1)]
+    k: usize,
+    /// search edges in radius [default: 50 meter, 0.0005 degree]
+    #[arg(long)]
+    radius: Option<f64>,
+    /// double radius up to this when no edge is found [default: radius]
+    #[arg(long)]
+    max_radius: Option<f64>,
+    /// column name of longitude in points file
+    #[arg(long, default_value = "lon")]
+    lon_column: String,
+    /// column name of latitude in points file
+    #[arg(long, default_value = "lat")]
+    lat_column: String,
+    /// output csv path [default: stdout]
+    #[arg(short, long, value_name = "OUTPUT_FILE")]
+    output: Option<PathBuf>,
+    #[command(flatten)]
+    network: NetworkArgs,
+    /// csv file with header, points in wgs84
+    #[arg(value_name = "POINTS_FILE")]
+    input_file: PathBuf,
+}
+
+#[derive(ClapArgs, Debug)]
+struct IsochroneArgs {
+    /// start point in wgs84: lon,lat
+    #[arg(long, value_parser = parse_point, allow_hyphen_values = true)]
+    from: algorithm::Point,
+    /// distance budget, in network length units
+    #[arg(long, required_unless_present = "time", conflicts_with = "time")]
+    distance: Option<f64>,
+    /// travel time budget, in seconds, needs --units meter
+    #[arg(long)]
+    time: Option<f64>,
+    /// constant speed used to convert travel time to distance, in m/s, speed limits are ignored
+    #[arg(long, default_value_t = 13.9)]
+    speed: f64,
+    /// snap start point to edges in radius [default: 1000 meter, 0.01 degree]
+    #[arg(long)]
+    radius: Option<f64>,
+    /// also output concave hull polygon of reachable segments
+    #[arg(long)]
+    hull: bool,
+    /// concavity of hull, smaller is tighter
+    #[arg(long, default_value_t = 2.0)]
+    concavity: f64,
+    /// output geojson path [default: stdout]
+    #[arg(short, long, value_name = "OUTPUT_FILE")]
+    output: Option<PathBuf>,
+    #[command(flatten)]
+    network: NetworkArgs,
+}
+
+#[derive(ClapArgs, Debug)]
+struct RouteArgs {
+    /// start point in wgs84: lon,lat
+    #[arg(long, value_parser = parse_point, allow_hyphen_values = true)]
+    from: algorithm::Point,
+    /// end point in wgs84: lon,lat
+    #[arg(long, value_parser = parse_point, allow_hyphen_values = true)]
+    to: algorithm::Point,
+    /// via points in wgs84, can be repeated: lon,lat
+    #[arg(long, value_parser = parse_point, allow_hyphen_values = true)]
+    via: Vec<algorithm::Point>,
+    /// snap points to edges in radius [default: 1000 meter, 0.01 degree]
+    #[arg(long)]
+    radius: Option<f64>,
+    /// speed of edges without speed limit used to estimate travel time, in m/s
+    #[arg(long, default_value_t = 13.9)]
+    speed: f64,
+    /// use contraction hierarchy for shortest paths
+    #[arg(long)]
+    ch: bool,
+    /// contraction hierarchy file of --ch [default: <ROAD_NETWORK_FILE>.ch]
+    #[arg(long, value_name = "CH_FILE", requires = "ch")]
+    ch_file: Option<PathBuf>,
+    /// output geojson path [default: stdout]
+    #[arg(short, long, value_name = "OUTPUT_FILE")]
+    output: Option<PathBuf>,
+    #[command(flatten)]
+    network: NetworkArgs,
+}
+
+// route isochrone nearest synth共用的路网参数
+#[derive(ClapArgs, Debug)]
+struct NetworkArgs {
+    /// distance units of network, radius and distances
+    #[arg(long, value_enum, default_value_t = Units::Meter)]
+    units: Units,
+    /// crs of road network
+    #[arg(long, default_value_t = Crs::Wgs84)]
+    network_crs: Crs,
+    /// crs used for routing: wgs84, utm (zone of network center) or utm:<zone><n|s>
+    #[arg(long, default_value = "wgs84")]
+    working_crs: String,
+    /// road network path
+    #[arg(short, long, value_name = "ROAD_NETWORK_FILE")]
+    network_file: PathBuf,
+}
+
+impl NetworkArgs {
+    // 和匹配一样加载路网并转换到工作坐标系
+    fn load(&self) -> (stmatch::MMatch, Crs) {
+        let (map_match, working_crs, _) = load_network(
+            &self.network_file,
+            &self.units,
+            &self.network_crs,
+            &self.working_crs,
+        );
+        (map_match, working_crs)
+    }
+}
+
+#[derive(ClapArgs, Debug)]
+struct BuildArgs {
+    /// snapshot output path [default: <ROAD_NETWORK_FILE>.rmm]
+    #[arg(short, long, value_name = "SNAPSHOT_FILE")]
+    output: Option<PathBuf>,
+    /// also build contraction hierarchy to <ROAD_NETWORK_FILE>.ch
+    #[arg(long)]
+    ch: bool,
+    /// distance units used for matching, the spatial index is built for it
+    #[arg(long, value_enum, default_value_t = Units::Meter)]
+    units: Units,
+    /// crs of road network
+    #[arg(long, default_value_t = Crs::Wgs84)]
+    network_crs: Crs,
+    /// crs used for matching: wgs84, utm (zone of network center) or utm:<zone><n|s>
+    #[arg(long, default_value = "wgs84")]
+    working_crs: String,
+    /// road network path
+    #[arg(value_name = "ROAD_NETWORK_FILE")]
+    network_file: PathBuf,
+}
+
+#[derive(ClapArgs, Debug)]
+struct ValidateNetworkArgs {
+    /// max distance between edge endpoints sharing a node, in meters
+    #[arg(long, default_value_t = 1.0)]
+    tolerance: f64,
+    /// max number of items printed for each problem
+    #[arg(long, default_value_t = 20)]
+    limit: usize,
+    /// crs of road network
+    #[arg(long, default_value_t = Crs::Wgs84)]
+    crs: Crs,
+    /// road network path
+    #[arg(value_name = "ROAD_NETWORK_FILE")]
+    network_file: PathBuf,
+}
+
+#[derive(ClapArgs, Debug)]
+struct MatchArgs {
+    /// distance units of network, candidates and config
+    #[arg(long, value_enum, default_value_t = Units::Meter)]
+    units: Units,
+    /// gps tolerance scope [default: 10 meter, 0.0001 degree]
+    #[arg(long)]
+    gps_err: Option<f64>,
+    /// find candidate points in radius [default: 1000 meter, 0.01 degree]
+    #[arg(long)]
+    radius: Option<f64>,
+    /// double radius up to this when no candidate is found [default: radius]
+    #[arg(long)]
+    max_radius: Option<f64>,
     /// selct k nearest candidate
     #[arg(long, default_value_t = 4)]
     knn: u16,
-    /// max speed
+    /// max speed in m/s
     #[arg(long, default_value_t = 30.0)]
     max_speed: f64,
     /// factor for speed
     #[arg(long, default_value_t = 4.0)]
     factor: f64,
-    /// reverse tolerance
-    #[arg(long, default_value_t = 4.0)]
-    reverse_tolerance: f64,
+    /// reverse tolerance [default: 50 meter, 4 degree]
+    #[arg(long)]
+    reverse_tolerance: Option<f64>,
+    /// crs of road network: wgs84, gcj02, bd09, webmercator, utm:51n or epsg code
+    #[arg(long, default_value_t = Crs::Wgs84)]
+    network_crs: Crs,
+    /// crs of gps trajectory
+    #[arg(long, default_value_t = Crs::Wgs84)]
+    traj_crs: Crs,
+    /// crs used for matching: wgs84, utm (zone of network center) or utm:<zone><n|s>
+    #[arg(long, default_value = "wgs84")]
+    working_crs: String,
+    /// routing between candidates, edge based routing supports turn restrictions and costs
+    #[arg(long, value_enum, default_value_t = Routing::Node)]
+    routing: Routing,
+    /// contraction hierarchy file of --routing ch [default: <ROAD_NETWORK_FILE>.ch]
+    #[arg(long, value_name = "CH_FILE")]
+    ch_file: Option<PathBuf>,
+    /// shortest path algorithm of node based routing
+    #[arg(long, value_enum, default_value_t = PathSearch::Dijkstra)]
+    path_algorithm: PathSearch,
+    /// turn restrictions file, csv (from_edge_id,to_edge_id[,restriction]) or osm json
+    #[arg(long, value_name = "FILE")]
+    turn_restrictions: Option<PathBuf>,
+    /// extra cost of a left turn, in network length units
+    #[arg(long, default_value_t = 0.0)]
+    left_turn_penalty: f64,
+    /// extra cost of a right turn, in network length units
+    #[arg(long, default_value_t = 0.0)]
+    right_turn_penalty: f64,
+    /// extra cost of a u-turn, in network length units
+    #[arg(long, default_value_t = 0.0)]
+    u_turn_penalty: f64,
+    /// drop points closer than this to the previous kept point, in network length units
+    #[arg(long, default_value_t = 0.0)]
+    min_point_distance: f64,
+    /// drop points reached or left faster than this, in km/h, needs timestamps
+    #[arg(long, value_name = "KMH")]
+    max_point_speed: Option<f64>,
+    /// sort points by timestamp when timestamps go backwards
+    #[arg(long)]
+    fix_timestamps: bool,
+    /// smooth gps jitter with a kalman filter
+    #[arg(long)]
+    kalman: bool,
+    /// acceleration noise of kalman filter, in m/s^2
+    #[arg(long, default_value_t = 2.0)]
+    kalman_process_noise: f64,
+    /// gps noise of kalman filter, in meters
+    #[arg(long, default_value_t = 10.0)]
+    kalman_measurement_noise: f64,
+    /// split trajectories at stay points and time gaps, match each trip separately
+    #[arg(long)]
+    segment: bool,
+    /// max distance of points in a stay [default: 200 meter, 0.002 degree]
+    #[arg(long)]
+    stay_distance: Option<f64>,
+    /// min duration of a stay, in seconds
+    #[arg(long, default_value_t = 300)]
+    stay_duration: u64,
+    /// split trajectory when time between points exceeds this, in seconds
+    #[arg(long, default_value_t = 600)]
+    max_gap: u64,
+    /// write matched points and points interpolated along the matched path as geojson
+    #[arg(long, value_name = "FILE")]
+    dense_output: Option<PathBuf>,
+    /// time between interpolated points, in seconds
+    #[arg(long, default_value_t = 10)]
+    interpolate_interval: u64,
+    /// distribute travel time by edge speed limits instead of constant speed
+    #[arg(long)]
+    follow_speed_limits: bool,
+    /// write entry/exit time, length and speed of every traversed edge as csv
+    #[arg(long, value_name = "FILE")]
+    travel_times: Option<PathBuf>,
+    /// read one trajectory per line from stdin and write one result per line to stdout
+    #[arg(long, conflicts_with_all = ["input_file", "segment", "dense_output", "travel_times"])]
+    stream: bool,
     /// road network path
-    #[arg(short, long, value_name = "ROAD_NETWORK_FILE")]
-    network_file: String,
+    #[arg(short, long, value_name = "ROAD_NETWORK_FILE", required = true)]
+    network_file: Option<String>,
 
     /// input  gps traj input
-    #[arg(value_name = "GPS_TRAJ_FILE")]
-    input_file: PathBuf,
+    #[arg(value_name = "GPS_TRAJ_FILE", required_unless_present = "stream")]
+    input_file: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Units {
+    /// meters, distances on lon/lat are computed with haversine
+    Meter,
+    /// euclidean distance on raw coordinates, compatible with old versions
+    Degree,
+}
+
+impl Units {
+    fn metric(&self) -> algorithm::Metric {
+        match self {
+            Units::Meter => algorithm::Metric::Haversine,
+            Units::Degree => algorithm::Metric::Euclidean,
+        }
+    }
+
+    // (gps_err, radius, reverse_tolerance) 的默认值
+    fn defaults(&self) -> (f64, f64, f64) {
+        match self {
+            Units::Meter => (10.0, 1000.0, 50.0),
+            Units::Degree => (0.0001, 0.01, 4.0),
+        }
+    }
+
+    // 停留范围的默认值
+    fn stay_distance(&self) -> f64 {
+        match self {
+            Units::Meter => 200.0,
+            Units::Degree => 0.002,
+        }
+    }
+
+    // 服务中吸附和路径规划查询半径的默认值
+    fn snap_radius(&self) -> f64 {
+        match self {
+            Units::Meter => 50.0,
+            Units::Degree => 0.0005,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Routing {
+    /// shortest path on nodes, turns are ignored
+    Node,
+    /// shortest path on edges, with turn restrictions and turn costs
+    Edge,
+    /// contraction hierarchies, fast on large networks, turns are ignored
+    Ch,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum PathSearch {
+    Dijkstra,
+    /// a* with straight line distance as heuristic
+    Astar,
+    /// bidirectional dijkstra
+    Bidirectional,
+}
+
+impl PathSearch {
+    fn algorithm(&self) -> graph::PathAlgorithm {
+        match self {
+            PathSearch::Dijkstra => graph::PathAlgorithm::Dijkstra,
+            PathSearch::Astar => graph::PathAlgorithm::AStar,
+            PathSearch::Bidirectional => graph::PathAlgorithm::Bidirectional,
+        }
+    }
 }
 
 fn main() {
-    utils::log::log_init();
     let args = Args::parse();
+    if args.command.is_none() && args.match_args.stream {
+        // stdout只输出匹配结果
+        utils::log::log_init_to(env_logger::Target::Stderr);
+    } else {
+        utils::log::log_init();
+    }
     debug!("{:?}", args);
 
-    info!("try constarct map matching network from geojson file");
-    let mut map_match = stmatch::MMatch::try_from(args.network_file.clone())
+    match args.command {
+        Some(Command::ValidateNetwork(args)) => validate_network(args),
+        Some(Command::Build(args)) => build_snapshot(args),
+        Some(Command::Route(args)) => route(args),
+        Some(Command::Isochrone(args)) => isochrone(args),
+        Some(Command::Nearest(args)) => nearest(args),
+        Some(Command::Aggregate(args)) => aggregate(*args),
+        Some(Command::Od(args)) => od(*args),
+        Some(Command::Evaluate(args)) => evaluate(*args),
+        Some(Command::Synth(args)) => synth(args),
+        Some(Command::Serve(args)) => serve(*args),
+        None if args.match_args.stream => match_stream(args.match_args),
+        None => match_trajs(args.match_args),
+    }
+}
+
+fn validate_network(args: ValidateNetworkArgs) {
+    info!("validate road network {}", args.network_file.display());
+    // 校验源文件本身 快照缓存中不包含被跳过的feature
+    let snapshot = graph::snapshot::is_snapshot(&args.network_file)
+        .and_then(|is_snapshot| match is_snapshot {
+            true => graph::snapshot::read(&args.network_file),
+            false => graph::snapshot::load_geojson(&args.network_file),
+        })
+        .and_then(|snapshot| snapshot.reproject(&args.crs, &Crs::Wgs84))
+        .expect("read road network failed: ");
+    let road_graph = graph::RoadGraph::new(snapshot.network);
+    let mut report = graph::validate_network(&road_graph, args.tolerance);
+    report.limit = args.limit;
+    println!("{}", report);
+    if !report.is_valid() {
+        std::process::exit(1);
+    }
+}
+
+fn build_snapshot(args: BuildArgs) {
+    let output = args
+        .output
+        .unwrap_or_else(|| graph::snapshot::default_snapshot_path(&args.network_file));
+    info!("read road network {}", args.network_file.display());
+    let reader =
+        BufReader::new(File::open(&args.network_file).expect("open road network failed: "));
+    let geojson = geojson::GeoJson::from_reader(reader).expect("read road network failed: ");
+    let network = graph::Network::try_from(geojson).expect("read road network failed: ");
+    let snapshot = graph::snapshot::Snapshot::new(network).expect("build snapshot failed: ");
+    // 空间索引按照匹配时的坐标系和距离构建 加载时参数一致才会使用
+    let working_crs = resolve_working_crs(&args.working_crs, &snapshot, &args.network_crs);
+    let snapshot = snapshot
+        .with_index(
+            &args.network_crs,
+            &working_crs,
+            working_metric(&args.units, &working_crs),
+        )
+        .expect("build spatial index failed: ");
+    graph::snapshot::write(&output, &snapshot).expect("write snapshot failed: ");
+    info!(
+        "write snapshot {} ({} nodes, {} edges)",
+        output.display(),
+        snapshot.network.nodes.len(),
+        snapshot.network.edges.len()
+    );
+    if args.ch {
+        let output = graph::ch::default_ch_path(&args.network_file);
+        let road_graph = graph::RoadGraph::new(snapshot.network);
+        let ch = graph::ContractionHierarchy::build(&road_graph);
+        graph::ch::write(&output, &ch).expect("write contraction hierarchy failed: ");
+        info!("write contraction hierarchy {}", output.display());
+    }
+}
+
+fn route(args: RouteArgs) {
+    let (mut map_match, working_crs) = args.network.load();
+    if args.ch {
+        load_contraction_hierarchy(&args.network.network_file, &args.ch_file, &mut map_match);
+    }
+    let mut points = vec![args.from];
+    points.extend(args.via.iter().copied());
+    points.push(args.to);
+    let points: Vec<algorithm::Point> = points.iter().map(|p| working_crs.from_wgs84(p)).collect();
+    let radius = args.radius.unwrap_or(args.network.units.defaults().1);
+    let route = map_match.route(&points, radius).expect("route failed: ");
+    let network = &map_match.road_graph().network;
+    let edges: Vec<String> = route
+        .edges
+        .iter()
+        .map(|e| network.edges[*e].get_edge_id())
+        .collect();
+    // 有限速的edge按照限速 其余按照--speed
+    let duration = route.duration(network, args.speed);
+    info!(
+        "route edges: {:?}, length: {:.1}, duration: {:.1}s",
+        edges, route.length, duration
+    );
+    let feature = feature(
+        geojson::Value::LineString(wgs84_line(&working_crs, &route.geometry)),
+        serde_json::json!({
+            "edges": edges,
+            "length": route.length,
+            "duration": duration,
+        }),
+    );
+    write_geojson(args.output, geojson::GeoJson::Feature(feature));
+}
+
+fn isochrone(args: IsochroneArgs) {
+    let units = args.network.units;
+    if args.time.is_some() && matches!(units, Units::Degree) {
+        panic!("--time requires --units meter");
+    }
+    let (map_match, working_crs) = args.network.load();
+    // 时间按照固定的--speed换算为距离 不考虑edge的限速
+    let max_distance = match (args.distance, args.time) {
+        (Some(distance), _) => distance,
+        (None, Some(time)) => time * args.speed,
+        (None, None) => unreachable!(),
+    };
+    let radius = args.radius.unwrap_or(units.defaults().1);
+    let isochrone = map_match
+        .isochrone(&working_crs.from_wgs84(&args.from), max_distance, radius)
+        .expect("isochrone failed: ");
+    info!(
+        "{} reachable segments within {:.1}",
+        isochrone.segments.len(),
+        max_distance
+    );
+    let network = &map_match.road_graph().network;
+    let mut features = vec![];
+    for segment in isochrone.segments.iter() {
+        let edge = &network.edges[segment.edge];
+        let geometry = map_match
+            .segment_geometry(segment)
+            .expect("segment geometry failed: ");
+        // 距离单位为度时没有时间
+        let time = matches!(units, Units::Meter).then_some(segment.cost / args.speed);
+        features.push(feature(
+            geojson::Value::LineString(wgs84_line(&working_crs, &geometry)),
+            serde_json::json!({
+                "edge_id": edge.get_edge_id(),
+                "start": segment.start,
+                "end": segment.end,
+                "complete": segment.start == 0.0 && segment.end >= edge.get_length(),
+                "distance": segment.cost,
+                "time": time,
+            }),
+        ));
+    }
+    if args.hull {
+        let hull = map_match
+            .isochrone_hull(&isochrone, args.concavity)
+            .expect("concave hull failed: ");
+        if let Some(hull) = hull {
+            let hull = hull.map_coords(|c| {
+                let p = working_crs.to_wgs84(&algorithm::Point(c.x, c.y));
+                geo::Coord { x: p.0, y: p.1 }
+            });
+            features.push(feature(
+                geojson::Value::from(&hull),
+                serde_json::json!({ "hull": true, "distance": max_distance }),
+            ));
+        }
+    }
+    write_geojson(
+        args.output,
+        geojson::GeoJson::FeatureCollection(geojson::FeatureCollection {
+            bbox: None,
+            features,
+            foreign_members: None,
+        }),
+    );
+}
+
+fn nearest(args: NearestArgs) {
+    let (map_match, working_crs) = args.network.load();
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_path(&args.input_file)
+        .expect("read points file failed: ");
+    let header = reader
+        .headers()
+        .expect("read points file header failed: ")
+        .clone();
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|c| c == name)
+            .unwrap_or_else(|| panic!("column {} not found in points file", name))
+    };
+    let (lon, lat) = (column(&args.lon_column), column(&args.lat_column));
+    let radius = args
+        .radius
+        .unwrap_or_else(|| args.network.units.snap_radius());
+    let max_radius = args.max_radius.unwrap_or(radius);
+
+    let output: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(File::create(path).expect("create csv failed: ")),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    let mut writer = csv::WriterBuilder::new().flexible(true).from_writer(output);
+    // 原始列之后追加吸附结果 每个点输出k行 没有吸附结果时吸附列为空
+    let mut columns = header.clone();
+    for name in [
+        "rank", "edge_id", "snap_lon", "snap_lat", "distance", "offset", "fraction",
+    ] {
+        columns.push_field(name);
+    }
+    writer.write_record(&columns).expect("write csv failed: ");
+    let (mut total, mut missed, mut skipped) = (0, 0, 0);
+    for record in reader.records() {
+        // 格式错误或者坐标无效的行跳过 行号为文件中的行号
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                warn!("skip invalid row: {}", e);
+                skipped += 1;
+                continue;
+            }
+        };
+        let line = record.position().map_or(0, |p| p.line());
+        let coord = |index: usize| record.get(index).and_then(|f| f.parse::<f64>().ok());
+        let point = match (coord(lon), coord(lat)) {
+            (Some(x), Some(y)) => working_crs.from_wgs84(&algorithm::Point(x, y)),
+            _ => {
+                warn!("skip line {}: invalid coordinate", line);
+                skipped += 1;
+                continue;
+            }
+        };
+        let snaps = map_match.nearest_adaptive(&point, args.k, radius, max_radius);
+        total += 1;
+        if snaps.is_empty() {
+            missed += 1;
+            let mut row = record.clone();
+            for _ in 0..7 {
+                row.push_field("");
+            }
+            writer.write_record(&row).expect("write csv failed: ");
+        }
+        for (rank, snap) in snaps.iter().enumerate() {
+            let mut row = record.clone();
+            let snap_point = working_crs.to_wgs84(&snap.point);
+            row.push_field(&(rank + 1).to_string());
+            row.push_field(&snap.edge_id);
+            row.push_field(&format!("{:.7}", snap_point.0));
+            row.push_field(&format!("{:.7}", snap_point.1));
+            row.push_field(&format!("{:.3}", snap.distance));
+            row.push_field(&format!("{:.3}", snap.offset));
+            row.push_field(&format!("{:.6}", snap.fraction));
+            writer.write_record(&row).expect("write csv failed: ");
+        }
+    }
+    writer.flush().expect("write csv failed: ");
+    info!(
+        "snapped {} points, {} without edge in {}, {} invalid rows skipped",
+        total, missed, max_radius, skipped
+    );
+}
+
+// 加载路网并转换到工作坐标系 返回匹配器、工作坐标系以及距离的度量
+fn load_network(
+    network_file: &Path,
+    units: &Units,
+    network_crs: &Crs,
+    working_crs: &str,
+) -> (stmatch::MMatch, Crs, algorithm::Metric) {
+    let snapshot =
+        graph::snapshot::load(network_file).expect("constarct map matching network failed: ");
+    let working_crs = resolve_working_crs(working_crs, &snapshot, network_crs);
+    let metric = working_metric(units, &working_crs);
+    info!("network crs: {}, working crs: {}", network_crs, working_crs);
+    let snapshot = snapshot
+        .reproject(network_crs, &working_crs)
+        .expect("reproject road network failed: ");
+    let map_match = stmatch::MMatch::from_snapshot(snapshot, metric)
         .expect("constarct map matching network failed: ");
+    (map_match, working_crs, metric)
+}
+
+// 工作坐标系中的折线转换到wgs84
+fn wgs84_line(working_crs: &Crs, line: &[[f64; 2]]) -> Vec<Vec<f64>> {
+    line.iter()
+        .map(|p| {
+            let p = working_crs.to_wgs84(&algorithm::Point(p[0], p[1]));
+            vec![p.0, p.1]
+        })
+        .collect()
+}
+
+fn feature(value: geojson::Value, properties: serde_json::Value) -> geojson::Feature {
+    geojson::Feature {
+        bbox: None,
+        geometry: Some(geojson::Geometry::new(value)),
+        id: None,
+        properties: properties.as_object().cloned(),
+        foreign_members: None,
+    }
+}
+
+// 输出到文件 没有指定文件时输出到stdout
+fn write_geojson(output: Option<PathBuf>, geojson: geojson::GeoJson) {
+    let content = geojson.to_string();
+    match output {
+        Some(path) => fs::write(path, content).expect("write geojson failed: "),
+        None => println!("{}", content),
+    }
+}
+
+fn match_trajs(args: MatchArgs) {
+    let mut outputs = MatchOutputs::new(&args);
+    match_batch(&args, |map_match, working_crs, matched| {
+        outputs.add(&args, map_match, working_crs, matched)
+    });
+    outputs.write(args);
+}
+
+// 逐行读取stdin中的轨迹 每条轨迹输出一行结果 单条轨迹出错时记录到stderr并继续
+fn match_stream(args: MatchArgs) {
+    let mut setup = match_setup(&args);
+    let mut stdout = std::io::stdout().lock();
+    let (mut matched, mut failed) = (0, 0);
+    let mut stdin = std::io::stdin().lock();
+    let mut buf = Vec::new();
+    for i in 0.. {
+        // 按字节读取一行 不是utf-8的行和其它出错的行一样跳过
+        buf.clear();
+        match stdin.read_until(b'\n', &mut buf) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                warn!("line {}: read stdin failed: {}", i + 1, e);
+                failed += 1;
+                break;
+            }
+        }
+        let line = match std::str::from_utf8(&buf) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("line {}: {}", i + 1, e);
+                failed += 1;
+                continue;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        match match_record(&args, &mut setup, line) {
+            Ok(mut value) => {
+                value["line"] = serde_json::json!(i + 1);
+                writeln!(stdout, "{}", value).expect("write stdout failed: ");
+                matched += 1;
+            }
+            Err(e) => {
+                warn!("line {}: {}", i + 1, e);
+                failed += 1;
+            }
+        }
+    }
+    info!("stream matched {} trajectories, {} failed", matched, failed);
+}
+
+// 匹配一行轨迹 edges和points和输入的点一一对应 预处理删除或者没有匹配的点为null
+fn match_record(
+    args: &MatchArgs,
+    setup: &mut MatchSetup,
+    line: &str,
+) -> anyhow::Result<serde_json::Value> {
+    // 需要排序的时间戳由预处理处理
+    let mut record = if args.fix_timestamps {
+        mm::parse_record_unordered(line)?
+    } else {
+        mm::parse_record(line)?
+    };
+    for info in record.traj.iter_mut() {
+        info.point = args.traj_crs.transform(&info.point, &setup.working_crs);
+    }
+    let cleaned = mm::preprocess(&record.traj, setup.config.metric, &setup.preprocess);
+    let result = setup.map_match.match_traj(&cleaned.traj, &setup.config)?;
+    let mut edges = vec![None; record.traj.len()];
+    let mut points = vec![None; record.traj.len()];
+    for (i, candidate) in result.matched_candidates.iter().enumerate() {
+        if let Some(candidate) = candidate {
+            let index = cleaned.original_index(i);
+            let point = setup
+                .working_crs
+                .transform(&candidate.closest_point, &args.traj_crs);
+            edges[index] = Some(&result.o_path[i]);
+            points[index] = Some([point.0, point.1]);
+        }
+    }
+    let mut evaluation = mm::Evaluation::default();
+    evaluation.add_breaks(&result);
+    Ok(serde_json::json!({
+        "id": record.id,
+        "edges": edges,
+        "points": points,
+        "cpath": result.cpath,
+        "length": result.legs.iter().map(|leg| leg.length).sum::<f64>(),
+        "breaks": evaluation.breaks,
+    }))
+}
+
+fn aggregate(args: AggregateArgs) {
+    let cfg = mm::AggregateConfig {
+        bucket_seconds: args.bucket_minutes * 60,
+        utc_offset: (args.utc_offset * 3600.0).round() as i64,
+        percentiles: args.percentiles.clone(),
+        complete_only: args.complete_only,
+    };
+    let mut aggregate = mm::EdgeAggregate::new(cfg).expect("invalid aggregate config: ");
+    let mut outputs = MatchOutputs::new(&args.match_args);
+    let (map_match, working_crs, _) =
+        match_batch(&args.match_args, |map_match, working_crs, matched| {
+            outputs.add(&args.match_args, map_match, working_crs, matched);
+            aggregate.add(map_match, matched.trip.traj_index, matched.result);
+        });
+    let network = &map_match.road_graph().network;
+    let stats: Vec<&mm::EdgeStats> = aggregate.edges().collect();
+    info!(
+        "aggregate {} matched trajectories on {} edges",
+        aggregate.trajs,
+        stats.len()
+    );
+    let number = |value: Option<f64>| value.map_or(String::new(), |v| format!("{:.3}", v));
+    let content = match args.format {
+        AggregateFormat::Csv => {
+            let mut header = vec![
+                "edge_id".to_string(),
+                "count".to_string(),
+                "trajs".to_string(),
+                "samples".to_string(),
+                "mean_speed".to_string(),
+            ];
+            header.extend(args.percentiles.iter().map(|p| format!("p{}", p)));
+            for bucket in 0..aggregate.bucket_count() {
+                let label = aggregate.bucket_label(bucket).replace(':', "");
+                header.push(format!("count_{}", label));
+                header.push(format!("speed_{}", label));
+            }
+            let mut rows = vec![header.join(",")];
+            for edge in stats.iter() {
+                let mut row = vec![
+                    network.edges[edge.edge_index].get_edge_id(),
+                    edge.count.to_string(),
+                    edge.trajs.to_string(),
+                    edge.speeds.len().to_string(),
+                    number(edge.mean_speed()),
+                ];
+                row.extend(
+                    args.percentiles
+                        .iter()
+                        .map(|p| number(edge.percentile_speed(*p))),
+                );
+                for bucket in 0..aggregate.bucket_count() {
+                    row.push(edge.bucket_counts[bucket].to_string());
+                    row.push(number(edge.bucket_mean_speed(bucket)));
+                }
+                rows.push(row.join(","));
+            }
+            rows.join("\n") + "\n"
+        }
+        AggregateFormat::Geojson => {
+            let features = stats
+                .iter()
+                .map(|edge| {
+                    let road = &network.edges[edge.edge_index];
+                    let mut properties = serde_json::json!({
+                        "edge_id": road.get_edge_id(),
+                        "count": edge.count,
+                        "trajs": edge.trajs,
+                        "samples": edge.speeds.len(),
+                        "mean_speed": edge.mean_speed(),
+                    });
+                    for p in args.percentiles.iter() {
+                        properties[format!("p{}", p)] = edge.percentile_speed(*p).into();
+                    }
+                    // 只输出有记录的时段
+                    properties["buckets"] = (0..aggregate.bucket_count())
+                        .filter(|bucket| edge.bucket_counts[*bucket] > 0)
+                        .map(|bucket| {
+                            serde_json::json!({
+                                "start": aggregate.bucket_label(bucket),
+                                "count": edge.bucket_counts[bucket],
+                                "mean_speed": edge.bucket_mean_speed(bucket),
+                            })
+                        })
+                        .collect::<Vec<_>>()
+                        .into();
+                    // 几何转换回路网的坐标系
+                    let line: algorithm::Line = road
+                        .get_geometry()
+                        .clone()
+                        .try_into()
+                        .expect("read edge geometry failed: ");
+                    let coords = line
+                        .0
+                        .iter()
+                        .map(|c| {
+                            let p = working_crs.transform(
+                                &algorithm::Point(c[0], c[1]),
+                                &args.match_args.network_crs,
+                            );
+                            vec![p.0, p.1]
+                        })
+                        .collect();
+                    feature(geojson::Value::LineString(coords), properties)
+                })
+                .collect();
+            geojson::GeoJson::FeatureCollection(geojson::FeatureCollection {
+                bbox: None,
+                features,
+                foreign_members: None,
+            })
+            .to_string()
+        }
+    };
+    match args.output {
+        Some(path) => fs::write(path, content).expect("write aggregate failed: "),
+        None => print!("{}", content),
+    }
+    outputs.write(args.match_args);
+}
+
+fn od(args: OdArgs) {
+    let level = match args.level {
+        OdLevelArg::Edge => mm::OdLevel::Edge,
+        OdLevelArg::Node => mm::OdLevel::Node,
+        OdLevelArg::Zone => {
+            let path = args.zones.as_ref().expect("--level zone requires --zones");
+            info!("read zones {}", path.display());
+            let reader = BufReader::new(File::open(path).expect("open zones failed: "));
+            let geojson = geojson::GeoJson::from_reader(reader).expect("read zones failed: ");
+            let zones = mm::Zones::from_geojson(geojson, &args.zone_id_property)
+                .expect("read zones failed: ");
+            info!("{} zones", zones.len());
+            mm::OdLevel::Zone(zones)
+        }
+    };
+    let window_seconds = args.window_minutes * 60;
+    let mut level = Some(level);
+    let mut matrix: Option<mm::OdMatrix> = None;
+    let mut outputs = MatchOutputs::new(&args.match_args);
+    match_batch(&args.match_args, |map_match, working_crs, matched| {
+        outputs.add(&args.match_args, map_match, working_crs, matched);
+        // 区域转换到匹配使用的坐标系
+        let matrix = matrix.get_or_insert_with(|| {
+            let mut level = level.take().unwrap();
+            if let mm::OdLevel::Zone(zones) = &mut level {
+                zones.reproject(&args.zone_crs, working_crs);
+            }
+            mm::OdMatrix::new(level, window_seconds)
+        });
+        matrix.add(matched.result);
+    });
+
+    let mut rows = vec!["window_start,window_end,origin,destination,count".to_string()];
+    if let Some(matrix) = matrix {
+        info!(
+            "{} trips with origin and destination, {} skipped",
+            matrix.total(),
+            matrix.skipped
+        );
+        for (window, origin, destination, count) in matrix.iter() {
+            // 不划分窗口或者时间戳未知时窗口为空
+            let (start, end) = match window {
+                Some(start) => (start.to_string(), (start + window_seconds).to_string()),
+                None => (String::new(), String::new()),
+            };
+            rows.push(format!(
+                "{},{},{},{},{}",
+                start, end, origin, destination, count
+            ));
+        }
+    }
+    let content = rows.join("\n") + "\n";
+    match args.output {
+        Some(path) => fs::write(path, content).expect("write csv failed: "),
+        None => print!("{}", content),
+    }
+    outputs.write(args.match_args);
+}
+
+fn evaluate(args: EvaluateArgs) {
+    info!("read ground truth {}", args.truth.display());
+    let content = fs::read_to_string(&args.truth).expect("read ground truth failed: ");
+    let value: serde_json::Value =
+        serde_json::from_str(&content).expect("read ground truth failed: ");
+    let truth = mm::read_ground_truth(&value).expect("read ground truth failed: ");
+    let mut evaluations = vec![mm::Evaluation::default(); truth.len()];
+    let mut matched_paths: Vec<Vec<String>> = vec![vec![]; truth.len()];
+    // 每条轨迹逐点匹配的edge 预处理或者切分删除的点为None
+    let mut matched_edges: Vec<Vec<Option<String>>> = vec![vec![]; truth.len()];
+    let map_match = if args.from_matched {
+        let input_file = args.match_args.input_file.clone().unwrap();
+        info!("read matched results {}", input_file.display());
+        let content = fs::read_to_string(&input_file).expect("read matched results failed: ");
+        let lines = content
+            .lines()
+            .enumerate()
+            .filter(|(_, l)| !l.trim().is_empty());
+        for (traj_index, (line, content)) in lines.enumerate() {
+            if traj_index >= truth.len() {
+                warn!("traj {} has no ground truth", traj_index);
+                continue;
+            }
+            let record = mm::parse_matched(content).unwrap_or_else(|e| {
+                panic!("read matched results failed: line {}: {}", line + 1, e)
+            });
+            evaluations[traj_index].breaks += record.breaks;
+            matched_edges[traj_index] = record.edges;
+            matched_paths[traj_index] = record.cpath;
+        }
+        match_setup(&args.match_args).map_match
+    } else {
+        let mut matched_points: Vec<HashMap<usize, String>> = vec![HashMap::new(); truth.len()];
+        let mut outputs = MatchOutputs::new(&args.match_args);
+        let (map_match, _, traj_lens) =
+            match_batch(&args.match_args, |map_match, working_crs, matched| {
+                outputs.add(&args.match_args, map_match, working_crs, matched);
+                let (trip, cleaned) = (matched.trip, matched.cleaned);
+                if trip.traj_index >= truth.len() {
+                    warn!("traj {} has no ground truth", trip.traj_index);
+                    return;
+                }
+                // 匹配点的序号换算成原始轨迹中的序号
+                for (i, candidate) in matched.result.matched_candidates.iter().enumerate() {
+                    if let Some(candidate) = candidate {
+                        let index = trip.start + cleaned.original_index(i);
+                        matched_points[trip.traj_index].insert(index, candidate.edge.get_edge_id());
+                    }
+                }
+                evaluations[trip.traj_index].add_breaks(matched.result);
+                matched_paths[trip.traj_index].extend(matched.result.cpath.iter().cloned());
+            });
+        for (traj_index, points) in matched_points.iter_mut().enumerate() {
+            let len = traj_lens.get(traj_index).copied().unwrap_or(0);
+            matched_edges[traj_index] = (0..len).map(|i| points.remove(&i)).collect();
+        }
+        outputs.write(args.match_args);
+        map_match
+    };
+
+    let network = &map_match.road_graph().network;
+    let mut total = mm::Evaluation::default();
+    let mut rows = vec![
+        "traj,points,point_accuracy,route_mismatch,precision,recall,unmatched,breaks".to_string(),
+    ];
+    let value = |v: Option<f64>| v.map_or(String::new(), |v| format!("{:.4}", v));
+    for (i, evaluation) in evaluations.iter_mut().enumerate() {
+        evaluation.add_edges(&matched_edges[i], |j| {
+            truth[i].points.get(j).cloned().flatten()
+        });
+        evaluation
+            .add_route(network, &matched_paths[i], &truth[i].path)
+            .expect("evaluate route failed: ");
+        total.merge(evaluation);
+        rows.push(format!(
+            "{},{},{},{},{},{},{},{}",
+            i,
+            evaluation.points,
+            value(evaluation.point_accuracy()),
+            value(evaluation.route_mismatch()),
+            value(evaluation.precision()),
+            value(evaluation.recall()),
+            evaluation.unmatched_points,
+            evaluation.breaks
+        ));
+    }
+    if let Some(path) = &args.output {
+        fs::write(path, rows.join("\n") + "\n").expect("write csv failed: ");
+    }
+    print!("{}", total);
+}
+
+fn synth(args: SynthArgs) {
+    // 长度、速度和噪声的参数都是米
+    if matches!(args.network.units, Units::Degree) {
+        panic!("synth requires --units meter");
+    }
+    let (map_match, working_crs) = args.network.load();
+    let cfg = mm::SynthConfig {
+        seed: args.seed,
+        interval: args.interval,
+        speed: args.speed,
+        noise: args.noise,
+        outlier_rate: args.outlier_rate,
+        outlier_distance: args.outlier_distance,
+        min_length: args.min_length,
+        max_length: args.max_length,
+        start_time: args.start_time,
+    };
+    let traces = map_match
+        .synthesize(&cfg, args.count)
+        .expect("synthesize trajectories failed: ");
+    info!(
+        "synthesize {} trajectories with {} points",
+        traces.len(),
+        traces.iter().map(|t| t.traj.len()).sum::<usize>()
+    );
+    // 时间戳写在属性中 匹配时直接读取
+    let features = traces
+        .iter()
+        .map(|trace| {
+            feature(
+                geojson::Value::LineString(
+                    trace
+                        .traj
+                        .iter()
+                        .map(|p| {
+                            let p = working_crs.to_wgs84(&p.point);
+                            vec![p.0, p.1]
+                        })
+                        .collect(),
+                ),
+                serde_json::json!({
+                    "timestamps": trace.traj.iter().map(|p| p.time_stamp).collect::<Vec<u64>>(),
+                    "outliers": trace.outliers,
+                    "length": trace.length,
+                }),
+            )
+        })
+        .collect();
+    if let Some(path) = &args.truth_output {
+        let truth: Vec<serde_json::Value> = traces
+            .iter()
+            .map(
+                |trace| serde_json::json!({"path": trace.truth.path, "points": trace.truth.points}),
+            )
+            .collect();
+        fs::write(path, serde_json::Value::Array(truth).to_string())
+            .expect("write ground truth failed: ");
+    }
+    write_geojson(
+        args.output,
+        geojson::GeoJson::FeatureCollection(geojson::FeatureCollection {
+            bbox: None,
+            features,
+            foreign_members: None,
+        }),
+    );
+}
+
+fn serve(args: ServeArgs) {
+    // 路网只读取一次 工作线程共享 请求中的坐标为--traj-crs
+    let match_args = &args.match_args;
+    let setup = match_setup(match_args);
+    let cfg = rmm::server::ServerConfig {
+        bind: args.bind.clone(),
+        matching: setup.config,
+        preprocess: setup.preprocess,
+        snap_radius: args
+            .snap_radius
+            .unwrap_or_else(|| match_args.units.snap_radius()),
+        crs: match_args.traj_crs,
+        working_crs: setup.working_crs,
+    };
+    rmm::server::serve(setup.map_match, args.workers, &cfg).expect("serve failed: ");
+}
+
+// 一个行程的匹配结果
+struct MatchedTrip<'a> {
+    index: usize, // 行程的序号 不切分时和轨迹序号一致
+    trip: &'a mm::Trip,
+    cleaned: &'a mm::Preprocessed,
+    result: &'a model::MMResult,
+}
+
+// 匹配使用的路网、工作坐标系和配置
+struct MatchSetup {
+    map_match: stmatch::MMatch,
+    working_crs: Crs,
+    config: model::Config,
+    preprocess: mm::PreprocessConfig,
+}
+
+// 加载路网并根据参数生成匹配和预处理的配置
+fn match_setup(args: &MatchArgs) -> MatchSetup {
+    let network_file = args.network_file.clone().unwrap();
+    info!("try constarct map matching network from geojson file");
+    let (mut map_match, working_crs, metric) = load_network(
+        Path::new(&network_file),
+        &args.units,
+        &args.network_crs,
+        &args.working_crs,
+    );
+    let routing = turn_routing(args, &mut map_match);
+
+    // 配置
+    let (gps_err, radius, reverse_tolerance) = args.units.defaults();
+    let config = model::Config {
+        gps_err: args.gps_err.unwrap_or(gps_err),
+        radius: args.radius.unwrap_or(radius),
+        max_radius: args.max_radius.unwrap_or(args.radius.unwrap_or(radius)),
+        v_max: args.max_speed,
+        factor: args.factor,
+        reverse_tolerance: args.reverse_tolerance.unwrap_or(reverse_tolerance),
+        knn: args.knn,
+        road_netwok_path: network_file.clone(),
+        metric,
+        routing,
+        path_algorithm: args.path_algorithm.algorithm(),
+    };
+
+    let preprocess = mm::PreprocessConfig {
+        min_distance: args.min_point_distance,
+        max_speed_kmh: args.max_point_speed,
+        // 距离单位为度时 速度按照经纬度之间的米计算
+        speed_metric: matches!(args.units, Units::Degree).then_some(algorithm::Metric::Haversine),
+        fix_timestamps: args.fix_timestamps,
+        kalman: args.kalman.then_some(mm::KalmanConfig {
+            process_noise: args.kalman_process_noise,
+            measurement_noise: args.kalman_measurement_noise,
+        }),
+    };
+
+    MatchSetup {
+        map_match,
+        working_crs,
+        config,
+        preprocess,
+    }
+}
+
+// 加载路网和轨迹 逐个行程预处理并匹配 匹配结果交给handle处理
+// 返回匹配使用的路网、工作坐标系以及每条轨迹的点数
+fn match_batch<F>(args: &MatchArgs, mut handle: F) -> (stmatch::MMatch, Crs, Vec<usize>)
+where
+    F: FnMut(&stmatch::MMatch, &Crs, &MatchedTrip),
+{
+    let MatchSetup {
+        mut map_match,
+        working_crs,
+        config,
+        preprocess,
+    } = match_setup(args);
+    let metric = config.metric;
+    let input_file = args.input_file.clone().unwrap();
 
     // 读取gps轨迹
     // let file = File::open(args.input_file).unwrap();
     // 检查文件后缀
-    let ext = args.input_file.extension().unwrap();
+    let ext = input_file.extension().unwrap();
 
-    let mutile_gps_trajs = if ext == "geojson" {
-        info!("read geojson file {} ", args.input_file.display());
-        let file = File::open(args.input_file).unwrap();
+    let mut mutile_gps_trajs = if ext == "geojson" {
+        info!("read geojson file {} ", input_file.display());
+        let file = File::open(input_file).unwrap();
         let reader = BufReader::new(file);
         let geojson = geojson::GeoJson::from_reader(reader).unwrap();
-        let gps_trajs = traj::MutileTrajectory::try_from(geojson).expect("read gps traj failed: ");
-        gps_trajs
+        traj::MutileTrajectory::try_from(geojson).expect("read gps traj failed: ")
     } else if ext == "wkt" {
-        info!("read wkt file {} ", args.input_file.display());
-        let content = fs::read_to_string(args.input_file).expect("read wkt file failed: ");
+        info!("read wkt file {} ", input_file.display());
+        let content = fs::read_to_string(input_file).expect("read wkt file failed: ");
         // let wkt: wkt::Wkt<f64> = wkt::Wkt::from_str(&content).unwrap();
         let wkt: wkt::Wkt<f64> = content.as_str().parse().expect("read gps traj failed: ");
         let gps_trajs: traj::MutileTrajectory = wkt.try_into().expect("read gps traj failed: ");
@@ -73,22 +1397,301 @@ fn main() {
     } else {
         panic!("gps file format not support");
     };
+    mutile_gps_trajs.reproject(&args.traj_crs, &working_crs);
+    let traj_lens: Vec<usize> = mutile_gps_trajs.trajs.iter().map(|t| t.len()).collect();
 
-    // 配置
-    let config = model::Config {
-        gps_err: args.gps_err,
-        radius: args.radius,
-        v_max: args.max_speed,
-        factor: args.factor,
-        reverse_tolerance: args.reverse_tolerance,
-        knn: args.knn,
-        road_netwok_path: args.network_file.clone(),
+    let trips = if args.segment {
+        let segment = mm::SegmentConfig {
+            stay_distance: args
+                .stay_distance
+                .unwrap_or_else(|| args.units.stay_distance()),
+            stay_duration: args.stay_duration,
+            max_gap: args.max_gap,
+            ..Default::default()
+        };
+        let segmentation = mutile_gps_trajs.segment(metric, &segment);
+        for stay in segmentation.stays.iter() {
+            info!(
+                "traj {} stays at ({:.6}, {:.6}) for {}s, points {}..={}",
+                stay.traj_index,
+                stay.center.0,
+                stay.center.1,
+                stay.duration(),
+                stay.start,
+                stay.end
+            );
+        }
+        info!(
+            "split {} trajectories into {} trips",
+            mutile_gps_trajs.trajs.len(),
+            segmentation.trips.len()
+        );
+        segmentation.trips
+    } else {
+        mutile_gps_trajs
+            .trajs
+            .into_iter()
+            .enumerate()
+            .map(|(traj_index, traj)| mm::Trip {
+                traj_index,
+                start: 0,
+                traj,
+            })
+            .collect()
     };
 
-    for gps_traj in mutile_gps_trajs.trajs.iter() {
+    for (trip_index, trip) in trips.iter().enumerate() {
+        if args.segment {
+            info!(
+                "match traj {} trip of {} points from point {}",
+                trip.traj_index,
+                trip.traj.len(),
+                trip.start
+            );
+        }
+        let cleaned = mm::preprocess(&trip.traj, metric, &preprocess);
+        let report = &cleaned.report;
+        if report.removed() > 0 || report.reordered > 0 {
+            // 行程内的序号换算成原始轨迹中的序号
+            let original = |indices: &[usize]| -> Vec<usize> {
+                indices.iter().map(|i| trip.start + i).collect()
+            };
+            info!(
+                "preprocess removed {} duplicate points {:?}, {} outliers {:?}, reordered {} points",
+                report.duplicates.len(),
+                original(&report.duplicates),
+                report.outliers.len(),
+                original(&report.outliers),
+                report.reordered
+            );
+        }
         let mm_result = map_match
-            .match_traj(gps_traj, &config)
+            .match_traj(&cleaned.traj, &config)
             .expect("msg match failed: ");
+        for (i, candidate) in mm_result.matched_candidates.iter().enumerate() {
+            if candidate.is_none() {
+                let index = trip.start + cleaned.original_index(i);
+                debug!("point {} has no matched edge", index);
+            }
+        }
         info!("match edges: {:?}", mm_result.o_path);
+
+        handle(
+            &map_match,
+            &working_crs,
+            &MatchedTrip {
+                index: trip_index,
+                trip,
+                cleaned: &cleaned,
+                result: &mm_result,
+            },
+        );
+    }
+    (map_match, working_crs, traj_lens)
+}
+
+// 匹配的附加输出 稠密轨迹和路段行程时间
+struct MatchOutputs {
+    interpolate: mm::InterpolateConfig,
+    dense_features: Vec<geojson::Feature>,
+    travel_rows: Vec<String>,
+}
+
+impl MatchOutputs {
+    fn new(args: &MatchArgs) -> Self {
+        MatchOutputs {
+            interpolate: mm::InterpolateConfig {
+                interval: args.interpolate_interval,
+                follow_speed_limit: args.follow_speed_limits,
+            },
+            dense_features: vec![],
+            travel_rows: vec![
+                "traj,trip,edge_id,entry_time,exit_time,length,duration,speed,complete".to_string(),
+            ],
+        }
+    }
+
+    fn add(
+        &mut self,
+        args: &MatchArgs,
+        map_match: &stmatch::MMatch,
+        working_crs: &Crs,
+        matched: &MatchedTrip,
+    ) {
+        let (trip, cleaned) = (matched.trip, matched.cleaned);
+        if args.travel_times.is_some() {
+            let network = &map_match.road_graph().network;
+            for travel in map_match.edge_travels(matched.result) {
+                let speed = travel
+                    .speed()
+                    .map_or(String::new(), |s| format!("{:.3}", s));
+                self.travel_rows.push(format!(
+                    "{},{},{},{:.3},{:.3},{:.3},{:.3},{},{}",
+                    trip.traj_index,
+                    matched.index,
+                    network.edges[travel.edge_index].get_edge_id(),
+                    travel.entry_time,
+                    travel.exit_time,
+                    travel.length(),
+                    travel.duration(),
+                    speed,
+                    travel.complete
+                ));
+            }
+        }
+
+        if args.dense_output.is_some() {
+            let dense = map_match
+                .interpolate(matched.result, &self.interpolate)
+                .expect("interpolate failed: ");
+            // 输出坐标转换回轨迹的坐标系
+            let mut points = traj::MutileTrajectory {
+                trajs: vec![mm::dense_trajectory(&dense)],
+            };
+            points.reproject(working_crs, &args.traj_crs);
+            let network = &map_match.road_graph().network;
+            for (p, info) in dense.iter().zip(points.trajs[0].iter()) {
+                let observed = p.observed.map(|i| trip.start + cleaned.original_index(i));
+                self.dense_features.push(feature(
+                    geojson::Value::Point(vec![info.point.0, info.point.1]),
+                    serde_json::json!({
+                        "traj": trip.traj_index,
+                        "trip": matched.index,
+                        "time": p.time_stamp,
+                        "edge_id": network.edges[p.edge_index].get_edge_id(),
+                        "offset": p.offset,
+                        "observed": observed,
+                    }),
+                ));
+            }
+        }
+    }
+
+    fn write(self, args: MatchArgs) {
+        if let Some(path) = args.travel_times {
+            info!("write {} edge travel times", self.travel_rows.len() - 1);
+            fs::write(path, self.travel_rows.join("\n") + "\n").expect("write csv failed: ");
+        }
+
+        if let Some(path) = args.dense_output {
+            info!("write {} dense points", self.dense_features.len());
+            write_geojson(
+                Some(path),
+                geojson::GeoJson::FeatureCollection(geojson::FeatureCollection {
+                    bbox: None,
+                    features: self.dense_features,
+                    foreign_members: None,
+                }),
+            );
+        }
+    }
+}
+
+// 加载转向限制和转向代价 返回候选点之间的路径查询方式
+fn turn_routing(args: &MatchArgs, map_match: &mut stmatch::MMatch) -> graph::RoutingMode {
+    let cost = graph::TurnCost {
+        left: args.left_turn_penalty,
+        right: args.right_turn_penalty,
+        u_turn: args.u_turn_penalty,
+        ..Default::default()
+    };
+    let has_cost = cost.left != 0.0 || cost.right != 0.0 || cost.u_turn != 0.0;
+    if args.routing == Routing::Ch {
+        if args.turn_restrictions.is_some() || has_cost {
+            panic!("--routing ch does not support turn restrictions or turn penalties");
+        }
+        let network_file = Path::new(args.network_file.as_ref().unwrap());
+        load_contraction_hierarchy(network_file, &args.ch_file, map_match);
+        return graph::RoutingMode::Contracted;
+    }
+    if args.turn_restrictions.is_none() && !has_cost {
+        return match args.routing {
+            Routing::Edge => graph::RoutingMode::EdgeBased,
+            _ => graph::RoutingMode::NodeBased,
+        };
+    }
+    let restrictions = match &args.turn_restrictions {
+        Some(path) => graph::TurnRestrictions::from_file(path, &map_match.road_graph().network)
+            .expect("load turn restrictions failed: "),
+        None => graph::TurnRestrictions::default(),
+    };
+    map_match.set_turn_model(graph::TurnModel::new(restrictions, cost));
+    if args.routing == Routing::Node {
+        info!("turn restrictions or turn penalties given, use edge based routing");
+    }
+    graph::RoutingMode::EdgeBased
+}
+
+// 加载预先构建的CH 不存在或者和路网不一致时重新构建
+fn load_contraction_hierarchy(
+    network_file: &Path,
+    ch_file: &Option<PathBuf>,
+    map_match: &mut stmatch::MMatch,
+) {
+    let path = ch_file
+        .clone()
+        .unwrap_or_else(|| graph::ch::default_ch_path(network_file));
+    let ch = if path.exists() {
+        match graph::ch::read(&path, map_match.road_graph()) {
+            Ok(ch) => {
+                info!("load contraction hierarchy {}", path.display());
+                Some(ch)
+            }
+            Err(e) => {
+                warn!("load contraction hierarchy failed: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let ch = ch.unwrap_or_else(|| {
+        info!("build contraction hierarchy, use `rmm build --ch` to build it ahead");
+        graph::ContractionHierarchy::build(map_match.road_graph())
+    });
+    map_match
+        .set_contraction_hierarchy(ch)
+        .expect("set contraction hierarchy failed: ");
+}
+
+// 解析 lon,lat
+fn parse_point(value: &str) -> Result<algorithm::Point, String> {
+    let coords: Vec<f64> = value
+        .split(',')
+        .map(|v| v.trim().parse::<f64>())
+        .collect::<Result<_, _>>()
+        .map_err(|e| e.to_string())?;
+    match coords[..] {
+        [lon, lat] => Ok(algorithm::Point(lon, lat)),
+        _ => Err(format!("expect lon,lat but got {}", value)),
+    }
+}
+
+// 解析匹配使用的坐标系 utm表示使用路网中心所在的UTM分带
+// 工作坐标系下的距离 投影坐标系的单位为米 直接使用欧式距离
+fn working_metric(units: &Units, working_crs: &Crs) -> algorithm::Metric {
+    if working_crs.is_geographic() {
+        units.metric()
+    } else {
+        match units {
+            Units::Meter => algorithm::Metric::Euclidean,
+            Units::Degree => panic!("--units degree requires a geographic working crs"),
+        }
+    }
+}
+
+fn resolve_working_crs(
+    value: &str,
+    snapshot: &graph::snapshot::Snapshot,
+    network_crs: &Crs,
+) -> Crs {
+    if value.eq_ignore_ascii_case("utm") {
+        let center = snapshot.center().expect("road network is empty");
+        return Crs::utm_for(&network_crs.to_wgs84(&center));
+    }
+    let crs: Crs = value.parse().expect("parse working crs failed: ");
+    if !crs.is_geographic() && !crs.is_metric() {
+        panic!("working crs {} is not a metric crs", crs);
     }
+    crs
 }
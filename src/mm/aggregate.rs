@@ -0,0 +1,253 @@
+use super::model::MMResult;
+use super::stmatch::MMatch;
+use anyhow::Result;
+use std::collections::BTreeMap;
+
+/*
+ * @file aggregate.rs
+ * 路段统计
+ * 批量匹配的结果按照edge累计经过次数、速度的均值和分位数, 以及按照一天中的时段统计,
+ * 用于生成流量图和速度图。速度来自路段行程时间, 只有带时间戳的轨迹参与速度和时段的统计。
+ */
+
+/// 统计配置
+#[derive(Clone, Debug)]
+pub struct AggregateConfig {
+    pub bucket_seconds: u64,   // 时段的长度 单位秒
+    pub utc_offset: i64,       // 划分时段使用的时区偏移 单位秒
+    pub percentiles: Vec<f64>, // 速度分位数 0-100
+    pub complete_only: bool,   // 只使用完整经过edge的记录统计速度
+}
+
+impl Default for AggregateConfig {
+    fn default() -> Self {
+        AggregateConfig {
+            bucket_seconds: 3600,
+            utc_offset: 0,
+            percentiles: vec![50.0, 85.0],
+            complete_only: false,
+        }
+    }
+}
+
+/// 单条edge的统计
+#[derive(Clone, Debug)]
+pub struct EdgeStats {
+    pub edge_index: usize,
+    pub count: usize,                 // 经过次数
+    pub trajs: usize,                 // 经过的轨迹数
+    pub speeds: Vec<f64>,             // 每次经过的速度 单位为路网长度单位/秒
+    pub bucket_counts: Vec<usize>,    // 每个时段进入的次数
+    pub bucket_speeds: Vec<Vec<f64>>, // 每个时段的速度
+    last_traj: Option<usize>,
+}
+
+impl EdgeStats {
+    fn new(edge_index: usize, buckets: usize) -> Self {
+        EdgeStats {
+            edge_index,
+            count: 0,
+            trajs: 0,
+            speeds: vec![],
+            bucket_counts: vec![0; buckets],
+            bucket_speeds: vec![vec![]; buckets],
+            last_traj: None,
+        }
+    }
+
+    pub fn mean_speed(&self) -> Option<f64> {
+        mean(&self.speeds)
+    }
+
+    /// 速度的分位数 p为0-100 相邻样本之间线性插值
+    pub fn percentile_speed(&self, p: f64) -> Option<f64> {
+        percentile(&self.speeds, p)
+    }
+
+    pub fn bucket_mean_speed(&self, bucket: usize) -> Option<f64> {
+        mean(&self.bucket_speeds[bucket])
+    }
+}
+
+fn mean(values: &[f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    Some(values.iter().sum::<f64>() / values.len() as f64)
+}
+
+fn percentile(values: &[f64], p: f64) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let rank = (p.clamp(0.0, 100.0) / 100.0) * (sorted.len() - 1) as f64;
+    let (lower, upper) = (rank.floor() as usize, rank.ceil() as usize);
+    Some(sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f64))
+}
+
+/// 按照edge累计的统计结果
+pub struct EdgeAggregate {
+    pub cfg: AggregateConfig,
+    pub trajs: usize, // 累计的匹配结果数量
+    edges: BTreeMap<usize, EdgeStats>,
+}
+
+impl EdgeAggregate {
+    pub fn new(cfg: AggregateConfig) -> Result<Self> {
+        if cfg.bucket_seconds == 0 || cfg.bucket_seconds > 86400 {
+            return Err(anyhow::anyhow!(
+                "bucket seconds should be in 1..=86400, got {}",
+                cfg.bucket_seconds
+            ));
+        }
+        Ok(EdgeAggregate {
+            cfg,
+            trajs: 0,
+            edges: BTreeMap::new(),
+        })
+    }
+
+    /// 一天划分的时段数量 最后一个时段可能不完整
+    pub fn bucket_count(&self) -> usize {
+        86400_u64.div_ceil(self.cfg.bucket_seconds) as usize
+    }
+
+    /// 时段的开始时间 HH:MM
+    pub fn bucket_label(&self, bucket: usize) -> String {
+        let start = bucket as u64 * self.cfg.bucket_seconds;
+        format!("{:02}:{:02}", start / 3600, start % 3600 / 60)
+    }
+
+    // unix秒所在的时段
+    fn bucket(&self, time: f64) -> usize {
+        let local = (time.floor() as i64 + self.cfg.utc_offset).rem_euclid(86400);
+        local as usize / self.cfg.bucket_seconds as usize
+    }
+
+    /// 累计一条轨迹(或行程)的匹配结果 同一条轨迹的多个行程使用相同的traj_index
+    pub fn add(&mut self, mm: &MMatch, traj_index: usize, result: &MMResult) {
+        self.trajs += 1;
+        let network = &mm.road_graph().network;
+        let buckets = self.bucket_count();
+        // 经过次数来自完整路径 没有时间戳的轨迹也参与统计
+        for edge_id in result.cpath.iter() {
+            let edge_index = match network.find_edge_index(edge_id) {
+                Some(index) => index,
+                None => continue,
+            };
+            let stats = self
+                .edges
+                .entry(edge_index)
+                .or_insert_with(|| EdgeStats::new(edge_index, buckets));
+            stats.count += 1;
+            if stats.last_traj != Some(traj_index) {
+                stats.last_traj = Some(traj_index);
+                stats.trajs += 1;
+            }
+        }
+        for travel in mm.edge_travels(result) {
+            let bucket = self.bucket(travel.entry_time);
+            let stats = match self.edges.get_mut(&travel.edge_index) {
+                Some(stats) => stats,
+                None => continue,
+            };
+            stats.bucket_counts[bucket] += 1;
+            if self.cfg.complete_only && !travel.complete {
+                continue;
+            }
+            if let Some(speed) = travel.speed() {
+                stats.speeds.push(speed);
+                stats.bucket_speeds[bucket].push(speed);
+            }
+        }
+    }
+
+    /// 按照edge索引排列的统计结果
+    pub fn edges(&self) -> impl Iterator<Item = &EdgeStats> {
+        self.edges.values()
+    }
+
+    pub fn get(&self, edge_index: usize) -> Option<&EdgeStats> {
+        self.edges.get(&edge_index)
+    }
+}
+
+// test
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::algorithm;
+    use crate::graph::fixture::{self, feature};
+    use crate::mm::TrajInfo;
+
+    #[test]
+    fn test_percentile() {
+        let values = vec![4.0, 1.0, 3.0, 2.0];
+        assert_eq!(percentile(&values, 0.0), Some(1.0));
+        assert_eq!(percentile(&values, 100.0), Some(4.0));
+        assert_eq!(percentile(&values, 50.0), Some(2.5));
+        assert_eq!(percentile(&[], 50.0), None);
+        assert_eq!(mean(&values), Some(2.5));
+    }
+
+    #[test]
+    fn test_aggregate() {
+        // 1 --1--> 2 --2--> 3 每条约95米
+        let network = fixture::geojson_network(&[
+            feature(1, 1, 2, "[[121.0,31.0],[121.001,31.0]]"),
+            feature(2, 2, 3, "[[121.001,31.0],[121.002,31.0]]"),
+        ])
+        .unwrap();
+        let mut mm = fixture::matcher(network).unwrap();
+        let config = fixture::config();
+        let traj = |start: u64, seconds: u64| -> Vec<TrajInfo> {
+            [(121.0, 0), (121.002, seconds)]
+                .iter()
+                .map(|(x, t)| TrajInfo {
+                    point: algorithm::Point(*x, 31.00001),
+                    time_stamp: if start == 0 { 0 } else { start + t },
+                })
+                .collect()
+        };
+
+        let mut aggregate = EdgeAggregate::new(AggregateConfig {
+            utc_offset: 8 * 3600,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(aggregate.bucket_count(), 24);
+        assert_eq!(aggregate.bucket_label(9), "09:00");
+        // 2023-01-01 00:00 UTC, 即东八区08:00 用时20秒和40秒 以及一条没有时间戳的轨迹
+        for (i, (start, seconds)) in [(1672531200, 20), (1672531200 + 600, 40), (0, 0)]
+            .iter()
+            .enumerate()
+        {
+            let result = mm.match_traj(&traj(*start, *seconds), &config).unwrap();
+            aggregate.add(&mm, i, &result);
+        }
+        assert_eq!(aggregate.trajs, 3);
+        let stats: Vec<&EdgeStats> = aggregate.edges().collect();
+        assert_eq!(stats.len(), 2);
+        let first = stats[0];
+        assert_eq!((first.count, first.trajs), (3, 3));
+        assert_eq!(first.speeds.len(), 2);
+        assert_eq!(first.bucket_counts[8], 2);
+        assert_eq!(first.bucket_counts.iter().sum::<usize>(), 2);
+        // 两次速度约为 95/10 和 95/20
+        let length = mm.road_graph().network.edges[0].get_length();
+        let mean = first.mean_speed().unwrap();
+        assert!((mean - (length / 10.0 + length / 20.0) / 2.0).abs() < 0.01);
+        let p50 = first.percentile_speed(50.0).unwrap();
+        assert!((p50 - mean).abs() < 1e-9);
+        assert!((first.bucket_mean_speed(8).unwrap() - mean).abs() < 1e-9);
+        assert!(first.bucket_mean_speed(0).is_none());
+
+        assert!(EdgeAggregate::new(AggregateConfig {
+            bucket_seconds: 0,
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,307 @@
+use super::model::MMResult;
+use crate::graph::Network;
+use anyhow::Result;
+use std::collections::HashSet;
+use std::fmt;
+
+/*
+ * @file evaluate.rs
+ * 匹配质量评估
+ * 和真实的路径比较: 逐点的匹配正确率, Newson & Krumm(2009)的路径误差比例,
+ * 按照长度加权的准确率和召回率, 以及匹配路径的断点数量。
+ * 可以直接评估匹配的结果, 也可以读取 `rmm --stream` 输出的匹配结果。
+ */
+
+/// 一条轨迹的真实路径
+#[derive(Clone, Debug, Default)]
+pub struct GroundTruth {
+    pub path: Vec<String>,           // 真实路径依次经过的edge id
+    pub points: Vec<Option<String>>, // 每个轨迹点所在的真实edge id 未知时为None 可以为空
+}
+
+/// 从json读取真实路径 每条轨迹一个对象{"path": [...], "points": [...]} 按照轨迹的顺序排列
+pub fn read_ground_truth(value: &serde_json::Value) -> Result<Vec<GroundTruth>> {
+    let trajs = value
+        .as_array()
+        .ok_or_else(|| anyhow::anyhow!("ground truth should be an array"))?;
+    trajs
+        .iter()
+        .enumerate()
+        .map(|(i, traj)| {
+            let path = traj["path"]
+                .as_array()
+                .ok_or_else(|| anyhow::anyhow!("ground truth {} has no path", i))?
+                .iter()
+                .map(|v| edge_id(v).ok_or_else(|| anyhow::anyhow!("invalid edge id {}", v)))
+                .collect::<Result<Vec<String>>>()?;
+            let points = match traj["points"].as_array() {
+                Some(points) => points.iter().map(edge_id).collect(),
+                None => vec![],
+            };
+            Ok(GroundTruth { path, points })
+        })
+        .collect()
+}
+
+/// 已经匹配的一条轨迹 `rmm --stream` 输出的一行
+#[derive(Clone, Debug, Default)]
+pub struct MatchedRecord {
+    pub edges: Vec<Option<String>>, // 每个轨迹点匹配的edge id 没有匹配的为None
+    pub cpath: Vec<String>,
+    pub breaks: usize,
+}
+
+/// 解析一行匹配结果 {"edges": [...], "cpath": [...], "breaks": n}
+pub fn parse_matched(line: &str) -> Result<MatchedRecord> {
+    let value: serde_json::Value = serde_json::from_str(line)?;
+    let ids = |key: &str| {
+        value[key]
+            .as_array()
+            .ok_or_else(|| anyhow::anyhow!("matched result has no {}", key))
+    };
+    let edges = ids("edges")?
+        .iter()
+        .map(|v| match v {
+            serde_json::Value::Null => Ok(None),
+            v => edge_id(v)
+                .map(Some)
+                .ok_or_else(|| anyhow::anyhow!("invalid edge id {}", v)),
+        })
+        .collect::<Result<_>>()?;
+    let cpath = ids("cpath")?
+        .iter()
+        .map(|v| edge_id(v).ok_or_else(|| anyhow::anyhow!("invalid edge id {}", v)))
+        .collect::<Result<_>>()?;
+    let breaks = value["breaks"].as_u64().unwrap_or(0) as usize;
+    Ok(MatchedRecord {
+        edges,
+        cpath,
+        breaks,
+    })
+}
+
+fn edge_id(v: &serde_json::Value) -> Option<String> {
+    match v {
+        serde_json::Value::String(id) => Some(id.clone()),
+        serde_json::Value::Number(id) => Some(id.to_string()),
+        _ => None,
+    }
+}
+
+/// 评估结果 可以累加多条轨迹
+#[derive(Clone, Debug, Default)]
+pub struct Evaluation {
+    pub trajs: usize,
+    pub points: usize,           // 有真实edge的点数
+    pub correct_points: usize,   // 匹配到真实edge的点数
+    pub unmatched_points: usize, // 没有匹配的点数
+    pub breaks: usize,           // 相邻匹配点之间没有路径的次数
+    pub truth_length: f64,       // 真实路径的长度
+    pub matched_length: f64,     // 匹配路径的长度
+    pub common_length: f64,      // 两者共有edge的长度
+}
+
+impl Evaluation {
+    /// 累计逐点的结果和断点 truth(i)为第i个匹配点的真实edge id
+    pub fn add_points<F>(&mut self, result: &MMResult, truth: F)
+    where
+        F: Fn(usize) -> Option<String>,
+    {
+        let edges: Vec<Option<String>> = result
+            .matched_candidates
+            .iter()
+            .map(|c| c.as_ref().map(|c| c.edge.get_edge_id()))
+            .collect();
+        self.add_edges(&edges, truth);
+        self.add_breaks(result);
+    }
+
+    /// 累计逐点的结果 edges[i]为第i个点匹配的edge id 没有匹配(包括预处理删除)的为None
+    pub fn add_edges<F>(&mut self, edges: &[Option<String>], truth: F)
+    where
+        F: Fn(usize) -> Option<String>,
+    {
+        for (i, edge) in edges.iter().enumerate() {
+            if edge.is_none() {
+                self.unmatched_points += 1;
+            }
+            if let Some(edge_id) = truth(i) {
+                self.points += 1;
+                if edge.as_ref() == Some(&edge_id) {
+                    self.correct_points += 1;
+                }
+            }
+        }
+    }
+
+    /// 累计匹配结果的断点
+    pub fn add_breaks(&mut self, result: &MMResult) {
+        let matched = result.matched_candidates.iter().flatten().count();
+        self.breaks += matched.saturating_sub(1 + result.legs.len());
+    }
+
+    /// 累计一条轨迹的路径比较 matched为匹配的完整路径 重复经过的edge只计算一次
+    pub fn add_route(
+        &mut self,
+        network: &Network,
+        matched: &[String],
+        truth: &[String],
+    ) -> Result<()> {
+        let length = |id: &String| -> Result<f64> {
+            network
+                .find_edge_index(id)
+                .map(|i| network.edges[i].get_length())
+                .ok_or_else(|| anyhow::anyhow!("edge {} not found", id))
+        };
+        let matched: HashSet<&String> = matched.iter().collect();
+        let truth: HashSet<&String> = truth.iter().collect();
+        for id in matched.iter() {
+            let l = length(id)?;
+            self.matched_length += l;
+            if truth.contains(id) {
+                self.common_length += l;
+            }
+        }
+        for id in truth.iter() {
+            self.truth_length += length(id)?;
+        }
+        self.trajs += 1;
+        Ok(())
+    }
+
+    pub fn merge(&mut self, other: &Evaluation) {
+        self.trajs += other.trajs;
+        self.points += other.points;
+        self.correct_points += other.correct_points;
+        self.unmatched_points += other.unmatched_points;
+        self.breaks += other.breaks;
+        self.truth_length += other.truth_length;
+        self.matched_length += other.matched_length;
+        self.common_length += other.common_length;
+    }
+
+    /// 逐点正确率
+    pub fn point_accuracy(&self) -> Option<f64> {
+        ratio(self.correct_points as f64, self.points as f64)
+    }
+
+    /// 路径误差比例 (错误删除的长度 + 错误增加的长度) / 真实路径长度
+    pub fn route_mismatch(&self) -> Option<f64> {
+        let missing = self.truth_length - self.common_length;
+        let extra = self.matched_length - self.common_length;
+        ratio(missing + extra, self.truth_length)
+    }
+
+    /// 长度加权的准确率 匹配路径中正确的比例
+    pub fn precision(&self) -> Option<f64> {
+        ratio(self.common_length, self.matched_length)
+    }
+
+    /// 长度加权的召回率 真实路径中被匹配到的比例
+    pub fn recall(&self) -> Option<f64> {
+        ratio(self.common_length, self.truth_length)
+    }
+}
+
+fn ratio(numerator: f64, denominator: f64) -> Option<f64> {
+    if denominator <= 0.0 {
+        return None;
+    }
+    Some(numerator / denominator)
+}
+
+impl fmt::Display for Evaluation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = |v: Option<f64>| v.map_or("-".to_string(), |v| format!("{:.4}", v));
+        writeln!(f, "trajectories: {}", self.trajs)?;
+        writeln!(
+            f,
+            "point accuracy: {} ({}/{})",
+            value(self.point_accuracy()),
+            self.correct_points,
+            self.points
+        )?;
+        writeln!(f, "unmatched points: {}", self.unmatched_points)?;
+        writeln!(f, "route mismatch: {}", value(self.route_mismatch()))?;
+        writeln!(f, "precision: {}", value(self.precision()))?;
+        writeln!(f, "recall: {}", value(self.recall()))?;
+        writeln!(f, "breaks: {}", self.breaks)
+    }
+}
+
+// test
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::algorithm;
+    use crate::graph::fixture::{self, feature};
+    use crate::mm::TrajInfo;
+
+    #[test]
+    fn test_evaluate() {
+        // 1 --1--> 2 --2--> 3 --3--> 4 edge 2和3长度相同 edge 1是两倍
+        let network = fixture::geojson_network(&[
+            feature(1, 1, 2, "[[121.0,31.0],[121.002,31.0]]"),
+            feature(2, 2, 3, "[[121.002,31.0],[121.003,31.0]]"),
+            feature(3, 3, 4, "[[121.003,31.0],[121.004,31.0]]"),
+        ])
+        .unwrap();
+        let mut mm = fixture::matcher(network).unwrap();
+        let config = fixture::config();
+        // 最后一个点离路网太远 没有匹配
+        let traj: Vec<TrajInfo> = [121.001, 121.0025, 121.0035, 121.0035]
+            .iter()
+            .enumerate()
+            .map(|(i, x)| TrajInfo {
+                point: algorithm::Point(*x, if i == 3 { 31.01 } else { 31.00001 }),
+                time_stamp: 0,
+            })
+            .collect();
+        let result = mm.match_traj(&traj, &config).unwrap();
+        assert_eq!(result.cpath, vec!["1", "2", "3"]);
+
+        let truth = read_ground_truth(
+            &serde_json::from_str(r#"[{"path": [1, 2], "points": ["1", 2, null, 3]}]"#).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(truth[0].path, vec!["1", "2"]);
+        assert_eq!(truth[0].points[2], None);
+
+        let mut evaluation = Evaluation::default();
+        evaluation.add_points(&result, |i| truth[0].points.get(i).cloned().flatten());
+        let network = &mm.road_graph().network;
+        evaluation
+            .add_route(network, &result.cpath, &truth[0].path)
+            .unwrap();
+        // 第4个点没有匹配 算作错误
+        assert_eq!((evaluation.correct_points, evaluation.points), (2, 3));
+        assert_eq!(evaluation.unmatched_points, 1);
+        assert_eq!(evaluation.breaks, 0);
+        // 多匹配了edge 3 长度为真实路径的三分之一
+        assert!((evaluation.route_mismatch().unwrap() - 1.0 / 3.0).abs() < 0.01);
+        assert!((evaluation.precision().unwrap() - 0.75).abs() < 0.01);
+        assert!((evaluation.recall().unwrap() - 1.0).abs() < 1e-9);
+
+        let mut total = Evaluation::default();
+        total.merge(&evaluation);
+        total.merge(&evaluation);
+        assert_eq!(total.trajs, 2);
+        assert!((total.precision().unwrap() - 0.75).abs() < 0.01);
+        assert!(total.to_string().contains("breaks: 0"));
+        assert!(evaluation
+            .add_route(network, &["9".to_string()], &[])
+            .is_err());
+
+        // 读取的匹配结果 预处理删除的点为null 算作没有匹配
+        let record =
+            parse_matched(r#"{"edges": ["1", null, 3, 3], "cpath": [1, 2, 3], "breaks": 1}"#)
+                .unwrap();
+        let mut evaluation = Evaluation::default();
+        evaluation.add_edges(&record.edges, |i| truth[0].points.get(i).cloned().flatten());
+        assert_eq!((evaluation.correct_points, evaluation.points), (2, 3));
+        assert_eq!(evaluation.unmatched_points, 1);
+        assert_eq!(record.cpath, result.cpath);
+        assert_eq!(record.breaks, 1);
+        assert!(parse_matched(r#"{"edges": [[1]], "cpath": []}"#).is_err());
+    }
+}
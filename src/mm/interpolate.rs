@@ -0,0 +1,219 @@
+use super::model::{MMResult, MatchedLeg};
+use super::stmatch::MMatch;
+use super::traj::{TrajInfo, Trajectory};
+use crate::algorithm;
+use anyhow::Result;
+
+/*
+ * @file interpolate.rs
+ * 轨迹插值
+ * 低频轨迹相邻两个匹配点之间沿着完整路径按照固定的时间间隔插入估计的位置,
+ * 得到稠密的匹配轨迹。每段之内假设匀速, 或者按照edge的限速分配行驶时间。
+ */
+
+/// 插值配置
+#[derive(Clone, Debug)]
+pub struct InterpolateConfig {
+    pub interval: u64,            // 插值的时间间隔 单位秒
+    pub follow_speed_limit: bool, // 按照edge限速分配时间 没有限速的edge使用同一段内限速的平均值
+}
+
+impl Default for InterpolateConfig {
+    fn default() -> Self {
+        InterpolateConfig {
+            interval: 10,
+            follow_speed_limit: false,
+        }
+    }
+}
+
+/// 稠密轨迹中的点
+#[derive(Clone, Debug)]
+pub struct DensePoint {
+    pub point: algorithm::Point,
+    pub time_stamp: u64,
+    pub edge_index: usize,       // 所在的路网edge索引
+    pub offset: f64,             // 在edge上的偏移量
+    pub observed: Option<usize>, // 观测点在matched_candidates中的序号 插值点为None
+}
+
+impl MMatch {
+    /// 沿着匹配结果的完整路径插值 时间戳未知或者没有路径的相邻点之间不插值
+    pub fn interpolate(
+        &self,
+        result: &MMResult,
+        cfg: &InterpolateConfig,
+    ) -> Result<Vec<DensePoint>> {
+        if cfg.interval == 0 {
+            return Err(anyhow::anyhow!("interpolate interval should be positive"));
+        }
+        let mut dense = vec![];
+        let mut legs = result.legs.iter().peekable();
+        for (index, candidate) in result.matched_candidates.iter().enumerate() {
+            let candidate = match candidate {
+                Some(candidate) => candidate,
+                None => continue,
+            };
+            dense.push(DensePoint {
+                point: candidate.closest_point,
+                time_stamp: candidate.ori_traj_point.time_stamp,
+                edge_index: candidate.edge_index,
+                offset: candidate.offset,
+                observed: Some(index),
+            });
+            while legs.peek().is_some_and(|leg| leg.from < index) {
+                legs.next();
+            }
+            if let Some(leg) = legs.peek().filter(|leg| leg.from == index) {
+                let to = result.matched_candidates[leg.to].as_ref().unwrap();
+                let (start, end) = (
+                    candidate.ori_traj_point.time_stamp,
+                    to.ori_traj_point.time_stamp,
+                );
+                if start != 0 && end > start {
+                    self.interpolate_leg(leg, start, end, cfg, &mut dense)?;
+                }
+            }
+        }
+        Ok(dense)
+    }
+
+    // 在(start, end)之间插入点
+    fn interpolate_leg(
+        &self,
+        leg: &MatchedLeg,
+        start: u64,
+        end: u64,
+        cfg: &InterpolateConfig,
+        dense: &mut Vec<DensePoint>,
+    ) -> Result<()> {
+        let network = &self.road_graph().network;
+        // 每个片段的行驶时间权重 匀速时为长度
+        let mut weights: Vec<f64> = leg.spans.iter().map(|s| s.end - s.start).collect();
+        if cfg.follow_speed_limit {
+            let limits: Vec<Option<f64>> = leg
+                .spans
+                .iter()
+                .map(|s| network.edges[s.edge_index].get_speed_limit())
+                .collect();
+            let known: Vec<f64> = limits.iter().flatten().copied().collect();
+            if !known.is_empty() {
+                let mean = known.iter().sum::<f64>() / known.len() as f64;
+                for (weight, limit) in weights.iter_mut().zip(limits.iter()) {
+                    *weight /= limit.unwrap_or(mean);
+                }
+            }
+        }
+        let total: f64 = weights.iter().sum();
+        if total <= 0.0 {
+            return Ok(());
+        }
+        let duration = (end - start) as f64;
+        let mut span = 0;
+        let mut passed = 0.0; // 之前片段的权重和
+        let mut time = start + cfg.interval;
+        while time < end {
+            let target = total * (time - start) as f64 / duration;
+            while span + 1 < weights.len() && passed + weights[span] < target {
+                passed += weights[span];
+                span += 1;
+            }
+            let s = &leg.spans[span];
+            let ratio = if weights[span] > 0.0 {
+                ((target - passed) / weights[span]).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            let offset = s.start + (s.end - s.start) * ratio;
+            let line: algorithm::Line = network.edges[s.edge_index]
+                .get_geometry()
+                .clone()
+                .try_into()?;
+            // 起止相同的子线段即为该偏移量处的点
+            let point = self.metric().substring(&line.0, offset, offset);
+            if let Some(p) = point.first() {
+                dense.push(DensePoint {
+                    point: algorithm::Point(p[0], p[1]),
+                    time_stamp: time,
+                    edge_index: s.edge_index,
+                    offset,
+                    observed: None,
+                });
+            }
+            time += cfg.interval;
+        }
+        Ok(())
+    }
+}
+
+/// 稠密轨迹转换成轨迹
+pub fn dense_trajectory(dense: &[DensePoint]) -> Trajectory {
+    dense
+        .iter()
+        .map(|p| TrajInfo {
+            point: p.point,
+            time_stamp: p.time_stamp,
+        })
+        .collect()
+}
+
+// test
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graph::fixture::{self, config};
+
+    // 1 --1--> 2 --2--> 3 每条约95米 edge 1限速60 edge 2限速30
+    fn build() -> MMatch {
+        let network = fixture::geojson_network(&[
+            r#"{"type":"Feature","properties":{"edge_id":1,"from_node_id":1,"to_node_id":2,"maxspeed":60},"geometry":{"type":"LineString","coordinates":[[121.0,31.0],[121.001,31.0]]}}"#,
+            r#"{"type":"Feature","properties":{"edge_id":2,"from_node_id":2,"to_node_id":3,"maxspeed":30},"geometry":{"type":"LineString","coordinates":[[121.001,31.0],[121.002,31.0]]}}"#,
+        ])
+        .unwrap();
+        fixture::matcher(network).unwrap()
+    }
+
+    #[test]
+    fn test_interpolate() {
+        let mut mm = build();
+        let traj = vec![
+            TrajInfo {
+                point: algorithm::Point(121.0, 31.00001),
+                time_stamp: 100,
+            },
+            TrajInfo {
+                point: algorithm::Point(121.002, 31.00001),
+                time_stamp: 130,
+            },
+        ];
+        let result = mm.match_traj(&traj, &config()).unwrap();
+        assert_eq!(result.cpath, vec!["1", "2"]);
+        assert_eq!(result.legs.len(), 1);
+        let length = result.legs[0].length;
+
+        // 匀速 每10秒约走三分之一
+        let cfg = InterpolateConfig::default();
+        let dense = mm.interpolate(&result, &cfg).unwrap();
+        let times: Vec<u64> = dense.iter().map(|p| p.time_stamp).collect();
+        assert_eq!(times, vec![100, 110, 120, 130]);
+        assert_eq!(dense[0].observed, Some(0));
+        assert_eq!(dense[3].observed, Some(1));
+        assert!(dense[1].observed.is_none() && dense[1].edge_index == 0);
+        assert!((dense[1].offset - length / 3.0).abs() < 0.5);
+        assert!((dense[2].point.0 - (121.0 + 0.002 * 2.0 / 3.0)).abs() < 1e-5);
+
+        // 按照限速 edge 1用时10秒 edge 2用时20秒
+        let cfg = InterpolateConfig {
+            interval: 5,
+            follow_speed_limit: true,
+        };
+        let dense = mm.interpolate(&result, &cfg).unwrap();
+        assert_eq!(dense.len(), 7);
+        assert!((dense[1].point.0 - 121.0005).abs() < 1e-5);
+        assert!((dense[3].point.0 - 121.00125).abs() < 1e-5);
+        assert_eq!(dense_trajectory(&dense).len(), 7);
+        assert!(mm
+            .interpolate(&result, &InterpolateConfig { interval: 0, ..cfg })
+            .is_err());
+    }
+}
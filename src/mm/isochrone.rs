@@ -0,0 +1,172 @@
+use super::stmatch::MMatch;
+use crate::algorithm;
+use anyhow::Result;
+use geo::algorithm::concave_hull::ConcaveHull;
+use petgraph::graph::NodeIndex;
+use petgraph::visit::EdgeRef;
+use std::collections::HashMap;
+
+/*
+ * @file isochrone.rs
+ * 等时圈/可达范围
+ * 从坐标吸附的位置出发, 计算代价不超过预算的所有edge片段。
+ */
+
+/// 可达的edge片段
+#[derive(Clone, Debug)]
+pub struct ReachableSegment {
+    pub edge: usize, // 路网edge索引
+    pub start: f64,  // 片段在edge上的起始偏移量
+    pub end: f64,    // 片段在edge上的结束偏移量
+    pub cost: f64,   // 到达片段起点的代价
+}
+
+pub struct Isochrone {
+    pub max_cost: f64,
+    pub segments: Vec<ReachableSegment>, // 按照edge索引排序
+}
+
+impl MMatch {
+    /// 从point出发 路网距离不超过max_cost的所有edge片段
+    /// max_cost的单位和路网长度一致, 行驶时间需要先按照速度换算成距离
+    pub fn isochrone(
+        &self,
+        point: &algorithm::Point,
+        max_cost: f64,
+        radius: f64,
+    ) -> Result<Isochrone> {
+        let snaps = self.snap_candidates(point, radius);
+        if snaps.is_empty() {
+            return Err(anyhow::anyhow!(
+                "no edge found within {} of ({}, {})",
+                radius,
+                point.0,
+                point.1
+            ));
+        }
+        let road_graph = self.road_graph();
+        let network = &road_graph.network;
+        let mut parts: HashMap<usize, Vec<(f64, f64, f64)>> = HashMap::new();
+        let mut sources = vec![];
+        for snap in snaps.iter() {
+            let length = snap.edge.get_length();
+            let end = length.min(snap.offset + max_cost);
+            parts
+                .entry(snap.edge_index)
+                .or_default()
+                .push((snap.offset, end, 0.0));
+            if let Some(node) = network.find_node_by_id(&snap.edge.get_to_node()) {
+                sources.push((node, length - snap.offset));
+            }
+        }
+        let reachable = road_graph.reachable_nodes(&sources, max_cost);
+        for (node, cost) in reachable.iter() {
+            for edge in road_graph.graph.edges(NodeIndex::new(*node)) {
+                let index = edge.id().index();
+                // 虚拟边不参与
+                if index >= network.edges.len() {
+                    continue;
+                }
+                let end = edge.weight().min(max_cost - cost);
+                parts.entry(index).or_default().push((0.0, end, *cost));
+            }
+        }
+
+        let mut segments = vec![];
+        let mut edges: Vec<usize> = parts.keys().copied().collect();
+        edges.sort_unstable();
+        for edge in edges {
+            let mut ranges = parts.remove(&edge).unwrap();
+            ranges.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+            let mut merged: Vec<ReachableSegment> = vec![];
+            for (start, end, cost) in ranges {
+                match merged.last_mut() {
+                    Some(last) if start <= last.end => last.end = last.end.max(end),
+                    _ => merged.push(ReachableSegment {
+                        edge,
+                        start,
+                        end,
+                        cost,
+                    }),
+                }
+            }
+            segments.extend(merged.into_iter().filter(|s| s.end > s.start));
+        }
+        Ok(Isochrone { max_cost, segments })
+    }
+
+    /// 可达片段的几何
+    pub fn segment_geometry(&self, segment: &ReachableSegment) -> Result<Vec<[f64; 2]>> {
+        let edge = &self.road_graph().network.edges[segment.edge];
+        let line: algorithm::Line = edge.get_geometry().clone().try_into()?;
+        Ok(self.metric().substring(&line.0, segment.start, segment.end))
+    }
+
+    /// 可达片段所有顶点的凹包 concavity越小越贴合 片段为空时返回None
+    pub fn isochrone_hull(
+        &self,
+        isochrone: &Isochrone,
+        concavity: f64,
+    ) -> Result<Option<geo::Polygon<f64>>> {
+        let mut points = vec![];
+        for segment in isochrone.segments.iter() {
+            for p in self.segment_geometry(segment)? {
+                points.push(geo::Point::new(p[0], p[1]));
+            }
+        }
+        if points.len() < 3 {
+            return Ok(None);
+        }
+        Ok(Some(geo::MultiPoint::from(points).concave_hull(concavity)))
+    }
+}
+
+// test
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graph::fixture::{self, feature};
+
+    // 0 --1--> 1 --2--> 2 --3--> 3 每段约95米
+    fn build() -> MMatch {
+        let network = fixture::geojson_network(&[
+            feature(1, 0, 1, "[[121.0,31.0],[121.001,31.0]]"),
+            feature(2, 1, 2, "[[121.001,31.0],[121.002,31.0]]"),
+            feature(3, 2, 3, "[[121.002,31.0],[121.003,31.0]]"),
+            feature(4, 1, 4, "[[121.001,31.0],[121.001,31.001]]"),
+        ])
+        .unwrap();
+        fixture::matcher(network).unwrap()
+    }
+
+    #[test]
+    fn test_isochrone() {
+        let mm = build();
+        let length = mm.road_graph().network.edges[0].get_length();
+        let start = algorithm::Point(121.0005, 31.00001);
+        let isochrone = mm.isochrone(&start, length, 50.0).unwrap();
+        let reached: Vec<(String, f64, f64)> = isochrone
+            .segments
+            .iter()
+            .map(|s| {
+                let edge = &mm.road_graph().network.edges[s.edge];
+                (edge.get_edge_id(), s.start, s.end)
+            })
+            .collect();
+        assert_eq!(reached.len(), 3);
+        // 起点所在edge的后半段 以及两条后继edge的前半段
+        assert_eq!(reached[0].0, "1");
+        assert!((reached[0].1 - length / 2.0).abs() < 1.0);
+        assert!((reached[0].2 - length).abs() < 1e-9);
+        assert_eq!(reached[1].0, "2");
+        assert!((reached[1].2 - length / 2.0).abs() < 1.0);
+        assert_eq!(reached[2].0, "4");
+        assert!(reached[2].1 == 0.0 && (reached[2].2 - length / 2.0).abs() < 1.0);
+
+        let hull = mm.isochrone_hull(&isochrone, 2.0).unwrap().unwrap();
+        assert!(hull.exterior().0.len() >= 4);
+        assert!(mm
+            .isochrone(&algorithm::Point(122.0, 31.0), 100.0, 50.0)
+            .is_err());
+    }
+}
@@ -1,6 +1,30 @@
 pub mod model;
 pub use model::*;
+pub mod aggregate;
+pub use aggregate::*;
+pub mod evaluate;
+pub use evaluate::*;
+pub mod interpolate;
+pub use interpolate::*;
+pub mod isochrone;
+pub use isochrone::*;
+pub mod od;
+pub use od::*;
+pub mod preprocess;
+pub use preprocess::*;
+pub mod route;
+pub use route::*;
+pub mod segment;
+pub use segment::*;
+pub mod snap;
+pub use snap::*;
 pub mod stmatch;
 pub use stmatch::*;
+pub mod stream;
+pub use stream::*;
+pub mod synth;
+pub use synth::*;
 pub mod traj;
 pub use traj::*;
+pub mod travel;
+pub use travel::*;
@@ -1,6 +1,6 @@
 use super::traj::TrajInfo;
 use crate::algorithm;
-use crate::graph::Edge;
+use crate::graph::{Edge, PathAlgorithm, RoutingMode};
 use std::cell::RefCell;
 use std::rc::Rc;
 
@@ -12,6 +12,7 @@ use std::rc::Rc;
 #[derive(Clone)]
 pub struct Candidate {
     pub edge: Edge,
+    pub edge_index: usize, // edge在路网中的索引
     // gps点到edge的距离
     pub distance: f64,
     pub offset: f64,                     // 偏移量
@@ -26,6 +27,7 @@ pub struct Layer {
     pub prev_layer: RefCell<Option<Rc<RefCell<Layer>>>>,
     pub cumulative_prob: f64, // 累积概率
     pub tp: f64,              // 转移概率
+    pub distance: f64,        // 从prev_layer转移过来的路网距离 不可达为f64::MAX
 }
 
 pub type Layers = Vec<Rc<RefCell<Layer>>>;
@@ -34,15 +36,39 @@ pub type LayerLists = Vec<Layers>;
 pub struct MMResult {
     pub o_path: Vec<String>,
     pub matched_candidates: Vec<Option<Candidate>>,
+    pub cpath: Vec<String>,    // 完整路径依次经过的edge id 相邻重复的已合并
+    pub legs: Vec<MatchedLeg>, // 相邻匹配点之间的路径 路网不连通的点对不包含在内
+}
+
+/// 完整路径在一条edge上经过的部分
+#[derive(Clone, Debug)]
+pub struct PathSpan {
+    pub edge_index: usize, // 路网edge索引
+    pub start: f64,        // 在edge上的起始偏移量
+    pub end: f64,          // 在edge上的结束偏移量
+}
+
+/// 相邻两个匹配点之间的路径
+#[derive(Clone, Debug)]
+pub struct MatchedLeg {
+    pub from: usize, // 起点在matched_candidates中的序号
+    pub to: usize,   // 终点在matched_candidates中的序号
+    pub spans: Vec<PathSpan>,
+    pub length: f64, // 单位和路网长度一致
 }
 
 #[derive(Clone)]
 pub struct Config {
+    // 以下距离的单位由metric决定 Haversine为米 Euclidean为坐标单位(度)
     pub gps_err: f64,
     pub radius: f64,
+    pub max_radius: f64, // radius内没有候选点时 半径逐次翻倍直到max_radius
     pub knn: u16,
     pub v_max: f64, // 最大速度
     pub factor: f64,
     pub reverse_tolerance: f64, // 反向公差
     pub road_netwok_path: String,
+    pub metric: algorithm::Metric,     // 距离的度量方式 需要和路网一致
+    pub routing: RoutingMode,          // 候选点之间路径查询的方式
+    pub path_algorithm: PathAlgorithm, // 基于节点查询时使用的最短路径算法
 }
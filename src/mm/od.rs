@@ -0,0 +1,252 @@
+use super::model::{Candidate, MMResult};
+use crate::algorithm;
+use anyhow::Result;
+use geo::algorithm::contains::Contains;
+use std::collections::BTreeMap;
+
+/*
+ * @file od.rs
+ * OD统计
+ * 每条轨迹第一个和最后一个匹配点分别作为起点和终点, 按照edge、节点或者用户提供的区域统计OD数量,
+ * 并按照出发时间划分时间窗口。
+ */
+
+/// 区域图层 每个区域是一个(多)多边形
+pub struct Zones {
+    ids: Vec<String>,
+    polygons: Vec<geo::MultiPolygon<f64>>,
+}
+
+impl Zones {
+    /// 从geojson读取区域 id依次取属性id_property、feature的id、feature的序号
+    pub fn from_geojson(geojson: geojson::GeoJson, id_property: &str) -> Result<Self> {
+        let features = match geojson {
+            geojson::GeoJson::FeatureCollection(collection) => collection.features,
+            geojson::GeoJson::Feature(feature) => vec![feature],
+            geojson::GeoJson::Geometry(geometry) => vec![geojson::Feature::from(geometry)],
+        };
+        let mut zones = Zones {
+            ids: vec![],
+            polygons: vec![],
+        };
+        for (index, feature) in features.into_iter().enumerate() {
+            let id = match feature.property(id_property) {
+                Some(serde_json::Value::String(id)) => id.clone(),
+                Some(serde_json::Value::Number(id)) => id.to_string(),
+                _ => match &feature.id {
+                    Some(geojson::feature::Id::String(id)) => id.clone(),
+                    Some(geojson::feature::Id::Number(id)) => id.to_string(),
+                    None => index.to_string(),
+                },
+            };
+            let geometry = feature
+                .geometry
+                .ok_or_else(|| anyhow::anyhow!("zone {} has no geometry", id))?;
+            let polygon = match geo::Geometry::<f64>::try_from(geometry.value)? {
+                geo::Geometry::Polygon(polygon) => geo::MultiPolygon(vec![polygon]),
+                geo::Geometry::MultiPolygon(polygons) => polygons,
+                _ => return Err(anyhow::anyhow!("zone {} is not a polygon", id)),
+            };
+            zones.ids.push(id);
+            zones.polygons.push(polygon);
+        }
+        Ok(zones)
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// 把所有区域从from坐标系转换到to坐标系
+    pub fn reproject(&mut self, from: &algorithm::Crs, to: &algorithm::Crs) {
+        if from == to {
+            return;
+        }
+        for polygons in self.polygons.iter_mut() {
+            for polygon in polygons.0.iter_mut() {
+                let transform = |line: &geo::LineString<f64>| -> geo::LineString<f64> {
+                    line.0
+                        .iter()
+                        .map(|c| {
+                            let p = from.transform(&algorithm::Point(c.x, c.y), to);
+                            geo::Coord { x: p.0, y: p.1 }
+                        })
+                        .collect()
+                };
+                let exterior = transform(polygon.exterior());
+                let interiors = polygon.interiors().iter().map(transform).collect();
+                *polygon = geo::Polygon::new(exterior, interiors);
+            }
+        }
+    }
+
+    /// 坐标所在的区域 多个区域重叠时返回第一个
+    pub fn locate(&self, point: &algorithm::Point) -> Option<&str> {
+        let point = geo::Point::new(point.0, point.1);
+        self.polygons
+            .iter()
+            .position(|polygon| polygon.contains(&point))
+            .map(|i| self.ids[i].as_str())
+    }
+}
+
+/// 起终点的统计粒度
+pub enum OdLevel {
+    Edge, // 匹配点所在的edge
+    Node, // 匹配点所在edge上较近的端点
+    Zone(Zones),
+}
+
+/// OD数量 key为(时间窗口开始的unix秒, 起点, 终点) 不划分窗口或者时间戳未知时窗口为None
+pub struct OdMatrix {
+    pub level: OdLevel,
+    pub window_seconds: u64, // 时间窗口的长度 0表示不划分
+    pub skipped: usize,      // 没有匹配点或者不在任何区域内的轨迹数
+    counts: BTreeMap<(Option<u64>, String, String), usize>,
+}
+
+impl OdMatrix {
+    pub fn new(level: OdLevel, window_seconds: u64) -> Self {
+        OdMatrix {
+            level,
+            window_seconds,
+            skipped: 0,
+            counts: BTreeMap::new(),
+        }
+    }
+
+    // 匹配点对应的起终点
+    fn locate(&self, candidate: &Candidate) -> Option<String> {
+        match &self.level {
+            OdLevel::Edge => Some(candidate.edge.get_edge_id()),
+            OdLevel::Node => Some(if candidate.offset * 2.0 < candidate.edge.get_length() {
+                candidate.edge.get_from_node()
+            } else {
+                candidate.edge.get_to_node()
+            }),
+            OdLevel::Zone(zones) => zones.locate(&candidate.closest_point).map(String::from),
+        }
+    }
+
+    /// 累计一条轨迹的起终点 没有起终点时返回false
+    pub fn add(&mut self, result: &MMResult) -> bool {
+        let mut matched = result.matched_candidates.iter().flatten();
+        let (first, last) = match (matched.next(), matched.last()) {
+            (Some(first), Some(last)) => (first, last),
+            (Some(first), None) => (first, first),
+            _ => {
+                self.skipped += 1;
+                return false;
+            }
+        };
+        let (origin, destination) = match (self.locate(first), self.locate(last)) {
+            (Some(origin), Some(destination)) => (origin, destination),
+            _ => {
+                self.skipped += 1;
+                return false;
+            }
+        };
+        // 按照出发时间划分窗口
+        let departure = first.ori_traj_point.time_stamp;
+        let window = match (departure, self.window_seconds) {
+            (0, _) | (_, 0) => None,
+            (t, w) => Some(t / w * w),
+        };
+        *self
+            .counts
+            .entry((window, origin, destination))
+            .or_insert(0) += 1;
+        true
+    }
+
+    /// 按照(窗口, 起点, 终点)排列的OD数量
+    pub fn iter(&self) -> impl Iterator<Item = (Option<u64>, &str, &str, usize)> {
+        self.counts
+            .iter()
+            .map(|((window, o, d), count)| (*window, o.as_str(), d.as_str(), *count))
+    }
+
+    pub fn total(&self) -> usize {
+        self.counts.values().sum()
+    }
+}
+
+// test
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graph::fixture::{self, feature};
+    use crate::mm::TrajInfo;
+
+    #[test]
+    fn test_od() {
+        // 1 --1--> 2 --2--> 3 每条约95米
+        let network = fixture::geojson_network(&[
+            feature(1, 1, 2, "[[121.0,31.0],[121.001,31.0]]"),
+            feature(2, 2, 3, "[[121.001,31.0],[121.002,31.0]]"),
+        ])
+        .unwrap();
+        let mut mm = fixture::matcher(network).unwrap();
+        let config = fixture::config();
+        let traj = |points: &[(f64, u64)]| -> Vec<TrajInfo> {
+            points
+                .iter()
+                .map(|(x, t)| TrajInfo {
+                    point: algorithm::Point(*x, 31.00001),
+                    time_stamp: *t,
+                })
+                .collect()
+        };
+        // 两条从edge 1到edge 2 出发时间在同一小时内 一条只在edge 1上(下一个小时) 一条没有匹配点
+        let results: Vec<MMResult> = [
+            traj(&[(121.0002, 3600), (121.0018, 3700)]),
+            traj(&[(121.0002, 7000), (121.0018, 7100)]),
+            traj(&[(121.0008, 7300)]),
+            traj(&[(121.1, 7300)]),
+        ]
+        .iter()
+        .map(|t| mm.match_traj(t, &config).unwrap())
+        .collect();
+
+        let mut od = OdMatrix::new(OdLevel::Edge, 3600);
+        for result in results.iter() {
+            od.add(result);
+        }
+        let counts: Vec<(Option<u64>, &str, &str, usize)> = od.iter().collect();
+        assert_eq!(
+            counts,
+            vec![(Some(3600), "1", "2", 2), (Some(7200), "1", "1", 1)]
+        );
+        assert_eq!((od.total(), od.skipped), (3, 1));
+
+        // 节点 起点靠近节点1 终点靠近节点3 只在edge 1上的点靠近节点2
+        let mut od = OdMatrix::new(OdLevel::Node, 0);
+        for result in results.iter() {
+            od.add(result);
+        }
+        let counts: Vec<(Option<u64>, &str, &str, usize)> = od.iter().collect();
+        assert_eq!(counts, vec![(None, "1", "3", 2), (None, "2", "2", 1)]);
+
+        // 区域 西边和中间两个区域 终点在区域外的轨迹不统计
+        let zones = r#"{"type":"FeatureCollection","features":[
+            {"type":"Feature","properties":{"zone_id":"west"},"geometry":{"type":"Polygon",
+             "coordinates":[[[120.999,30.999],[121.001,30.999],[121.001,31.001],[120.999,31.001],[120.999,30.999]]]}},
+            {"type":"Feature","id":7,"properties":{},"geometry":{"type":"Polygon",
+             "coordinates":[[[121.001,30.999],[121.0015,30.999],[121.0015,31.001],[121.001,31.001],[121.001,30.999]]]}}
+        ]}"#;
+        let zones = Zones::from_geojson(zones.parse().unwrap(), "zone_id").unwrap();
+        assert_eq!(zones.len(), 2);
+        assert_eq!(zones.locate(&algorithm::Point(121.0012, 31.0)), Some("7"));
+        let mut od = OdMatrix::new(OdLevel::Zone(zones), 3600);
+        for result in results.iter() {
+            od.add(result);
+        }
+        let counts: Vec<(Option<u64>, &str, &str, usize)> = od.iter().collect();
+        assert_eq!(counts, vec![(Some(7200), "west", "west", 1)]);
+        assert_eq!(od.skipped, 3);
+    }
+}
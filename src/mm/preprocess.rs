@@ -0,0 +1,322 @@
+use super::traj::{TrajInfo, Trajectory};
+use crate::algorithm;
+
+/*
+ * @file preprocess.rs
+ * 轨迹预处理
+ * 匹配之前清洗原始轨迹: 修正时间戳顺序、去除重复/静止点、去除速度异常的漂移点,
+ * 以及可选的卡尔曼滤波平滑。输出的每个点都记录了在原始轨迹中的序号。
+ * 距离的单位由metric决定, 速度阈值按照米计算, 距离单位不是米时通过speed_metric指定计算速度的距离。
+ */
+
+/// 卡尔曼滤波参数 匀速模型
+#[derive(Clone, Copy, Debug)]
+pub struct KalmanConfig {
+    pub process_noise: f64,     // 加速度噪声 单位m/s^2
+    pub measurement_noise: f64, // gps观测噪声 单位米
+}
+
+impl Default for KalmanConfig {
+    fn default() -> Self {
+        KalmanConfig {
+            process_noise: 2.0,
+            measurement_noise: 10.0,
+        }
+    }
+}
+
+/// 预处理配置 默认不做任何处理
+#[derive(Clone, Debug, Default)]
+pub struct PreprocessConfig {
+    pub min_distance: f64, // 和上一个保留点的距离小于该值视为重复/静止点 0表示不去重
+    pub max_speed_kmh: Option<f64>, // 相邻点速度超过该值视为漂移点 需要时间戳
+    pub speed_metric: Option<algorithm::Metric>, // 计算速度的距离 单位需要为米 None时使用metric
+    pub fix_timestamps: bool, // 时间戳倒退时按照时间戳重新排序
+    pub kalman: Option<KalmanConfig>,
+}
+
+/// 预处理过程中去除和修改的点 序号均为原始轨迹中的序号
+#[derive(Clone, Debug, Default)]
+pub struct PreprocessReport {
+    pub duplicates: Vec<usize>, // 重复/静止点
+    pub outliers: Vec<usize>,   // 速度异常的漂移点
+    pub reordered: usize,       // 因为时间戳倒退被移动的点数
+    pub smoothed: bool,         // 是否经过卡尔曼滤波
+}
+
+impl PreprocessReport {
+    pub fn removed(&self) -> usize {
+        self.duplicates.len() + self.outliers.len()
+    }
+}
+
+pub struct Preprocessed {
+    pub traj: Trajectory,
+    pub index: Vec<usize>, // 输出点在原始轨迹中的序号
+    pub report: PreprocessReport,
+}
+
+impl Preprocessed {
+    /// 输出第i个点在原始轨迹中的序号
+    pub fn original_index(&self, i: usize) -> usize {
+        self.index[i]
+    }
+}
+
+/// 按照配置清洗轨迹 时间戳未知(为0)时跳过依赖时间的步骤
+pub fn preprocess(
+    traj: &Trajectory,
+    metric: algorithm::Metric,
+    cfg: &PreprocessConfig,
+) -> Preprocessed {
+    let mut report = PreprocessReport::default();
+    let timed = !traj.is_empty() && traj.iter().all(|p| p.time_stamp != 0);
+    let mut points: Vec<(usize, TrajInfo)> = traj.iter().cloned().enumerate().collect();
+
+    // 时间戳倒退 稳定排序保持相同时间戳的原始顺序
+    if cfg.fix_timestamps && timed {
+        points.sort_by_key(|(_, p)| p.time_stamp);
+        report.reordered = points
+            .iter()
+            .enumerate()
+            .filter(|(i, (index, _))| i != index)
+            .count();
+    }
+
+    // 重复/静止点
+    if cfg.min_distance > 0.0 {
+        let mut kept: Vec<(usize, TrajInfo)> = Vec::with_capacity(points.len());
+        for (index, point) in points {
+            match kept.last() {
+                Some((_, last))
+                    if metric.distance(&last.point, &point.point) < cfg.min_distance =>
+                {
+                    report.duplicates.push(index);
+                }
+                _ => kept.push((index, point)),
+            }
+        }
+        points = kept;
+    }
+
+    // 漂移点 进入和离开的速度都超过阈值
+    if let (Some(max_speed), true) = (cfg.max_speed_kmh, timed) {
+        let max_speed = max_speed / 3.6;
+        let speed_metric = cfg.speed_metric.unwrap_or(metric);
+        let speed = |a: &TrajInfo, b: &TrajInfo| {
+            let dt = b.time_stamp.abs_diff(a.time_stamp);
+            if dt == 0 {
+                return 0.0;
+            }
+            speed_metric.distance(&a.point, &b.point) / dt as f64
+        };
+        let mut kept: Vec<(usize, TrajInfo)> = Vec::with_capacity(points.len());
+        for i in 0..points.len() {
+            let (index, point) = &points[i];
+            let next = points.get(i + 1).map(|(_, p)| p);
+            let outlier = match (kept.last(), next) {
+                (Some((_, prev)), Some(next)) => {
+                    speed(prev, point) > max_speed && speed(point, next) > max_speed
+                }
+                (Some((_, prev)), None) => speed(prev, point) > max_speed,
+                // 第一个点 离开的速度异常并且后续两个点之间正常时才是漂移点
+                (None, Some(next)) => {
+                    speed(point, next) > max_speed
+                        && points
+                            .get(i + 2)
+                            .is_none_or(|(_, after)| speed(next, after) <= max_speed)
+                }
+                (None, None) => false,
+            };
+            if outlier {
+                report.outliers.push(*index);
+            } else {
+                kept.push((*index, point.clone()));
+            }
+        }
+        points = kept;
+    }
+
+    if let Some(kalman) = cfg.kalman {
+        smooth(&mut points, metric, &kalman);
+        report.smoothed = true;
+    }
+    report.duplicates.sort_unstable();
+    report.outliers.sort_unstable();
+    let (index, traj) = points.into_iter().unzip();
+    Preprocessed {
+        traj,
+        index,
+        report,
+    }
+}
+
+// 匀速模型的卡尔曼滤波 x和y方向分别滤波
+// 噪声参数按照第一个点处的坐标跨度换算成坐标单位, 时间戳未知时间隔按照1秒计算
+fn smooth(points: &mut [(usize, TrajInfo)], metric: algorithm::Metric, cfg: &KalmanConfig) {
+    if points.len() < 2 {
+        return;
+    }
+    let origin = points[0].1.point;
+    let (r_x, r_y) = metric.coord_span(cfg.measurement_noise, &origin);
+    let (q_x, q_y) = metric.coord_span(cfg.process_noise, &origin);
+    let mut x = Kalman1d::new(origin.0, r_x, q_x);
+    let mut y = Kalman1d::new(origin.1, r_y, q_y);
+    for i in 1..points.len() {
+        let (prev, cur) = (points[i - 1].1.time_stamp, points[i].1.time_stamp);
+        let dt = if prev != 0 && cur != 0 {
+            cur.abs_diff(prev) as f64
+        } else {
+            1.0
+        };
+        let point = &mut points[i].1.point;
+        point.0 = x.update(point.0, dt);
+        point.1 = y.update(point.1, dt);
+    }
+}
+
+// 状态为(位置, 速度)的一维卡尔曼滤波
+struct Kalman1d {
+    position: f64,
+    velocity: f64,
+    p: [[f64; 2]; 2], // 协方差
+    r: f64,           // 观测噪声方差
+    q: f64,           // 加速度噪声方差
+}
+
+impl Kalman1d {
+    fn new(position: f64, measurement_noise: f64, process_noise: f64) -> Self {
+        let r = measurement_noise * measurement_noise;
+        Kalman1d {
+            position,
+            velocity: 0.0,
+            p: [[r, 0.0], [0.0, r]],
+            r,
+            q: process_noise * process_noise,
+        }
+    }
+
+    fn update(&mut self, measurement: f64, dt: f64) -> f64 {
+        // 预测
+        self.position += self.velocity * dt;
+        let p = self.p;
+        let (dt2, dt3, dt4) = (dt * dt, dt * dt * dt, dt * dt * dt * dt);
+        self.p = [
+            [
+                p[0][0] + dt * (p[1][0] + p[0][1]) + dt2 * p[1][1] + self.q * dt4 / 4.0,
+                p[0][1] + dt * p[1][1] + self.q * dt3 / 2.0,
+            ],
+            [
+                p[1][0] + dt * p[1][1] + self.q * dt3 / 2.0,
+                p[1][1] + self.q * dt2,
+            ],
+        ];
+        // 更新
+        let s = self.p[0][0] + self.r;
+        let (k0, k1) = (self.p[0][0] / s, self.p[1][0] / s);
+        let residual = measurement - self.position;
+        self.position += k0 * residual;
+        self.velocity += k1 * residual;
+        let p = self.p;
+        self.p = [
+            [(1.0 - k0) * p[0][0], (1.0 - k0) * p[0][1]],
+            [p[1][0] - k1 * p[0][0], p[1][1] - k1 * p[0][1]],
+        ];
+        self.position
+    }
+}
+
+// test
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn traj(points: &[(f64, f64, u64)]) -> Trajectory {
+        points
+            .iter()
+            .map(|(x, y, t)| TrajInfo {
+                point: algorithm::Point(*x, *y),
+                time_stamp: *t,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_preprocess() {
+        // 每10秒向东约95米 第3个点漂移到北边约1公里 第5个点原地不动 第7个点时间戳倒退
+        let raw = traj(&[
+            (121.000, 31.0, 100),
+            (121.001, 31.0, 110),
+            (121.002, 31.01, 120),
+            (121.003, 31.0, 130),
+            (121.003, 31.0, 135),
+            (121.005, 31.0, 150),
+            (121.004, 31.0, 140),
+        ]);
+        let cfg = PreprocessConfig {
+            min_distance: 5.0,
+            max_speed_kmh: Some(120.0),
+            speed_metric: None,
+            fix_timestamps: true,
+            kalman: None,
+        };
+        let result = preprocess(&raw, algorithm::Metric::Haversine, &cfg);
+        assert_eq!(result.index, vec![0, 1, 3, 6, 5]);
+        assert_eq!(result.report.duplicates, vec![4]);
+        assert_eq!(result.report.outliers, vec![2]);
+        assert_eq!(result.report.reordered, 2);
+        assert_eq!(result.report.removed(), 2);
+        assert_eq!(result.original_index(3), 6);
+        assert!(result
+            .traj
+            .windows(2)
+            .all(|w| w[0].time_stamp < w[1].time_stamp));
+
+        // 默认配置不做任何处理 时间戳未知时跳过速度检查
+        let untimed = traj(&[(121.0, 31.0, 0), (121.1, 31.0, 0), (121.0, 31.0, 0)]);
+        let result = preprocess(&untimed, algorithm::Metric::Haversine, &cfg);
+        assert_eq!(result.index, vec![0, 1, 2]);
+        let result = preprocess(&raw, algorithm::Metric::Haversine, &Default::default());
+        assert_eq!(result.index, (0..raw.len()).collect::<Vec<_>>());
+
+        // 距离单位为度时 速度按照speed_metric换算成米
+        let degree = PreprocessConfig {
+            min_distance: 0.00005,
+            speed_metric: Some(algorithm::Metric::Haversine),
+            ..cfg
+        };
+        let result = preprocess(&raw, algorithm::Metric::Euclidean, &degree);
+        assert_eq!(result.report.outliers, vec![2]);
+        assert_eq!(result.report.duplicates, vec![4]);
+    }
+
+    #[test]
+    fn test_kalman_smooth() {
+        // 沿着纬线匀速行驶 纬度方向有交替的抖动
+        let raw: Trajectory = (0..30)
+            .map(|i| TrajInfo {
+                point: algorithm::Point(
+                    121.0 + 0.0001 * i as f64,
+                    31.0 + if i % 2 == 0 { 0.00005 } else { -0.00005 },
+                ),
+                time_stamp: 1000 + i,
+            })
+            .collect();
+        let cfg = PreprocessConfig {
+            kalman: Some(KalmanConfig::default()),
+            ..Default::default()
+        };
+        let result = preprocess(&raw, algorithm::Metric::Haversine, &cfg);
+        assert!(result.report.smoothed);
+        let jitter = |t: &Trajectory| {
+            t[10..]
+                .iter()
+                .map(|p| (p.point.1 - 31.0).abs())
+                .sum::<f64>()
+        };
+        assert!(jitter(&result.traj) < jitter(&raw) / 2.0);
+        // 经度方向跟随匀速运动
+        let last = result.traj.last().unwrap().point.0;
+        assert!((last - raw.last().unwrap().point.0).abs() < 0.00005);
+    }
+}
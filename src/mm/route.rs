@@ -0,0 +1,362 @@
+use super::model::{Candidate, MMResult};
+use super::stmatch::MMatch;
+use super::TrajInfo;
+use crate::algorithm;
+use crate::graph::{Network, Path};
+use anyhow::Result;
+
+/*
+ * @file route.rs
+ * 基于坐标的路径查询
+ * 坐标先通过候选点查询吸附到最近的edge上, 再在路网上查询路径。
+ */
+
+/// 依次经过多个坐标的路径
+pub struct Route {
+    pub edges: Vec<usize>,      // 依次经过的路网edge索引 相邻重复的已合并
+    pub length: f64,            // 单位和路网长度一致
+    pub real_lengths: Vec<f64>, // 每个edge上经过的真实长度 单位米 和edges一一对应
+    pub geometry: Vec<[f64; 2]>,
+}
+
+impl Route {
+    /// 行驶时间(秒) 有限速的edge按照限速 其余按照speed(米/秒)
+    pub fn duration(&self, network: &Network, speed: f64) -> f64 {
+        self.edges
+            .iter()
+            .zip(self.real_lengths.iter())
+            .map(|(edge, length)| {
+                let speed = network.edges[*edge]
+                    .get_speed_limit()
+                    .map_or(speed, |kmh| kmh / 3.6);
+                length / speed
+            })
+            .sum()
+    }
+}
+
+impl MMatch {
+    /// 依次经过points(起点 途经点 终点)的最短路径
+    pub fn route(&self, points: &[algorithm::Point], radius: f64) -> Result<Route> {
+        if points.len() < 2 {
+            return Err(anyhow::anyhow!("route needs at least 2 points"));
+        }
+        // 双向道路两个方向的edge到点的距离相同 都作为候选
+        let mut layers: Vec<Vec<Candidate>> = vec![];
+        for point in points {
+            let candidates = self.snap_candidates(point, radius);
+            if candidates.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "no edge found within {} of ({}, {})",
+                    radius,
+                    point.0,
+                    point.1
+                ));
+            }
+            layers.push(candidates);
+        }
+        // 动态规划 选择总长度最短的候选组合 (累计长度, 上一层的候选, 路径)
+        let mut costs: Vec<Vec<(f64, usize, Option<Path>)>> =
+            vec![layers[0].iter().map(|_| (0.0, 0, None)).collect()];
+        for i in 1..layers.len() {
+            let mut layer = vec![];
+            for to in layers[i].iter() {
+                let mut best: (f64, usize, Option<Path>) = (f64::MAX, 0, None);
+                for (j, from) in layers[i - 1].iter().enumerate() {
+                    let prev = costs[i - 1][j].0;
+                    if prev == f64::MAX {
+                        continue;
+                    }
+                    if let Ok(mut paths) = self.short_k_path_between(from, to, 1) {
+                        if prev + paths[0].cost < best.0 {
+                            best = (prev + paths[0].cost, j, Some(paths.remove(0)));
+                        }
+                    }
+                }
+                layer.push(best);
+            }
+            costs.push(layer);
+        }
+        let (mut index, _) = costs
+            .last()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .min_by(|a, b| a.1 .0.partial_cmp(&b.1 .0).unwrap())
+            .unwrap();
+        if costs.last().unwrap()[index].0 == f64::MAX {
+            return Err(anyhow::anyhow!("no path"));
+        }
+        // 回溯每一段的起止候选和路径
+        let mut legs = vec![];
+        for i in (1..layers.len()).rev() {
+            let (_, prev, path) = &costs[i][index];
+            legs.push((
+                &layers[i - 1][*prev],
+                &layers[i][index],
+                path.clone().unwrap(),
+            ));
+            index = *prev;
+        }
+        legs.reverse();
+        let mut route = Route {
+            edges: vec![],
+            length: 0.0,
+            real_lengths: vec![],
+            geometry: vec![],
+        };
+        for (from, to, path) in legs {
+            route.length += path.cost;
+            let last = path.edges.len() - 1;
+            for (i, edge_index) in path.edges.iter().enumerate() {
+                let edge = &self.road_graph().network.edges[*edge_index];
+                let line: algorithm::Line = edge.get_geometry().clone().try_into()?;
+                let start = if i == 0 { from.offset } else { 0.0 };
+                let end = if i == last {
+                    to.offset
+                } else {
+                    edge.get_length()
+                };
+                let part = self.metric().substring(&line.0, start, end);
+                let real_length = if edge.get_length() > 0.0 {
+                    (end - start) / edge.get_length() * edge.get_real_length()
+                } else {
+                    0.0
+                };
+                if route.edges.last() != Some(edge_index) {
+                    route.edges.push(*edge_index);
+                    route.real_lengths.push(0.0);
+                }
+                *route.real_lengths.last_mut().unwrap() += real_length;
+                for p in part {
+                    if route.geometry.last() != Some(&p) {
+                        route.geometry.push(p);
+                    }
+                }
+            }
+        }
+        Ok(route)
+    }
+
+    /// 匹配结果完整路径的几何 路径在没有连通的匹配点之间断开 每段连续的路径一条折线
+    pub fn matched_geometry(&self, result: &MMResult) -> Result<Vec<Vec<[f64; 2]>>> {
+        let network = &self.road_graph().network;
+        let mut lines: Vec<Vec<[f64; 2]>> = vec![];
+        let mut last_to = None;
+        for leg in result.legs.iter() {
+            if last_to != Some(leg.from) {
+                lines.push(vec![]);
+            }
+            last_to = Some(leg.to);
+            let line = lines.last_mut().unwrap();
+            for span in leg.spans.iter() {
+                let edge: algorithm::Line = network.edges[span.edge_index]
+                    .get_geometry()
+                    .clone()
+                    .try_into()?;
+                for p in self.metric().substring(&edge.0, span.start, span.end) {
+                    if line.last() != Some(&p) {
+                        line.push(p);
+                    }
+                }
+            }
+        }
+        lines.retain(|line| line.len() >= 2);
+        Ok(lines)
+    }
+
+    /// radius范围内距离最近的候选点 距离相同(比如双向道路)时返回多个
+    pub fn snap_candidates(&self, point: &algorithm::Point, radius: f64) -> Vec<Candidate> {
+        let traj_point = TrajInfo {
+            point: *point,
+            time_stamp: 0,
+        };
+        let mut candidates = self.query_candidate(&traj_point, radius, 8, radius, None);
+        if let Some(nearest) = candidates.first().map(|c| c.distance) {
+            candidates.retain(|c| c.distance - nearest <= 1e-9 * nearest.max(1.0));
+        }
+        candidates
+    }
+
+    /// 将坐标吸附到radius范围内最近的edge上
+    pub fn snap_point(&self, point: &algorithm::Point, radius: f64) -> Option<Candidate> {
+        let traj_point = TrajInfo {
+            point: *point,
+            time_stamp: 0,
+        };
+        self.query_candidate(&traj_point, radius, 1, radius, None)
+            .into_iter()
+            .next()
+    }
+
+    /// 两个吸附点之间k条无环的最短路径 按代价从小到大排列
+    /// 返回路径的edges为路网edge索引 首尾分别为起点和终点所在的edge,
+    /// nodes为中间经过的节点, cost包含首尾edge上的部分长度。
+    pub fn short_k_path_between(
+        &self,
+        from: &Candidate,
+        to: &Candidate,
+        k: usize,
+    ) -> Result<Vec<Path>> {
+        let network = &self.road_graph().network;
+        let node = |id: String| {
+            network
+                .find_node_by_id(&id)
+                .ok_or_else(|| anyhow::anyhow!("node {} not found", id))
+        };
+        // 同一条edge上顺行 不需要经过节点
+        // 经过节点的路径最后会回到同一条edge上 不是无环的路径 所以只有这一条
+        if from.edge_index == to.edge_index && to.offset >= from.offset {
+            return Ok(vec![Path {
+                cost: to.offset - from.offset,
+                nodes: vec![],
+                edges: vec![from.edge_index],
+            }]);
+        }
+        let start = node(from.edge.get_to_node())?;
+        let end = node(to.edge.get_from_node())?;
+        let head = from.edge.get_length() - from.offset;
+        // 只需要最短路径并且加载了CH时使用CH查询
+        let middle = match self.road_graph().contraction_hierarchy() {
+            Some(ch) if k == 1 => vec![ch
+                .query(start, end)
+                .ok_or_else(|| anyhow::anyhow!("no path"))?],
+            _ => self.road_graph().short_k_path(start, end, k)?,
+        };
+        let paths = middle
+            .into_iter()
+            .map(|path| {
+                let mut edges = vec![from.edge_index];
+                edges.extend(path.edges);
+                edges.push(to.edge_index);
+                Path {
+                    cost: head + path.cost + to.offset,
+                    nodes: path.nodes,
+                    edges,
+                }
+            })
+            .collect();
+        Ok(paths)
+    }
+
+    /// 两个坐标之间k条无环的最短路径 坐标在radius范围内没有edge时返回错误
+    pub fn short_k_path_by_point(
+        &self,
+        from: &algorithm::Point,
+        to: &algorithm::Point,
+        k: usize,
+        radius: f64,
+    ) -> Result<Vec<Path>> {
+        let snap = |point: &algorithm::Point| {
+            self.snap_point(point, radius).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "no edge found within {} of ({}, {})",
+                    radius,
+                    point.0,
+                    point.1
+                )
+            })
+        };
+        self.short_k_path_between(&snap(from)?, &snap(to)?, k)
+    }
+}
+
+// test
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graph::fixture::{self, feature};
+
+    // 两条平行的路 1->2->3 以及 1->4->3
+    fn build() -> MMatch {
+        let network = fixture::geojson_network(&[
+            feature(1, 1, 2, "[[121.0,31.0],[121.001,31.0]]"),
+            feature(2, 2, 3, "[[121.001,31.0],[121.002,31.0]]"),
+            feature(3, 1, 4, "[[121.0,31.0],[121.001,31.001]]"),
+            feature(4, 4, 3, "[[121.001,31.001],[121.002,31.0]]"),
+            feature(5, 0, 1, "[[120.999,31.0],[121.0,31.0]]"),
+            feature(6, 3, 5, "[[121.002,31.0],[121.003,31.0]]"),
+        ])
+        .unwrap();
+        fixture::matcher(network).unwrap()
+    }
+
+    #[test]
+    fn test_short_k_path_by_point() {
+        let mm = build();
+        let from = algorithm::Point(120.9995, 31.00001);
+        let to = algorithm::Point(121.0025, 31.00001);
+        let paths = mm.short_k_path_by_point(&from, &to, 3, 50.0).unwrap();
+        assert_eq!(paths.len(), 2);
+        let ids: Vec<Vec<String>> = paths
+            .iter()
+            .map(|p| {
+                p.edges
+                    .iter()
+                    .map(|e| mm.road_graph().network.edges[*e].get_edge_id())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(ids[0], vec!["5", "1", "2", "6"]);
+        assert_eq!(ids[1], vec!["5", "3", "4", "6"]);
+        // 起点和终点各在edge中间 部分长度约为一半
+        let network = &mm.road_graph().network;
+        let half = network.edges[4].get_length() / 2.0;
+        let (a, b) = (network.find_node_by_id("1"), network.find_node_by_id("3"));
+        let middle = mm.road_graph().short_path(a.unwrap(), b.unwrap()).unwrap();
+        assert!((paths[0].cost - (middle.cost + 2.0 * half)).abs() < 1.0);
+
+        // 同一条edge上顺行只有直接到达的一条路径
+        let same = mm
+            .short_k_path_by_point(&from, &algorithm::Point(120.9998, 31.0), 3, 50.0)
+            .unwrap();
+        assert_eq!(same.len(), 1);
+        assert_eq!((same[0].edges.len(), same[0].nodes.len()), (1, 0));
+        assert!(mm
+            .short_k_path_by_point(&from, &algorithm::Point(122.0, 31.0), 1, 50.0)
+            .is_err());
+    }
+
+    #[test]
+    fn test_route_with_via() {
+        let mm = build();
+        let from = algorithm::Point(120.9995, 31.00001);
+        let via = algorithm::Point(121.0005, 31.0006);
+        let to = algorithm::Point(121.0025, 31.00001);
+        let route = mm.route(&[from, via, to], 50.0).unwrap();
+        let network = &mm.road_graph().network;
+        let ids: Vec<String> = route
+            .edges
+            .iter()
+            .map(|e| network.edges[*e].get_edge_id())
+            .collect();
+        assert_eq!(ids, vec!["5", "3", "4", "6"]);
+        let near =
+            |p: &[f64; 2], x: f64, y: f64| (p[0] - x).abs() < 1e-7 && (p[1] - y).abs() < 1e-7;
+        assert!(near(route.geometry.first().unwrap(), 120.9995, 31.0));
+        assert!(near(route.geometry.last().unwrap(), 121.0025, 31.0));
+        let length = algorithm::Metric::Haversine.linestring_length(&geojson::Geometry::new(
+            geojson::Value::LineString(route.geometry.iter().map(|p| p.to_vec()).collect()),
+        ));
+        assert!((length.unwrap() - route.length).abs() < 1.0);
+        let real_length: f64 = route.real_lengths.iter().sum();
+        assert!((real_length - route.length).abs() < 1e-6);
+        assert!(mm.route(&[from], 50.0).is_err());
+
+        // 有限速的edge按照限速计算行驶时间
+        let duration = route.duration(network, 10.0);
+        assert!((duration - route.length / 10.0).abs() < 1e-6);
+        let mut limited = network.clone();
+        std::sync::Arc::make_mut(&mut limited.edges)[route.edges[1]].set_speed_limit(Some(72.0));
+        let saved = route.real_lengths[1] / 10.0 - route.real_lengths[1] / 20.0;
+        assert!((route.duration(&limited, 10.0) - (duration - saved)).abs() < 1e-6);
+
+        // 加载CH后使用CH查询 结果相同
+        let mut mm = build();
+        let ch = crate::graph::ContractionHierarchy::build(mm.road_graph());
+        mm.set_contraction_hierarchy(ch).unwrap();
+        let contracted = mm.route(&[from, via, to], 50.0).unwrap();
+        assert_eq!(contracted.edges, route.edges);
+        assert!((contracted.length - route.length).abs() < 1e-6);
+    }
+}
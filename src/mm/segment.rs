@@ -0,0 +1,245 @@
+use super::traj::{MutileTrajectory, TrajInfo, Trajectory};
+use crate::algorithm;
+
+/*
+ * @file segment.rs
+ * 停留点检测和行程切分
+ * 长时间的轨迹先按照时间间隔切开, 再检测停留点, 停留点之间的部分作为独立的行程分别匹配。
+ * 依赖时间戳, 时间戳未知(为0)的轨迹整体作为一个行程。
+ */
+
+/// 切分配置
+#[derive(Clone, Debug)]
+pub struct SegmentConfig {
+    pub stay_distance: f64, // 停留范围 单位由metric决定
+    pub stay_duration: u64, // 停留的最短时长 单位秒
+    pub max_gap: u64,       // 相邻点时间间隔超过该值时切开 单位秒
+    pub min_points: usize,  // 点数少于该值的行程丢弃
+}
+
+impl Default for SegmentConfig {
+    fn default() -> Self {
+        SegmentConfig {
+            stay_distance: 200.0,
+            stay_duration: 300,
+            max_gap: 600,
+            min_points: 2,
+        }
+    }
+}
+
+/// 停留事件 序号均为原始轨迹中的序号
+#[derive(Clone, Debug)]
+pub struct StayPoint {
+    pub traj_index: usize,        // 所属轨迹在MutileTrajectory中的序号
+    pub start: usize,             // 第一个停留点的序号
+    pub end: usize,               // 最后一个停留点的序号(包含)
+    pub center: algorithm::Point, // 停留点的平均位置
+    pub arrival: u64,
+    pub departure: u64,
+}
+
+impl StayPoint {
+    pub fn duration(&self) -> u64 {
+        self.departure - self.arrival
+    }
+}
+
+/// 切分出的行程
+#[derive(Clone, Debug)]
+pub struct Trip {
+    pub traj_index: usize, // 所属轨迹在MutileTrajectory中的序号
+    pub start: usize,      // 第一个点在原始轨迹中的序号 行程内第i个点为start + i
+    pub traj: Trajectory,
+}
+
+/// 切分结果 行程和停留事件都按照(轨迹序号, 起始序号)排列
+#[derive(Clone, Debug, Default)]
+pub struct Segmentation {
+    pub trips: Vec<Trip>,
+    pub stays: Vec<StayPoint>,
+}
+
+impl MutileTrajectory {
+    /// 切分所有轨迹
+    pub fn segment(&self, metric: algorithm::Metric, cfg: &SegmentConfig) -> Segmentation {
+        let mut result = Segmentation::default();
+        for (traj_index, traj) in self.trajs.iter().enumerate() {
+            let timed = traj.iter().all(|p| p.time_stamp != 0);
+            if !timed {
+                result.push_trip(traj_index, 0, traj, cfg);
+                continue;
+            }
+            for (start, end) in split_gaps(traj, cfg.max_gap) {
+                let stays = stay_points(&traj[start..end], metric, cfg);
+                // 停留点之间的部分为行程
+                let mut from = start;
+                for mut stay in stays {
+                    stay.traj_index = traj_index;
+                    stay.start += start;
+                    stay.end += start;
+                    result.push_trip(traj_index, from, &traj[from..stay.start], cfg);
+                    from = stay.end + 1;
+                    result.stays.push(stay);
+                }
+                result.push_trip(traj_index, from, &traj[from..end], cfg);
+            }
+        }
+        result
+    }
+}
+
+impl Segmentation {
+    fn push_trip(
+        &mut self,
+        traj_index: usize,
+        start: usize,
+        traj: &[TrajInfo],
+        cfg: &SegmentConfig,
+    ) {
+        if traj.len() < cfg.min_points.max(1) {
+            return;
+        }
+        self.trips.push(Trip {
+            traj_index,
+            start,
+            traj: traj.to_vec(),
+        });
+    }
+}
+
+// 按照时间间隔切开 返回[start, end)
+fn split_gaps(traj: &Trajectory, max_gap: u64) -> Vec<(usize, usize)> {
+    let mut ranges = vec![];
+    let mut start = 0;
+    for i in 1..traj.len() {
+        if traj[i].time_stamp.saturating_sub(traj[i - 1].time_stamp) > max_gap {
+            ranges.push((start, i));
+            start = i;
+        }
+    }
+    if start < traj.len() {
+        ranges.push((start, traj.len()));
+    }
+    ranges
+}
+
+/// 检测停留点 连续的点都在第一个点的stay_distance范围内并且持续时间超过stay_duration
+/// 返回的序号是traj中的序号
+pub fn stay_points(
+    traj: &[TrajInfo],
+    metric: algorithm::Metric,
+    cfg: &SegmentConfig,
+) -> Vec<StayPoint> {
+    let mut stays = vec![];
+    // 时间戳有序时 以i为起点时长不够并且在far处离开 那么i之后离far也超出范围的点
+    // 范围不会超过far 时长更短 不需要重新扫描 避免静止但时长不够的长序列反复扫描
+    let ordered = traj.windows(2).all(|w| w[0].time_stamp <= w[1].time_stamp);
+    let mut far: Option<usize> = None;
+    let mut i = 0;
+    while i < traj.len() {
+        if let Some(far) = far.filter(|far| i < *far) {
+            if metric.distance(&traj[i].point, &traj[far].point) > cfg.stay_distance {
+                i += 1;
+                continue;
+            }
+        }
+        let mut j = i + 1;
+        while j < traj.len() && metric.distance(&traj[i].point, &traj[j].point) <= cfg.stay_distance
+        {
+            j += 1;
+        }
+        let (arrival, departure) = (traj[i].time_stamp, traj[j - 1].time_stamp);
+        if j - i > 1 && departure.saturating_sub(arrival) >= cfg.stay_duration {
+            let count = (j - i) as f64;
+            let (x, y) = traj[i..j]
+                .iter()
+                .fold((0.0, 0.0), |(x, y), p| (x + p.point.0, y + p.point.1));
+            stays.push(StayPoint {
+                traj_index: 0,
+                start: i,
+                end: j - 1,
+                center: algorithm::Point(x / count, y / count),
+                arrival,
+                departure,
+            });
+            i = j;
+        } else if ordered && j == traj.len() {
+            // 之后的起点时长只会更短
+            break;
+        } else {
+            far = ordered.then_some(j);
+            i += 1;
+        }
+    }
+    stays
+}
+
+// test
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn point(x: f64, t: u64) -> TrajInfo {
+        TrajInfo {
+            point: algorithm::Point(121.0 + x, 31.0),
+            time_stamp: t,
+        }
+    }
+
+    #[test]
+    fn test_segment() {
+        // 行驶 -> 停留10分钟 -> 行驶 -> 信号中断约50分钟 -> 行驶
+        let mut traj = vec![];
+        for i in 0..5 {
+            traj.push(point(0.003 * i as f64, 1000 + 30 * i));
+        }
+        for i in 0..6 {
+            traj.push(point(0.01505 + 0.00001 * (i % 2) as f64, 1150 + 120 * i));
+        }
+        for i in 0..4 {
+            traj.push(point(0.018 + 0.003 * i as f64, 1780 + 30 * i));
+        }
+        for i in 0..3 {
+            traj.push(point(0.03 + 0.003 * i as f64, 5000 + 30 * i));
+        }
+        let trajs = MutileTrajectory {
+            trajs: vec![traj, vec![point(0.0, 0), point(0.1, 0)]],
+        };
+        let result = trajs.segment(algorithm::Metric::Haversine, &SegmentConfig::default());
+
+        let trips: Vec<(usize, usize, usize)> = result
+            .trips
+            .iter()
+            .map(|t| (t.traj_index, t.start, t.traj.len()))
+            .collect();
+        assert_eq!(trips, vec![(0, 0, 5), (0, 11, 4), (0, 15, 3), (1, 0, 2)]);
+        assert_eq!(result.stays.len(), 1);
+        let stay = &result.stays[0];
+        assert_eq!((stay.start, stay.end), (5, 10));
+        assert_eq!(stay.duration(), 600);
+        assert!((stay.center.0 - 121.01505).abs() < 0.0001);
+
+        // 停留时间不够时不切分
+        let cfg = SegmentConfig {
+            stay_duration: 3600,
+            max_gap: 7200,
+            ..Default::default()
+        };
+        let result = trajs.segment(algorithm::Metric::Haversine, &cfg);
+        assert!(result.stays.is_empty());
+        assert_eq!(result.trips.len(), 2);
+
+        // 时长不够的静止序列之后 从序列中间开始的停留仍然可以检测到
+        let mut traj: Vec<TrajInfo> = (0..2000).map(|i| point(0.0, 1000 + i / 10)).collect();
+        traj.push(point(0.01, 1300));
+        traj.extend((0..10).map(|i| point(0.0095, 1300 + 60 * i)));
+        let stays = stay_points(
+            &traj,
+            algorithm::Metric::Haversine,
+            &SegmentConfig::default(),
+        );
+        assert_eq!(stays.len(), 1);
+        assert_eq!((stays[0].start, stays[0].end), (2000, 2010));
+    }
+}
@@ -0,0 +1,214 @@
+use super::stmatch::{MMatch, SegmentRef};
+use crate::algorithm;
+
+/*
+ * @file snap.rs
+ * 坐标吸附
+ * 将坐标投影到附近的edge上, 不依赖轨迹点和候选点的上下文。
+ */
+
+/// 坐标在edge上的投影
+#[derive(Clone, Debug)]
+pub struct Snap {
+    pub edge_id: String,
+    pub edge_index: usize,       // 路网edge索引
+    pub point: algorithm::Point, // 投影点
+    pub distance: f64,           // 坐标到投影点的距离 单位和路网长度一致
+    pub offset: f64,             // 投影点距edge起点的偏移量 单位和路网长度一致
+    pub offset_meters: f64,      // 投影点距edge起点的偏移量 单位米
+    pub fraction: f64,           // 偏移量占edge长度的比例 [0, 1]
+}
+
+impl MMatch {
+    /// radius范围内距离最近的k个投影 按距离从小到大排列
+    pub fn snap(&self, point: &algorithm::Point, radius: f64, k: usize) -> Vec<Snap> {
+        self.nearest(point, k, Some(radius))
+    }
+
+    /// 距离最近的k条edge上的投影 max_distance为None时不限制距离
+    pub fn nearest(
+        &self,
+        point: &algorithm::Point,
+        k: usize,
+        max_distance: Option<f64>,
+    ) -> Vec<Snap> {
+        let max_distance = max_distance.unwrap_or(f64::INFINITY);
+        self.nearby(point)
+            .take_while(|snap| snap.distance <= max_distance)
+            .take(k)
+            .collect()
+    }
+
+    /// radius内没有edge时半径逐次翻倍, 返回第一个包含edge的半径内最近的k条edge
+    /// 半径超过max_radius仍然没有edge时返回空
+    pub fn nearest_adaptive(
+        &self,
+        point: &algorithm::Point,
+        k: usize,
+        radius: f64,
+        max_radius: f64,
+    ) -> Vec<Snap> {
+        match self.adaptive_radius(point, radius, max_radius) {
+            Some(radius) => self.nearest(point, k, Some(radius)),
+            None => vec![],
+        }
+    }
+
+    /// 翻倍radius直到包含最近的edge 不超过max_radius
+    pub fn adaptive_radius(
+        &self,
+        point: &algorithm::Point,
+        radius: f64,
+        max_radius: f64,
+    ) -> Option<f64> {
+        let (_, nearest) = self.query_nearby(point).next()?;
+        if nearest > max_radius.max(radius) {
+            return None;
+        }
+        let mut grown = radius;
+        while grown < nearest {
+            grown = if grown > 0.0 { grown * 2.0 } else { nearest };
+        }
+        Some(grown.min(max_radius.max(radius)))
+    }
+
+    // 由近到远遍历edge上的投影
+    pub(crate) fn nearby<'a>(
+        &'a self,
+        point: &algorithm::Point,
+    ) -> impl Iterator<Item = Snap> + 'a {
+        let point = *point;
+        self.query_nearby(&point)
+            .map(move |(segment, _)| self.project_segment(&point, &segment))
+    }
+
+    // 坐标在片段上的投影 偏移量换算到整条edge上
+    fn project_segment(&self, point: &algorithm::Point, segment: &SegmentRef) -> Snap {
+        let edge = &self.road_graph().network.edges[segment.edge];
+        let line = &self.edge_line(segment.edge)[segment.start..=segment.end];
+        let (distance, offset, closest) = self.metric().linear_reference(*point, line);
+        // 局部投影计算的偏移量和edge长度可能有微小的误差
+        let length = edge.get_length();
+        let offset = (segment.offset + offset).min(length);
+        let fraction = if length > 0.0 { offset / length } else { 0.0 };
+        Snap {
+            edge_id: edge.get_edge_id(),
+            edge_index: segment.edge,
+            point: closest,
+            distance,
+            offset,
+            offset_meters: fraction * edge.get_real_length(),
+            fraction,
+        }
+    }
+}
+
+// test
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graph::fixture::{self, feature};
+    use crate::graph::snapshot;
+
+    #[test]
+    fn test_snap() {
+        let network = fixture::geojson_network(&[
+            feature(1, 1, 2, "[[121.0,31.0],[121.001,31.0]]"),
+            feature(2, 2, 3, "[[121.0,31.0005],[121.001,31.0005]]"),
+            feature(3, 3, 4, "[[121.002,31.0],[121.004,31.004]]"),
+        ])
+        .unwrap();
+        let mm = fixture::matcher(network).unwrap();
+
+        let point = algorithm::Point(121.00025, 31.0001);
+        let snaps = mm.snap(&point, 100.0, 2);
+        assert_eq!(snaps.len(), 2);
+        assert_eq!(snaps[0].edge_id, "1");
+        assert_eq!(snaps[1].edge_id, "2");
+        assert!(
+            (snaps[0].point.0 - 121.00025).abs() < 1e-7 && (snaps[0].point.1 - 31.0).abs() < 1e-7
+        );
+        assert!((snaps[0].fraction - 0.25).abs() < 1e-3);
+        assert!((snaps[0].offset_meters - snaps[0].offset).abs() < 1e-6);
+        // 纬度差0.0001约11米
+        assert!((snaps[0].distance - 11.1).abs() < 0.2);
+        assert!(snaps[0].distance < snaps[1].distance);
+
+        assert_eq!(mm.snap(&point, 100.0, 1).len(), 1);
+        assert_eq!(mm.snap(&point, 20.0, 5).len(), 1);
+        assert!(mm.snap(&algorithm::Point(122.0, 31.0), 100.0, 1).is_empty());
+
+        // 按照真实距离排序 斜线edge 3的外包矩形更近但是edge 2距离更近
+        let far = algorithm::Point(121.0005, 31.002);
+        let nearest = mm.nearest(&far, 3, None);
+        let ids: Vec<&str> = nearest.iter().map(|s| s.edge_id.as_str()).collect();
+        assert_eq!(ids, vec!["2", "3", "1"]);
+        assert!(mm.nearest(&far, 1, Some(100.0)).is_empty());
+        // 约167米 半径50逐次翻倍到200
+        assert_eq!(mm.adaptive_radius(&far, 50.0, 1000.0), Some(200.0));
+        assert_eq!(mm.nearest_adaptive(&far, 5, 50.0, 1000.0).len(), 1);
+        assert!(mm.nearest_adaptive(&far, 5, 50.0, 100.0).is_empty());
+    }
+
+    #[test]
+    fn test_snap_utm_network() {
+        // UTM坐标的路网 匹配也在UTM下进行 坐标系不变时也要按照经纬度计算真实长度
+        let utm = algorithm::Crs::Utm {
+            zone: 51,
+            north: true,
+        };
+        let (x, y) = (350000.0, 3430000.0);
+        let network = fixture::geojson_network(&[feature(
+            1,
+            1,
+            2,
+            &format!("[[{},{}],[{},{}]]", x, y, x + 200.0, y),
+        )])
+        .unwrap();
+        let snapshot = snapshot::Snapshot::new(network)
+            .unwrap()
+            .reproject(&utm, &utm)
+            .unwrap();
+        let mm = MMatch::from_snapshot(snapshot, algorithm::Metric::Euclidean).unwrap();
+        let snap = &mm.snap(&algorithm::Point(x + 50.0, y + 10.0), 20.0, 1)[0];
+        assert!((snap.offset - 50.0).abs() < 1e-6);
+        // UTM的比例因子使得投影距离和真实距离相差不到千分之一
+        assert!(
+            (snap.offset_meters - 50.0).abs() < 0.1,
+            "{}",
+            snap.offset_meters
+        );
+    }
+
+    #[test]
+    fn test_snap_segment_chunks() {
+        // 一条有10个顶点的锯齿状长edge 按照4段切分成3个片段
+        let coordinates: Vec<String> = (0..10)
+            .map(|i| {
+                format!(
+                    "[{},{}]",
+                    121.0 + 0.001 * i as f64,
+                    31.0 + 0.0005 * (i % 2) as f64
+                )
+            })
+            .collect();
+        let coordinates = format!("[{}]", coordinates.join(","));
+        let network = fixture::geojson_network(&[feature(1, 1, 2, &coordinates)]).unwrap();
+        let mm = fixture::matcher(network).unwrap();
+        let line = mm.edge_line(0).to_vec();
+        let chunks = snapshot::segment_chunks(0, &line, algorithm::Metric::Haversine);
+        let ranges: Vec<(usize, usize)> = chunks.iter().map(|(_, _, s)| (s.start, s.end)).collect();
+        assert_eq!(ranges, vec![(0, 4), (4, 8), (8, 9)]);
+
+        // 片段上的投影和整条edge上的投影一致
+        let point = algorithm::Point(121.0072, 31.0004);
+        let snap = &mm.snap(&point, 100.0, 1)[0];
+        let (distance, offset, _) = algorithm::Metric::Haversine.linear_reference(point, &line);
+        assert!((snap.distance - distance).abs() < 1e-6);
+        assert!((snap.offset - offset).abs() < 0.5);
+        assert_eq!(
+            mm.query_bbox(&rtree_rs::Rect::new([120.0, 30.0], [122.0, 32.0])),
+            vec![0]
+        );
+    }
+}
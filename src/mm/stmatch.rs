@@ -1,59 +1,162 @@
-use super::model::{Candidate, Config, Layer, LayerLists, Layers, MMResult};
+use super::model::{Candidate, Config, Layer, LayerLists, Layers, MMResult, MatchedLeg, PathSpan};
 use super::TrajInfo;
 use super::Trajectory;
 use crate::algorithm;
-use crate::graph::{network, Edge, EdgeType, RoadGraph};
+use crate::graph::{
+    snapshot, ContractionHierarchy, Edge, EdgeType, Path as GraphPath, RoadGraph, RoutingMode,
+    TurnModel,
+};
 use anyhow;
-use geojson;
 use log::{debug, error, info, warn};
 use rtree_rs::{RTree, Rect};
 use std::cell::RefCell;
-use std::{fs::File, io::BufReader, rc::Rc};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
 
+pub use crate::graph::snapshot::SegmentRef;
+
+/// 匹配器 clone时共享路网的edge、空间索引和折线 只复制图的拓扑
+/// 匹配时的虚拟节点只加入各自的图中 多个线程各持有一份clone即可并发匹配
+#[derive(Clone)]
 pub struct MMatch {
     road_graph: RoadGraph,
-    road_rtree: RTree<2, f64, usize>,
+    road_rtree: Arc<RTree<2, f64, SegmentRef>>,
+    lines: Arc<Vec<Vec<[f64; 2]>>>, // edge的折线坐标 和路网edge索引一致
 }
 
 // from network file to map matching
 impl TryFrom<String> for MMatch {
     type Error = anyhow::Error;
     fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
-        debug!("loading netwok from : {}", value);
-        let file = File::open(value.clone())?;
-        debug!("open file {} success", value);
-        let reader = BufReader::new(file);
-        debug!("load geojson file {} ", value);
-        let geojson = geojson::GeoJson::from_reader(reader)?;
-        let network = network::Network::try_from(geojson)?;
-        info!("load road network success file: {} ", value);
-        let road_graph = RoadGraph::new(network);
+        MMatch::new(&value, algorithm::Metric::default())
+    }
+}
+
+impl MMatch {
+    /// 加载路网 metric决定路网长度以及候选点距离的单位
+    pub fn new(path: &str, metric: algorithm::Metric) -> anyhow::Result<Self> {
+        debug!("loading netwok from : {}", path);
+        let snapshot = snapshot::load(Path::new(path))?;
+        info!("load road network success file: {} ", path);
+        MMatch::from_snapshot(snapshot, metric)
+    }
+
+    /// 由已经加载(以及坐标转换)的路网构建
+    pub fn from_snapshot(
+        mut snapshot: snapshot::Snapshot,
+        metric: algorithm::Metric,
+    ) -> anyhow::Result<Self> {
+        snapshot.network.set_metric(metric)?;
+        let road_graph = RoadGraph::new(snapshot.network);
         debug!("build road graph success");
+        // 直接读取坐标 不经过geo的类型转换
+        let mut lines: Vec<Vec<[f64; 2]>> = Vec::with_capacity(road_graph.network.edges.len());
+        for edge in road_graph.network.edges.iter() {
+            match &edge.get_geometry().value {
+                geojson::Value::LineString(line) => {
+                    lines.push(line.iter().map(|p| [p[0], p[1]]).collect())
+                }
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "edge {} geometry is not linestring",
+                        edge.get_edge_id()
+                    ))
+                }
+            }
+        }
+        // 快照中保存的索引和当前的坐标以及距离一致时直接使用
+        let segments = match snapshot.index.filter(|index| index.matches(metric)) {
+            Some(index) => {
+                debug!("use spatial index of snapshot");
+                index.segments
+            }
+            None => lines
+                .iter()
+                .enumerate()
+                .flat_map(|(edge, line)| snapshot::segment_chunks(edge, line, metric))
+                .collect(),
+        };
         let mut road_rtree = RTree::new();
-        for (index, edge) in road_graph.network.edges.iter().enumerate() {
-            let points = edge.get_geom_rect()?;
-            let rect = Rect::new([points.0 .0, points.0 .1], [points.1 .0, points.1 .1]);
-            road_rtree.insert(rect, index);
+        let chunks = segments.len();
+        for (min, max, segment) in segments {
+            road_rtree.insert(Rect::new(min, max), segment);
         }
-        debug!("build road rtree success");
+        debug!("build road rtree success, {} segment chunks", chunks);
         info!("construct map matching success... ");
         Ok(MMatch {
             road_graph,
-            road_rtree,
+            road_rtree: Arc::new(road_rtree),
+            lines: Arc::new(lines),
         })
     }
+
+    pub fn metric(&self) -> algorithm::Metric {
+        self.road_graph.network.get_metric()
+    }
+
+    /// 设置转向模型 需要配合RoutingMode::EdgeBased使用
+    pub fn set_turn_model(&mut self, turns: TurnModel) {
+        self.road_graph.set_turn_model(turns);
+    }
+
+    /// 设置Contraction Hierarchies 需要配合RoutingMode::Contracted使用
+    pub fn set_contraction_hierarchy(&mut self, ch: ContractionHierarchy) -> anyhow::Result<()> {
+        self.road_graph.set_contraction_hierarchy(ch)
+    }
+
+    pub fn road_graph(&self) -> &RoadGraph {
+        &self.road_graph
+    }
 }
 
 impl MMatch {
     // 查询bbox内的edge 返回edge索引
     pub fn query_bbox(&self, bbox: &Rect<2, f64>) -> Vec<usize> {
+        let mut seen = HashSet::new();
         let mut result = Vec::new();
         for item in self.road_rtree.search(*bbox) {
-            result.push(*item.data);
+            if seen.insert(item.data.edge) {
+                result.push(item.data.edge);
+            }
         }
         result
     }
 
+    // edge的折线坐标
+    pub(crate) fn edge_line(&self, edge_index: usize) -> &[[f64; 2]] {
+        &self.lines[edge_index]
+    }
+
+    // 由近到远遍历edge 返回(距离最近的片段, 到edge的距离) 每条edge只返回一次
+    // 内部节点按照到外包矩形的距离排序, 叶子节点按照到片段的真实距离排序
+    pub(crate) fn query_nearby<'a>(
+        &'a self,
+        point: &algorithm::Point,
+    ) -> impl Iterator<Item = (SegmentRef, f64)> + 'a {
+        let point = *point;
+        let metric = self.metric();
+        let mut seen = HashSet::new();
+        self.road_rtree
+            .nearby(move |rect, item| match item {
+                Some(segment) => {
+                    let line = &self.lines[segment.edge][segment.start..=segment.end];
+                    metric.linear_reference(point, line).0
+                }
+                // 矩形内离坐标最近的点 经纬度下为近似下界
+                None => {
+                    let closest = algorithm::Point(
+                        point.0.clamp(rect.min[0], rect.max[0]),
+                        point.1.clamp(rect.min[1], rect.max[1]),
+                    );
+                    metric.distance(&point, &closest)
+                }
+            })
+            .map(|item| (*item.data, item.dist))
+            .filter(move |(segment, _)| seen.insert(segment.edge))
+    }
+
     // 查询候选者
     pub fn query_candidate(
         &self,
@@ -63,45 +166,41 @@ impl MMatch {
         gps_err: f64,
         prev_candidate: Option<Rc<Candidate>>,
     ) -> Vec<Candidate> {
-        // 构建bbox
-        let bbox = Rect::new(
-            [traj_point.point.0 - radius, traj_point.point.1 - radius],
-            [traj_point.point.0 + radius, traj_point.point.1 + radius],
-        );
-        let edge_indices = self.query_bbox(&bbox);
         let mut candidates = Vec::new();
         let mut prev_edge_id = "".to_string();
-        if prev_candidate.is_some() {
-            let p = prev_candidate.unwrap().clone();
+        if let Some(p) = prev_candidate {
             prev_edge_id = p.edge.get_edge_id().clone();
         }
         let mut exit_candidate: Option<Candidate> = None;
 
-        for (_, edge_index) in edge_indices.iter().enumerate() {
-            let edge = self.road_graph.network.find_edge_by_index(*edge_index);
+        let nearby = self
+            .nearby(&traj_point.point)
+            .take_while(|snap| snap.distance <= radius);
+        for snap in nearby {
+            // 已经有knn个候选者 并且不需要继续查找上一个候选者所在的edge
+            if candidates.len() >= knn as usize
+                && (prev_edge_id.is_empty() || exit_candidate.is_some())
+            {
+                break;
+            }
+            let edge = self.road_graph.network.find_edge_by_index(snap.edge_index);
 
             match edge {
                 Some(edge) => {
-                    let line: algorithm::Line = edge.get_geometry().clone().try_into().unwrap();
-                    let (distance, offset, close_point) =
-                        algorithm::linear_reference_distance(traj_point.point, &line.0);
-                    if distance > radius {
-                        continue;
-                    }
-                    if offset > edge.get_length() {
-                        continue;
-                    }
                     let mut candidate = Candidate {
-                        edge: edge,
-                        distance: distance,
-                        offset: offset,
-                        closest_point: close_point,
+                        edge,
+                        edge_index: snap.edge_index,
+                        distance: snap.distance,
+                        offset: snap.offset,
+                        closest_point: snap.point,
                         dummy_node_id: "".to_string(),
-                        ep: self.calc_ep(distance, gps_err),
+                        ep: self.calc_ep(snap.distance, gps_err),
                         ori_traj_point: traj_point.clone(),
                     };
+                    // 带上edge id 双向道路的两条edge投影点相同 需要区分开
                     candidate.dummy_node_id = format!(
-                        "{:.16}-{:.16}-{:.16}-{:.16}",
+                        "{}-{:.16}-{:.16}-{:.16}-{:.16}",
+                        candidate.edge.get_edge_id(),
                         candidate.closest_point.0,
                         candidate.closest_point.1,
                         traj_point.point.0,
@@ -114,7 +213,7 @@ impl MMatch {
                     candidates.push(candidate);
                 }
                 None => {
-                    error!("edge not found by index {}", edge_index);
+                    error!("edge not found by index {}", snap.edge_index);
                     continue;
                 }
             }
@@ -128,7 +227,7 @@ impl MMatch {
         }
         let candidate = exit_candidate.unwrap();
         let exit_candiate_edge_id = candidate.edge.get_edge_id();
-        for (_, candidate) in candidates.iter().enumerate() {
+        for candidate in candidates.iter() {
             if candidate.edge.get_edge_id() == exit_candiate_edge_id {
                 return candidates;
             }
@@ -138,6 +237,15 @@ impl MMatch {
         candidates
     }
 
+    // 候选点的查询半径 没有候选点时按照配置扩大
+    fn candidate_radius(&self, traj_point: &TrajInfo, cfg: &Config) -> f64 {
+        if cfg.max_radius <= cfg.radius {
+            return cfg.radius;
+        }
+        self.adaptive_radius(&traj_point.point, cfg.radius, cfg.max_radius)
+            .unwrap_or(cfg.radius)
+    }
+
     // calc ep
     fn calc_ep(&self, dist: f64, gps_err: f64) -> f64 {
         let a = dist / gps_err;
@@ -150,7 +258,7 @@ impl MMatch {
 
     fn calc_tp(&self, gps_dist: f64, candidate_dist: f64) -> f64 {
         if gps_dist > candidate_dist {
-            return 1.0 as f64;
+            return 1.0;
         }
         gps_dist / candidate_dist
     }
@@ -176,64 +284,90 @@ impl MMatch {
 
 impl MMatch {
     pub fn match_traj(&mut self, traj: &Trajectory, cfg: &Config) -> anyhow::Result<MMResult> {
-        if traj.len() == 0 {
+        if traj.is_empty() {
             return Err(anyhow::anyhow!("trajectory is empty"));
         }
+        if cfg.metric != self.metric() {
+            return Err(anyhow::anyhow!(
+                "config metric {:?} not match road network metric {:?}",
+                cfg.metric,
+                self.metric()
+            ));
+        }
+        if cfg.routing == RoutingMode::Contracted
+            && self.road_graph.contraction_hierarchy().is_none()
+        {
+            return Err(anyhow::anyhow!("contraction hierarchy not built"));
+        }
+        // 上一次匹配加入的虚拟节点不再需要 避免图不断增长
+        self.road_graph.clear_dummy();
         let mut layer_lists = LayerLists::new();
 
-        let condicates = self.query_candidate(&traj[0], cfg.radius, cfg.knn, cfg.gps_err, None);
+        let radius = self.candidate_radius(&traj[0], cfg);
+        let condicates = self.query_candidate(&traj[0], radius, cfg.knn, cfg.gps_err, None);
         let mut cur_layers = Layers::new();
         // 如果没有候选者 添加一个空的候选者
 
         for mut candidate in condicates {
-            // 开始构建虚拟图
-            let from_node_index = self
-                .road_graph
-                .network
-                .find_node_by_id(&candidate.edge.get_from_node());
-            if from_node_index.is_none() {
-                error!(
-                    "from node not found by id {}",
-                    candidate.edge.get_from_node()
+            // 基于edge的查询直接在路网上进行 不需要虚拟图
+            if cfg.routing == RoutingMode::NodeBased {
+                // 开始构建虚拟图
+                let from_node_index = self
+                    .road_graph
+                    .network
+                    .find_node_by_id(&candidate.edge.get_from_node());
+                if from_node_index.is_none() {
+                    error!(
+                        "from node not found by id {}",
+                        candidate.edge.get_from_node()
+                    );
+                    continue;
+                }
+                let to_node_index = self
+                    .road_graph
+                    .network
+                    .find_node_by_id(&candidate.edge.get_to_node());
+                if to_node_index.is_none() {
+                    error!("to node not found by id {}", candidate.edge.get_to_node());
+                    continue;
+                }
+                // 构建虚拟的node 并添加到图中
+                // a--->b     a-->cs--->b
+                // self.road_graph.network.add_node(dummy_node_id.clone())?;
+                // 生成一个虚拟边
+                use uuid::Uuid;
+                let edge = Edge::new(
+                    Uuid::new_v4().to_string(),
+                    candidate.edge.get_from_node().clone(),
+                    candidate.dummy_node_id.clone(),
+                    candidate.offset,
+                    0.0,
+                    EdgeType::Dummy,
+                    "dummy_edge".to_string(),
+                    candidate.edge.get_geometry().clone(),
                 );
-                continue;
-            }
-            let to_node_index = self
-                .road_graph
-                .network
-                .find_node_by_id(&candidate.edge.get_to_node());
-            if to_node_index.is_none() {
-                error!("to node not found by id {}", candidate.edge.get_to_node());
-                continue;
+                self.road_graph.update_graph(edge)?;
+                // 生成cs---->b 这个虚拟边
+                let edge = Edge::new(
+                    Uuid::new_v4().to_string(),
+                    candidate.dummy_node_id.clone(),
+                    candidate.edge.get_to_node().clone(),
+                    candidate.edge.get_length() - candidate.offset,
+                    0.0,
+                    EdgeType::Dummy,
+                    "dummy_edge".to_string(),
+                    candidate.edge.get_geometry().clone(),
+                );
+                self.road_graph.update_graph(edge)?;
+                if let Some(node) = self
+                    .road_graph
+                    .network
+                    .find_node_by_id(&candidate.dummy_node_id)
+                {
+                    self.road_graph
+                        .set_node_point(node, candidate.closest_point);
+                }
             }
-            // 构建虚拟的node 并添加到图中
-            // a--->b     a-->cs--->b
-            // self.road_graph.network.add_node(dummy_node_id.clone())?;
-            // 生成一个虚拟边
-            use uuid::Uuid;
-            let edge = Edge::new(
-                Uuid::new_v4().to_string(),
-                candidate.edge.get_from_node().clone(),
-                candidate.dummy_node_id.clone(),
-                candidate.offset,
-                0.0,
-                EdgeType::Dummy,
-                "dummy_edge".to_string(),
-                candidate.edge.get_geometry().clone(),
-            );
-            self.road_graph.update_graph(edge)?;
-            // 生成cs---->b 这个虚拟边
-            let edge = Edge::new(
-                Uuid::new_v4().to_string(),
-                candidate.dummy_node_id.clone(),
-                candidate.edge.get_to_node().clone(),
-                candidate.edge.get_length() - candidate.offset,
-                0.0,
-                EdgeType::Dummy,
-                "dummy_edge".to_string(),
-                candidate.edge.get_geometry().clone(),
-            );
-            self.road_graph.update_graph(edge)?;
             // 计算输出概率
             candidate.ep = self.calc_ep(candidate.distance, cfg.gps_err);
             let ep = candidate.ep;
@@ -242,21 +376,18 @@ impl MMatch {
                 prev_layer: RefCell::new(None),
                 cumulative_prob: ep.ln(),
                 tp: 0.0,
+                distance: f64::MAX,
             })));
         }
         layer_lists.push(cur_layers);
 
-        for (index, trj) in (&traj[1..]).iter().enumerate() {
+        for (index, trj) in traj[1..].iter().enumerate() {
             let index = index + 1;
             let mut cur_layers = Layers::new();
-            let prev_max_condate = MMatch::max_prob_candidate(&layer_lists.last().unwrap());
-            let prev_candiate = if prev_max_condate.is_none() {
-                None
-            } else {
-                Some(Rc::new(prev_max_condate.unwrap()))
-            };
-            let condicates =
-                self.query_candidate(trj, cfg.radius, cfg.knn, cfg.gps_err, prev_candiate);
+            let prev_max_condate = MMatch::max_prob_candidate(layer_lists.last().unwrap());
+            let prev_candiate = prev_max_condate.map(Rc::new);
+            let radius = self.candidate_radius(trj, cfg);
+            let condicates = self.query_candidate(trj, radius, cfg.knn, cfg.gps_err, prev_candiate);
             if condicates.is_empty() {
                 warn!("no candidate found in {} gps point", index + 1);
                 layer_lists.push(cur_layers);
@@ -268,120 +399,136 @@ impl MMatch {
                     prev_layer: RefCell::new(None),
                     cumulative_prob: f64::MIN,
                     tp: 0.0,
+                    distance: f64::MAX,
                 })));
             }
             let prev_layers = layer_lists.last().unwrap();
+            let contracted_table = if cfg.routing == RoutingMode::Contracted {
+                self.contracted_table(prev_layers, &cur_layers)
+            } else {
+                HashMap::new()
+            };
             for prev_layer in prev_layers {
                 for cur_layer in cur_layers.iter_mut() {
                     // let cur_condidate = cur_layer.borrow().candidate.as_ref().unwrap();
                     let cur = cur_layer.borrow();
                     let cur_condidate = cur.candidate.as_ref().unwrap();
-                    // 更新虚拟图
-                    let from_node_index = self
-                        .road_graph
-                        .network
-                        .find_node_by_id(&cur_condidate.edge.get_from_node());
-                    if from_node_index.is_none() {
-                        error!(
-                            "from node not found by id {}",
-                            cur_condidate.edge.get_from_node()
-                        );
-                        continue;
-                    }
-                    let to_node_index = self
-                        .road_graph
-                        .network
-                        .find_node_by_id(&cur_condidate.edge.get_to_node());
-                    if to_node_index.is_none() {
-                        error!(
-                            "to node not found by id {}",
-                            cur_condidate.edge.get_to_node()
+                    if cfg.routing == RoutingMode::NodeBased {
+                        // 更新虚拟图
+                        let from_node_index = self
+                            .road_graph
+                            .network
+                            .find_node_by_id(&cur_condidate.edge.get_from_node());
+                        if from_node_index.is_none() {
+                            error!(
+                                "from node not found by id {}",
+                                cur_condidate.edge.get_from_node()
+                            );
+                            continue;
+                        }
+                        let to_node_index = self
+                            .road_graph
+                            .network
+                            .find_node_by_id(&cur_condidate.edge.get_to_node());
+                        if to_node_index.is_none() {
+                            error!(
+                                "to node not found by id {}",
+                                cur_condidate.edge.get_to_node()
+                            );
+                            continue;
+                        }
+                        // 构建当前层的虚拟的node 并添加到图中
+                        // a--->b     a-->cs--->b
+                        // 生成一个虚拟边 a--->cs
+                        use uuid::Uuid;
+                        let edge = Edge::new(
+                            Uuid::new_v4().to_string(),
+                            cur_condidate.edge.get_from_node().clone(),
+                            cur_condidate.dummy_node_id.clone(),
+                            cur_condidate.offset,
+                            0.0,
+                            EdgeType::Dummy,
+                            "dummy_edge".to_string(),
+                            cur_condidate.edge.get_geometry().clone(),
                         );
-                        continue;
-                    }
-                    // 构建当前层的虚拟的node 并添加到图中
-                    // a--->b     a-->cs--->b
-                    // 生成一个虚拟边 a--->cs
-                    use uuid::Uuid;
-                    let edge = Edge::new(
-                        Uuid::new_v4().to_string(),
-                        cur_condidate.edge.get_from_node().clone(),
-                        cur_condidate.dummy_node_id.clone(),
-                        cur_condidate.offset,
-                        0.0,
-                        EdgeType::Dummy,
-                        "dummy_edge".to_string(),
-                        cur_condidate.edge.get_geometry().clone(),
-                    );
-                    self.road_graph.update_graph(edge)?;
-                    // 生成cs---->b 这个虚拟边
-                    let edge = Edge::new(
-                        Uuid::new_v4().to_string(),
-                        cur_condidate.dummy_node_id.clone(),
-                        cur_condidate.edge.get_to_node().clone(),
-                        cur_condidate.edge.get_length() - cur_condidate.offset,
-                        0.0,
-                        EdgeType::Dummy,
-                        "dummy_edge".to_string(),
-                        cur_condidate.edge.get_geometry().clone(),
-                    );
-                    self.road_graph.update_graph(edge)?;
-
-                    // 此时还需要判断前一层的候选者是否和当前候选者在同一个边上 如果在同一个边上 还需要构建两者都联通关系 否着路径查询时 两者关联不上
-                    let prev_candidate_edge_id = if prev_layer.borrow().candidate.is_some() {
-                        prev_layer
-                            .borrow()
-                            .candidate
-                            .as_ref()
-                            .unwrap()
-                            .edge
-                            .get_edge_id()
-                    } else {
-                        "".to_string()
-                    };
-                    if prev_candidate_edge_id == cur_condidate.edge.get_edge_id() {
-                        let mut edge = Edge::new(
+                        self.road_graph.update_graph(edge)?;
+                        // 生成cs---->b 这个虚拟边
+                        let edge = Edge::new(
                             Uuid::new_v4().to_string(),
-                            prev_layer
-                                .borrow()
-                                .candidate
-                                .as_ref()
-                                .unwrap()
-                                .dummy_node_id
-                                .clone(),
                             cur_condidate.dummy_node_id.clone(),
-                            cur_condidate.offset
-                                - prev_layer.borrow().candidate.as_ref().unwrap().offset,
+                            cur_condidate.edge.get_to_node().clone(),
+                            cur_condidate.edge.get_length() - cur_condidate.offset,
                             0.0,
                             EdgeType::Dummy,
                             "dummy_edge".to_string(),
+                            cur_condidate.edge.get_geometry().clone(),
+                        );
+                        self.road_graph.update_graph(edge)?;
+                        if let Some(node) = self
+                            .road_graph
+                            .network
+                            .find_node_by_id(&cur_condidate.dummy_node_id)
+                        {
+                            self.road_graph
+                                .set_node_point(node, cur_condidate.closest_point);
+                        }
+
+                        // 此时还需要判断前一层的候选者是否和当前候选者在同一个边上 如果在同一个边上 还需要构建两者都联通关系 否着路径查询时 两者关联不上
+                        let prev_candidate_edge_id = if prev_layer.borrow().candidate.is_some() {
                             prev_layer
                                 .borrow()
                                 .candidate
                                 .as_ref()
                                 .unwrap()
                                 .edge
-                                .get_geometry()
-                                .clone(),
-                        );
-                        let mut updata_graph_ok = false;
-                        if edge.get_length() > 0.0 {
-                            updata_graph_ok = true;
-                        } else if prev_layer.borrow().candidate.as_ref().unwrap().offset
-                            - cur_condidate.offset
-                            < cfg.reverse_tolerance
-                        // 后一个GPS点在前一个GPS点之前 如果范围可控 认为依旧有效
-                        {
-                            edge.set_length(0.0000000001);
-                            updata_graph_ok = true;
-                        }
-                        if updata_graph_ok {
-                            self.road_graph.update_graph(edge)?;
+                                .get_edge_id()
+                        } else {
+                            "".to_string()
+                        };
+                        if prev_candidate_edge_id == cur_condidate.edge.get_edge_id() {
+                            let mut edge = Edge::new(
+                                Uuid::new_v4().to_string(),
+                                prev_layer
+                                    .borrow()
+                                    .candidate
+                                    .as_ref()
+                                    .unwrap()
+                                    .dummy_node_id
+                                    .clone(),
+                                cur_condidate.dummy_node_id.clone(),
+                                cur_condidate.offset
+                                    - prev_layer.borrow().candidate.as_ref().unwrap().offset,
+                                0.0,
+                                EdgeType::Dummy,
+                                "dummy_edge".to_string(),
+                                prev_layer
+                                    .borrow()
+                                    .candidate
+                                    .as_ref()
+                                    .unwrap()
+                                    .edge
+                                    .get_geometry()
+                                    .clone(),
+                            );
+                            let mut updata_graph_ok = false;
+                            if edge.get_length() > 0.0 {
+                                updata_graph_ok = true;
+                            } else if prev_layer.borrow().candidate.as_ref().unwrap().offset
+                                - cur_condidate.offset
+                                < cfg.reverse_tolerance
+                            // 后一个GPS点在前一个GPS点之前 如果范围可控 认为依旧有效
+                            {
+                                edge.set_length(0.0000000001);
+                                updata_graph_ok = true;
+                            }
+                            if updata_graph_ok {
+                                self.road_graph.update_graph(edge)?;
+                            }
                         }
                     }
 
                     // 计算前一个GPS 点和当前gps点之间的距离
-                    let gps_distance = algorithm::eu_distance(
+                    let gps_distance = cfg.metric.distance(
                         &prev_layer
                             .borrow()
                             .candidate
@@ -392,38 +539,62 @@ impl MMatch {
                         &cur_condidate.ori_traj_point.point,
                     );
                     // 给出两个轨迹点最大距离限制
-                    let max_gps_distance = if trj.time_stamp == 0 || traj[index - 1].time_stamp == 0
-                    {
-                        gps_distance * cfg.factor * 4.0
-                    } else {
-                        let time_diff = trj.time_stamp - traj[index - 1].time_stamp;
-                        cfg.v_max * cfg.factor * time_diff as f64
+                    // 没有时间戳(为0)时按照gps距离的倍数限制 有时间戳时按照最大速度限制
+                    // 旧版本把点的序号当作时间戳 相当于每个点间隔1秒
+                    // 时间戳倒退(没有经过--fix-timestamps排序)时同样当作时间未知
+                    let time_diff = match (trj.time_stamp, traj[index - 1].time_stamp) {
+                        (0, _) | (_, 0) => None,
+                        (cur, prev) => cur.checked_sub(prev),
                     };
-                    // 查询最短路径
+                    let max_gps_distance = match time_diff {
+                        Some(time_diff) => cfg.v_max * cfg.factor * time_diff as f64,
+                        None => gps_distance * cfg.factor * 4.0,
+                    };
+                    // 查询最短路径 超过最大距离的路径不再搜索
                     let prev = prev_layer.borrow();
                     let prev_candidate = prev.candidate.as_ref().unwrap();
-                    // let prev_candidate = prev_layer.borrow().candidate.unwrap();
-                    let prev_node_index = self
-                        .road_graph
-                        .network
-                        .find_node_by_id(&prev_candidate.dummy_node_id);
-                    if prev_node_index.is_none() {
-                        error!("prev_node_index {} is none", &prev_candidate.dummy_node_id);
-                        continue;
-                    }
-                    let cur_node_index = self
-                        .road_graph
-                        .network
-                        .find_node_by_id(&cur_condidate.dummy_node_id);
-                    if cur_node_index.is_none() {
-                        error!("cur_node_index {} is none", &cur_condidate.dummy_node_id);
-                        continue;
-                    }
-
-                    let mut candidate_distance = self
-                        .road_graph
-                        .short_path(prev_node_index.unwrap(), cur_node_index.unwrap())
-                        .unwrap_or(f64::MAX);
+                    let mut candidate_distance = match cfg.routing {
+                        RoutingMode::NodeBased => {
+                            let prev_node_index = self
+                                .road_graph
+                                .network
+                                .find_node_by_id(&prev_candidate.dummy_node_id);
+                            if prev_node_index.is_none() {
+                                error!("prev_node_index {} is none", &prev_candidate.dummy_node_id);
+                                continue;
+                            }
+                            let cur_node_index = self
+                                .road_graph
+                                .network
+                                .find_node_by_id(&cur_condidate.dummy_node_id);
+                            if cur_node_index.is_none() {
+                                error!("cur_node_index {} is none", &cur_condidate.dummy_node_id);
+                                continue;
+                            }
+                            self.road_graph
+                                .short_path_by_within(
+                                    prev_node_index.unwrap(),
+                                    cur_node_index.unwrap(),
+                                    cfg.path_algorithm,
+                                    // 浮点误差
+                                    max_gps_distance * (1.0 + 1e-9) + 1e-9,
+                                )
+                                .map(|path| path.cost)
+                                .unwrap_or(f64::MAX)
+                        }
+                        RoutingMode::EdgeBased => self.edge_based_distance(
+                            prev_candidate,
+                            cur_condidate,
+                            max_gps_distance,
+                            cfg,
+                        ),
+                        RoutingMode::Contracted => self.contracted_distance(
+                            prev_candidate,
+                            cur_condidate,
+                            cfg,
+                            &contracted_table,
+                        ),
+                    };
                     if candidate_distance > max_gps_distance {
                         candidate_distance = f64::MAX;
                     }
@@ -437,6 +608,7 @@ impl MMatch {
                     if cumu_prob > cur_layer_cumu_pro {
                         cur_layer.borrow_mut().cumulative_prob = cumu_prob;
                         cur_layer.borrow_mut().tp = tp;
+                        cur_layer.borrow_mut().distance = candidate_distance;
                         *cur_layer.borrow_mut().prev_layer.borrow_mut() = Some(prev_layer.clone());
                     }
                 }
@@ -447,22 +619,179 @@ impl MMatch {
         }
 
         // 回溯
-        Ok(self.back_tracking(&layer_lists))
+        let (mut result, transitions) = self.back_tracking(&layer_lists);
+        self.complete_path(&mut result, &transitions, cfg);
+        Ok(result)
     }
 
-    // 回溯
-    fn back_tracking(&self, layer_lists: &LayerLists) -> MMResult {
+    // 两个候选点在同一个edge上 并且前后顺序正确时 返回沿edge的距离
+    fn same_edge_distance(prev: &Candidate, cur: &Candidate, cfg: &Config) -> Option<f64> {
+        if prev.edge_index != cur.edge_index {
+            return None;
+        }
+        if cur.offset - prev.offset > 0.0 {
+            return Some(cur.offset - prev.offset);
+        }
+        // 后一个GPS点在前一个GPS点之前 如果范围可控 认为依旧有效
+        if prev.offset - cur.offset < cfg.reverse_tolerance {
+            return Some(0.0000000001);
+        }
+        None
+    }
+
+    // 两个候选点之间代价不超过distance的路径 路径的edges为路网edge索引 首尾为候选点所在的edge
+    fn leg_path(
+        &self,
+        from: &Candidate,
+        to: &Candidate,
+        distance: f64,
+        cfg: &Config,
+    ) -> Option<GraphPath> {
+        if let Some(cost) = MMatch::same_edge_distance(from, to, cfg) {
+            return Some(GraphPath {
+                cost,
+                nodes: vec![],
+                edges: vec![from.edge_index],
+            });
+        }
+        let head = from.edge.get_length() - from.offset;
+        // 浮点误差
+        let max_cost = (distance - head - to.offset) * (1.0 + 1e-9) + 1e-9;
+        let middle = match cfg.routing {
+            RoutingMode::EdgeBased => self
+                .road_graph
+                .short_path_edge_based_within(from.edge_index, to.edge_index, max_cost)
+                .ok()?,
+            RoutingMode::NodeBased | RoutingMode::Contracted => {
+                let (start, end) = (self.candidate_nodes(from)?.1, self.candidate_nodes(to)?.0);
+                match self.road_graph.contraction_hierarchy() {
+                    Some(ch) if cfg.routing == RoutingMode::Contracted => ch.query(start, end)?,
+                    _ => self
+                        .road_graph
+                        .short_path_within(start, end, max_cost)
+                        .ok()?,
+                }
+            }
+        };
+        let mut edges = vec![from.edge_index];
+        edges.extend(middle.edges);
+        edges.push(to.edge_index);
+        Some(GraphPath {
+            cost: distance,
+            nodes: middle.nodes,
+            edges,
+        })
+    }
+
+    // 基于edge查询两个候选点之间不超过max_distance的路网距离 没有路径时返回f64::MAX
+    fn edge_based_distance(
+        &self,
+        prev: &Candidate,
+        cur: &Candidate,
+        max_distance: f64,
+        cfg: &Config,
+    ) -> f64 {
+        if let Some(distance) = MMatch::same_edge_distance(prev, cur, cfg) {
+            return distance;
+        }
+        let head = prev.edge.get_length() - prev.offset;
+        // 浮点误差
+        let max_cost = (max_distance - head - cur.offset) * (1.0 + 1e-9) + 1e-9;
+        if max_cost < 0.0 {
+            return f64::MAX;
+        }
+        match self.road_graph.short_path_edge_based_within(
+            prev.edge_index,
+            cur.edge_index,
+            max_cost,
+        ) {
+            Ok(path) => head + path.cost + cur.offset,
+            Err(_) => f64::MAX,
+        }
+    }
+
+    // 候选点所在edge的起点和终点在图中的索引
+    fn candidate_nodes(&self, candidate: &Candidate) -> Option<(usize, usize)> {
+        let network = &self.road_graph.network;
+        Some((
+            network.find_node_by_id(&candidate.edge.get_from_node())?,
+            network.find_node_by_id(&candidate.edge.get_to_node())?,
+        ))
+    }
+
+    // 使用CH计算前一层候选点所在edge终点到当前层候选点所在edge起点的距离
+    fn contracted_table(
+        &self,
+        prev_layers: &Layers,
+        cur_layers: &Layers,
+    ) -> HashMap<(usize, usize), f64> {
+        let nodes = |layers: &Layers, to: bool| -> Vec<usize> {
+            let mut nodes: Vec<usize> = layers
+                .iter()
+                .filter_map(|layer| {
+                    let layer = layer.borrow();
+                    let (from_node, to_node) = self.candidate_nodes(layer.candidate.as_ref()?)?;
+                    Some(if to { to_node } else { from_node })
+                })
+                .collect();
+            nodes.sort_unstable();
+            nodes.dedup();
+            nodes
+        };
+        let sources = nodes(prev_layers, true);
+        let targets = nodes(cur_layers, false);
+        let ch = self.road_graph.contraction_hierarchy().unwrap();
+        let distances = ch.many_to_many(&sources, &targets);
+        let mut table = HashMap::new();
+        for (i, source) in sources.iter().enumerate() {
+            for (j, target) in targets.iter().enumerate() {
+                table.insert((*source, *target), distances[i][j]);
+            }
+        }
+        table
+    }
+
+    // 基于CH的距离 没有路径时返回f64::MAX
+    fn contracted_distance(
+        &self,
+        prev: &Candidate,
+        cur: &Candidate,
+        cfg: &Config,
+        table: &HashMap<(usize, usize), f64>,
+    ) -> f64 {
+        if let Some(distance) = MMatch::same_edge_distance(prev, cur, cfg) {
+            return distance;
+        }
+        let (prev_nodes, cur_nodes) = match (self.candidate_nodes(prev), self.candidate_nodes(cur))
+        {
+            (Some(prev_nodes), Some(cur_nodes)) => (prev_nodes, cur_nodes),
+            _ => return f64::MAX,
+        };
+        match table.get(&(prev_nodes.1, cur_nodes.0)) {
+            Some(distance) if *distance < f64::MAX => {
+                prev.edge.get_length() - prev.offset + distance + cur.offset
+            }
+            _ => f64::MAX,
+        }
+    }
+
+    // 回溯 同时返回每个点从上一个点转移过来的路网距离 没有转移时为None
+    fn back_tracking(&self, layer_lists: &LayerLists) -> (MMResult, Vec<Option<f64>>) {
         let mut result = MMResult {
             o_path: vec![],
             matched_candidates: vec![],
+            cpath: vec![],
+            legs: vec![],
         };
+        let mut transitions = vec![];
 
-        let mut prev_layer: Option<Rc<RefCell<Layer>>> = None;
+        let mut prev_layer: Option<Rc<RefCell<Layer>>>;
         let last_layers = &layer_lists[layer_lists.len() - 1];
         if last_layers.is_empty() {
             prev_layer = None;
             result.o_path.push("".to_string());
             result.matched_candidates.push(None);
+            transitions.push(None);
         } else {
             let max_layer = last_layers.iter().max_by(|a, b| {
                 a.borrow()
@@ -477,6 +806,7 @@ impl MMatch {
             result
                 .matched_candidates
                 .push(Some(max_layer.candidate.as_ref().unwrap().clone()));
+            transitions.push(Self::transition(&max_layer));
             prev_layer = if max_layer.prev_layer.borrow().is_none() {
                 None
             } else {
@@ -501,6 +831,7 @@ impl MMatch {
                 result
                     .matched_candidates
                     .push(Some(max_layer.candidate.as_ref().unwrap().clone()));
+                transitions.push(Self::transition(&max_layer));
                 prev_layer = if max_layer.prev_layer.borrow().is_none() {
                     None
                 } else {
@@ -514,6 +845,7 @@ impl MMatch {
             let can = cur.as_ref().borrow().candidate.as_ref().unwrap().clone();
             result.o_path.push(edge_id);
             result.matched_candidates.push(Some(can));
+            transitions.push(Self::transition(&cur.borrow()));
             prev_layer = if cur.as_ref().borrow().prev_layer.borrow().is_none() {
                 None
             } else {
@@ -525,6 +857,287 @@ impl MMatch {
 
         result.o_path.reverse();
         result.matched_candidates.reverse();
-        result
+        transitions.reverse();
+        (result, transitions)
+    }
+
+    // 回溯时经过的转移 prev_layer为空时没有转移
+    fn transition(layer: &Layer) -> Option<f64> {
+        if layer.prev_layer.borrow().is_none() || layer.distance == f64::MAX {
+            return None;
+        }
+        Some(layer.distance)
+    }
+
+    // 相邻匹配点之间的路径 组成完整路径
+    // 只计算维特比回溯经过的转移 使用和匹配相同的路径查询方式, 查询的代价不超过转移时的路网距离
+    fn complete_path(&self, result: &mut MMResult, transitions: &[Option<f64>], cfg: &Config) {
+        let matched: Vec<(usize, &Candidate)> = result
+            .matched_candidates
+            .iter()
+            .enumerate()
+            .filter_map(|(i, c)| c.as_ref().map(|c| (i, c)))
+            .collect();
+        for pair in matched.windows(2) {
+            let ((from_index, from), (to_index, to)) = (pair[0], pair[1]);
+            let distance = match transitions.get(to_index) {
+                Some(Some(distance)) if to_index == from_index + 1 => *distance,
+                _ => continue,
+            };
+            let path = match self.leg_path(from, to, distance, cfg) {
+                Some(path) => path,
+                None => {
+                    warn!(
+                        "no path between matched point {} and {}",
+                        from_index, to_index
+                    );
+                    continue;
+                }
+            };
+            let last = path.edges.len() - 1;
+            let spans: Vec<PathSpan> = path
+                .edges
+                .iter()
+                .enumerate()
+                .map(|(i, edge_index)| {
+                    let start = if i == 0 { from.offset } else { 0.0 };
+                    let end = if i == last {
+                        to.offset
+                    } else {
+                        self.road_graph.network.edges[*edge_index].get_length()
+                    };
+                    // 同一条edge上在reverse_tolerance内后退时 视为停在原地
+                    PathSpan {
+                        edge_index: *edge_index,
+                        start,
+                        end: end.max(start),
+                    }
+                })
+                .collect();
+            let length = spans.iter().map(|s| s.end - s.start).sum();
+            result.legs.push(MatchedLeg {
+                from: from_index,
+                to: to_index,
+                spans,
+                length,
+            });
+        }
+        let network = &self.road_graph.network;
+        for leg in result.legs.iter() {
+            for span in leg.spans.iter() {
+                let edge_id = network.edges[span.edge_index].get_edge_id();
+                if result.cpath.last() != Some(&edge_id) {
+                    result.cpath.push(edge_id);
+                }
+            }
+        }
+        // 只有一个匹配点时完整路径为该点所在的edge
+        if result.cpath.is_empty() {
+            if let Some((_, candidate)) = matched.first() {
+                result.cpath.push(candidate.edge.get_edge_id());
+            }
+        }
+    }
+}
+
+// test
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graph::fixture::{self, config};
+    use crate::graph::{Network, TurnCost, TurnRestrictions};
+
+    // 相对原点的米 每10秒一个点
+    fn traj(points: &[(f64, f64)]) -> Trajectory {
+        points
+            .iter()
+            .enumerate()
+            .map(|(i, (x, y))| {
+                let p = fixture::coord(*x, *y);
+                TrajInfo {
+                    point: algorithm::Point(p[0], p[1]),
+                    time_stamp: 1000 + i as u64 * 10,
+                }
+            })
+            .collect()
+    }
+
+    fn o_path(network: Network, points: &[(f64, f64)]) -> Vec<String> {
+        fixture::matcher(network)
+            .unwrap()
+            .match_traj(&traj(points), &config())
+            .unwrap()
+            .o_path
+    }
+
+    #[test]
+    fn test_match_grid() {
+        // 向东再向北 以及反方向 双向道路的两条edge几何相同 需要按照行驶方向区分
+        let points = [
+            (30.0, 5.0),
+            (150.0, -5.0),
+            (205.0, 50.0),
+            (195.0, 150.0),
+            (250.0, 205.0),
+        ];
+        let mut mm = fixture::matcher(fixture::grid(4, 100.0).unwrap()).unwrap();
+        let result = mm.match_traj(&traj(&points), &config()).unwrap();
+        assert_eq!(
+            result.o_path,
+            vec!["0_0>1_0", "1_0>2_0", "2_0>2_1", "2_1>2_2", "2_2>3_2"]
+        );
+        assert_eq!(result.cpath, result.o_path);
+        // 候选点投影到edge上
+        let first = result.matched_candidates[0].as_ref().unwrap();
+        assert!((first.offset - 30.0).abs() < 0.1);
+        assert!((first.distance - 5.0).abs() < 0.1);
+        let expected = fixture::coord(30.0, 0.0);
+        assert!((first.closest_point.0 - expected[0]).abs() < 1e-6);
+        assert!((first.closest_point.1 - expected[1]).abs() < 1e-6);
+
+        let reversed: Vec<(f64, f64)> = points.iter().rev().copied().collect();
+        let result = mm.match_traj(&traj(&reversed), &config()).unwrap();
+        assert_eq!(
+            result.o_path,
+            vec!["3_2>2_2", "2_2>2_1", "2_1>2_0", "2_0>1_0", "1_0>0_0"]
+        );
+        let last = result.matched_candidates[4].as_ref().unwrap();
+        assert!((last.offset - 70.0).abs() < 0.1);
+
+        // 时间戳倒退时按照时间未知处理
+        let mut unordered = traj(&points);
+        unordered[3].time_stamp = 900;
+        let result = mm.match_traj(&unordered, &config()).unwrap();
+        assert_eq!(result.o_path[3], "2_1>2_2");
+    }
+
+    #[test]
+    fn test_dummy_node_per_edge() {
+        // 双向道路的两条edge投影点相同 虚拟节点按照edge区分
+        let mm = fixture::matcher(fixture::grid(4, 100.0).unwrap()).unwrap();
+        let point = &traj(&[(50.0, -5.0)])[0];
+        let candidates = mm.query_candidate(point, 50.0, 4, 10.0, None);
+        let find = |id: &str| {
+            candidates
+                .iter()
+                .find(|c| c.edge.get_edge_id() == id)
+                .unwrap()
+        };
+        let (forward, backward) = (find("0_0>1_0"), find("1_0>0_0"));
+        assert_eq!(
+            (forward.closest_point.0, forward.closest_point.1),
+            (backward.closest_point.0, backward.closest_point.1)
+        );
+        assert_ne!(forward.dummy_node_id, backward.dummy_node_id);
+    }
+
+    #[test]
+    fn test_complete_path_follows_routing() {
+        // 1_0>2_0 禁止左转进入2_0>2_1 基于edge匹配时完整路径需要绕行
+        let points = [(50.0, -5.0), (150.0, -5.0), (195.0, 150.0)];
+        let mut mm = fixture::matcher(fixture::grid(4, 100.0).unwrap()).unwrap();
+        let result = mm.match_traj(&traj(&points), &config()).unwrap();
+        assert_eq!(
+            result.cpath,
+            vec!["0_0>1_0", "1_0>2_0", "2_0>2_1", "2_1>2_2"]
+        );
+
+        let network = &mm.road_graph().network;
+        let index = |id: &str| network.find_edge_index(id).unwrap();
+        let mut restrictions = TurnRestrictions::default();
+        restrictions
+            .add(index("1_0>2_0"), index("2_0>2_1"), "no_left_turn")
+            .unwrap();
+        mm.set_turn_model(TurnModel::new(restrictions, TurnCost::default()));
+        let cfg = Config {
+            routing: RoutingMode::EdgeBased,
+            ..config()
+        };
+        let result = mm.match_traj(&traj(&points), &cfg).unwrap();
+        assert_eq!(result.o_path, vec!["0_0>1_0", "1_0>2_0", "2_1>2_2"]);
+        assert_eq!(
+            result.cpath,
+            vec!["0_0>1_0", "1_0>2_0", "2_0>3_0", "3_0>3_1", "3_1>2_1", "2_1>2_2"]
+        );
+        assert!((result.legs[1].length - 400.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_match_parallel_roads() {
+        let network = fixture::parallel_roads;
+        assert_eq!(
+            o_path(
+                network().unwrap(),
+                &[
+                    (-100.0, 3.0),
+                    (150.0, 27.0),
+                    (300.0, 32.0),
+                    (450.0, 28.0),
+                    (700.0, 2.0)
+                ]
+            ),
+            vec!["w>a", "c>d", "c>d", "c>d", "b>e"]
+        );
+        assert_eq!(
+            o_path(
+                network().unwrap(),
+                &[
+                    (-100.0, 3.0),
+                    (150.0, 3.0),
+                    (300.0, -2.0),
+                    (450.0, 2.0),
+                    (700.0, 2.0)
+                ]
+            ),
+            vec!["w>a", "a>b", "a>b", "a>b", "b>e"]
+        );
+    }
+
+    #[test]
+    fn test_match_roundabout() {
+        // 从西边进入 逆时针绕行3/4圈 从北边离开
+        let d = 40.0 * std::f64::consts::FRAC_1_SQRT_2;
+        assert_eq!(
+            o_path(
+                fixture::roundabout().unwrap(),
+                &[(-150.0, 3.0), (-d, -d), (d, -d), (d, d), (3.0, 150.0)]
+            ),
+            vec!["W>w", "w>s", "s>e", "e>n", "n>N"]
+        );
+    }
+
+    #[test]
+    fn test_match_one_way_loop() {
+        // 顺时针绕行一圈多 第二次经过a>b
+        let mut mm = fixture::matcher(fixture::one_way_loop().unwrap()).unwrap();
+        let points = [
+            (-100.0, 3.0),
+            (-3.0, 150.0),
+            (200.0, 303.0),
+            (397.0, 150.0),
+            (200.0, -3.0),
+            (-3.0, 100.0),
+        ];
+        let result = mm.match_traj(&traj(&points), &config()).unwrap();
+        assert_eq!(
+            result.o_path,
+            vec!["o>a", "a>b", "b>c", "c>d", "d>a", "a>b"]
+        );
+        assert_eq!(result.cpath, result.o_path);
+    }
+
+    #[test]
+    fn test_match_dual_carriageway() {
+        // 点更靠近另一侧的车道 但只有按照行驶方向的车道可以连通
+        let westbound: Vec<(f64, f64)> = (0..6).map(|i| (550.0 - i as f64 * 100.0, 8.0)).collect();
+        assert_eq!(
+            o_path(fixture::dual_carriageway().unwrap(), &westbound),
+            vec!["e2>m2", "e2>m2", "e2>m2", "m2>w2", "m2>w2", "m2>w2"]
+        );
+        let eastbound: Vec<(f64, f64)> = (0..6).map(|i| (50.0 + i as f64 * 100.0, 12.0)).collect();
+        assert_eq!(
+            o_path(fixture::dual_carriageway().unwrap(), &eastbound),
+            vec!["w1>m1", "w1>m1", "w1>m1", "m1>e1", "m1>e1", "m1>e1"]
+        );
     }
 }
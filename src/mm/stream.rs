@@ -0,0 +1,167 @@
+use super::traj::{MutileTrajectory, TrajInfo, Trajectory};
+use crate::algorithm;
+use anyhow::Result;
+use serde_json::Value;
+
+/*
+ * @file stream.rs
+ * 逐行读取的轨迹记录
+ * 每行一条轨迹, 可以是geojson的Feature或Geometry, 也可以是紧凑的JSON:
+ * {"id": "a", "coords": [[x, y], ...], "times": [t, ...]}, times为unix秒, 可以省略。
+ * Feature的id取自feature id或者properties中的id, timestamps和文件输入相同。
+ * 时间戳需要单调不减, 需要由预处理排序的记录使用parse_record_unordered。
+ */
+
+/// 一行中的一条轨迹
+#[derive(Debug)]
+pub struct TrajRecord {
+    pub id: Option<Value>, // 原样输出 方便和输入对应
+    pub traj: Trajectory,
+}
+
+/// 解析一行记录 时间戳倒退时返回错误
+pub fn parse_record(line: &str) -> Result<TrajRecord> {
+    let record = parse_record_unordered(line)?;
+    let times = &record.traj;
+    if let Some(i) = (1..times.len()).find(|&i| times[i].time_stamp < times[i - 1].time_stamp) {
+        return Err(anyhow::anyhow!(
+            "times go backwards at point {}: {} after {}",
+            i,
+            times[i].time_stamp,
+            times[i - 1].time_stamp
+        ));
+    }
+    Ok(record)
+}
+
+/// 解析一行记录 不检查时间戳的顺序
+pub fn parse_record_unordered(line: &str) -> Result<TrajRecord> {
+    let value: Value = serde_json::from_str(line)?;
+    if value.get("type").is_some() {
+        parse_geojson(value)
+    } else {
+        parse_compact(value)
+    }
+}
+
+fn parse_geojson(value: Value) -> Result<TrajRecord> {
+    let geojson = geojson::GeoJson::from_json_value(value)?;
+    let id = match &geojson {
+        geojson::GeoJson::Feature(feature) => match &feature.id {
+            Some(geojson::feature::Id::String(id)) => Some(Value::from(id.clone())),
+            Some(geojson::feature::Id::Number(id)) => Some(Value::from(id.clone())),
+            None => feature.property("id").cloned(),
+        },
+        geojson::GeoJson::Geometry(_) => None,
+        geojson::GeoJson::FeatureCollection(_) => {
+            return Err(anyhow::anyhow!(
+                "one trajectory per line, got a feature collection"
+            ))
+        }
+    };
+    let traj = MutileTrajectory::try_from(geojson)?
+        .trajs
+        .into_iter()
+        .next()
+        .unwrap_or_default();
+    Ok(TrajRecord { id, traj })
+}
+
+fn parse_compact(value: Value) -> Result<TrajRecord> {
+    let coords = value["coords"]
+        .as_array()
+        .ok_or_else(|| anyhow::anyhow!("coords should be an array of [x, y]"))?;
+    let times: Option<Vec<u64>> = match value.get("times") {
+        Some(times) => Some(
+            times
+                .as_array()
+                .and_then(|times| times.iter().map(|t| t.as_u64()).collect())
+                .ok_or_else(|| anyhow::anyhow!("times should be an array of unix seconds"))?,
+        ),
+        None => None,
+    };
+    if let Some(times) = &times {
+        if times.len() != coords.len() {
+            return Err(anyhow::anyhow!(
+                "times has {} items but coords has {} points",
+                times.len(),
+                coords.len()
+            ));
+        }
+    }
+    let traj = coords
+        .iter()
+        .enumerate()
+        .map(|(i, p)| {
+            let point = match p.as_array().map(|p| p.as_slice()) {
+                Some([x, y, ..]) => x.as_f64().zip(y.as_f64()),
+                _ => None,
+            };
+            let (x, y) = point.ok_or_else(|| anyhow::anyhow!("invalid point {}", p))?;
+            Ok(TrajInfo {
+                point: algorithm::Point(x, y),
+                // 没有时间信息 0表示未知
+                time_stamp: times.as_ref().map_or(0, |t| t[i]),
+            })
+        })
+        .collect::<Result<Trajectory>>()?;
+    Ok(TrajRecord {
+        id: value.get("id").cloned(),
+        traj,
+    })
+}
+
+// test
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_record() {
+        let record =
+            parse_record(r#"{"id": 7, "coords": [[1.0, 2.0], [3.0, 4.0]], "times": [10, 20]}"#)
+                .unwrap();
+        assert_eq!(record.id, Some(Value::from(7)));
+        assert_eq!(record.traj.len(), 2);
+        assert_eq!(
+            (record.traj[1].point.0, record.traj[1].time_stamp),
+            (3.0, 20)
+        );
+
+        let record = parse_record(
+            r#"{"type": "Feature", "id": "a", "properties": {"timestamps": [1, 2]},
+                "geometry": {"type": "LineString", "coordinates": [[1, 2], [3, 4]]}}"#,
+        )
+        .unwrap();
+        assert_eq!(record.id, Some(Value::from("a")));
+        assert_eq!(record.traj[0].time_stamp, 1);
+
+        let record = parse_record(
+            r#"{"type": "Feature", "properties": {"id": "b"},
+                "geometry": {"type": "MultiPoint", "coordinates": [[1, 2]]}}"#,
+        )
+        .unwrap();
+        assert_eq!(record.id, Some(Value::from("b")));
+        let record =
+            parse_record(r#"{"type": "LineString", "coordinates": [[1, 2], [3, 4]]}"#).unwrap();
+        assert_eq!((record.id, record.traj.len()), (None, 2));
+
+        assert!(parse_record("{").is_err());
+        assert!(parse_record(r#"{"coords": [[1.0]]}"#).is_err());
+        assert!(parse_record(r#"{"coords": [[1, 2]], "times": [1, 2]}"#).is_err());
+        assert!(
+            parse_record(r#"{"type": "Feature", "geometry": null, "properties": {}}"#).is_err()
+        );
+        assert!(parse_record(r#"{"type": "FeatureCollection", "features": []}"#).is_err());
+
+        // 时间戳倒退 相同的时间戳可以接受
+        let line = r#"{"coords": [[1, 2], [3, 4], [5, 6]], "times": [10, 10, 5]}"#;
+        assert!(parse_record(line).is_err());
+        assert_eq!(parse_record_unordered(line).unwrap().traj[2].time_stamp, 5);
+        assert!(parse_record(
+            r#"{"type": "Feature", "properties": {"timestamps": [2, 1]},
+                "geometry": {"type": "LineString", "coordinates": [[1, 2], [3, 4]]}}"#
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,364 @@
+use super::evaluate::GroundTruth;
+use super::stmatch::MMatch;
+use super::traj::{TrajInfo, Trajectory};
+use crate::algorithm;
+use anyhow::Result;
+
+/*
+ * @file synth.rs
+ * 合成轨迹
+ * 在路网上随机选取起终点得到最短路径, 沿着路径按照固定的速度和采样间隔生成轨迹点,
+ * 再加上高斯噪声和离群点, 同时给出真实路径。使用固定种子的随机数, 相同的配置生成相同的轨迹,
+ * 用于匹配的测试和基准。
+ */
+
+// 每条轨迹最多尝试的起终点数量
+const MAX_ATTEMPTS: usize = 100;
+
+/// 合成配置 长度、速度、噪声和离群距离的单位都和路网长度一致 haversine为米
+#[derive(Clone, Debug)]
+pub struct SynthConfig {
+    pub seed: u64,             // 随机数种子
+    pub interval: u64,         // 采样间隔 单位秒
+    pub speed: f64,            // 行驶速度 单位/秒
+    pub noise: f64,            // 高斯噪声的标准差
+    pub outlier_rate: f64,     // 每个点成为离群点的概率
+    pub outlier_distance: f64, // 离群点偏离真实位置的距离
+    pub min_length: f64,       // 路径长度的范围
+    pub max_length: f64,
+    pub start_time: u64, // 第一个点的时间戳 unix秒
+}
+
+impl Default for SynthConfig {
+    fn default() -> Self {
+        SynthConfig {
+            seed: 0,
+            interval: 10,
+            speed: 10.0,
+            noise: 10.0,
+            outlier_rate: 0.0,
+            outlier_distance: 200.0,
+            min_length: 1000.0,
+            max_length: 5000.0,
+            start_time: 1672531200,
+        }
+    }
+}
+
+impl SynthConfig {
+    fn check(&self) -> Result<()> {
+        if self.interval == 0 || self.speed <= 0.0 {
+            return Err(anyhow::anyhow!("interval and speed should be positive"));
+        }
+        if self.noise < 0.0 || self.outlier_distance < 0.0 {
+            return Err(anyhow::anyhow!(
+                "noise and outlier distance should not be negative"
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.outlier_rate) {
+            return Err(anyhow::anyhow!("outlier rate should be in 0..=1"));
+        }
+        if self.min_length > self.max_length {
+            return Err(anyhow::anyhow!("min length is greater than max length"));
+        }
+        if self.start_time == 0 {
+            // 时间戳0表示未知
+            return Err(anyhow::anyhow!("start time should be positive"));
+        }
+        Ok(())
+    }
+}
+
+/// 一条合成轨迹
+#[derive(Clone, Debug)]
+pub struct SyntheticTrace {
+    pub traj: Trajectory,
+    pub truth: GroundTruth,   // 真实路径和每个点所在的edge
+    pub outliers: Vec<usize>, // 离群点的序号
+    pub length: f64,          // 实际行驶的长度
+}
+
+// splitmix64 不依赖外部库 结果只由种子决定
+struct Random(u64);
+
+impl Random {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    // [0, 1)
+    fn uniform(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1_u64 << 53) as f64
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        ((self.uniform() * n as f64) as usize).min(n - 1)
+    }
+
+    // Box-Muller 标准正态分布
+    fn gaussian(&mut self) -> f64 {
+        let u = 1.0 - self.uniform();
+        let v = self.uniform();
+        (-2.0 * u.ln()).sqrt() * (2.0 * std::f64::consts::PI * v).cos()
+    }
+}
+
+impl MMatch {
+    /// 生成count条合成轨迹 路网中找不到长度合适的路径时返回错误
+    pub fn synthesize(&self, cfg: &SynthConfig, count: usize) -> Result<Vec<SyntheticTrace>> {
+        cfg.check()?;
+        let network = &self.road_graph().network;
+        if network.nodes.is_empty() {
+            return Err(anyhow::anyhow!("road network is empty"));
+        }
+        let mut random = Random(cfg.seed);
+        let mut traces = Vec::with_capacity(count);
+        for i in 0..count {
+            let trace = (0..MAX_ATTEMPTS)
+                .find_map(|_| self.synthesize_one(cfg, &mut random).transpose())
+                .transpose()?
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "trace {}: no route with length in {}..={} after {} attempts",
+                        i,
+                        cfg.min_length,
+                        cfg.max_length,
+                        MAX_ATTEMPTS
+                    )
+                })?;
+            traces.push(trace);
+        }
+        Ok(traces)
+    }
+
+    // 随机的起终点不满足条件时返回None
+    fn synthesize_one(
+        &self,
+        cfg: &SynthConfig,
+        random: &mut Random,
+    ) -> Result<Option<SyntheticTrace>> {
+        let network = &self.road_graph().network;
+        let from = random.below(network.nodes.len());
+        let to = random.below(network.nodes.len());
+        let path = match self.road_graph().short_path(from, to) {
+            Ok(path) => path,
+            Err(_) => return Ok(None),
+        };
+        if path.edges.is_empty()
+            || path.edges.iter().any(|e| *e >= network.edges.len())
+            || path.cost < cfg.min_length
+            || path.cost > cfg.max_length
+        {
+            return Ok(None);
+        }
+        // 从第一条edge的前半段出发 到最后一条edge的后半段结束
+        let edges: Vec<&crate::graph::Edge> =
+            path.edges.iter().map(|e| &network.edges[*e]).collect();
+        let start = random.uniform() * edges[0].get_length() * 0.5;
+        let last = edges[edges.len() - 1].get_length();
+        let end = last * (0.5 + random.uniform() * 0.5);
+        let length = path.cost - start - (last - end);
+        let samples = (length / (cfg.speed * cfg.interval as f64)).floor() as usize + 1;
+        if samples < 2 {
+            return Ok(None);
+        }
+
+        let lines = edges
+            .iter()
+            .map(|e| e.get_geometry().clone().try_into())
+            .collect::<Result<Vec<algorithm::Line>>>()?;
+        let metric = self.metric();
+        let mut trace = SyntheticTrace {
+            traj: Trajectory::new(),
+            truth: GroundTruth {
+                path: edges.iter().map(|e| e.get_edge_id()).collect(),
+                points: vec![],
+            },
+            outliers: vec![],
+            length,
+        };
+        let mut edge = 0;
+        let mut passed = 0.0; // 之前edge的长度和
+        for i in 0..samples {
+            let time = i as u64 * cfg.interval;
+            let distance = start + cfg.speed * time as f64;
+            while edge + 1 < edges.len() && passed + edges[edge].get_length() < distance {
+                passed += edges[edge].get_length();
+                edge += 1;
+            }
+            let offset = (distance - passed).clamp(0.0, edges[edge].get_length());
+            let point = match metric.substring(&lines[edge].0, offset, offset).first() {
+                Some(p) => algorithm::Point(p[0], p[1]),
+                None => return Ok(None),
+            };
+            // 噪声按照路网长度的单位换算成坐标 haversine时为米
+            let (kx, ky) = metric.coord_span(1.0, &point);
+            let (mut dx, mut dy) = (random.gaussian() * cfg.noise, random.gaussian() * cfg.noise);
+            if random.uniform() < cfg.outlier_rate {
+                let angle = random.uniform() * 2.0 * std::f64::consts::PI;
+                dx += angle.cos() * cfg.outlier_distance;
+                dy += angle.sin() * cfg.outlier_distance;
+                trace.outliers.push(i);
+            }
+            trace.traj.push(TrajInfo {
+                point: algorithm::Point(point.0 + dx * kx, point.1 + dy * ky),
+                time_stamp: cfg.start_time + time,
+            });
+            trace.truth.points.push(Some(edges[edge].get_edge_id()));
+        }
+        Ok(Some(trace))
+    }
+}
+
+// test
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graph::fixture;
+    use crate::mm::Evaluation;
+
+    #[test]
+    fn test_synthesize() {
+        // 节点间隔不是每次采样行驶距离的整数倍 采样点不会都落在路口上
+        let mut mm = fixture::matcher(fixture::grid(5, 130.0).unwrap()).unwrap();
+        let cfg = SynthConfig {
+            seed: 7,
+            noise: 3.0,
+            min_length: 300.0,
+            max_length: 800.0,
+            ..Default::default()
+        };
+        let traces = mm.synthesize(&cfg, 5).unwrap();
+        assert_eq!(traces.len(), 5);
+        // 相同的种子生成相同的轨迹
+        let again = mm.synthesize(&cfg, 5).unwrap();
+        for (a, b) in traces.iter().zip(again.iter()) {
+            assert_eq!(a.truth.path, b.truth.path);
+            let (p, q) = (a.traj[0].point, b.traj[0].point);
+            assert_eq!((p.0, p.1), (q.0, q.1));
+        }
+        let other = mm
+            .synthesize(
+                &SynthConfig {
+                    seed: 8,
+                    ..cfg.clone()
+                },
+                1,
+            )
+            .unwrap();
+        let (p, q) = (other[0].traj[0].point, traces[0].traj[0].point);
+        assert_ne!((p.0, p.1), (q.0, q.1));
+
+        let network = &mm.road_graph().network;
+        for trace in traces.iter() {
+            // 真实路径首尾相接 每个点的edge都在路径上 时间间隔固定
+            for pair in trace.truth.path.windows(2) {
+                let a = network.find_edge_by_id(&pair[0]).unwrap();
+                let b = network.find_edge_by_id(&pair[1]).unwrap();
+                assert_eq!(a.get_to_node(), b.get_from_node());
+            }
+            assert_eq!(trace.truth.points.len(), trace.traj.len());
+            for id in trace.truth.points.iter().flatten() {
+                assert!(trace.truth.path.contains(id));
+            }
+            assert!(trace
+                .traj
+                .windows(2)
+                .all(|p| p[1].time_stamp - p[0].time_stamp == 10));
+            assert!(trace.outliers.is_empty());
+        }
+
+        // 小噪声的轨迹匹配结果接近真实路径
+        let config = fixture::config();
+        let mut total = Evaluation::default();
+        for trace in traces.iter() {
+            let result = mm.match_traj(&trace.traj, &config).unwrap();
+            let mut evaluation = Evaluation::default();
+            evaluation.add_points(&result, |i| trace.truth.points[i].clone());
+            evaluation
+                .add_route(&mm.road_graph().network, &result.cpath, &trace.truth.path)
+                .unwrap();
+            total.merge(&evaluation);
+        }
+        assert!(total.point_accuracy().unwrap() > 0.9);
+        assert!(total.recall().unwrap() > 0.9);
+
+        let outliers = mm
+            .synthesize(
+                &SynthConfig {
+                    outlier_rate: 1.0,
+                    ..cfg.clone()
+                },
+                1,
+            )
+            .unwrap();
+        assert_eq!(outliers[0].outliers.len(), outliers[0].traj.len());
+        assert!(mm
+            .synthesize(
+                &SynthConfig {
+                    interval: 0,
+                    ..cfg.clone()
+                },
+                1
+            )
+            .is_err());
+        assert!(mm
+            .synthesize(
+                &SynthConfig {
+                    min_length: 1e6,
+                    max_length: 1e7,
+                    ..cfg
+                },
+                1
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_synthesize_units() {
+        // 离群点偏离的距离和路网长度的单位一致 haversine为米 投影坐标系为坐标的单位
+        let utm = algorithm::Crs::Utm {
+            zone: 51,
+            north: true,
+        };
+        let mut projected = fixture::grid(5, 130.0).unwrap();
+        projected.set_metric(algorithm::Metric::Euclidean).unwrap();
+        projected.reproject(&algorithm::Crs::Wgs84, &utm).unwrap();
+        for mm in [
+            fixture::matcher(fixture::grid(5, 130.0).unwrap()).unwrap(),
+            MMatch::from_snapshot(
+                crate::graph::snapshot::Snapshot::new(projected).unwrap(),
+                algorithm::Metric::Euclidean,
+            )
+            .unwrap(),
+        ] {
+            let cfg = SynthConfig {
+                seed: 3,
+                noise: 0.0,
+                outlier_distance: 100.0,
+                min_length: 300.0,
+                max_length: 800.0,
+                ..Default::default()
+            };
+            // 第一个点之前的随机数相同 只差离群点的偏移
+            let clean = mm.synthesize(&cfg, 1).unwrap();
+            let outlier = mm
+                .synthesize(
+                    &SynthConfig {
+                        outlier_rate: 1.0,
+                        ..cfg
+                    },
+                    1,
+                )
+                .unwrap();
+            let distance = mm
+                .metric()
+                .distance(&clean[0].traj[0].point, &outlier[0].traj[0].point);
+            assert!((distance - 100.0).abs() < 1.0, "{}", distance);
+        }
+    }
+}
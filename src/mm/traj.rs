@@ -15,6 +15,48 @@ pub struct MutileTrajectory {
     pub trajs: Vec<Trajectory>,
 }
 
+impl MutileTrajectory {
+    /// 把所有轨迹点从from坐标系转换到to坐标系
+    pub fn reproject(&mut self, from: &algorithm::Crs, to: &algorithm::Crs) {
+        if from == to {
+            return;
+        }
+        for traj in self.trajs.iter_mut() {
+            for info in traj.iter_mut() {
+                info.point = from.transform(&info.point, to);
+            }
+        }
+    }
+}
+
+// feature属性中的timestamps(unix秒)作为轨迹点的时间戳 数量需要和轨迹点一致
+fn set_timestamps(
+    trajs: &mut [Trajectory],
+    properties: &Option<geojson::JsonObject>,
+) -> anyhow::Result<()> {
+    let times = match properties.as_ref().and_then(|p| p.get("timestamps")) {
+        Some(times) => times,
+        None => return Ok(()),
+    };
+    let times: Vec<u64> = times
+        .as_array()
+        .and_then(|times| times.iter().map(|t| t.as_u64()).collect())
+        .ok_or_else(|| anyhow::anyhow!("timestamps should be an array of unix seconds"))?;
+    for traj in trajs.iter_mut() {
+        if traj.len() != times.len() {
+            return Err(anyhow::anyhow!(
+                "timestamps has {} items but trajectory has {} points",
+                times.len(),
+                traj.len()
+            ));
+        }
+        for (info, time) in traj.iter_mut().zip(times.iter()) {
+            info.time_stamp = *time;
+        }
+    }
+    Ok(())
+}
+
 // 实现轨迹转换trait
 impl TryFrom<geojson::GeoJson> for MutileTrajectory {
     type Error = anyhow::Error;
@@ -23,11 +65,17 @@ impl TryFrom<geojson::GeoJson> for MutileTrajectory {
         match value {
             geojson::GeoJson::FeatureCollection(fc) => {
                 for feature in fc.features {
-                    match feature.geometry.unwrap().value {
+                    let count = trajs.trajs.len();
+                    match feature
+                        .geometry
+                        .ok_or_else(|| anyhow::anyhow!("feature has no geometry"))?
+                        .value
+                    {
                         geojson::Value::LineString(line) => {
                             let mut traj = Trajectory::new();
-                            for (i, point) in line.into_iter().enumerate() {
-                                let time_stamp = i as u64;
+                            for point in line.into_iter() {
+                                // 没有时间信息 0表示未知
+                                let time_stamp = 0;
                                 traj.push(TrajInfo {
                                     point: algorithm::Point(point[0], point[1]),
                                     time_stamp,
@@ -37,8 +85,9 @@ impl TryFrom<geojson::GeoJson> for MutileTrajectory {
                         }
                         geojson::Value::MultiPoint(multi_points) => {
                             let mut traj = Trajectory::new();
-                            for (i, point) in multi_points.into_iter().enumerate() {
-                                let time_stamp = i as u64;
+                            for point in multi_points.into_iter() {
+                                // 没有时间信息 0表示未知
+                                let time_stamp = 0;
                                 traj.push(TrajInfo {
                                     point: algorithm::Point(point[0], point[1]),
                                     time_stamp,
@@ -52,42 +101,53 @@ impl TryFrom<geojson::GeoJson> for MutileTrajectory {
                             ));
                         }
                     }
+                    set_timestamps(&mut trajs.trajs[count..], &feature.properties)?;
                 }
             }
-            geojson::GeoJson::Feature(feature) => match feature.geometry.unwrap().value {
-                geojson::Value::LineString(line) => {
-                    let mut traj = Trajectory::new();
-                    for (i, point) in line.into_iter().enumerate() {
-                        let time_stamp = i as u64;
-                        traj.push(TrajInfo {
-                            point: algorithm::Point(point[0], point[1]),
-                            time_stamp,
-                        });
+            geojson::GeoJson::Feature(feature) => {
+                match feature
+                    .geometry
+                    .ok_or_else(|| anyhow::anyhow!("feature has no geometry"))?
+                    .value
+                {
+                    geojson::Value::LineString(line) => {
+                        let mut traj = Trajectory::new();
+                        for point in line.into_iter() {
+                            // 没有时间信息 0表示未知
+                            let time_stamp = 0;
+                            traj.push(TrajInfo {
+                                point: algorithm::Point(point[0], point[1]),
+                                time_stamp,
+                            });
+                        }
+                        trajs.trajs.push(traj);
                     }
-                    trajs.trajs.push(traj);
-                }
-                geojson::Value::MultiPoint(multi_points) => {
-                    let mut traj = Trajectory::new();
-                    for (i, point) in multi_points.into_iter().enumerate() {
-                        let time_stamp = i as u64;
-                        traj.push(TrajInfo {
-                            point: algorithm::Point(point[0], point[1]),
-                            time_stamp,
-                        });
+                    geojson::Value::MultiPoint(multi_points) => {
+                        let mut traj = Trajectory::new();
+                        for point in multi_points.into_iter() {
+                            // 没有时间信息 0表示未知
+                            let time_stamp = 0;
+                            traj.push(TrajInfo {
+                                point: algorithm::Point(point[0], point[1]),
+                                time_stamp,
+                            });
+                        }
+                        trajs.trajs.push(traj);
+                    }
+                    _ => {
+                        return Err(anyhow::anyhow!(
+                            "geometry only support Point, LineString, MultiPoint"
+                        ));
                     }
-                    trajs.trajs.push(traj);
-                }
-                _ => {
-                    return Err(anyhow::anyhow!(
-                        "geometry only support Point, LineString, MultiPoint"
-                    ));
                 }
-            },
+                set_timestamps(&mut trajs.trajs, &feature.properties)?;
+            }
             geojson::GeoJson::Geometry(geom) => match geom.value {
                 geojson::Value::LineString(line) => {
                     let mut traj = Trajectory::new();
-                    for (i, point) in line.into_iter().enumerate() {
-                        let time_stamp = i as u64;
+                    for point in line.into_iter() {
+                        // 没有时间信息 0表示未知
+                        let time_stamp = 0;
                         traj.push(TrajInfo {
                             point: algorithm::Point(point[0], point[1]),
                             time_stamp,
@@ -97,8 +157,9 @@ impl TryFrom<geojson::GeoJson> for MutileTrajectory {
                 }
                 geojson::Value::MultiPoint(multi_points) => {
                     let mut traj = Trajectory::new();
-                    for (i, point) in multi_points.into_iter().enumerate() {
-                        let time_stamp = i as u64;
+                    for point in multi_points.into_iter() {
+                        // 没有时间信息 0表示未知
+                        let time_stamp = 0;
                         traj.push(TrajInfo {
                             point: algorithm::Point(point[0], point[1]),
                             time_stamp,
@@ -124,8 +185,9 @@ impl TryFrom<wkt::Wkt<f64>> for MutileTrajectory {
         match value.item {
             wkt::Geometry::LineString(line) => {
                 let mut traj = Trajectory::new();
-                for (i, point) in line.0.into_iter().enumerate() {
-                    let time_stamp = i as u64;
+                for point in line.0.into_iter() {
+                    // 没有时间信息 0表示未知
+                    let time_stamp = 0;
                     traj.push(TrajInfo {
                         point: algorithm::Point(point.x, point.y),
                         time_stamp,
@@ -135,8 +197,9 @@ impl TryFrom<wkt::Wkt<f64>> for MutileTrajectory {
             }
             wkt::Geometry::MultiPoint(multi_points) => {
                 let mut traj = Trajectory::new();
-                for (i, point) in multi_points.0.into_iter().enumerate() {
-                    let time_stamp = i as u64;
+                for point in multi_points.0.into_iter() {
+                    // 没有时间信息 0表示未知
+                    let time_stamp = 0;
                     traj.push(TrajInfo {
                         point: algorithm::Point(
                             point.0.as_ref().unwrap().x,
@@ -154,3 +217,27 @@ impl TryFrom<wkt::Wkt<f64>> for MutileTrajectory {
         Ok(trajs)
     }
 }
+
+// test
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_geojson_timestamps() {
+        let line = r#"{"type": "LineString", "coordinates": [[1, 2], [3, 4]]}"#;
+        let trajs = MutileTrajectory::try_from(line.parse::<geojson::GeoJson>().unwrap()).unwrap();
+        // 没有时间信息时为0 不再使用点的序号
+        let times: Vec<u64> = trajs.trajs[0].iter().map(|p| p.time_stamp).collect();
+        assert_eq!(times, vec![0, 0]);
+
+        let feature = format!(
+            r#"{{"type": "Feature", "geometry": {}, "properties": {{"timestamps": [10, 20]}}}}"#,
+            line
+        );
+        let trajs =
+            MutileTrajectory::try_from(feature.parse::<geojson::GeoJson>().unwrap()).unwrap();
+        let times: Vec<u64> = trajs.trajs[0].iter().map(|p| p.time_stamp).collect();
+        assert_eq!(times, vec![10, 20]);
+    }
+}
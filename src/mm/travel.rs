@@ -0,0 +1,166 @@
+use super::model::MMResult;
+use super::stmatch::MMatch;
+
+/*
+ * @file travel.rs
+ * 路段行程时间
+ * 根据匹配点的偏移量和时间戳, 沿着完整路径按照匀速插值得到进入和离开每条edge的时间,
+ * 进而得到每条edge上的行驶速度。相邻的路径在同一条edge上连续时合并成一条记录。
+ */
+
+/// 一次经过edge的记录
+#[derive(Clone, Debug)]
+pub struct EdgeTravel {
+    pub edge_index: usize, // 路网edge索引
+    pub start: f64,        // 进入时在edge上的偏移量
+    pub end: f64,          // 离开时在edge上的偏移量
+    pub entry_time: f64,   // 进入时间 unix秒
+    pub exit_time: f64,    // 离开时间 unix秒
+    pub complete: bool,    // 是否完整经过整条edge 首尾edge的时间只覆盖匹配点之间的部分
+}
+
+impl EdgeTravel {
+    /// 经过的长度 单位和路网长度一致
+    pub fn length(&self) -> f64 {
+        self.end - self.start
+    }
+
+    pub fn duration(&self) -> f64 {
+        self.exit_time - self.entry_time
+    }
+
+    /// 平均速度 单位为路网长度单位/秒 用时为0时返回None
+    pub fn speed(&self) -> Option<f64> {
+        let duration = self.duration();
+        if duration <= 0.0 {
+            return None;
+        }
+        Some(self.length() / duration)
+    }
+}
+
+impl MMatch {
+    /// 匹配结果经过的每条edge的进入/离开时间 按照经过的顺序排列
+    /// 时间戳未知或者不递增的相邻匹配点之间的路径不计算
+    pub fn edge_travels(&self, result: &MMResult) -> Vec<EdgeTravel> {
+        let network = &self.road_graph().network;
+        let mut travels: Vec<EdgeTravel> = vec![];
+        let mut last_to = None; // 上一段计算过的路径的终点
+        for leg in result.legs.iter() {
+            let time = |i: usize| {
+                result.matched_candidates[i]
+                    .as_ref()
+                    .map_or(0, |c| c.ori_traj_point.time_stamp)
+            };
+            let (start, end) = (time(leg.from), time(leg.to));
+            if start == 0 || end <= start {
+                last_to = None;
+                continue;
+            }
+            let total: f64 = leg.spans.iter().map(|s| s.end - s.start).sum();
+            let duration = (end - start) as f64;
+            // 和上一段路径首尾相接时 首个片段接在上一条记录后面
+            let connected = last_to == Some(leg.from);
+            let mut passed = 0.0;
+            for (i, span) in leg.spans.iter().enumerate() {
+                let length = span.end - span.start;
+                let entry_time = if total > 0.0 {
+                    start as f64 + duration * passed / total
+                } else {
+                    start as f64
+                };
+                passed += length;
+                let exit_time = if total > 0.0 {
+                    start as f64 + duration * passed / total
+                } else {
+                    end as f64
+                };
+                let edge_length = network.edges[span.edge_index].get_length();
+                match travels.last_mut() {
+                    Some(last)
+                        if i == 0
+                            && connected
+                            && last.edge_index == span.edge_index
+                            && (last.end - span.start).abs() < 1e-6 =>
+                    {
+                        last.end = span.end;
+                        last.exit_time = exit_time;
+                        last.complete = last.start <= 0.0 && last.end >= edge_length;
+                    }
+                    _ => travels.push(EdgeTravel {
+                        edge_index: span.edge_index,
+                        start: span.start,
+                        end: span.end,
+                        entry_time,
+                        exit_time,
+                        complete: span.start <= 0.0 && span.end >= edge_length,
+                    }),
+                }
+            }
+            last_to = Some(leg.to);
+        }
+        travels
+    }
+}
+
+// test
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::algorithm;
+    use crate::graph::fixture::{self, feature};
+    use crate::mm::TrajInfo;
+
+    // 1 --1--> 2 --2--> 3 --3--> 4 每条约95米
+    fn build() -> MMatch {
+        let network = fixture::geojson_network(&[
+            feature(1, 1, 2, "[[121.0,31.0],[121.001,31.0]]"),
+            feature(2, 2, 3, "[[121.001,31.0],[121.002,31.0]]"),
+            feature(3, 3, 4, "[[121.002,31.0],[121.003,31.0]]"),
+        ])
+        .unwrap();
+        fixture::matcher(network).unwrap()
+    }
+
+    #[test]
+    fn test_edge_travels() {
+        let mut mm = build();
+        let config = fixture::config();
+        // edge 1中点 -> edge 2中点 -> edge 3中点 每段用时20秒
+        let traj: Vec<TrajInfo> = [(121.0005, 100), (121.0015, 120), (121.0025, 140)]
+            .iter()
+            .map(|(x, t)| TrajInfo {
+                point: algorithm::Point(*x, 31.00001),
+                time_stamp: *t,
+            })
+            .collect();
+        let result = mm.match_traj(&traj, &config).unwrap();
+        let travels = mm.edge_travels(&result);
+        assert_eq!(travels.len(), 3);
+        let edges: Vec<usize> = travels.iter().map(|t| t.edge_index).collect();
+        assert_eq!(edges, vec![0, 1, 2]);
+
+        // 首尾edge只覆盖一半 中间的edge完整经过
+        assert!(!travels[0].complete && travels[1].complete && !travels[2].complete);
+        assert!((travels[0].entry_time - 100.0).abs() < 1e-6);
+        assert!((travels[0].exit_time - 110.0).abs() < 0.1);
+        assert!((travels[1].entry_time - 110.0).abs() < 0.1);
+        assert!((travels[1].exit_time - 130.0).abs() < 0.1);
+        assert!((travels[2].exit_time - 140.0).abs() < 1e-6);
+        // 约95米用时20秒
+        let speed = travels[1].speed().unwrap();
+        assert!((speed - travels[1].length() / 20.0).abs() < 0.1);
+        assert!((travels[1].length() - 95.4).abs() < 1.0);
+
+        // 没有时间戳时不计算
+        let untimed: Vec<TrajInfo> = traj
+            .iter()
+            .map(|p| TrajInfo {
+                point: p.point,
+                time_stamp: 0,
+            })
+            .collect();
+        let result = mm.match_traj(&untimed, &config).unwrap();
+        assert!(mm.edge_travels(&result).is_empty());
+    }
+}
@@ -0,0 +1,390 @@
+use crate::algorithm::{self, Crs};
+use crate::graph::{self, PathAlgorithm, RoutingMode};
+use crate::mm::model::{Config, MMResult};
+use crate::mm::stmatch::MMatch;
+use crate::mm::traj::{TrajInfo, Trajectory};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+
+/*
+ * @file python.rs
+ * python绑定 需要开启python feature, 使用maturin构建
+ * 路网加载后转换到wgs84, 距离单位为米。坐标可以是[[x, y], ...]的list、tuple或者numpy数组,
+ * 结果为dict, 坐标为list, 可以直接用numpy.asarray转换。匹配和路径规划期间释放GIL。
+ * Network可以在多个线程中同时使用: 匹配时从池中取一个匹配器的clone(共享路网, 只复制图的拓扑),
+ * 吸附和路径规划直接使用只读的匹配器。
+ */
+
+fn value_error<E: std::fmt::Display>(e: E) -> PyErr {
+    PyValueError::new_err(e.to_string())
+}
+
+// 逐行读取坐标 支持任意可迭代对象
+fn read_points(points: &Bound<'_, PyAny>) -> PyResult<Vec<algorithm::Point>> {
+    points
+        .try_iter()?
+        .map(|row| {
+            let row = row?;
+            let mut values = row.try_iter()?.map(|v| v?.extract::<f64>());
+            match (values.next(), values.next()) {
+                (Some(x), Some(y)) => Ok(algorithm::Point(x?, y?)),
+                _ => Err(value_error(format!("invalid point {}", row))),
+            }
+        })
+        .collect()
+}
+
+// 坐标和unix秒时间戳组成轨迹 没有时间戳时为0
+fn read_traj(coords: &Bound<'_, PyAny>, times: Option<&Bound<'_, PyAny>>) -> PyResult<Trajectory> {
+    let points = read_points(coords)?;
+    let times: Vec<u64> = match times {
+        Some(times) => times
+            .try_iter()?
+            .map(|t| t?.extract::<u64>())
+            .collect::<PyResult<_>>()?,
+        None => vec![0; points.len()],
+    };
+    if times.len() != points.len() {
+        return Err(value_error(format!(
+            "times has {} items but coords has {} points",
+            times.len(),
+            points.len()
+        )));
+    }
+    Ok(points
+        .into_iter()
+        .zip(times)
+        .map(|(point, time_stamp)| TrajInfo { point, time_stamp })
+        .collect())
+}
+
+/// 匹配参数 单位为米和米每秒
+#[pyclass(name = "Config")]
+#[derive(Clone)]
+pub struct PyConfig {
+    inner: Config,
+}
+
+#[pymethods]
+impl PyConfig {
+    #[new]
+    #[pyo3(signature = (gps_err=10.0, radius=1000.0, max_radius=None, knn=4, max_speed=30.0, factor=4.0, reverse_tolerance=50.0, path_algorithm="dijkstra"))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        gps_err: f64,
+        radius: f64,
+        max_radius: Option<f64>,
+        knn: u16,
+        max_speed: f64,
+        factor: f64,
+        reverse_tolerance: f64,
+        path_algorithm: &str,
+    ) -> PyResult<Self> {
+        let path_algorithm = match path_algorithm {
+            "dijkstra" => PathAlgorithm::Dijkstra,
+            "astar" => PathAlgorithm::AStar,
+            "bidirectional" => PathAlgorithm::Bidirectional,
+            _ => {
+                return Err(value_error(format!(
+                    "unknown path algorithm {}, expect dijkstra, astar or bidirectional",
+                    path_algorithm
+                )))
+            }
+        };
+        Ok(PyConfig {
+            inner: Config {
+                gps_err,
+                radius,
+                max_radius: max_radius.unwrap_or(radius),
+                knn,
+                v_max: max_speed,
+                factor,
+                reverse_tolerance,
+                road_netwok_path: "".to_string(),
+                metric: algorithm::Metric::Haversine,
+                routing: RoutingMode::NodeBased,
+                path_algorithm,
+            },
+        })
+    }
+}
+
+/// 加载到内存中的路网 可以重复匹配
+#[pyclass(name = "Network", frozen)]
+pub struct PyNetwork {
+    mm: MMatch,               // 只读 不加入虚拟节点
+    idle: Mutex<Vec<MMatch>>, // 空闲的匹配器
+}
+
+impl PyNetwork {
+    // 取一个空闲的匹配器 没有时clone一个 用完放回
+    fn with_matcher<T>(&self, f: impl FnOnce(&mut MMatch) -> T) -> T {
+        let idle = || self.idle.lock().unwrap_or_else(PoisonError::into_inner);
+        let mm = idle().pop();
+        let mut mm = mm.unwrap_or_else(|| self.mm.clone());
+        let result = f(&mut mm);
+        idle().push(mm);
+        result
+    }
+}
+
+#[pymethods]
+impl PyNetwork {
+    /// 读取geojson路网或者rmm build生成的快照 crs为路网的坐标系
+    #[new]
+    #[pyo3(signature = (path, crs="wgs84"))]
+    fn new(path: &str, crs: &str) -> PyResult<Self> {
+        let crs: Crs = crs.parse().map_err(value_error)?;
+        let snapshot = graph::snapshot::load(Path::new(path))
+            .and_then(|snapshot| snapshot.reproject(&crs, &Crs::Wgs84))
+            .map_err(value_error)?;
+        let mm =
+            MMatch::from_snapshot(snapshot, algorithm::Metric::Haversine).map_err(value_error)?;
+        Ok(PyNetwork {
+            mm,
+            idle: Mutex::new(vec![]),
+        })
+    }
+
+    /// 路网的edge数量
+    fn __len__(&self) -> usize {
+        self.mm.road_graph().network.edges.len()
+    }
+
+    /// 匹配一条轨迹 返回dict: edges和points与输入的点一一对应 没有匹配的点为None
+    #[pyo3(signature = (coords, times=None, config=None))]
+    fn match_traj(
+        &self,
+        py: Python<'_>,
+        coords: &Bound<'_, PyAny>,
+        times: Option<&Bound<'_, PyAny>>,
+        config: Option<PyConfig>,
+    ) -> PyResult<PyObject> {
+        let traj = read_traj(coords, times)?;
+        let config = config_or_default(config)?;
+        let result = py
+            .allow_threads(|| self.with_matcher(|mm| mm.match_traj(&traj, &config)))
+            .map_err(value_error)?;
+        result_dict(py, &result)
+    }
+
+    /// 依次匹配多条轨迹 整个批次释放GIL times中的元素可以为None
+    /// 单条轨迹出错时对应的结果为{"error": 原因} 不影响其它轨迹
+    #[pyo3(signature = (trajs, times=None, config=None))]
+    fn match_batch(
+        &self,
+        py: Python<'_>,
+        trajs: &Bound<'_, PyAny>,
+        times: Option<&Bound<'_, PyAny>>,
+        config: Option<PyConfig>,
+    ) -> PyResult<Vec<PyObject>> {
+        let coords: Vec<Bound<'_, PyAny>> = trajs.try_iter()?.collect::<PyResult<_>>()?;
+        let times: Vec<Option<Bound<'_, PyAny>>> = match times {
+            Some(times) => times
+                .try_iter()?
+                .map(|t| t.map(|t| (!t.is_none()).then_some(t)))
+                .collect::<PyResult<_>>()?,
+            None => vec![None; coords.len()],
+        };
+        if times.len() != coords.len() {
+            return Err(value_error(format!(
+                "times has {} items but trajs has {}",
+                times.len(),
+                coords.len()
+            )));
+        }
+        // 读取失败的轨迹直接作为错误 不参与匹配
+        let trajs: Vec<Result<Trajectory, String>> = coords
+            .iter()
+            .zip(times.iter())
+            .map(|(coords, times)| {
+                read_traj(coords, times.as_ref()).map_err(|e| e.value(py).to_string())
+            })
+            .collect();
+        let config = config_or_default(config)?;
+        let results: Vec<Result<MMResult, String>> = py.allow_threads(|| {
+            self.with_matcher(|mm| {
+                trajs
+                    .into_iter()
+                    .map(|traj| mm.match_traj(&traj?, &config).map_err(|e| e.to_string()))
+                    .collect()
+            })
+        });
+        results
+            .iter()
+            .map(|result| match result {
+                Ok(result) => result_dict(py, result),
+                Err(e) => error_dict(py, e),
+            })
+            .collect()
+    }
+
+    /// 每个坐标半径radius米内最近的k个投影 返回list[list[dict]]
+    #[pyo3(signature = (points, radius=50.0, k=1))]
+    fn snap(
+        &self,
+        py: Python<'_>,
+        points: &Bound<'_, PyAny>,
+        radius: f64,
+        k: usize,
+    ) -> PyResult<Vec<Vec<PyObject>>> {
+        let points = read_points(points)?;
+        let mm = &self.mm;
+        let snaps: Vec<_> = py.allow_threads(|| {
+            points
+                .iter()
+                .map(|point| mm.snap(point, radius, k))
+                .collect()
+        });
+        snaps
+            .iter()
+            .map(|snaps| {
+                snaps
+                    .iter()
+                    .map(|s| {
+                        let dict = PyDict::new(py);
+                        dict.set_item("edge_id", &s.edge_id)?;
+                        dict.set_item("point", (s.point.0, s.point.1))?;
+                        dict.set_item("distance", s.distance)?;
+                        dict.set_item("offset", s.offset)?;
+                        dict.set_item("fraction", s.fraction)?;
+                        Ok(dict.into_any().unbind())
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// 依次经过所有坐标的最短路径 返回dict: edges, length, geometry
+    #[pyo3(signature = (points, radius=50.0))]
+    fn route(&self, py: Python<'_>, points: &Bound<'_, PyAny>, radius: f64) -> PyResult<PyObject> {
+        let points = read_points(points)?;
+        let mm = &self.mm;
+        let route = py
+            .allow_threads(|| mm.route(&points, radius))
+            .map_err(value_error)?;
+        let network = &self.mm.road_graph().network;
+        let edges: Vec<String> = route
+            .edges
+            .iter()
+            .map(|e| network.edges[*e].get_edge_id())
+            .collect();
+        let dict = PyDict::new(py);
+        dict.set_item("edges", edges)?;
+        dict.set_item("length", route.length)?;
+        dict.set_item("geometry", route.geometry)?;
+        Ok(dict.into_any().unbind())
+    }
+}
+
+fn config_or_default(config: Option<PyConfig>) -> PyResult<Config> {
+    match config {
+        Some(config) => Ok(config.inner),
+        None => PyConfig::new(10.0, 1000.0, None, 4, 30.0, 4.0, 50.0, "dijkstra").map(|c| c.inner),
+    }
+}
+
+fn result_dict(py: Python<'_>, result: &MMResult) -> PyResult<PyObject> {
+    let edges: Vec<Option<&String>> = result
+        .o_path
+        .iter()
+        .map(|id| (!id.is_empty()).then_some(id))
+        .collect();
+    let points: Vec<Option<(f64, f64)>> = result
+        .matched_candidates
+        .iter()
+        .map(|c| c.as_ref().map(|c| (c.closest_point.0, c.closest_point.1)))
+        .collect();
+    let dict = PyDict::new(py);
+    dict.set_item("edges", edges)?;
+    dict.set_item("points", points)?;
+    dict.set_item("cpath", &result.cpath)?;
+    dict.set_item(
+        "length",
+        result.legs.iter().map(|leg| leg.length).sum::<f64>(),
+    )?;
+    Ok(dict.into_any().unbind())
+}
+
+fn error_dict(py: Python<'_>, error: &str) -> PyResult<PyObject> {
+    let dict = PyDict::new(py);
+    dict.set_item("error", error)?;
+    Ok(dict.into_any().unbind())
+}
+
+/// python模块 import rmm
+#[pymodule]
+fn rmm(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyConfig>()?;
+    m.add_class::<PyNetwork>()?;
+    Ok(())
+}
+
+// test
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graph::{fixture, snapshot::Snapshot};
+    use pyo3::ffi::c_str;
+
+    #[test]
+    fn test_python_network() {
+        let snapshot = Snapshot::new(fixture::grid(4, 100.0).unwrap()).unwrap();
+        let path = std::env::temp_dir().join(format!("rmm-python-{}.rmm", std::process::id()));
+        graph::snapshot::write(&path, &snapshot).unwrap();
+        let coords: Vec<[f64; 2]> = [(30.0, 5.0), (150.0, -5.0), (250.0, 5.0)]
+            .iter()
+            .map(|(x, y)| fixture::coord(*x, *y))
+            .collect();
+
+        pyo3::prepare_freethreaded_python();
+        let result = Python::with_gil(|py| -> PyResult<()> {
+            let module = PyModule::new(py, "rmm")?;
+            rmm(&module)?;
+            let globals = PyDict::new(py);
+            globals.set_item("rmm", module)?;
+            globals.set_item("path", path.to_str())?;
+            globals.set_item("coords", coords)?;
+            // 匹配释放GIL时其它线程同时匹配、吸附和规划路径
+            py.run(
+                c_str!(
+                    r#"
+import threading
+net = rmm.Network(path)
+cfg = rmm.Config(radius=50.0)
+assert len(net) == 48
+result = net.match_traj(coords, times=[100, 110, 120], config=cfg)
+assert result["edges"] == ["0_0>1_0", "1_0>2_0", "2_0>3_0"], result
+assert result["cpath"] == result["edges"]
+batch = net.match_batch([coords, [[0.0, 0.0]]], config=cfg)
+assert batch[0]["edges"] == result["edges"] and "error" not in batch[0]
+assert batch[1]["edges"] == [None]
+
+errors = []
+def work():
+    try:
+        for _ in range(20):
+            assert net.match_traj(coords, config=cfg)["edges"] == result["edges"]
+            assert len(net.snap(coords, k=2)[0]) == 2
+            assert len(net.route([coords[0], coords[2]])["edges"]) == 3
+    except Exception as e:
+        errors.append(e)
+threads = [threading.Thread(target=work) for _ in range(4)]
+for t in threads:
+    t.start()
+for t in threads:
+    t.join()
+assert not errors, errors
+"#
+                ),
+                Some(&globals),
+                None,
+            )
+        });
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+    }
+}
@@ -0,0 +1,399 @@
+use crate::algorithm::{self, Crs};
+use crate::mm::model::Config;
+use crate::mm::preprocess::{preprocess, PreprocessConfig};
+use crate::mm::stmatch::MMatch;
+use crate::mm::traj::MutileTrajectory;
+use anyhow::Result;
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread;
+
+/*
+ * @file server.rs
+ * 本地HTTP匹配服务
+ * 路网只加载一次, 常驻内存响应请求。匹配时会在图中加入虚拟节点, 所以每个工作线程持有一份匹配器的clone,
+ * edge、空间索引、CH和转向模型在线程间共享, 每个线程只复制图的拓扑。线程数即并发数。
+ * 请求和响应都是JSON, 坐标为crs, 匹配在working_crs下进行, 距离的单位和路网长度一致。
+ */
+
+/// 服务配置
+#[derive(Clone)]
+pub struct ServerConfig {
+    pub bind: String,                 // 监听地址 例如127.0.0.1:8080
+    pub matching: Config,             // 匹配参数
+    pub preprocess: PreprocessConfig, // 匹配前的轨迹预处理
+    pub snap_radius: f64,             // 吸附和路径规划默认的查询半径
+    pub crs: Crs,                     // 请求和响应中坐标的坐标系
+    pub working_crs: Crs,             // 路网所在的坐标系
+}
+
+impl ServerConfig {
+    // 请求中的坐标转换到路网坐标系
+    fn request_point(&self, p: &algorithm::Point) -> algorithm::Point {
+        self.crs.transform(p, &self.working_crs)
+    }
+
+    // 路网坐标系中的坐标转换到响应的坐标系
+    fn response_point(&self, p: &algorithm::Point) -> [f64; 2] {
+        let p = self.working_crs.transform(p, &self.crs);
+        [p.0, p.1]
+    }
+
+    fn response_line(&self, line: &[[f64; 2]]) -> Vec<[f64; 2]> {
+        line.iter()
+            .map(|p| self.response_point(&algorithm::Point(p[0], p[1])))
+            .collect()
+    }
+}
+
+// 请求的错误 (状态码, 信息)
+type Failure = (u16, String);
+
+fn bad_request<E: std::fmt::Display>(e: E) -> Failure {
+    (400, e.to_string())
+}
+
+/// 处理一个请求 返回状态码和响应 不涉及网络 方便测试
+pub fn handle(
+    mm: &mut MMatch,
+    cfg: &ServerConfig,
+    method: &str,
+    url: &str,
+    body: &str,
+) -> (u16, Value) {
+    let path = url.split('?').next().unwrap_or("");
+    let result = match (method, path) {
+        ("GET", "/health") => Ok(health(mm)),
+        ("POST", "/match") => match_trajectory(mm, cfg, body),
+        ("POST", "/snap") => snap(mm, cfg, body),
+        ("POST", "/route") => route(mm, cfg, body),
+        (_, "/health" | "/match" | "/snap" | "/route") => {
+            Err((405, format!("method {} not allowed", method)))
+        }
+        _ => Err((404, format!("{} not found", path))),
+    };
+    match result {
+        Ok(value) => (200, value),
+        Err((status, message)) => (status, json!({ "error": message })),
+    }
+}
+
+// 处理请求时panic返回500 工作线程继续处理之后的请求
+// 匹配开始时会清理上一次的虚拟节点 匹配器可以继续使用
+fn catch_panic<F: FnOnce() -> (u16, Value)>(f: F) -> (u16, Value) {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|e| {
+        let message = e
+            .downcast_ref::<&str>()
+            .map(|m| m.to_string())
+            .or_else(|| e.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        warn!("request panicked: {}", message);
+        (
+            500,
+            json!({ "error": format!("internal error: {}", message) }),
+        )
+    })
+}
+
+fn health(mm: &MMatch) -> Value {
+    let network = &mm.road_graph().network;
+    json!({
+        "status": "ok",
+        "edges": network.edges.len(),
+    })
+}
+
+// 请求体为一条轨迹的geojson Feature或者Geometry properties中可以带timestamps
+fn match_trajectory(mm: &mut MMatch, cfg: &ServerConfig, body: &str) -> Result<Value, Failure> {
+    let geojson: geojson::GeoJson = body.parse().map_err(bad_request)?;
+    if let geojson::GeoJson::FeatureCollection(_) = geojson {
+        return Err((
+            400,
+            "post one trajectory as a feature or geometry".to_string(),
+        ));
+    }
+    let mut traj = MutileTrajectory::try_from(geojson)
+        .map_err(bad_request)?
+        .trajs
+        .into_iter()
+        .next()
+        .ok_or_else(|| bad_request("trajectory is empty"))?;
+    for info in traj.iter_mut() {
+        info.point = cfg.request_point(&info.point);
+    }
+    let cleaned = preprocess(&traj, cfg.matching.metric, &cfg.preprocess);
+    let result = mm
+        .match_traj(&cleaned.traj, &cfg.matching)
+        .map_err(bad_request)?;
+    let geometry: Vec<Vec<[f64; 2]>> = mm
+        .matched_geometry(&result)
+        .map_err(|e| (500, e.to_string()))?
+        .iter()
+        .map(|line| cfg.response_line(line))
+        .collect();
+    let geometry = match geometry.len() {
+        1 => json!({ "type": "LineString", "coordinates": geometry[0] }),
+        _ => json!({ "type": "MultiLineString", "coordinates": geometry }),
+    };
+    // 和请求中的点一一对应 预处理删除或者没有匹配的点为null
+    let mut edges: Vec<Option<&String>> = vec![None; traj.len()];
+    let mut points: Vec<Option<[f64; 2]>> = vec![None; traj.len()];
+    for (i, candidate) in result.matched_candidates.iter().enumerate() {
+        if let Some(candidate) = candidate {
+            let index = cleaned.original_index(i);
+            edges[index] = Some(&result.o_path[i]);
+            points[index] = Some(cfg.response_point(&candidate.closest_point));
+        }
+    }
+    Ok(json!({
+        "edges": edges,
+        "cpath": result.cpath,
+        "points": points,
+        "length": result.legs.iter().map(|leg| leg.length).sum::<f64>(),
+        "geometry": geometry,
+    }))
+}
+
+// 请求中的坐标数组 [[x, y], ...] 转换到路网坐标系
+fn read_points(cfg: &ServerConfig, request: &Value) -> Result<Vec<algorithm::Point>, Failure> {
+    request["points"]
+        .as_array()
+        .ok_or_else(|| bad_request("points should be an array of [x, y]"))?
+        .iter()
+        .map(|p| match p.as_array().map(|p| p.as_slice()) {
+            Some([x, y, ..]) => match (x.as_f64(), y.as_f64()) {
+                (Some(x), Some(y)) => Ok(cfg.request_point(&algorithm::Point(x, y))),
+                _ => Err(bad_request(format!("invalid point {}", p))),
+            },
+            _ => Err(bad_request(format!("invalid point {}", p))),
+        })
+        .collect()
+}
+
+// {"points": [[x, y], ...], "k": 1, "radius": 50} 每个坐标返回最近的k个投影
+fn snap(mm: &MMatch, cfg: &ServerConfig, body: &str) -> Result<Value, Failure> {
+    let request: Value = serde_json::from_str(body).map_err(bad_request)?;
+    let points = read_points(cfg, &request)?;
+    let k = request["k"].as_u64().unwrap_or(1) as usize;
+    let radius = request["radius"].as_f64().unwrap_or(cfg.snap_radius);
+    let snaps: Vec<Value> = points
+        .iter()
+        .map(|point| {
+            mm.snap(point, radius, k)
+                .iter()
+                .map(|s| {
+                    json!({
+                        "edge_id": s.edge_id,
+                        "point": cfg.response_point(&s.point),
+                        "distance": s.distance,
+                        "offset": s.offset,
+                        "fraction": s.fraction,
+                    })
+                })
+                .collect()
+        })
+        .collect();
+    Ok(json!({ "snaps": snaps }))
+}
+
+// {"points": [[x, y], ...], "radius": 1000} 依次经过所有坐标的最短路径
+fn route(mm: &MMatch, cfg: &ServerConfig, body: &str) -> Result<Value, Failure> {
+    let request: Value = serde_json::from_str(body).map_err(bad_request)?;
+    let points = read_points(cfg, &request)?;
+    let radius = request["radius"].as_f64().unwrap_or(cfg.snap_radius);
+    let route = mm.route(&points, radius).map_err(bad_request)?;
+    let network = &mm.road_graph().network;
+    let edges: Vec<String> = route
+        .edges
+        .iter()
+        .map(|e| network.edges[*e].get_edge_id())
+        .collect();
+    Ok(json!({
+        "edges": edges,
+        "length": route.length,
+        "geometry": { "type": "LineString", "coordinates": cfg.response_line(&route.geometry) },
+    }))
+}
+
+/// 启动服务 workers个工作线程 阻塞直到所有线程退出
+pub fn serve(mm: MMatch, workers: usize, cfg: &ServerConfig) -> Result<()> {
+    let server = tiny_http::Server::http(&cfg.bind)
+        .map_err(|e| anyhow::anyhow!("listen on {} failed: {}", cfg.bind, e))?;
+    let server = Arc::new(server);
+    let workers = workers.max(1);
+    info!("serve on http://{} with {} workers", cfg.bind, workers);
+    let workers: Vec<thread::JoinHandle<()>> = (0..workers)
+        .map(|_| {
+            let mut mm = mm.clone();
+            let server = server.clone();
+            let cfg = cfg.clone();
+            thread::spawn(move || loop {
+                let mut request = match server.recv() {
+                    Ok(request) => request,
+                    Err(e) => {
+                        warn!("receive request failed: {}", e);
+                        break;
+                    }
+                };
+                let mut body = String::new();
+                let (status, value) = match request.as_reader().read_to_string(&mut body) {
+                    Ok(_) => catch_panic(|| {
+                        handle(
+                            &mut mm,
+                            &cfg,
+                            request.method().as_str(),
+                            request.url(),
+                            &body,
+                        )
+                    }),
+                    Err(e) => (400, json!({ "error": e.to_string() })),
+                };
+                debug!("{} {} {}", request.method(), request.url(), status);
+                let header = tiny_http::Header::from_bytes("Content-Type", "application/json")
+                    .expect("invalid header");
+                let response = tiny_http::Response::from_string(value.to_string())
+                    .with_status_code(status)
+                    .with_header(header);
+                if let Err(e) = request.respond(response) {
+                    warn!("send response failed: {}", e);
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker
+            .join()
+            .map_err(|_| anyhow::anyhow!("worker thread panicked"))?;
+    }
+    Ok(())
+}
+
+// test
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graph::{fixture, RoutingMode, TurnCost, TurnModel, TurnRestrictions};
+
+    #[test]
+    fn test_handle() {
+        let mut mm = fixture::matcher(fixture::grid(4, 100.0).unwrap()).unwrap();
+        let cfg = ServerConfig {
+            bind: "127.0.0.1:0".to_string(),
+            matching: fixture::config(),
+            preprocess: PreprocessConfig::default(),
+            snap_radius: 50.0,
+            crs: Crs::Wgs84,
+            working_crs: Crs::Wgs84,
+        };
+        let coords = |points: &[(f64, f64)]| -> Vec<[f64; 2]> {
+            points.iter().map(|(x, y)| fixture::coord(*x, *y)).collect()
+        };
+
+        let (status, value) = handle(&mut mm, &cfg, "GET", "/health", "");
+        assert_eq!((status, value["edges"].as_u64()), (200, Some(48)));
+
+        // 向东经过两条edge 最后一个点离路网太远
+        let body = json!({
+            "type": "Feature",
+            "geometry": {
+                "type": "LineString",
+                "coordinates": coords(&[(30.0, 5.0), (150.0, -5.0), (150.0, 400.0)]),
+            },
+            "properties": { "timestamps": [100, 110, 120] },
+        });
+        let (status, value) = handle(&mut mm, &cfg, "POST", "/match", &body.to_string());
+        assert_eq!(status, 200);
+        assert_eq!(value["edges"], json!(["0_0>1_0", "1_0>2_0", null]));
+        assert_eq!(value["cpath"], json!(["0_0>1_0", "1_0>2_0"]));
+        assert_eq!(value["geometry"]["type"], "LineString");
+        assert!((value["length"].as_f64().unwrap() - 120.0).abs() < 0.5);
+        assert!(value["points"][2].is_null());
+        // 多次匹配不会让图不断增长
+        let nodes = mm.road_graph().network.nodes.len();
+        handle(&mut mm, &cfg, "POST", "/match", &body.to_string());
+        assert_eq!(mm.road_graph().network.nodes.len(), nodes);
+        // 工作线程的clone共享edge 虚拟节点只加入各自的图
+        let mut worker = mm.clone();
+        let (network, other) = (&mm.road_graph().network, &worker.road_graph().network);
+        assert!(Arc::ptr_eq(&network.edges, &other.edges));
+        let (status, _) = handle(&mut worker, &cfg, "POST", "/match", &body.to_string());
+        assert_eq!(status, 200);
+        assert_eq!(mm.road_graph().network.nodes.len(), nodes);
+
+        let body = json!({ "points": coords(&[(50.0, 3.0)]), "k": 2 }).to_string();
+        let (status, value) = handle(&mut mm, &cfg, "POST", "/snap", &body);
+        assert_eq!(status, 200);
+        assert_eq!(value["snaps"][0].as_array().unwrap().len(), 2);
+        assert!((value["snaps"][0][0]["distance"].as_f64().unwrap() - 3.0).abs() < 0.1);
+
+        let body = json!({ "points": coords(&[(50.0, 3.0), (250.0, -3.0)]) }).to_string();
+        let (status, value) = handle(&mut mm, &cfg, "POST", "/route", &body);
+        assert_eq!(status, 200);
+        assert_eq!(value["edges"], json!(["0_0>1_0", "1_0>2_0", "2_0>3_0"]));
+
+        assert_eq!(handle(&mut mm, &cfg, "POST", "/match", "{").0, 400);
+        assert_eq!(
+            handle(&mut mm, &cfg, "POST", "/route", r#"{"points": [1]}"#).0,
+            400
+        );
+        assert_eq!(handle(&mut mm, &cfg, "GET", "/match", "").0, 405);
+        assert_eq!(handle(&mut mm, &cfg, "GET", "/nothing", "").0, 404);
+
+        // panic返回500 之后的请求不受影响
+        let (status, value) = catch_panic(|| panic!("broken request"));
+        assert_eq!(status, 500);
+        assert!(value["error"].as_str().unwrap().contains("broken request"));
+        assert_eq!(
+            catch_panic(|| handle(&mut mm, &cfg, "GET", "/health", "")).0,
+            200
+        );
+    }
+
+    #[test]
+    fn test_handle_routing_and_crs() {
+        // 1_0>2_0 禁止左转进入2_0>2_1 基于edge的路径需要绕行 请求和响应的坐标为gcj02
+        let mut mm = fixture::matcher(fixture::grid(4, 100.0).unwrap()).unwrap();
+        let network = &mm.road_graph().network;
+        let index = |id: &str| network.find_edge_index(id).unwrap();
+        let mut restrictions = TurnRestrictions::default();
+        restrictions
+            .add(index("1_0>2_0"), index("2_0>2_1"), "no_left_turn")
+            .unwrap();
+        mm.set_turn_model(TurnModel::new(restrictions, TurnCost::default()));
+        let cfg = ServerConfig {
+            bind: "127.0.0.1:0".to_string(),
+            matching: Config {
+                routing: RoutingMode::EdgeBased,
+                ..fixture::config()
+            },
+            preprocess: PreprocessConfig::default(),
+            snap_radius: 50.0,
+            crs: Crs::Gcj02,
+            working_crs: Crs::Wgs84,
+        };
+        let gcj02 = |x: f64, y: f64| {
+            let p = fixture::coord(x, y);
+            Crs::Wgs84.transform(&algorithm::Point(p[0], p[1]), &Crs::Gcj02)
+        };
+        let coords: Vec<[f64; 2]> = [(50.0, -5.0), (150.0, -5.0), (195.0, 150.0)]
+            .iter()
+            .map(|(x, y)| {
+                let p = gcj02(*x, *y);
+                [p.0, p.1]
+            })
+            .collect();
+        let body = json!({ "type": "LineString", "coordinates": coords }).to_string();
+        let (status, value) = handle(&mut mm, &cfg, "POST", "/match", &body);
+        assert_eq!(status, 200);
+        assert_eq!(
+            value["cpath"],
+            json!(["0_0>1_0", "1_0>2_0", "2_0>3_0", "3_0>3_1", "3_1>2_1", "2_1>2_2"])
+        );
+        let expected = gcj02(50.0, 0.0);
+        let point = &value["points"][0];
+        assert!((point[0].as_f64().unwrap() - expected.0).abs() < 1e-6);
+        assert!((point[1].as_f64().unwrap() - expected.1).abs() < 1e-6);
+    }
+}
@@ -1,9 +1,14 @@
 pub fn log_init() {
+    log_init_to(env_logger::Target::Stdout);
+}
+
+/// 日志输出到指定的目标 stdout用于输出结果时日志写到stderr
+pub fn log_init_to(target: env_logger::Target) {
     use std::io::Write;
     // env_logger 通过环境变量 RUST_LOG 控制日志输出
     std::env::set_var("RUST_LOG", "debug");
     env_logger::builder()
-        .target(env_logger::Target::Stdout)
+        .target(target)
         .format(|buf, record| writeln!(buf, "{}: {}", record.level(), record.args()))
         .init();
     // log::set_max_level(log::LevelFilter::Debug);